@@ -28,62 +28,131 @@ create table if not exists staging.mpesa_statement (
             initiation_time text not null,
             details text not null,
             transaction_status text not null,
-            paid_in double precision,
-            withdrawn double precision,
-            balance double precision,
+            paid_in numeric,
+            withdrawn numeric,
+            balance numeric,
             balance_confirmed bool,
             reason_type text,
             other_party_info text,
             linked_transaction_id text,
-            ac_no text
+            ac_no text,
+            shortcode text
         );
 
 create table if not exists production.mpesa_statement (
             receipt_no text not null,
-            completion_time timestamp not null,
-            initiation_time timestamp not null,
+            completion_time timestamptz not null,
+            initiation_time timestamptz not null,
             details text not null,
             transaction_status text not null,
-            paid_in double precision,
-            withdrawn double precision,
-            balance double precision,
+            paid_in numeric,
+            withdrawn numeric,
+            balance numeric,
             balance_confirmed bool,
             reason_type text,
             other_party_info text,
             linked_transaction_id text,
-            ac_no text
+            ac_no text,
+            -- The paybill or till the statement belongs to
+            shortcode text,
+            -- daraja until a statement upload with the same receipt_no replaces the row
+            source text not null default 'statement' check (source in ('statement', 'daraja')),
+            id bigint generated always as identity primary key
         );
+
+create index if not exists mpesa_statement_receipt_no on production.mpesa_statement (receipt_no);
+
+--- Units
+
+-- Branches. unit_name is the name the HIS records on collection receipts
+create table if not exists production.units (
+	unit_name text primary key,
+	description text
+);
+
+-- The units a user may read. Admins see every unit
+create table if not exists production.user_units (
+	username text not null,
+	unit_name text not null references production.units (unit_name) on delete cascade,
+	primary key (username, unit_name)
+);
+
+-- The unit a bank or wallet account collects for. Accounts without a row belong to head office
+create table if not exists production.account_units (
+	account text primary key,
+	unit_name text not null references production.units (unit_name) on delete cascade
+);
+
+-- The unit (branch) each paybill or till collects for. Payments to a mapped shortcode are only
+-- matched to that unit's collection receipts
+create table if not exists production.mpesa_shortcodes (
+	shortcode text primary key,
+	unit_name text not null references production.units (unit_name),
+	description text
+);
+
+-- The unit a statement row belongs to: the unit of its M-Pesa shortcode, else the unit of its
+-- account. Null for rows of head office accounts
+create or replace function production.statement_unit(account text, shortcode text) returns text as $$
+	select
+		coalesce(
+		(
+		select
+			s.unit_name
+		from
+			production.mpesa_shortcodes s
+		where
+			s.shortcode = statement_unit.shortcode),
+		(
+		select
+			a.unit_name
+		from
+			production.account_units a
+		where
+			a.account = statement_unit.account))
+$$ language sql stable;
+
+-- Raw Daraja callbacks as received, for audit and replay
+create table if not exists production.daraja_callbacks (
+	id bigint generated always as identity primary key,
+	kind text not null,
+	receipt_no text,
+	payload jsonb not null,
+	accepted bool not null,
+	received_at timestamptz not null default now()
+);
+
 -- Collection Details 
 create table staging.collection_details (
 	receipt_no text not null,
 	receipt_date text null,
 	patient_name text null,
 	payee text null,
-	cash double precision null,
-	cheque double precision null,
-	card double precision,
+	cash numeric null,
+	cheque numeric null,
+	card numeric,
 	card_no text null,
-	mpesa double precision null,
-	e_transfer double precision null,
+	mpesa numeric null,
+	e_transfer numeric null,
 	transaction_no text null,
-	adv_used double precision null,
+	adv_used numeric null,
 	employee_name text null,
 	unit_name text null
 );
 
 create table if not exists production.collection_details (
 	receipt_no text not null,
-	receipt_date timestamp null,
+	receipt_date timestamptz null,
 	patient_name text null,
 	payee text null,
-	cash double precision null,
-	cheque double precision null,
-	card double precision not null,
+	cash numeric null,
+	cheque numeric null,
+	card numeric not null,
 	card_no text null,
-	mpesa double precision null,
-	e_transfer double precision null,
+	mpesa numeric null,
+	e_transfer numeric null,
 	transaction_no varchar(30) null,
-	adv_used double precision null,
+	adv_used numeric null,
 	employee_name text null,
 	unit_name text null
 );
@@ -100,11 +169,11 @@ create table staging.bill_details (
 	payee text null,
 	service_name text null,
 	quantity double precision null,
-	rate_per_unit double precision null,
-	discount double precision null,
-	gross double precision null,
-	paid_amount double precision null,
-	outstanding double precision null,
+	rate_per_unit numeric null,
+	discount numeric null,
+	gross numeric null,
+	paid_amount numeric null,
+	outstanding numeric null,
 	service_doctor text null,
 	department text null,
 	consulting_doctor text null,
@@ -115,7 +184,7 @@ create table staging.bill_details (
 );
 
 create table production.bill_details (
-	bill_date timestamp null,
+	bill_date timestamptz null,
 	bill_no text null,
 	skypeid text null,
 	uhid text null,
@@ -124,17 +193,18 @@ create table production.bill_details (
 	payee text null,
 	service_name text null,
 	quantity int null,
-	rate_per_unit double precision null,
-	discount double precision null,
-	gross double precision null,
-	paid_amount double precision null,
-	outstanding double precision null,
+	rate_per_unit numeric null,
+	discount numeric null,
+	gross numeric null,
+	paid_amount numeric null,
+	outstanding numeric null,
 	service_doctor text null,
 	department text null,
 	consulting_doctor text null,
 	referring_doctor text null,
 	servicing_doctor text null,
-	payment_mode text null
+	payment_mode text null,
+	unit text null
 );
 
 
@@ -150,7 +220,8 @@ create table if not exists staging.lab_visits (
 	phone_number text,
 	sample_date text,
 	result text,
-	email_address text
+	email_address text,
+	unit_name text
 );
 
 create table production.lab_visits (
@@ -161,9 +232,10 @@ create table production.lab_visits (
 	age_unit text null,
 	gender text null,
 	phone_number text null,
-	sample_date timestamp null,
+	sample_date timestamptz null,
 	result text null,
 	email_address text null,
+	unit_name text null,
 	constraint lab_visits_pkey primary key (sample_number)
 );
 
@@ -175,17 +247,19 @@ create table if not exists staging.registered_patients (
 	age text,
 	gender text,
 	address text,
-	contact_no text
+	contact_no text,
+	unit_name text
 );
 
 create table if not exists production.registered_patients (
 	uhid text null,
-	"date" timestamp null,
+	"date" timestamptz null,
 	patient_name text null,
 	age text null,
 	gender text null,
 	address text null,
-	contact_no text null
+	contact_no text null,
+	unit_name text null
 );
 
 --- Mtiba Statement table definitions
@@ -200,9 +274,9 @@ create table if not exists staging.mtiba_statement (
 	payer_name text,
 	sender_name text,
 	medical_program_name text,
-	amount_for_display double precision,
-	transaction_date text,
-	payment_date text,
+	amount_for_display numeric,
+	transaction_date timestamp,
+	payment_date timestamp,
 	transaction_type text
 );
 
@@ -216,9 +290,9 @@ create table production.mtiba_statement (
 	payername text null,
 	sendername text null,
 	medicalprogramname text null,
-	amountfordisplay double precision null,
-	transactiondate timestamp null,
-	paymentdate timestamp null,
+	amountfordisplay numeric null,
+	transactiondate timestamptz null,
+	paymentdate timestamptz null,
 	transactiontype text null
 );
 
@@ -230,21 +304,24 @@ create table if not exists staging.absa (
 	description text,
 	user_reference_number text,
 	cheque_number int,
-	debit_amount double precision,
-	credit_amount double precision,
-	running_balance double precision
+	debit_amount numeric,
+	credit_amount numeric,
+	running_balance numeric,
+	currency text
 );
 
 
 create table if not exists production.absa_statement (
-	transaction_date timestamp,
-	value_date timestamp,
+	transaction_date timestamptz,
+	value_date timestamptz,
 	description text,
 	user_reference_number text null,
 	cheque_number int null,
-	debit_amount double precision null,
-	credit_amount double precision null,
-	running_balance double precision null);
+	debit_amount numeric null,
+	credit_amount numeric null,
+	running_balance numeric null,
+	currency text not null default 'KES',
+	id bigint generated always as identity primary key);
 
 
 --- Pdq Breakdowns table definitions
@@ -259,9 +336,9 @@ create table if not exists staging.pdq_breakdowns (
 	payment_date text,
 	terminal_id int,
 	auth_id text,
-	amount double precision,
-	commission double precision,
-	net_amount double precision,
+	amount numeric,
+	commission numeric,
+	net_amount numeric,
 	trxn_type text,
 	currency text,
 	pmnt_type text,
@@ -270,7 +347,7 @@ create table if not exists staging.pdq_breakdowns (
 	commercial_name text,
 	arn_reference text,
 	retrieval_ref_no text,
-	tip_amount double precision,
+	tip_amount numeric,
 	card_present text
 );
 
@@ -279,14 +356,14 @@ create table if not exists production.pdq_breakdowns (
 	location_no int null,
 	legal_name text null,
 	card_no text null,
-	txn_date timestamp null,
-	processing_date timestamp null,
-	payment_date timestamp null,
+	txn_date timestamptz null,
+	processing_date timestamptz null,
+	payment_date timestamptz null,
 	terminal_id int4 null,
 	auth_id text null,
-	amount double precision null,
-	commission double precision null,
-	net_amount double precision null,
+	amount numeric null,
+	commission numeric null,
+	net_amount numeric null,
 	trxn_type text null,
 	currency text null,
 	pmnt_type text null,
@@ -295,8 +372,9 @@ create table if not exists production.pdq_breakdowns (
 	commercial_name text null,
 	arn_reference text null,
 	retrieval_ref_no text null,
-	tip_amount double precision null,
-	card_present text null
+	tip_amount numeric null,
+	card_present text null,
+	id bigint generated always as identity primary key
 );
 
 
@@ -307,42 +385,781 @@ create table if not exists staging.sidian_statement (
 	reference text,
 	narration text,
 	chequenumber int,
-	debit double precision,
-	credit double precision,
-	balance double precision
+	debit numeric,
+	credit numeric,
+	balance numeric,
+	currency text
 );
 
 create table if not exists production.sidian_statement (
-	date timestamp,
-	valuedate timestamp null,
+	date timestamptz,
+	valuedate timestamptz null,
 	reference text null,
 	narration text null,
 	chequenumber int null,
-	debit double precision null,
-	credit double precision null,
-	balance double precision
+	debit numeric null,
+	credit numeric null,
+	balance numeric,
+	currency text not null default 'KES',
+	id bigint generated always as identity primary key
 );
 
 --- CFC Bank table definitions
 
 create table if not exists staging.cfc_statement (
-	date text,
+	date timestamp,
 	transaction text,
-	value_date text,
-	debit double precision,
-	credit double precision,
-	ledger_balance double precision,
-	available_balance double precision
+	value_date timestamp,
+	debit numeric,
+	credit numeric,
+	ledger_balance numeric,
+	available_balance numeric,
+	currency text
 );
 
 create table if not exists production.cfc_statement (
-	date timestamp,
+	date timestamptz,
 	transaction text,
+	value_date timestamptz,
+	debit numeric,
+	credit numeric,
+	ledger_balance numeric,
+	available_balance numeric,
+	currency text not null default 'KES',
+	id bigint generated always as identity primary key
+);
+
+--- Equity, KCB, Co-operative Bank and NCBA table definitions
+-- The four banks share one layout. bank tells their rows apart
+
+create table if not exists staging.bank_statements (
+	bank text not null,
+	transaction_date timestamp not null,
 	value_date timestamp,
-	debit double precision,
-	credit double precision,
-	ledger_balance double precision,
-	available_balance double precision
+	narration text,
+	reference text,
+	debit numeric,
+	credit numeric,
+	balance numeric,
+	currency text
+);
+
+create table if not exists production.bank_statements (
+	bank text not null check (bank in ('equity', 'kcb', 'coop', 'ncba')),
+	transaction_date timestamptz not null,
+	value_date timestamptz,
+	narration text,
+	reference text,
+	debit numeric,
+	credit numeric,
+	balance numeric,
+	currency text not null default 'KES',
+	id bigint generated always as identity primary key
+);
+
+create index if not exists bank_statements_bank_date on production.bank_statements (bank, transaction_date);
+
+--- Airtel Money table definitions
+
+create table if not exists staging.airtel_statement (
+	transaction_id text not null,
+	transaction_time timestamp not null,
+	transaction_type text not null,
+	transaction_status text not null,
+	sender_msisdn text,
+	sender_name text,
+	paid_in numeric,
+	withdrawn numeric,
+	balance numeric,
+	linked_transaction_id text
+);
+
+create table if not exists production.airtel_statement (
+	transaction_id text not null,
+	transaction_time timestamptz not null,
+	transaction_type text not null,
+	transaction_status text not null,
+	sender_msisdn text,
+	sender_name text,
+	paid_in numeric,
+	withdrawn numeric,
+	balance numeric,
+	linked_transaction_id text,
+	id bigint generated always as identity primary key
+);
+
+create unique index if not exists airtel_statement_transaction_id on production.airtel_statement (transaction_id);
+
+--- Reconciliation decisions
+
+-- Staff decisions on matching a collection receipt (billing number) to a statement row.
+-- Decisions are never deleted. Reversing one stamps reversed_at so the history stays attributed
+create table if not exists production.reconciliation_decisions (
+	id bigint generated always as identity primary key,
+	billing_number text not null,
+	statement_account text not null default 'mpesa',
+	statement_reference text not null,
+	decision text not null check (decision in ('confirm', 'reject', 'manual')),
+	comment text null,
+	decided_by text not null,
+	decided_at timestamptz not null default now(),
+	reversed_by text null,
+	reversed_at timestamptz null,
+	reversal_comment text null
+);
+
+create unique index if not exists reconciliation_decisions_active on production.reconciliation_decisions (billing_number, statement_account, statement_reference)
+where reversed_at is null;
+
+--- Exception queue
+
+-- Items that need follow-up, generated from reconciliation runs. item_key identifies the
+-- underlying problem so regenerating does not duplicate or reopen items
+create table if not exists production.exceptions (
+	id bigint generated always as identity primary key,
+	item_key text not null unique,
+	kind text not null check (kind in ('unmatched_payment', 'unmatched_receipt', 'typo_transaction_code', 'over_claimed_payment', 'reversed_transaction')),
+	day date not null,
+	billing_number text null,
+	statement_reference text null,
+	amount numeric null,
+	details text null,
+	-- Null for items of head office accounts
+	unit_name text null,
+	status text not null default 'open' check (status in ('open', 'investigating', 'resolved')),
+	assigned_to text null,
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
+
+create table if not exists production.exception_notes (
+	id bigint generated always as identity primary key,
+	exception_id bigint not null references production.exceptions (id),
+	author text not null,
+	note text not null,
+	created_at timestamptz not null default now()
+);
+
+--- HIS sync
+
+-- Progress of each table pulled from the HIS database. The watermark is the latest receipt,
+-- bill or registration date already copied
+create table if not exists production.sync_watermarks (
+	source text primary key,
+	watermark timestamp,
+	last_run_at timestamptz not null,
+	last_success_at timestamptz,
+	rows_last_run bigint not null default 0,
+	last_error text
+);
+
+--- Jobs
+
+-- Background work. Workers claim queued jobs with for update skip locked, so several server
+-- processes can share the queue
+create table if not exists production.jobs (
+	id bigint generated always as identity primary key,
+	kind text not null,
+	payload jsonb not null default '{}',
+	status text not null default 'queued' check (status in ('queued', 'running', 'succeeded', 'failed')),
+	attempts int not null default 0,
+	max_attempts int not null default 5,
+	run_at timestamptz not null default now(),
+	locked_at timestamptz,
+	locked_by text,
+	last_error text,
+	result jsonb,
+	created_at timestamptz not null default now(),
+	finished_at timestamptz,
+	-- Progress of upload jobs
+	rows_processed bigint not null default 0,
+	rows_failed bigint not null default 0,
+	errors jsonb not null default '[]',
+	-- The user who asked for the job, null for scheduled jobs
+	requested_by text
+);
+
+create index if not exists jobs_queue on production.jobs (run_at) where status in ('queued', 'running');
+
+-- Files accepted by /uploads/{source}. Their rows are kept in production.upload_rows until
+-- the upload job finishes, then purged
+create table if not exists production.uploads (
+	id bigint generated always as identity primary key,
+	source text not null,
+	created_at timestamptz not null default now(),
+	purged_at timestamptz
+);
+
+create table if not exists production.upload_rows (
+	upload_id bigint not null references production.uploads (id) on delete cascade,
+	row_no integer not null,
+	row jsonb not null,
+	primary key (upload_id, row_no)
+);
+
+-- Cron schedules (minute hour day-of-month month day-of-week) that enqueue jobs
+create table if not exists production.job_schedules (
+	name text primary key,
+	kind text not null,
+	payload jsonb not null default '{}',
+	cron text not null,
+	enabled bool not null default true,
+	last_enqueued_at timestamptz
+);
+
+insert
+	into
+	production.job_schedules (name,
+	kind,
+	cron)
+values ('nightly_reconciliation',
+'reconciliation',
+'30 2 * * *'),
+('refresh_materialized_views',
+'refresh_materialized_views',
+'0 * * * *'),
+('his_sync',
+'his_sync',
+'*/5 * * * *')
+on
+conflict (name) do nothing;
+
+--- Period locks
+
+-- A signed-off date range of a source. Uploads and reconciliation decisions that touch an active
+-- lock are rejected until an Admin unlocks it with a reason
+create table if not exists production.period_locks (
+	id bigint generated always as identity primary key,
+	source text not null,
+	from_date date not null,
+	to_date date not null,
+	locked_by text not null,
+	locked_at timestamptz not null default now(),
+	comment text null,
+	unlocked_by text null,
+	unlocked_at timestamptz null,
+	unlock_reason text null,
+	check (from_date <= to_date)
 );
 
+-- Reconciliation results as they were when the period was locked
+create table if not exists production.period_lock_snapshots (
+	lock_id bigint primary key references production.period_locks (id),
+	snapshot jsonb not null,
+	created_at timestamptz not null default now()
+);
+
+--- Transfers between accounts
+
+-- A debit in one account paired with the credit it produced in another. PDQ settlements are
+-- paired as a daily total so the debit side has no row id, and the PDQ rows the total settled
+-- are kept in settled_row_ids
+create table if not exists production.transfers (
+	id bigint generated always as identity primary key,
+	debit_account text not null,
+	debit_row_id bigint null,
+	settled_row_ids bigint[] null,
+	debit_date timestamptz not null,
+	credit_account text not null,
+	credit_row_id bigint not null,
+	credit_date timestamptz not null,
+	amount numeric not null,
+	currency text not null default 'KES',
+	detected_at timestamptz not null default now(),
+	unique (credit_account, credit_row_id)
+);
+
+create unique index if not exists transfers_debit_row on production.transfers (debit_account, debit_row_id)
+where debit_row_id is not null;
+
+--- Categorization of statement narrations
+
+-- Rules are tried in ascending priority. Every condition that is set must hold:
+-- the pattern (keyword list or regex) against the reference and narration, the direction and the amount range
+create table if not exists production.categorization_rules (
+	id bigint generated always as identity primary key,
+	category text not null,
+	priority int not null default 100,
+	account text null,
+	match_type text not null default 'keyword' check (match_type in ('keyword', 'regex')),
+	pattern text null,
+	direction text null check (direction in ('debit', 'credit')),
+	min_amount numeric null,
+	max_amount numeric null,
+	active bool not null default true
+);
+
+create table if not exists production.transaction_categories (
+	account text not null,
+	source_row_id bigint not null,
+	-- Null when no rule matched, so uploads only run the rules against rows they added
+	category text null,
+	rule_id bigint null references production.categorization_rules (id) on delete set null,
+	categorized_at timestamptz not null default now(),
+	primary key (account, source_row_id)
+);
+
+--- Exchange rates
+
+-- Dated rates: one base_currency buys rate quote_currency. A day without a rate uses the
+-- latest earlier one
+create table if not exists production.fx_rates (
+	base_currency text not null,
+	quote_currency text not null,
+	rate_date date not null,
+	rate numeric not null check (rate > 0),
+	primary key (base_currency, quote_currency, rate_date)
+);
+
+-- What one from_currency is worth in to_currency on a day, through a direct or an inverted
+-- rate. Null when neither pair has a rate on or before the day
+create or replace function production.fx_rate(from_currency text, to_currency text, on_date date) returns numeric as $$
+	select
+		case
+			when from_currency = to_currency then 1
+			else coalesce(
+			(
+			select
+				r.rate
+			from
+				production.fx_rates r
+			where
+				r.base_currency = from_currency
+				and r.quote_currency = to_currency
+				and r.rate_date <= on_date
+			order by
+				r.rate_date desc
+			limit 1),
+			(
+			select
+				1 / r.rate
+			from
+				production.fx_rates r
+			where
+				r.base_currency = to_currency
+				and r.quote_currency = from_currency
+				and r.rate_date <= on_date
+			order by
+				r.rate_date desc
+			limit 1))
+		end
+$$ language sql stable;
+
+--- Time zones
+
+-- The zone a source writes its times in. Statements and the HIS export wall-clock times without
+-- a zone; they are read in this zone and stored as instants. Sources without a row use the
+-- session zone, which the server sets to its display zone
+create table if not exists production.source_time_zones (
+	source text primary key,
+	time_zone text not null
+);
+
+create or replace function production.source_zone(source text) returns text as $$
+	select
+		coalesce(
+		(
+		select
+			z.time_zone
+		from
+			production.source_time_zones z
+		where
+			z.source = source_zone.source),
+		current_setting('TimeZone'))
+$$ language sql stable;
+
+-- A time as written by the source, as an instant
+create or replace function production.source_time(source text, local_time timestamp) returns timestamptz as $$
+	select
+		local_time at time zone production.source_zone(source)
+$$ language sql stable;
+
+--- M-Pesa reversals and charges
+
+-- Completed statement rows by what they are. Reversals and charges point at the transaction
+-- they belong to through linked_transaction_id
+create or replace view production.mpesa_transactions as
+select
+	s.id,
+	s.receipt_no,
+	s.completion_time,
+	case
+		when s.reason_type ilike '%reversal%' then 'reversal'
+		when s.reason_type ilike '%charge%' then 'charge'
+		when coalesce(s.paid_in, 0) > 0 then 'payment'
+		else 'withdrawal'
+	end as kind,
+	coalesce(s.paid_in, 0) - abs(coalesce(s.withdrawn, 0)) as amount,
+	nullif(trim(s.linked_transaction_id), '') as linked_transaction_id,
+	s.reason_type,
+	s.other_party_info,
+	s.shortcode,
+	production.statement_unit('mpesa', s.shortcode) as unit_name
+from
+	production.mpesa_statement s
+where
+	s.transaction_status = 'Completed';
+
+-- Payments received net of the reversals and charges linked to them
+create or replace view production.mpesa_payments as
+select
+	p.id,
+	p.receipt_no,
+	p.completion_time,
+	p.amount as paid_in,
+	coalesce(r.reversed, 0) as reversed,
+	coalesce(c.charged, 0) as charged,
+	p.amount - coalesce(r.reversed, 0) as net_paid_in,
+	r.reversal_nos,
+	p.other_party_info,
+	p.shortcode,
+	p.unit_name
+from
+	production.mpesa_transactions p
+left join (
+	select
+		linked_transaction_id,
+		sum(abs(amount)) as reversed,
+		string_agg(receipt_no, ',' order by completion_time) as reversal_nos
+	from
+		production.mpesa_transactions
+	where
+		kind = 'reversal'
+	group by
+		linked_transaction_id) r on
+	r.linked_transaction_id = p.receipt_no
+left join (
+	select
+		linked_transaction_id,
+		sum(abs(amount)) as charged
+	from
+		production.mpesa_transactions
+	where
+		kind = 'charge'
+	group by
+		linked_transaction_id) c on
+	c.linked_transaction_id = p.receipt_no
+where
+	p.kind = 'payment';
+
+-- Airtel Money rows classified like production.mpesa_transactions
+create or replace view production.airtel_transactions as
+select
+	s.id,
+	s.transaction_id as receipt_no,
+	s.transaction_time as completion_time,
+	case
+		when s.transaction_type ilike '%reversal%'
+		or s.transaction_type ilike '%rollback%' then 'reversal'
+		when s.transaction_type ilike '%charge%'
+		or s.transaction_type ilike '%fee%' then 'charge'
+		when coalesce(s.paid_in, 0) > 0 then 'payment'
+		else 'withdrawal'
+	end as kind,
+	coalesce(s.paid_in, 0) - abs(coalesce(s.withdrawn, 0)) as amount,
+	nullif(trim(s.linked_transaction_id), '') as linked_transaction_id,
+	s.transaction_type as reason_type,
+	concat_ws(' - ', s.sender_msisdn, s.sender_name) as other_party_info,
+	production.statement_unit('airtel', null) as unit_name
+from
+	production.airtel_statement s
+where
+	s.transaction_status ilike 'success%'
+	or s.transaction_status ilike 'completed';
+
+-- Airtel Money payments net of the reversals and charges linked to them
+create or replace view production.airtel_payments as
+select
+	p.id,
+	p.receipt_no,
+	p.completion_time,
+	p.amount as paid_in,
+	coalesce(r.reversed, 0) as reversed,
+	coalesce(c.charged, 0) as charged,
+	p.amount - coalesce(r.reversed, 0) as net_paid_in,
+	r.reversal_nos,
+	p.other_party_info,
+	null::text as shortcode,
+	p.unit_name
+from
+	production.airtel_transactions p
+left join (
+	select
+		linked_transaction_id,
+		sum(abs(amount)) as reversed,
+		string_agg(receipt_no, ',' order by completion_time) as reversal_nos
+	from
+		production.airtel_transactions
+	where
+		kind = 'reversal'
+	group by
+		linked_transaction_id) r on
+	r.linked_transaction_id = p.receipt_no
+left join (
+	select
+		linked_transaction_id,
+		sum(abs(amount)) as charged
+	from
+		production.airtel_transactions
+	where
+		kind = 'charge'
+	group by
+		linked_transaction_id) c on
+	c.linked_transaction_id = p.receipt_no
+where
+	p.kind = 'payment';
+
+-- The wallet a receipt's transaction code belongs to. The HIS records every mobile money
+-- payment under mpesa, so the code tells them apart. Airtel Money codes look like
+-- MP240312.1523.A12345, M-Pesa codes are ten letters and digits
+create or replace function production.mobile_wallet(transaction_code text) returns text as $$
+	select
+		case
+			when upper(trim(transaction_code)) ~ '^[A-Z]{2}[0-9]{6}\.[0-9]{4}\.[A-Z][0-9]{5}$' then 'airtel'
+			else 'mpesa'
+		end
+$$ language sql immutable;
+
+-- Payments of every mobile money wallet, matched against collection receipts of that wallet
+create or replace view production.mobile_money_payments as
+select
+	'mpesa' as wallet,
+	*
+from
+	production.mpesa_payments
+union all
+select
+	'airtel',
+	*
+from
+	production.airtel_payments;
+
+-- Mobile money receipts of the days matched to payments of their wallet. A levenshtein distance
+-- under 3 is an automatic match unless staff rejected that pair. A confirmed or manual decision
+-- replaces the automatic matches of that bill. Fully reversed payments are never matched
+-- automatically, and a payment to a shortcode mapped to a unit only matches that unit's receipts
+create or replace function production.collection_matches(first_day date, last_day date)
+returns table (billing_number text, wallet text, receipt_no text, distance integer, match_source text, comments text) as $$
+	with bills as (
+		select
+			receipt_no as billing_number,
+			upper(transaction_no) as transaction_code,
+			production.mobile_wallet(transaction_no) as wallet,
+			unit_name
+		from
+			production.collection_details
+		where
+			mpesa > 0
+			and receipt_date::date between first_day and last_day
+	),
+	decisions as (
+		select
+			*
+		from
+			production.reconciliation_decisions
+		where
+			reversed_at is null
+			and statement_account in ('mpesa', 'airtel')
+	)
+	select
+		b.billing_number,
+		b.wallet,
+		s.receipt_no::text,
+		levenshtein(b.transaction_code, s.receipt_no),
+		'automatic',
+		null::text
+	from
+		bills b
+	join production.mobile_money_payments s on
+		s.wallet = b.wallet
+		and levenshtein(b.transaction_code, s.receipt_no) < 3
+	left join production.mpesa_shortcodes u on
+		u.shortcode = s.shortcode
+	where
+		s.net_paid_in > 0
+		and (u.unit_name is null
+			or u.unit_name = b.unit_name)
+		and not exists (
+		select
+			1
+		from
+			decisions d
+		where
+			d.billing_number = b.billing_number
+			and d.statement_account = b.wallet
+			and (d.decision in ('confirm', 'manual')
+				or (d.decision = 'reject'
+					and d.statement_reference = s.receipt_no)))
+	union all
+	select
+		b.billing_number,
+		b.wallet,
+		d.statement_reference,
+		null,
+		case
+			d.decision when 'confirm' then 'confirmed'
+			else 'manual'
+		end,
+		d.comment
+	from
+		bills b
+	join decisions d on
+		d.billing_number = b.billing_number
+		and d.statement_account = b.wallet
+	where
+		d.decision in ('confirm', 'manual')
+$$ language sql stable;
+
+--- Ledger
+
+-- Every money source normalized to one shape, amounts in the currency of the row
+create or replace view production.ledger_rows as
+select
+	'mpesa' as account,
+	completion_time as date,
+	completion_time as value_date,
+	receipt_no as reference,
+	concat_ws(' | ', details, reason_type) as narration,
+	abs(coalesce(withdrawn, 0)) as debit,
+	coalesce(paid_in, 0) as credit,
+	balance as statement_balance,
+	id as source_row_id,
+	production.statement_unit('mpesa', shortcode) as unit_name,
+	'KES' as currency,
+	shortcode
+from
+	production.mpesa_statement
+where
+	transaction_status = 'Completed'
+union all
+select
+	'absa',
+	transaction_date,
+	value_date,
+	user_reference_number,
+	description,
+	coalesce(debit_amount, 0),
+	coalesce(credit_amount, 0),
+	running_balance,
+	id,
+	production.statement_unit('absa', null),
+	currency,
+	null
+from
+	production.absa_statement
+union all
+select
+	'sidian',
+	date,
+	valuedate,
+	reference,
+	narration,
+	coalesce(debit, 0),
+	coalesce(credit, 0),
+	balance,
+	id,
+	production.statement_unit('sidian', null),
+	currency,
+	null
+from
+	production.sidian_statement
+union all
+select
+	'cfc',
+	date,
+	value_date,
+	null,
+	transaction,
+	coalesce(debit, 0),
+	coalesce(credit, 0),
+	ledger_balance,
+	id,
+	production.statement_unit('cfc', null),
+	currency,
+	null
+from
+	production.cfc_statement
+union all
+select
+	bank,
+	transaction_date,
+	value_date,
+	reference,
+	narration,
+	coalesce(debit, 0),
+	coalesce(credit, 0),
+	balance,
+	id,
+	production.statement_unit(bank, null),
+	currency,
+	null
+from
+	production.bank_statements
+union all
+select
+	'airtel',
+	transaction_time,
+	transaction_time,
+	transaction_id,
+	concat_ws(' | ', transaction_type, sender_name),
+	abs(coalesce(withdrawn, 0)),
+	coalesce(paid_in, 0),
+	balance,
+	id,
+	production.statement_unit('airtel', null),
+	'KES',
+	null
+from
+	production.airtel_statement
+where
+	transaction_status ilike 'success%'
+	or transaction_status ilike 'completed'
+union all
+select
+	'pdq',
+	txn_date,
+	payment_date,
+	retrieval_ref_no,
+	concat_ws(' ', scheme, commercial_name, card_no),
+	0,
+	coalesce(net_amount, 0),
+	null,
+	id,
+	production.statement_unit('pdq', null),
+	coalesce(currency, 'KES'),
+	null
+from
+	production.pdq_breakdowns;
+
+-- Ledger rows with a running balance. It starts from the opening balance implied by the first
+-- statement row of each account and currency, and of each paybill or till on M-Pesa, which all
+-- share the account
+create or replace view production.ledger_entries as
+select
+	entries.*,
+	coalesce(first_value(statement_balance - credit + debit) over account_order, 0)
+		+ sum(credit - debit) over account_order as running_balance,
+	exists (
+	select
+		1
+	from
+		production.transfers t
+	where
+		(t.debit_account = entries.account
+			and t.debit_row_id = entries.source_row_id)
+		or (t.credit_account = entries.account
+			and t.credit_row_id = entries.source_row_id)
+		or (entries.account = 'pdq'
+			and t.debit_account = 'pdq'
+			and t.debit_row_id is null
+			and t.currency = entries.currency
+			and entries.source_row_id = any(t.settled_row_ids))) as is_transfer,
+	c.category
+from
+	production.ledger_rows entries
+left join production.transaction_categories c on
+	c.account = entries.account
+	and c.source_row_id = entries.source_row_id
+window account_order as (partition by entries.account, entries.shortcode, entries.currency order by entries.date, entries.source_row_id);
+
 commit;
\ No newline at end of file
@@ -25,23 +25,48 @@ select strip_all_triggers();
 -- Update production.mpesa_statement when staging.mpesa_statement is updated while casting the column types
 create or replace function update_production_mpesa_statement() returns trigger as $update_production_mpesa_statement$
 	begin
+	-- Payments received through Daraja are replaced by the statement row with the same receipt_no
+	update
+	production.mpesa_statement a
+set
+	completion_time = production.source_time('mpesa', b.completion_time::timestamp),
+	initiation_time = production.source_time('mpesa', b.initiation_time::timestamp),
+	details = b.details,
+	transaction_status = b.transaction_status,
+	paid_in = b.paid_in::numeric,
+	withdrawn = b.withdrawn::numeric,
+	balance = b.balance::numeric,
+	balance_confirmed = b.balance_confirmed::boolean,
+	reason_type = b.reason_type,
+	other_party_info = b.other_party_info,
+	linked_transaction_id = b.linked_transaction_id,
+	ac_no = b.ac_no,
+	shortcode = coalesce(b.shortcode, a.shortcode),
+	source = 'statement'
+from
+	public.foreign_mpesa b
+where
+	a.receipt_no = b.receipt_no
+	and a.source = 'daraja';
+
 	insert
 	into
 	production.mpesa_statement
 select 
 	receipt_no,
-	completion_time::timestamp,
-	initiation_time::timestamp,
+	production.source_time('mpesa', completion_time::timestamp),
+	production.source_time('mpesa', initiation_time::timestamp),
 	details,
 	transaction_status,
-	paid_in::double precision,
-	withdrawn::double precision,
-	balance::double precision,
+	paid_in::numeric,
+	withdrawn::numeric,
+	balance::numeric,
 	balance_confirmed::boolean,
 	reason_type,
 	other_party_info,
 	linked_transaction_id,
-	ac_no
+	ac_no,
+	shortcode
 from
 	public.foreign_mpesa b
 where
@@ -78,7 +103,7 @@ where
   not exists (
   select
     (receipt_no,
-    receipt_date::timestamp,
+    production.source_time('his', receipt_date::timestamp),
     patient_name,
     payee,
     cash,
@@ -102,7 +127,7 @@ insert
   production.collection_details 
 select
   (receipt_no,
-  receipt_date::timestamp,
+  production.source_time('his', receipt_date::timestamp),
   patient_name,
   payee,
   cash,
@@ -129,4 +154,102 @@ update
 	on
 	staging.collection_details for each row execute function update_production_collection_details();
 
-commit;
\ No newline at end of file
+commit;
+-- Move rows from staging.bank_statements to production.bank_statements. Rows already in production
+-- (an overlapping export uploaded again) are dropped
+create or replace function update_production_bank_statements() returns trigger as $update_production_bank_statements$
+	begin
+with moved_rows as (
+delete
+from
+  staging.bank_statements
+returning *
+)
+insert
+  into
+  production.bank_statements (bank, transaction_date, value_date, narration, reference, debit, credit, balance, currency)
+select
+  distinct a.bank,
+  production.source_time(a.bank, a.transaction_date),
+  production.source_time(a.bank, a.value_date),
+  a.narration,
+  a.reference,
+  a.debit,
+  a.credit,
+  a.balance,
+  coalesce(a.currency, 'KES')
+from
+  moved_rows a
+where
+  not exists (
+  select
+    1
+  from
+    production.bank_statements b
+  where
+    a.bank = b.bank
+    and production.source_time(a.bank, a.transaction_date) = b.transaction_date
+    and a.reference is not distinct from b.reference
+    and a.debit is not distinct from b.debit
+    and a.credit is not distinct from b.credit
+    and a.balance is not distinct from b.balance
+    and coalesce(a.currency, 'KES') = b.currency);
+
+	RETURN NULL;
+end;
+
+$update_production_bank_statements$ language plpgsql;
+
+-- Create Trigger that watches staging.bank_statements for new rows
+create trigger update_bank_statements after
+insert
+	on
+	staging.bank_statements for each statement execute function update_production_bank_statements();
+
+-- Move rows from staging.airtel_statement to production.airtel_statement. A transaction already
+-- in production keeps its row
+create or replace function update_production_airtel_statement() returns trigger as $update_production_airtel_statement$
+	begin
+with moved_rows as (
+delete
+from
+  staging.airtel_statement
+returning *
+)
+insert
+  into
+  production.airtel_statement (transaction_id, transaction_time, transaction_type, transaction_status, sender_msisdn, sender_name, paid_in, withdrawn, balance, linked_transaction_id)
+select
+  distinct on (a.transaction_id) a.transaction_id,
+  production.source_time('airtel', a.transaction_time),
+  a.transaction_type,
+  a.transaction_status,
+  a.sender_msisdn,
+  a.sender_name,
+  a.paid_in,
+  a.withdrawn,
+  a.balance,
+  a.linked_transaction_id
+from
+  moved_rows a
+where
+  not exists (
+  select
+    1
+  from
+    production.airtel_statement b
+  where
+    a.transaction_id = b.transaction_id)
+order by
+  a.transaction_id;
+
+	RETURN NULL;
+end;
+
+$update_production_airtel_statement$ language plpgsql;
+
+-- Create Trigger that watches staging.airtel_statement for new rows
+create trigger update_airtel_statement after
+insert
+	on
+	staging.airtel_statement for each statement execute function update_production_airtel_statement();
@@ -10,12 +10,18 @@ create materialized view public.mpesa_reconciliations as
         receipt_date,
         patient_name,
         mpesa,
-        UPPER(transaction_no) "transaction_code"
+        UPPER(transaction_no) "transaction_code",
+        unit_name
     FROM
         public.foreign_collection_details
     WHERE
         mpesa > 0
-        AND (unit_name = 'MEDITEST DIAGNOSTIC SERVICES LTD.')),
+        -- Every unit in production.units, each receipt tagged with its unit
+        AND unit_name IN (
+        SELECT
+            unit_name
+        FROM
+            production.units)),
           mpesa_statement AS (
     SELECT
         receipt_no,
@@ -46,7 +52,8 @@ create materialized view public.mpesa_reconciliations as
         mpesa_statement.receipt_no,
         levenshtein(mpesa_bill_list.transaction_code,
         mpesa_statement.receipt_no) AS distance,
-        completion_time
+        completion_time,
+        unit_name
     FROM
         mpesa_bill_list,
         mpesa_statement
@@ -85,7 +92,8 @@ create materialized view public.mpesa_reconciliations as
         transaction_code,
         NULL AS receipt_no,
         NULL AS distance,
-        NULL AS completion_time
+        NULL AS completion_time,
+        unit_name
     FROM
         unmatched_transactions)
     SELECT
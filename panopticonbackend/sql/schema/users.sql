@@ -7,6 +7,8 @@ CREATE TABLE internal.users (
 	name VARCHAR(200) NOT NULL,
 	username    VARCHAR(50) UNIQUE NOT NULL,
     password VARCHAR(50),
+	-- guest, user or admin
+	scope VARCHAR(10) NOT NULL DEFAULT 'guest',
 	UNIQUE (username)
 );
 
@@ -16,6 +18,18 @@ CREATE TABLE internal.sessions (
 	created TIMESTAMP DEFAULT NOW(),
 	CONSTRAINT fk_user_sessions
 	FOREIGN KEY("user")
-	REFERENCES testing.users(email)
+	REFERENCES internal.users(email)
+	ON DELETE CASCADE
+);
+
+-- Short-lived tickets for the notification stream, which browsers open without an
+-- Authorization header
+CREATE TABLE internal.stream_tickets (
+	ticket VARCHAR(200) PRIMARY KEY,
+	username VARCHAR(50) NOT NULL,
+	expires_at TIMESTAMPTZ NOT NULL,
+	CONSTRAINT fk_user_stream_tickets
+	FOREIGN KEY(username)
+	REFERENCES internal.users(username)
 	ON DELETE CASCADE
 );
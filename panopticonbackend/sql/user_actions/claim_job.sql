@@ -0,0 +1,26 @@
+-- The next due job. A running job whose lock has not been renewed for 5 minutes was left by a
+-- worker that stopped and is picked up again. The worker fails it without running it when that
+-- takes it past max_attempts
+update
+	production.jobs
+set
+	status = 'running',
+	attempts = attempts + 1,
+	locked_at = now(),
+	locked_by = $1
+where
+	id = (
+	select
+		id
+	from
+		production.jobs
+	where
+		(status = 'queued'
+			and run_at <= now())
+		or (status = 'running'
+			and locked_at < now() - interval '5 minutes')
+	order by
+		run_at
+	limit 1
+	for update skip locked)
+returning *
\ No newline at end of file
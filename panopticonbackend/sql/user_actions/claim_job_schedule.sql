@@ -0,0 +1,10 @@
+-- Only one process enqueues a schedule for a given minute
+update
+	production.job_schedules
+set
+	last_enqueued_at = $2
+where
+	name = $1
+	and (last_enqueued_at is null
+		or last_enqueued_at < $2)
+returning name
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Only while the worker still holds the job. A job another worker took over is left to it
+update
+	production.jobs
+set
+	status = 'succeeded',
+	result = $2,
+	last_error = null,
+	finished_at = now()
+where
+	id = $1
+	and status = 'running'
+	and locked_by = $3
\ No newline at end of file
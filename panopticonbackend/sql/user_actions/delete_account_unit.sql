@@ -0,0 +1,6 @@
+delete
+from
+	production.account_units
+where
+	account = $1
+	and unit_name = $2
\ No newline at end of file
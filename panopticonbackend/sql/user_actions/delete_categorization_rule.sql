@@ -0,0 +1 @@
+delete from production.categorization_rules where id = $1
\ No newline at end of file
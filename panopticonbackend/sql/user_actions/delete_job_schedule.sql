@@ -0,0 +1,5 @@
+delete
+from
+	production.job_schedules
+where
+	name = $1
\ No newline at end of file
@@ -0,0 +1,5 @@
+delete
+from
+	production.mpesa_shortcodes
+where
+	shortcode = $1
\ No newline at end of file
@@ -0,0 +1,5 @@
+delete
+from
+	production.source_time_zones
+where
+	source = $1
\ No newline at end of file
@@ -0,0 +1,6 @@
+delete
+from
+	production.user_units
+where
+	username = $1
+	and unit_name = $2
\ No newline at end of file
@@ -0,0 +1,23 @@
+-- Requeue after $3 seconds, or fail for good when $3 is null. Only while worker $4 still holds
+-- the job
+update
+	production.jobs
+set
+	status = case
+		when $3::float8 is null then 'failed'
+		else 'queued'
+	end,
+	run_at = case
+		when $3::float8 is null then run_at
+		else now() + make_interval(secs => $3::float8)
+	end,
+	last_error = $2,
+	locked_at = null,
+	locked_by = null,
+	finished_at = case
+		when $3::float8 is null then now()
+	end
+where
+	id = $1
+	and status = 'running'
+	and locked_by = $4
\ No newline at end of file
@@ -0,0 +1,12 @@
+select
+	transaction_date::timestamp as date,
+	description as reference,
+	coalesce(debit_amount, 0) as debit,
+	coalesce(credit_amount, 0) as credit,
+	running_balance as balance
+from
+	production.absa_statement
+where
+	transaction_date::date between $1 and $2
+order by
+	transaction_date
\ No newline at end of file
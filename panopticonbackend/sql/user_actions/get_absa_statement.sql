@@ -1 +0,0 @@
-SELECT * FROM production.absa_statement
\ No newline at end of file
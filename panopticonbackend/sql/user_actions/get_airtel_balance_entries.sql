@@ -0,0 +1,13 @@
+select
+	transaction_time at time zone production.source_zone('airtel') as date,
+	transaction_id as reference,
+	abs(coalesce(withdrawn, 0)) as debit,
+	coalesce(paid_in, 0) as credit,
+	balance
+from
+	production.airtel_statement
+where
+	transaction_time::date between $1 and $2
+order by
+	transaction_time,
+	id
\ No newline at end of file
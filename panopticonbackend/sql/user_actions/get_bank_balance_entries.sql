@@ -0,0 +1,14 @@
+select
+	transaction_date at time zone production.source_zone(bank) as date,
+	coalesce(reference, narration) as reference,
+	coalesce(debit, 0) as debit,
+	coalesce(credit, 0) as credit,
+	balance
+from
+	production.bank_statements
+where
+	transaction_date::date between $1 and $2
+	and bank = $3
+order by
+	transaction_date,
+	id
\ No newline at end of file
@@ -0,0 +1,17 @@
+select
+	bank,
+	transaction_date,
+	value_date,
+	narration,
+	reference,
+	debit,
+	credit,
+	balance,
+	currency
+from
+	production.bank_statements
+where
+	bank = $1
+order by
+	transaction_date,
+	id
\ No newline at end of file
@@ -1 +0,0 @@
-SELECT * FROM production.bill_details
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Dates of the collection receipts a decision touches
+select
+	receipt_date::date as day
+from
+	production.collection_details
+where
+	receipt_no = $1
+	and receipt_date is not null
\ No newline at end of file
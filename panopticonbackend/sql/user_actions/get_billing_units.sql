@@ -0,0 +1,7 @@
+-- Units of the collection receipts of a bill
+select
+	distinct unit_name
+from
+	production.collection_details
+where
+	receipt_no = $1
\ No newline at end of file
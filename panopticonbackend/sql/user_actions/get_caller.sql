@@ -0,0 +1,19 @@
+-- The user a session token belongs to, with the units they are assigned to
+select
+	u.username,
+	u.scope,
+	array(
+	select
+		uu.unit_name
+	from
+		production.user_units uu
+	where
+		uu.username = u.username
+	order by
+		uu.unit_name) as units
+from
+	internal.sessions s
+join internal.users u on
+	u.email = s."user"
+where
+	s."token" = $1
\ No newline at end of file
@@ -0,0 +1 @@
+select * from production.categorization_rules order by priority, id
\ No newline at end of file
@@ -0,0 +1,12 @@
+select
+	date::timestamp as date,
+	transaction as reference,
+	coalesce(debit, 0) as debit,
+	coalesce(credit, 0) as credit,
+	ledger_balance as balance
+from
+	production.cfc_statement
+where
+	date::date between $1 and $2
+order by
+	date
\ No newline at end of file
@@ -1 +0,0 @@
-SELECT * FROM production.cfc_statement
\ No newline at end of file
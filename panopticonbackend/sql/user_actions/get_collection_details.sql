@@ -1 +0,0 @@
-select * from production.collection_details limit 1000;
\ No newline at end of file
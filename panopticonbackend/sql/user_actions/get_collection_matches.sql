@@ -0,0 +1,72 @@
+-- The payment each uploaded M-Pesa or Airtel Money receipt is matched to, as
+-- production.collection_matches decides it: a confirmed or manual decision for the bill, else the
+-- closest payment of the receipt's wallet that staff did not reject. Arrays hold one element per
+-- receipt
+select
+	c.billing_number,
+	c.cashier,
+	c.unit_name,
+	c.transaction_code,
+	c.mpesa,
+	p.receipt_no,
+	p.net_paid_in as paid_in
+from
+	unnest($1::text[],
+	$2::text[],
+	$3::text[],
+	$4::text[],
+	$5::numeric[]) as c(billing_number,
+	cashier,
+	unit_name,
+	transaction_code,
+	mpesa)
+left join lateral (
+	select
+		d.statement_reference as receipt_no,
+		s.net_paid_in,
+		-1 as distance
+	from
+		production.reconciliation_decisions d
+	left join production.mobile_money_payments s on
+		s.wallet = d.statement_account
+		and s.receipt_no = d.statement_reference
+	where
+		d.reversed_at is null
+		and d.billing_number = c.billing_number
+		and d.statement_account = production.mobile_wallet(c.transaction_code)
+		and d.decision in ('confirm', 'manual')
+	union all
+	select
+		s.receipt_no,
+		s.net_paid_in,
+		levenshtein(upper(c.transaction_code), s.receipt_no)
+	from
+		production.mobile_money_payments s
+	where
+		s.wallet = production.mobile_wallet(c.transaction_code)
+		and levenshtein(upper(c.transaction_code), s.receipt_no) < 3
+		and s.net_paid_in > 0
+		and not exists (
+		select
+			1
+		from
+			production.mpesa_shortcodes u
+		where
+			u.shortcode = s.shortcode
+			and u.unit_name is distinct from c.unit_name)
+		and not exists (
+		select
+			1
+		from
+			production.reconciliation_decisions d
+		where
+			d.reversed_at is null
+			and d.billing_number = c.billing_number
+			and d.statement_account = s.wallet
+			and (d.decision in ('confirm', 'manual')
+				or (d.decision = 'reject'
+					and d.statement_reference = s.receipt_no)))
+	order by
+		distance
+	limit 1) p on
+	true
\ No newline at end of file
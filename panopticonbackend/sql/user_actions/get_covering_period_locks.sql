@@ -0,0 +1,15 @@
+-- Active locks of a source that cover any of the given dates
+select
+	*
+from
+	production.period_locks
+where
+	source = $1
+	and unlocked_at is null
+	and exists (
+	select
+		1
+	from
+		unnest($2::date[]) d
+	where
+		d between from_date and to_date)
\ No newline at end of file
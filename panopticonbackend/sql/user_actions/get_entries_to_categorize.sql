@@ -0,0 +1,18 @@
+-- Ledger rows to run the rules against. Already categorized rows are only returned when $4 is true
+select
+	r.account,
+	r.source_row_id,
+	r.reference,
+	r.narration,
+	r.debit,
+	r.credit
+from
+	production.ledger_rows r
+left join production.transaction_categories c on
+	c.account = r.account
+	and c.source_row_id = r.source_row_id
+where
+	($1::text is null or r.account = $1)
+	and ($2::date is null or r.date::date >= $2)
+	and ($3::date is null or r.date::date <= $3)
+	and ($4 or c.category is null)
\ No newline at end of file
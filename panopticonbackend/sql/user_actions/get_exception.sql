@@ -0,0 +1 @@
+select * from production.exceptions where id = $1
\ No newline at end of file
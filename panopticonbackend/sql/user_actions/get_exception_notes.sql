@@ -0,0 +1 @@
+select * from production.exception_notes where exception_id = $1 order by created_at
\ No newline at end of file
@@ -0,0 +1,14 @@
+select
+	*
+from
+	production.exceptions
+where
+	($1::text is null or status = $1)
+	and ($2::text is null or kind = $2)
+	and ($3::text is null or assigned_to = $3)
+	and ($4::date is null or day >= $4)
+	and ($5::date is null or day <= $5)
+	and ($6::text[] is null or unit_name = any($6))
+order by
+	day,
+	id
\ No newline at end of file
@@ -0,0 +1,94 @@
+-- Collection receipts from the range whose mobile money code looks abused, on M-Pesa or Airtel.
+-- Usage of a code is counted over every bill so that reuse across days is caught. Payments are
+-- net of their reversals, so a reversed payment covers only what is left of it
+with usage as (
+select
+	upper(trim(c.transaction_no)) as code,
+	production.mobile_wallet(c.transaction_no) as wallet,
+	count(*) as bills,
+	sum(c.mpesa) as billed
+from
+	production.collection_details c
+where
+	c.mpesa > 0
+	and c.transaction_no is not null
+group by
+	upper(trim(c.transaction_no)),
+	production.mobile_wallet(c.transaction_no)),
+attempts as (
+select
+	'mpesa' as wallet,
+	s.receipt_no,
+	min(s.completion_time) as completion_time,
+	bool_or(s.transaction_status = 'Completed') as completed
+from
+	production.mpesa_statement s
+group by
+	s.receipt_no
+union all
+select
+	'airtel',
+	s.transaction_id,
+	min(s.transaction_time),
+	bool_or(s.transaction_status ilike 'success%'
+		or s.transaction_status ilike 'completed')
+from
+	production.airtel_statement s
+group by
+	s.transaction_id),
+payments as (
+select
+	p.wallet,
+	p.receipt_no,
+	count(*) filter (
+	where p.net_paid_in > 0) as payments,
+	sum(p.net_paid_in) as paid_in
+from
+	production.mobile_money_payments p
+group by
+	p.wallet,
+	p.receipt_no),
+signals as (
+select
+	coalesce(c.employee_name, 'unknown') as employee_name,
+	c.receipt_no as billing_number,
+	c.receipt_date,
+	u.code as transaction_code,
+	u.wallet,
+	c.mpesa,
+	u.bills as bills_on_code,
+	p.payments as statement_payments,
+	u.billed as total_billed,
+	p.paid_in,
+	a.completion_time,
+	coalesce(u.bills > p.payments and p.payments > 0, false) as reused_code,
+	coalesce(u.billed > coalesce(p.paid_in, 0) and a.receipt_no is not null, false) as over_claimed,
+	coalesce(a.completion_time > c.receipt_date, false) as completed_after_receipt,
+	coalesce(not a.completed, false) as not_completed
+from
+	production.collection_details c
+inner join usage u on
+	u.code = upper(trim(c.transaction_no))
+left join attempts a on
+	a.wallet = u.wallet
+	and a.receipt_no = u.code
+left join payments p on
+	p.wallet = u.wallet
+	and p.receipt_no = u.code
+where
+	c.mpesa > 0
+	and c.receipt_date::date between $1 and $2
+	and ($3::text[] is null
+		or c.unit_name = any($3)))
+select
+	*
+from
+	signals
+where
+	reused_code
+	or over_claimed
+	or completed_after_receipt
+	or not_completed
+order by
+	employee_name,
+	receipt_date
\ No newline at end of file
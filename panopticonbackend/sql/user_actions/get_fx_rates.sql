@@ -0,0 +1,14 @@
+select
+	*
+from
+	production.fx_rates
+where
+	($1::text is null
+		or base_currency = $1
+		or quote_currency = $1)
+	and ($2::date is null or rate_date >= $2)
+	and ($3::date is null or rate_date <= $3)
+order by
+	base_currency,
+	quote_currency,
+	rate_date
\ No newline at end of file
@@ -0,0 +1,6 @@
+select
+	*
+from
+	production.jobs
+where
+	id = $1
\ No newline at end of file
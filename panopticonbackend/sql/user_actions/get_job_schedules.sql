@@ -0,0 +1,6 @@
+select
+	*
+from
+	production.job_schedules
+order by
+	name
\ No newline at end of file
@@ -0,0 +1,31 @@
+-- Results can be large, so they are only returned for a single job
+select
+	id,
+	kind,
+	payload,
+	status,
+	attempts,
+	max_attempts,
+	run_at,
+	locked_at,
+	locked_by,
+	last_error,
+	null::jsonb as result,
+	created_at,
+	finished_at,
+	rows_processed,
+	rows_failed,
+	errors,
+	requested_by
+from
+	production.jobs
+where
+	($1::text is null
+		or status = $1)
+	and ($2::text is null
+		or kind = $2)
+	and ($3::text is null
+		or requested_by = $3)
+order by
+	id desc
+limit 200
\ No newline at end of file
@@ -0,0 +1,169 @@
+-- Daily totals that become journals. Each kind maps to a debit and credit account in the chart of accounts.
+-- Mobile money collections count once matched to their wallet, automatically or by a staff decision.
+-- Transfers name the accounts themselves: money leaves debit_account and arrives in credit_account.
+-- Totals are per currency and converted to the reporting currency $3 with the rate of their day; reporting_amount
+-- is null without a rate
+with statement as (
+	select
+		*
+	from
+		production.mpesa_statement
+	where
+		transaction_status = 'Completed'
+),
+matches as (
+	select
+		*
+	from
+		production.collection_matches($1, $2)
+),
+sources as (
+	select
+		receipt_date::date as day,
+		'mpesa_collections' as kind,
+		sum(mpesa) as amount,
+		'KES' as currency,
+		null::text as debit_account,
+		null::text as credit_account
+	from
+		production.collection_details c
+	where
+		mpesa > 0
+		and receipt_date::date between $1 and $2
+		and exists (
+		select
+			1
+		from
+			matches m
+		where
+			m.billing_number = c.receipt_no
+			and m.wallet = 'mpesa')
+	group by
+		1
+	union all
+	select
+		receipt_date::date,
+		'airtel_collections',
+		sum(mpesa),
+		'KES',
+		null,
+		null
+	from
+		production.collection_details c
+	where
+		mpesa > 0
+		and receipt_date::date between $1 and $2
+		and exists (
+		select
+			1
+		from
+			matches m
+		where
+			m.billing_number = c.receipt_no
+			and m.wallet = 'airtel')
+	group by
+		1
+	union all
+	select
+		receipt_date::date,
+		'card_collections',
+		sum(card),
+		'KES',
+		null,
+		null
+	from
+		production.collection_details
+	where
+		card > 0
+		and receipt_date::date between $1 and $2
+	group by
+		1
+	union all
+	select
+		payment_date::date,
+		'pdq_commission',
+		sum(commission),
+		'KES',
+		null,
+		null
+	from
+		production.pdq_breakdowns
+	where
+		commission > 0
+		and payment_date::date between $1 and $2
+	group by
+		1
+	union all
+	select
+		completion_time::date,
+		'mpesa_charges',
+		sum(abs(withdrawn)),
+		'KES',
+		null,
+		null
+	from
+		statement
+	where
+		withdrawn <> 0
+		and reason_type ilike '%charge%'
+		and completion_time::date between $1 and $2
+	group by
+		1
+	union all
+	select
+		completion_time::date,
+		'mpesa_transfers',
+		sum(abs(withdrawn)),
+		'KES',
+		null,
+		null
+	from
+		statement
+	where
+		withdrawn <> 0
+		and reason_type not ilike '%charge%'
+		and (reason_type ilike '%withdraw%'
+			or reason_type ilike '%to bank%'
+			or reason_type ilike '%settlement%')
+		and not exists (
+		select
+			1
+		from
+			production.transfers t
+		where
+			t.debit_account = 'mpesa'
+			and t.debit_row_id = statement.id)
+		and completion_time::date between $1 and $2
+	group by
+		1
+	union all
+	select
+		credit_date::date,
+		'transfer',
+		sum(amount),
+		currency,
+		debit_account,
+		credit_account
+	from
+		production.transfers
+	where
+		credit_date::date between $1 and $2
+	group by
+		1,
+		4,
+		5,
+		6
+)
+select
+	day,
+	kind,
+	amount,
+	currency,
+	amount * production.fx_rate(currency, $3, day) as reporting_amount,
+	debit_account,
+	credit_account
+from
+	sources
+order by
+	day,
+	kind
\ No newline at end of file
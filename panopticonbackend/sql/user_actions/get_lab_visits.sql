@@ -1 +0,0 @@
-SELECT * FROM production.lab_visits
\ No newline at end of file
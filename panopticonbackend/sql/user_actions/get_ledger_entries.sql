@@ -0,0 +1,18 @@
+-- $7 is the reporting currency. Rows without a rate to it on their day have no reporting amounts
+select
+	*,
+	debit * production.fx_rate(currency, $7, date::date) as reporting_debit,
+	credit * production.fx_rate(currency, $7, date::date) as reporting_credit
+from
+	production.ledger_entries
+where
+	($1::date is null or date::date >= $1)
+	and ($2::date is null or date::date <= $2)
+	and ($3::text is null or account = $3)
+	and ($4::text is null or reference ilike '%' || $4 || '%' or narration ilike '%' || $4 || '%')
+	and (not coalesce($5::bool, false) or not is_transfer)
+	and ($6::text[] is null or unit_name = any($6))
+order by
+	date,
+	account,
+	source_row_id
\ No newline at end of file
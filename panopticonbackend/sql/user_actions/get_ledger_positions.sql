@@ -0,0 +1,20 @@
+-- Closing balance of every account, M-Pesa paybill or till and currency as at the end of the given
+-- date, and its worth in the reporting currency $3 at that date. $2 keeps the accounts, paybills and
+-- tills of those units
+select
+	distinct on (account, shortcode, currency) account,
+	shortcode,
+	currency,
+	running_balance as balance,
+	running_balance * production.fx_rate(currency, $3, coalesce($1::date, current_date)) as reporting_balance
+from
+	production.ledger_entries
+where
+	($1::date is null or date::date <= $1)
+	and ($2::text[] is null or production.statement_unit(account, shortcode) = any($2))
+order by
+	account,
+	shortcode,
+	currency,
+	date desc,
+	source_row_id desc
\ No newline at end of file
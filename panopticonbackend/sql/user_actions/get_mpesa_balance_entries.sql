@@ -0,0 +1,12 @@
+select
+	completion_time at time zone production.source_zone('mpesa') as date,
+	receipt_no as reference,
+	abs(coalesce(withdrawn, 0)) as debit,
+	coalesce(paid_in, 0) as credit,
+	balance
+from
+	production.mpesa_statement
+where
+	completion_time::date between $1 and $2
+order by
+	completion_time
\ No newline at end of file
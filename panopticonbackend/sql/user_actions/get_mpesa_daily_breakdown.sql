@@ -0,0 +1,29 @@
+-- Gross payments, reversals, charges and withdrawals of each day of the range
+select
+	completion_time::date as day,
+	count(*) filter (
+	where kind = 'payment') as payments,
+	coalesce(sum(amount) filter (
+	where kind = 'payment'), 0) as gross_paid_in,
+	count(*) filter (
+	where kind = 'reversal') as reversals,
+	coalesce(sum(-amount) filter (
+	where kind = 'reversal'), 0) as reversed,
+	count(*) filter (
+	where kind = 'charge') as charges,
+	coalesce(sum(abs(amount)) filter (
+	where kind = 'charge'), 0) as charged,
+	coalesce(sum(amount) filter (
+	where kind in ('payment', 'reversal')), 0) as net_paid_in,
+	coalesce(sum(abs(amount)) filter (
+	where kind = 'withdrawal'), 0) as withdrawn
+from
+	production.mpesa_transactions
+where
+	completion_time::date between $1 and $2
+	and ($3::text[] is null
+		or unit_name = any($3))
+group by
+	1
+order by
+	1
\ No newline at end of file
@@ -0,0 +1,22 @@
+-- Reversals completed in the range with the transaction they reverse
+select
+	r.receipt_no,
+	r.completion_time,
+	r.amount,
+	r.linked_transaction_id,
+	r.reason_type,
+	r.unit_name,
+	o.completion_time as original_completion_time,
+	o.amount as original_amount
+from
+	production.mpesa_transactions r
+left join production.mpesa_transactions o on
+	o.receipt_no = r.linked_transaction_id
+	and o.kind <> 'reversal'
+where
+	r.kind = 'reversal'
+	and r.completion_time::date between $1 and $2
+	and ($3::text[] is null
+		or r.unit_name = any($3))
+order by
+	r.completion_time
\ No newline at end of file
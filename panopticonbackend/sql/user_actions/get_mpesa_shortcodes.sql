@@ -0,0 +1,30 @@
+-- Paybills and tills that are mapped to a unit or appear on a statement, with the balance of
+-- their latest completed row
+with latest as (
+	select
+		distinct on (shortcode) shortcode,
+		completion_time,
+		balance
+	from
+		production.mpesa_statement
+	where
+		shortcode is not null
+		and balance is not null
+		and transaction_status = 'Completed'
+	order by
+		shortcode,
+		completion_time desc,
+		id desc
+)
+select
+	coalesce(m.shortcode, l.shortcode) as shortcode,
+	m.unit_name,
+	m.description,
+	l.balance,
+	l.completion_time as balance_at
+from
+	production.mpesa_shortcodes m
+full join latest l on
+	l.shortcode = m.shortcode
+order by
+	1
\ No newline at end of file
@@ -1,13 +0,0 @@
-select * from production.mpesa_statement limit 1000
-
--- SELECT id, col, col, col 
---   FROM output 
---  ORDER BY id DESC
---  LIMIT 10000;
-
--- --- 
-
--- SELECT id, col, col, col
---    FROM output
---   WHERE id < my_captured_id_value
---   ORDER BY id DESC;
\ No newline at end of file
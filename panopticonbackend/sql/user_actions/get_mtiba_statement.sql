@@ -1 +0,0 @@
-select * from production.mtiba_statement
\ No newline at end of file
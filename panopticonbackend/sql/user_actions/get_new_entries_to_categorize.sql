@@ -0,0 +1,20 @@
+-- Rows of account $1 the rules have not been run against yet
+select
+	r.account,
+	r.source_row_id,
+	r.reference,
+	r.narration,
+	r.debit,
+	r.credit
+from
+	production.ledger_rows r
+where
+	r.account = $1
+	and not exists (
+	select
+		1
+	from
+		production.transaction_categories c
+	where
+		c.account = r.account
+		and c.source_row_id = r.source_row_id)
\ No newline at end of file
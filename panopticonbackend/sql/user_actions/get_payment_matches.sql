@@ -0,0 +1,51 @@
+-- New M-Pesa payments with the collection receipts matched to them, as
+-- production.collection_matches decides it: receipts whose code matches, unless staff rejected
+-- the pair or decided the bill against another payment, and receipts decided against the payment.
+-- A payment claimed by several receipts appears once per receipt
+select
+	c.receipt_no as billing_number,
+	c.employee_name as cashier,
+	c.unit_name,
+	upper(c.transaction_no) as transaction_code,
+	c.mpesa,
+	p.receipt_no,
+	p.net_paid_in as paid_in
+from
+	production.mpesa_payments p
+left join production.collection_details c on
+	c.mpesa > 0
+	and production.mobile_wallet(c.transaction_no) = 'mpesa'
+	and ((levenshtein(upper(c.transaction_no), p.receipt_no) < 3
+		and not exists (
+		select
+			1
+		from
+			production.mpesa_shortcodes u
+		where
+			u.shortcode = p.shortcode
+			and u.unit_name is distinct from c.unit_name)
+		and not exists (
+		select
+			1
+		from
+			production.reconciliation_decisions d
+		where
+			d.reversed_at is null
+			and d.billing_number = c.receipt_no
+			and d.statement_account = 'mpesa'
+			and (d.decision in ('confirm', 'manual')
+				or (d.decision = 'reject'
+					and d.statement_reference = p.receipt_no))))
+	or exists (
+		select
+			1
+		from
+			production.reconciliation_decisions d
+		where
+			d.reversed_at is null
+			and d.billing_number = c.receipt_no
+			and d.statement_account = 'mpesa'
+			and d.statement_reference = p.receipt_no
+			and d.decision in ('confirm', 'manual')))
+where
+	p.receipt_no = any($1)
\ No newline at end of file
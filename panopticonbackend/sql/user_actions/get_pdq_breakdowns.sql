@@ -1 +0,0 @@
-SELECT * FROM production.pdq_statement
\ No newline at end of file
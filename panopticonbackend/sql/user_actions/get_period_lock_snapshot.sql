@@ -0,0 +1 @@
+select * from production.period_lock_snapshots where lock_id = $1
\ No newline at end of file
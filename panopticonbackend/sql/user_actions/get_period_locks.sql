@@ -0,0 +1,10 @@
+select
+	*
+from
+	production.period_locks
+where
+	($1::text is null or source = $1)
+	and ($2 or unlocked_at is null)
+	and ($3::text[] is null or production.statement_unit(source, null) = any($3))
+order by
+	from_date desc
\ No newline at end of file
@@ -1 +1,49 @@
-select * from production.mpesa_reconciliations where receipt_date::date = $1
\ No newline at end of file
+-- Mobile money collections of a day matched to the statement of their wallet (M-Pesa or Airtel).
+-- production.collection_matches applies the automatic matching and the staff decisions. Payments
+-- are net of their reversals. $2 limits the bills to those units
+with bills as (
+	select
+		receipt_no as billing_number,
+		employee_name as cashier,
+		receipt_date,
+		patient_name,
+		mpesa,
+		upper(transaction_no) as transaction_code,
+		production.mobile_wallet(transaction_no) as wallet,
+		unit_name
+	from
+		production.collection_details
+	where
+		mpesa > 0
+		and receipt_date::date = $1
+		and ($2::text[] is null
+			or unit_name = any($2))
+)
+select
+	b.billing_number,
+	b.cashier,
+	b.receipt_date,
+	b.patient_name,
+	b.mpesa,
+	b.transaction_code,
+	b.wallet,
+	b.unit_name,
+	m.receipt_no,
+	s.net_paid_in as paid_in,
+	s.reversed,
+	s.completion_time,
+	s.shortcode,
+	m.distance,
+	coalesce(m.match_source, 'unmatched') as match_source,
+	m.comments
+from
+	bills b
+left join production.collection_matches($1, $1) m on
+	m.billing_number = b.billing_number
+	and m.wallet = b.wallet
+left join production.mobile_money_payments s on
+	s.wallet = b.wallet
+	and s.receipt_no = m.receipt_no
+order by
+	b.receipt_date,
+	b.billing_number
\ No newline at end of file
@@ -0,0 +1 @@
+select * from production.reconciliation_decisions where id = $1
\ No newline at end of file
@@ -0,0 +1,19 @@
+select
+	*
+from
+	production.reconciliation_decisions
+where
+	($1::text is null or billing_number = $1)
+	and ($2::text is null or statement_reference = $2)
+	and ($3 or reversed_at is null)
+	and ($4::text[] is null
+		or exists (
+		select
+			1
+		from
+			production.collection_details c
+		where
+			c.receipt_no = billing_number
+			and c.unit_name = any($4)))
+order by
+	decided_at desc
\ No newline at end of file
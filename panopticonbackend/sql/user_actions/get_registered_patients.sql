@@ -1 +0,0 @@
-SELECT * FROM production.registered_patients
\ No newline at end of file
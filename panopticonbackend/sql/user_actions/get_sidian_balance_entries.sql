@@ -0,0 +1,12 @@
+select
+	date::timestamp as date,
+	coalesce(reference, narration) as reference,
+	coalesce(debit, 0) as debit,
+	coalesce(credit, 0) as credit,
+	balance
+from
+	production.sidian_statement
+where
+	date::date between $1 and $2
+order by
+	date
\ No newline at end of file
@@ -1 +0,0 @@
-SELECT * FROM production.sidian_statement
\ No newline at end of file
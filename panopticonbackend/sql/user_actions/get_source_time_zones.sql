@@ -0,0 +1,6 @@
+select
+	*
+from
+	production.source_time_zones
+order by
+	source
\ No newline at end of file
@@ -0,0 +1 @@
+select production.statement_unit($1, $2) as unit_name
\ No newline at end of file
@@ -0,0 +1,6 @@
+select
+	watermark
+from
+	production.sync_watermarks
+where
+	source = $1
\ No newline at end of file
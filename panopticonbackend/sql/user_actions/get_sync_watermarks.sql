@@ -0,0 +1,6 @@
+select
+	*
+from
+	production.sync_watermarks
+order by
+	source
\ No newline at end of file
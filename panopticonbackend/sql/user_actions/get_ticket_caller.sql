@@ -0,0 +1,26 @@
+-- The user a stream ticket was issued to, with the units they are assigned to. The ticket is
+-- used up
+with used as (
+delete
+from
+	internal.stream_tickets
+where
+	ticket = $1
+	and expires_at > now()
+returning username)
+select
+	u.username,
+	u.scope,
+	array(
+	select
+		uu.unit_name
+	from
+		production.user_units uu
+	where
+		uu.username = u.username
+	order by
+		uu.unit_name) as units
+from
+	used
+join internal.users u on
+	u.username = used.username
\ No newline at end of file
@@ -0,0 +1,56 @@
+-- Ledger rows that could be one side of a transfer and are not paired yet.
+-- PDQ settlements are offered as one debit per payment date and currency, with the rows they settle
+select
+	'debit' as side,
+	account,
+	source_row_id as row_id,
+	date,
+	debit as amount,
+	currency,
+	reference,
+	narration,
+	null::bigint[] as settled_row_ids
+from
+	production.ledger_entries
+where
+	debit > 0
+	and not is_transfer
+	and date::date between $1 and $2
+union all
+select
+	'credit',
+	account,
+	source_row_id,
+	date,
+	credit,
+	currency,
+	reference,
+	narration,
+	null
+from
+	production.ledger_entries
+where
+	credit > 0
+	and account <> 'pdq'
+	and not is_transfer
+	and date::date between $1 and $2
+union all
+select
+	'debit',
+	'pdq',
+	null,
+	value_date::date::timestamptz,
+	sum(credit),
+	currency,
+	null,
+	'PDQ settlement',
+	array_agg(source_row_id order by source_row_id)
+from
+	production.ledger_entries
+where
+	account = 'pdq'
+	and not is_transfer
+	and value_date::date between $1 and $2
+group by
+	value_date::date,
+	currency
\ No newline at end of file
@@ -0,0 +1,17 @@
+select
+	debit_account,
+	debit_row_id,
+	settled_row_ids,
+	debit_date,
+	credit_account,
+	credit_row_id,
+	credit_date,
+	amount,
+	currency
+from
+	production.transfers
+where
+	debit_date::date between $1 and $2
+	or credit_date::date between $1 and $2
+order by
+	debit_date
\ No newline at end of file
@@ -0,0 +1,44 @@
+-- Collections receipted and mobile money received per unit and day, with one consolidated row
+-- per day across all units. Receipts and payments without a unit belong to head office and
+-- have a null unit_name. Both are in shillings and are converted to the reporting currency $3
+with activity as (
+	select
+		receipt_date::date as day,
+		unit_name,
+		coalesce(cash, 0) + coalesce(cheque, 0) + coalesce(card, 0) + coalesce(mpesa, 0) + coalesce(e_transfer, 0) as collected,
+		coalesce(mpesa, 0) as mobile_money_receipted,
+		0::numeric as mobile_money_paid_in
+	from
+		production.collection_details
+	where
+		receipt_date::date between $1 and $2
+union all
+	select
+		completion_time::date,
+		unit_name,
+		0,
+		0,
+		net_paid_in
+	from
+		production.mobile_money_payments
+	where
+		completion_time::date between $1 and $2
+)
+select
+	day,
+	unit_name,
+	grouping(unit_name) = 1 as consolidated,
+	$3::text as currency,
+	sum(collected) * production.fx_rate('KES', $3, day) as collected,
+	sum(mobile_money_receipted) * production.fx_rate('KES', $3, day) as mobile_money_receipted,
+	sum(mobile_money_paid_in) * production.fx_rate('KES', $3, day) as mobile_money_paid_in
+from
+	activity
+group by
+	grouping sets ((day,
+	unit_name),
+	(day))
+order by
+	day,
+	consolidated,
+	unit_name
\ No newline at end of file
@@ -0,0 +1,35 @@
+-- Units with the users, accounts and M-Pesa shortcodes assigned to them
+select
+	u.unit_name,
+	u.description,
+	array(
+	select
+		uu.username
+	from
+		production.user_units uu
+	where
+		uu.unit_name = u.unit_name
+	order by
+		uu.username) as users,
+	array(
+	select
+		a.account
+	from
+		production.account_units a
+	where
+		a.unit_name = u.unit_name
+	order by
+		a.account) as accounts,
+	array(
+	select
+		s.shortcode
+	from
+		production.mpesa_shortcodes s
+	where
+		s.unit_name = u.unit_name
+	order by
+		s.shortcode) as shortcodes
+from
+	production.units u
+order by
+	u.unit_name
\ No newline at end of file
@@ -0,0 +1,50 @@
+-- Completed M-Pesa and Airtel Money payments of a day that no collection receipt claims.
+-- Transfers between our own accounts, fully reversed payments and payments with a staff decision
+-- are not customer payments to chase
+select
+	s.wallet,
+	s.receipt_no,
+	s.completion_time,
+	s.net_paid_in as paid_in,
+	s.other_party_info,
+	s.unit_name
+from
+	production.mobile_money_payments s
+where
+	s.net_paid_in > 0
+	and s.completion_time::date = $1
+	and not exists (
+	select
+		1
+	from
+		production.collection_details c
+	where
+		c.mpesa > 0
+		and production.mobile_wallet(c.transaction_no) = s.wallet
+		and levenshtein(upper(c.transaction_no), s.receipt_no) < 3
+		and not exists (
+		select
+			1
+		from
+			production.mpesa_shortcodes u
+		where
+			u.shortcode = s.shortcode
+			and u.unit_name is distinct from c.unit_name))
+	and not exists (
+	select
+		1
+	from
+		production.reconciliation_decisions d
+	where
+		d.reversed_at is null
+		and d.statement_account = s.wallet
+		and d.statement_reference = s.receipt_no
+		and d.decision in ('confirm', 'manual'))
+	and not exists (
+	select
+		1
+	from
+		production.transfers t
+	where
+		t.credit_account = s.wallet
+		and t.credit_row_id = s.id)
\ No newline at end of file
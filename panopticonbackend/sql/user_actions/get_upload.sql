@@ -0,0 +1,9 @@
+select
+	id,
+	source,
+	created_at,
+	purged_at
+from
+	production.uploads
+where
+	id = $1
\ No newline at end of file
@@ -0,0 +1,12 @@
+select
+	upload_id,
+	row_no,
+	row
+from
+	production.upload_rows
+where
+	upload_id = $1
+	and row_no > $2
+order by
+	row_no
+limit $3
\ No newline at end of file
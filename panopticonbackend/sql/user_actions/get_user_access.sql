@@ -0,0 +1,17 @@
+-- The user a background job acts for, with the units they are assigned to
+select
+	u.username,
+	u.scope,
+	array(
+	select
+		uu.unit_name
+	from
+		production.user_units uu
+	where
+		uu.username = u.username
+	order by
+		uu.unit_name) as units
+from
+	internal.users u
+where
+	u.username = $1
\ No newline at end of file
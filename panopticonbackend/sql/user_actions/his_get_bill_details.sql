@@ -0,0 +1,45 @@
+-- Runs against the HIS database. New bill lines after the watermark, in the shape of a bill
+-- details upload. The batch is cut at a bill_date boundary
+select
+	to_char(bill_date, 'YYYY-MM-DD HH24:MI:SS') as bill_date,
+	bill_no,
+	skypeid,
+	uhid,
+	visit as visit_type,
+	patient_name,
+	payee,
+	service_name,
+	quantity::float8 as quantity,
+	rate_per_unit::numeric as rate_per_unit,
+	discount::numeric as discount,
+	gross::numeric as gross,
+	paid_amount::numeric as paid_amount,
+	outstanding::numeric as outstanding,
+	service_doc,
+	department,
+	consulting_dr,
+	referring_dr,
+	servicing_dr,
+	payment_mode,
+	$3::text as unit,
+	bill_date as watermark
+from
+	public.bill_details
+where
+	bill_date > $1
+	and bill_date <= (
+	select
+		max(bill_date)
+	from
+		(
+		select
+			bill_date
+		from
+			public.bill_details
+		where
+			bill_date > $1
+		order by
+			bill_date
+		limit $2) batch)
+order by
+	bill_date
\ No newline at end of file
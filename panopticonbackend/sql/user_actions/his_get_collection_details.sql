@@ -0,0 +1,41 @@
+-- Runs against the HIS database. New receipts after the watermark, in the shape of a collection
+-- details upload. The batch is cut at a receipt_date boundary so no row sharing the last
+-- timestamp is left behind
+select
+	receipt_no,
+	to_char(receipt_date, 'YYYY-MM-DD HH24:MI:SS') as receipt_date,
+	patient_name,
+	payee,
+	cash::numeric as cash,
+	cheque::numeric as cheque,
+	card::numeric as card,
+	nullif(trim(card_no::text), '') as card_no,
+	mpesa::numeric as mpesa,
+	e_transfer::numeric as e_transfer,
+	transaction_no,
+	case
+		when adv_used ~ '^-?[0-9]+(\.[0-9]+)?$' then adv_used::numeric
+	end as adv_used,
+	employee_name,
+	unit_name,
+	receipt_date as watermark
+from
+	public.collection_details
+where
+	receipt_date > $1
+	and receipt_date <= (
+	select
+		max(receipt_date)
+	from
+		(
+		select
+			receipt_date
+		from
+			public.collection_details
+		where
+			receipt_date > $1
+		order by
+			receipt_date
+		limit $2) batch)
+order by
+	receipt_date
\ No newline at end of file
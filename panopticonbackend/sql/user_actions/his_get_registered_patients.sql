@@ -0,0 +1,32 @@
+-- Runs against the HIS database. Patients registered after the watermark. The batch is cut at a
+-- registration date boundary
+select
+	uhid,
+	to_char("date", 'YYYY-MM-DD HH24:MI:SS') as "date",
+	patient_name,
+	age::text as age,
+	gender::text as gender,
+	address,
+	contact_no,
+	$3::text as unit_name,
+	"date" as watermark
+from
+	public.registered_patients
+where
+	"date" > $1
+	and "date" <= (
+	select
+		max("date")
+	from
+		(
+		select
+			"date"
+		from
+			public.registered_patients
+		where
+			"date" > $1
+		order by
+			"date"
+		limit $2) batch)
+order by
+	"date"
\ No newline at end of file
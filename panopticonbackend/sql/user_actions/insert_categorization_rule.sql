@@ -0,0 +1,21 @@
+insert
+	into
+	production.categorization_rules (category,
+	priority,
+	account,
+	match_type,
+	pattern,
+	direction,
+	min_amount,
+	max_amount,
+	active)
+values ($1,
+$2,
+$3,
+$4,
+$5,
+$6,
+$7,
+$8,
+$9)
+returning *
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Kept as soon as it arrives. accepted is set once the callback has been handled
+insert
+	into
+	production.daraja_callbacks (kind,
+	receipt_no,
+	payload,
+	accepted)
+values ($1,
+$2,
+$3,
+false)
+returning id
\ No newline at end of file
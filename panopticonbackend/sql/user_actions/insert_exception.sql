@@ -0,0 +1,19 @@
+insert
+	into
+	production.exceptions (item_key,
+	kind,
+	day,
+	billing_number,
+	statement_reference,
+	amount,
+	details,
+	unit_name)
+values ($1,
+$2,
+$3,
+$4,
+$5,
+$6,
+$7,
+$8)
+on conflict (item_key) do nothing
\ No newline at end of file
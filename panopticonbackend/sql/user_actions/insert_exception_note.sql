@@ -0,0 +1,9 @@
+insert
+	into
+	production.exception_notes (exception_id,
+	author,
+	note)
+values ($1,
+$2,
+$3)
+returning *
\ No newline at end of file
@@ -0,0 +1,13 @@
+insert
+	into
+	production.jobs (kind,
+	payload,
+	run_at,
+	max_attempts,
+	requested_by)
+values ($1,
+$2,
+coalesce($3, now()),
+coalesce($4, 5),
+$5)
+returning *
\ No newline at end of file
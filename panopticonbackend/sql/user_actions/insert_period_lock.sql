@@ -0,0 +1,13 @@
+insert
+	into
+	production.period_locks (source,
+	from_date,
+	to_date,
+	locked_by,
+	comment)
+values ($1,
+$2,
+$3,
+$4,
+$5)
+returning *
\ No newline at end of file
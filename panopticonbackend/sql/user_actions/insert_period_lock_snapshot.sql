@@ -0,0 +1 @@
+insert into production.period_lock_snapshots (lock_id, snapshot) values ($1, $2)
\ No newline at end of file
@@ -0,0 +1,15 @@
+insert
+	into
+	production.reconciliation_decisions (billing_number,
+	statement_account,
+	statement_reference,
+	decision,
+	comment,
+	decided_by)
+values ($1,
+$2,
+$3,
+$4,
+$5,
+$6)
+returning *
\ No newline at end of file
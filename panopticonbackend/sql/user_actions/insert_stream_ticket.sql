@@ -0,0 +1,17 @@
+-- A stream ticket for the caller, usable once within a minute. Expired tickets are cleared
+with expired as (
+delete
+from
+	internal.stream_tickets
+where
+	expires_at < now())
+insert
+	into
+	internal.stream_tickets (ticket,
+	username,
+	expires_at)
+values ($1,
+$2,
+now() + interval '1 minute')
+returning ticket,
+expires_at
\ No newline at end of file
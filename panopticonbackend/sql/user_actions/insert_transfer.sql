@@ -0,0 +1,30 @@
+insert
+	into
+	production.transfers (debit_account,
+	debit_row_id,
+	settled_row_ids,
+	debit_date,
+	credit_account,
+	credit_row_id,
+	credit_date,
+	amount,
+	currency)
+values ($1,
+$2,
+$3,
+$4,
+$5,
+$6,
+$7,
+$8,
+$9)
+on conflict do nothing
+returning debit_account,
+debit_row_id,
+settled_row_ids,
+debit_date,
+credit_account,
+credit_row_id,
+credit_date,
+amount,
+currency
\ No newline at end of file
@@ -0,0 +1,5 @@
+insert
+	into
+	production.uploads (source)
+values ($1)
+returning id
\ No newline at end of file
@@ -0,0 +1,8 @@
+insert
+	into
+	production.user_units (username,
+	unit_name)
+values ($1,
+$2)
+on
+conflict do nothing
\ No newline at end of file
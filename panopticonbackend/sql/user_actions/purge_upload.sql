@@ -0,0 +1,13 @@
+with purged as (
+	delete
+	from
+		production.upload_rows
+	where
+		upload_id = $1
+)
+update
+	production.uploads
+set
+	purged_at = now()
+where
+	id = $1
\ No newline at end of file
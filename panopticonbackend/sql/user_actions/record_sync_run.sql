@@ -0,0 +1,27 @@
+-- A failed run keeps the previous watermark and records the error
+insert
+	into
+	production.sync_watermarks (source,
+	watermark,
+	last_run_at,
+	last_success_at,
+	rows_last_run,
+	last_error)
+values ($1,
+$2,
+now(),
+case
+	when $4::text is null then now()
+end,
+$3,
+$4)
+on
+conflict (source) do
+update
+set
+	watermark = coalesce(excluded.watermark, production.sync_watermarks.watermark),
+	last_run_at = excluded.last_run_at,
+	last_success_at = coalesce(excluded.last_success_at, production.sync_watermarks.last_success_at),
+	rows_last_run = excluded.rows_last_run,
+	last_error = excluded.last_error
+returning *
\ No newline at end of file
@@ -0,0 +1 @@
+refresh materialized view concurrently public.mpesa_reconciliations
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Renewed by the worker while the job runs, so it is not taken for a stopped worker's job
+update
+	production.jobs
+set
+	locked_at = now()
+where
+	id = $1
+	and status = 'running'
+	and locked_by = $2
\ No newline at end of file
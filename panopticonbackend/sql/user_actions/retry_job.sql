@@ -0,0 +1,12 @@
+update
+	production.jobs
+set
+	status = 'queued',
+	attempts = 0,
+	run_at = now(),
+	last_error = null,
+	finished_at = null
+where
+	id = $1
+	and status = 'failed'
+returning *
\ No newline at end of file
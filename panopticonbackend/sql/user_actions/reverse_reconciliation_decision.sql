@@ -0,0 +1,10 @@
+update
+	production.reconciliation_decisions
+set
+	reversed_by = $2,
+	reversed_at = now(),
+	reversal_comment = $3
+where
+	id = $1
+	and reversed_at is null
+returning *
\ No newline at end of file
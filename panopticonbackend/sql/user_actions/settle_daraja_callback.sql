@@ -0,0 +1,8 @@
+-- The outcome of a recorded callback, with the receipt read from it
+update
+	production.daraja_callbacks
+set
+	receipt_no = coalesce($2, receipt_no),
+	accepted = $3
+where
+	id = $1
\ No newline at end of file
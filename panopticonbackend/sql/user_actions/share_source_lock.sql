@@ -0,0 +1,4 @@
+-- Held by writers of a source until they commit, so a period cannot be locked between their
+-- lock check and their commit. Writers share it with each other
+select
+	pg_advisory_xact_lock_shared(hashtext($1))
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Held while a period of the source is locked, which waits for the writers that already
+-- passed their lock check and keeps new ones out until the lock is committed
+select
+	pg_advisory_xact_lock(hashtext($1))
\ No newline at end of file
@@ -0,0 +1,10 @@
+update
+	production.period_locks
+set
+	unlocked_by = $2,
+	unlocked_at = now(),
+	unlock_reason = $3
+where
+	id = $1
+	and unlocked_at is null
+returning *
\ No newline at end of file
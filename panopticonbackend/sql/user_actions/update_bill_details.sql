@@ -1 +0,0 @@
-COPY staging.bill_details from STDIN BINARY
\ No newline at end of file
@@ -0,0 +1,15 @@
+update
+	production.categorization_rules
+set
+	category = $2,
+	priority = $3,
+	account = $4,
+	match_type = $5,
+	pattern = $6,
+	direction = $7,
+	min_amount = $8,
+	max_amount = $9,
+	active = $10
+where
+	id = $1
+returning *
\ No newline at end of file
@@ -1 +0,0 @@
-COPY staging.cfc_statement from STDIN BINARY
\ No newline at end of file
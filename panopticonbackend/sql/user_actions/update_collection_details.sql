@@ -1 +0,0 @@
-COPY staging.collection_details from STDIN BINARY
\ No newline at end of file
@@ -0,0 +1,9 @@
+update
+	production.exceptions
+set
+	status = coalesce($2, status),
+	assigned_to = coalesce($3, assigned_to),
+	updated_at = now()
+where
+	id = $1
+returning *
\ No newline at end of file
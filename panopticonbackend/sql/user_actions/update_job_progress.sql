@@ -0,0 +1,10 @@
+-- Also renews the lock so a long upload is not taken for a dead worker's job
+update
+	production.jobs
+set
+	rows_processed = $2,
+	rows_failed = $3,
+	errors = $4,
+	locked_at = now()
+where
+	id = $1
\ No newline at end of file
@@ -1 +0,0 @@
-COPY staging.lab_visits from STDIN BINARY
\ No newline at end of file
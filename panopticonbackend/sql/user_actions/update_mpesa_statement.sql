@@ -1,3 +0,0 @@
-COPY staging.mpesa_statement from stdin BINARY
-
--- Returns no of rows inserted
\ No newline at end of file
@@ -1 +0,0 @@
-COPY staging.pdq_breakdowns from stdin BINARY
\ No newline at end of file
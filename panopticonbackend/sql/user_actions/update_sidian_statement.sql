@@ -1 +0,0 @@
-COPY staging.sidian_statement from STDIN BINARY
\ No newline at end of file
@@ -0,0 +1,11 @@
+insert
+	into
+	production.account_units (account,
+	unit_name)
+values ($1,
+$2)
+on
+conflict (account) do
+update
+set
+	unit_name = excluded.unit_name
\ No newline at end of file
@@ -0,0 +1,55 @@
+-- Payments received through Daraja. A later callback for the same receipt_no updates its status
+-- and balance. Rows that came from a statement upload are never touched. Times are
+-- M-Pesa wall-clock times
+with updated as (
+update
+	production.mpesa_statement
+set
+	transaction_status = $5,
+	balance = coalesce($8, balance),
+	shortcode = coalesce(shortcode, $12)
+where
+	receipt_no = $1
+	and source = 'daraja'
+returning id)
+insert
+	into
+	production.mpesa_statement (receipt_no,
+	completion_time,
+	initiation_time,
+	details,
+	transaction_status,
+	paid_in,
+	withdrawn,
+	balance,
+	balance_confirmed,
+	reason_type,
+	other_party_info,
+	linked_transaction_id,
+	ac_no,
+	shortcode,
+	source)
+select
+	$1,
+	production.source_time('mpesa', $2),
+	production.source_time('mpesa', $3),
+	$4,
+	$5,
+	$6,
+	$7,
+	$8,
+	false,
+	$9,
+	$10,
+	null,
+	$11,
+	$12,
+	'daraja'
+where
+	not exists (
+	select
+		1
+	from
+		production.mpesa_statement
+	where
+		receipt_no = $1)
\ No newline at end of file
@@ -0,0 +1,17 @@
+insert
+	into
+	production.fx_rates (base_currency,
+	quote_currency,
+	rate_date,
+	rate)
+values ($1,
+$2,
+$3,
+$4)
+on
+conflict (base_currency,
+quote_currency,
+rate_date) do
+update
+set
+	rate = excluded.rate
\ No newline at end of file
@@ -0,0 +1,21 @@
+insert
+	into
+	production.job_schedules (name,
+	kind,
+	payload,
+	cron,
+	enabled)
+values ($1,
+$2,
+$3,
+$4,
+$5)
+on
+conflict (name) do
+update
+set
+	kind = excluded.kind,
+	payload = excluded.payload,
+	cron = excluded.cron,
+	enabled = excluded.enabled
+returning *
\ No newline at end of file
@@ -0,0 +1,14 @@
+insert
+	into
+	production.mpesa_shortcodes (shortcode,
+	unit_name,
+	description)
+values ($1,
+$2,
+$3)
+on
+conflict (shortcode) do
+update
+set
+	unit_name = excluded.unit_name,
+	description = excluded.description
\ No newline at end of file
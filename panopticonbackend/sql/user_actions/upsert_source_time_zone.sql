@@ -0,0 +1,11 @@
+insert
+	into
+	production.source_time_zones (source,
+	time_zone)
+values ($1,
+$2)
+on
+conflict (source) do
+update
+set
+	time_zone = excluded.time_zone
\ No newline at end of file
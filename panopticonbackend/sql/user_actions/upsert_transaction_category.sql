@@ -0,0 +1,17 @@
+insert
+	into
+	production.transaction_categories (account,
+	source_row_id,
+	category,
+	rule_id)
+values ($1,
+$2,
+$3,
+$4)
+on conflict (account,
+source_row_id) do
+update
+set
+	category = excluded.category,
+	rule_id = excluded.rule_id,
+	categorized_at = now()
\ No newline at end of file
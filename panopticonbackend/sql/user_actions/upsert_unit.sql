@@ -0,0 +1,11 @@
+insert
+	into
+	production.units (unit_name,
+	description)
+values ($1,
+$2)
+on
+conflict (unit_name) do
+update
+set
+	description = excluded.description
\ No newline at end of file
@@ -0,0 +1,252 @@
+pub mod access {
+    use crate::errors::errors::MyError;
+    use crate::models::models::{Job, Scope};
+    use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+    use chrono::{DateTime, Utc};
+    use deadpool_postgres::{Client, Pool};
+    use serde::Serialize;
+    use std::future::Future;
+    use std::pin::Pin;
+    use tokio_postgres::{GenericClient, Row};
+    use uuid::Uuid;
+
+    /// The user behind a request, from the session token sent as `Authorization: Bearer <token>`.
+    /// Admins read every unit, which is the consolidated head office view. Users read the units
+    /// assigned to them in production.user_units. Guests read nothing
+    #[derive(Debug)]
+    pub struct Caller {
+        pub username: String,
+        // None for admins
+        units: Option<Vec<String>>,
+    }
+
+    impl Caller {
+        async fn from_token(client: &Client, token: &str) -> Result<Caller, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_caller.sql");
+
+            let row = client
+                .query_opt(stmt, &[&token])
+                .await?
+                .ok_or(MyError::Unauthorized)?;
+            Caller::from_row(row)
+        }
+
+        /// The user a stream ticket was issued to. A ticket is used once
+        pub async fn from_ticket(client: &Client, ticket: &str) -> Result<Caller, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_ticket_caller.sql");
+
+            let row = client
+                .query_opt(stmt, &[&ticket])
+                .await?
+                .ok_or(MyError::Unauthorized)?;
+            Caller::from_row(row)
+        }
+
+        /// The user a background job was requested by, with the access they have now
+        pub async fn for_username(client: &Client, username: &str) -> Result<Caller, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_user_access.sql");
+
+            let row = client
+                .query_opt(stmt, &[&username])
+                .await?
+                .ok_or_else(|| MyError::Forbidden(format!("{} is no longer a user", username)))?;
+            Caller::from_row(row)
+        }
+
+        fn from_row(row: Row) -> Result<Caller, MyError> {
+            let username: String = row.get("username");
+            let units = match Scope::from_name(row.get("scope")) {
+                Scope::Admin => None,
+                Scope::User => Some(row.get::<_, Vec<String>>("units")),
+                Scope::Guest => {
+                    return Err(MyError::Forbidden(format!(
+                        "{} has not been given access yet",
+                        username
+                    )))
+                }
+            };
+
+            Ok(Caller { username, units })
+        }
+
+        /// The units the caller reads, None for every unit
+        pub fn units(&self) -> Option<&[String]> {
+            self.units.as_deref()
+        }
+
+        /// Whether the caller sees every unit and the accounts of head office
+        pub fn is_consolidated(&self) -> bool {
+            self.units.is_none()
+        }
+
+        /// Admins are the callers that read every unit. Period sign-off is reserved for them
+        pub fn ensure_admin(&self) -> Result<(), MyError> {
+            match self.is_consolidated() {
+                true => Ok(()),
+                false => Err(MyError::Forbidden(format!(
+                    "{} is not an Admin",
+                    self.username
+                ))),
+            }
+        }
+
+        pub fn ensure_consolidated(&self) -> Result<(), MyError> {
+            match self.is_consolidated() {
+                true => Ok(()),
+                false => Err(MyError::Forbidden(format!(
+                    "{} is limited to their own units. This needs every unit",
+                    self.username
+                ))),
+            }
+        }
+
+        /// Rows of a unit, or of head office for None
+        pub fn ensure_unit(&self, unit_name: Option<&str>) -> Result<(), MyError> {
+            match (&self.units, unit_name) {
+                (None, _) => Ok(()),
+                (Some(units), Some(unit_name)) if units.iter().any(|unit| unit == unit_name) => {
+                    Ok(())
+                }
+                (Some(_), Some(unit_name)) => Err(MyError::Forbidden(format!(
+                    "{} does not read {}",
+                    self.username, unit_name
+                ))),
+                (Some(_), None) => Err(MyError::Forbidden(format!(
+                    "{} does not read head office accounts",
+                    self.username
+                ))),
+            }
+        }
+
+        /// A bank or wallet account, or one M-Pesa shortcode of it, belongs to the unit it is
+        /// mapped to in production.account_units or production.mpesa_shortcodes
+        pub async fn ensure_account(
+            &self,
+            client: &Client,
+            account: &str,
+            shortcode: Option<&str>,
+        ) -> Result<(), MyError> {
+            self.ensure_account_in(&***client, account, shortcode).await
+        }
+
+        /// The same check inside an open transaction, for uploads whose rows name their own
+        /// shortcode
+        pub async fn ensure_account_in<C: GenericClient + Sync>(
+            &self,
+            client: &C,
+            account: &str,
+            shortcode: Option<&str>,
+        ) -> Result<(), MyError> {
+            if self.is_consolidated() {
+                return Ok(());
+            }
+            let stmt = include_str!("../sql/user_actions/get_statement_unit.sql");
+
+            let unit_name: Option<String> = client
+                .query_one(stmt, &[&account, &shortcode])
+                .await?
+                .get("unit_name");
+            self.ensure_unit(unit_name.as_deref())
+        }
+
+        /// Every unit a bill was receipted in. Bills the caller cannot see are not found
+        pub async fn ensure_bill(
+            &self,
+            client: &Client,
+            billing_number: &str,
+        ) -> Result<(), MyError> {
+            if self.is_consolidated() {
+                return Ok(());
+            }
+            let stmt = include_str!("../sql/user_actions/get_billing_units.sql");
+
+            let units: Vec<Option<String>> = client
+                .query(stmt, &[&billing_number])
+                .await?
+                .into_iter()
+                .map(|row| row.get("unit_name"))
+                .collect();
+            if units.is_empty() {
+                return Err(MyError::NotFound);
+            }
+            units
+                .iter()
+                .try_for_each(|unit_name| self.ensure_unit(unit_name.as_deref()))
+        }
+
+        /// Whose jobs the caller follows: their own, or every job for None
+        pub fn requester(&self) -> Option<&str> {
+            match self.is_consolidated() {
+                true => None,
+                false => Some(&self.username),
+            }
+        }
+
+        /// Jobs of other users are not found
+        pub fn ensure_job(&self, job: &Job) -> Result<(), MyError> {
+            match self.requester() {
+                Some(username) if job.requested_by.as_deref() != Some(username) => {
+                    Err(MyError::NotFound)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// The unit asked for, if the caller reads it, otherwise all of the caller's units
+        pub fn narrow(&self, unit_name: Option<String>) -> Result<Option<Vec<String>>, MyError> {
+            match unit_name {
+                Some(unit_name) => {
+                    self.ensure_unit(Some(&unit_name))?;
+                    Ok(Some(vec![unit_name]))
+                }
+                None => Ok(self.units.clone()),
+            }
+        }
+    }
+
+    /// Stands in for the session token where a browser cannot send headers, as with
+    /// EventSource. Passed as ?ticket= and valid once, for a minute
+    #[derive(Serialize, Debug)]
+    pub struct StreamTicket {
+        pub ticket: String,
+        pub expires_at: DateTime<Utc>,
+    }
+
+    impl StreamTicket {
+        pub async fn issue(client: &Client, caller: &Caller) -> Result<StreamTicket, MyError> {
+            let stmt = include_str!("../sql/user_actions/insert_stream_ticket.sql");
+
+            let row = client
+                .query_one(stmt, &[&Uuid::new_v4().to_string(), &caller.username])
+                .await?;
+            Ok(StreamTicket {
+                ticket: row.get("ticket"),
+                expires_at: row.get("expires_at"),
+            })
+        }
+    }
+
+    impl FromRequest for Caller {
+        type Error = MyError;
+        type Future = Pin<Box<dyn Future<Output = Result<Caller, MyError>>>>;
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let pool = req
+                .app_data::<web::Data<Pool>>()
+                .cloned()
+                .expect("the database pool is registered as app data");
+            let token = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|token| token.trim().to_string());
+
+            Box::pin(async move {
+                let token = token.ok_or(MyError::Unauthorized)?;
+                let client: Client = pool.get().await.map_err(MyError::PoolError)?;
+                Caller::from_token(&client, &token).await
+            })
+        }
+    }
+}
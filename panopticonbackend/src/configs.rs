@@ -1,16 +1,209 @@
 pub mod config {
     pub use ::config::ConfigError;
+    use chrono_tz::Tz;
     use serde::Deserialize;
+    use std::collections::HashMap;
     #[derive(Deserialize)]
     pub struct Config {
         pub server_addr: String,
         pub pg: deadpool_postgres::Config,
+        #[serde(default)]
+        pub journal: ChartOfAccounts,
+        #[serde(default)]
+        pub daraja: DarajaConfig,
+        /// The HIS database to pull collections, bills and patients from. Sync is off when unset
+        #[serde(default)]
+        pub his: Option<HisConfig>,
+        #[serde(default)]
+        pub reporting: ReportingConfig,
+        #[serde(default)]
+        pub timezone: TimeZoneConfig,
+        /// Per source overrides of the date and amount formats, keyed by source name
+        #[serde(default)]
+        pub parsing: HashMap<String, FormatConfig>,
+        #[serde(default)]
+        pub uploads: UploadLimits,
     }
     impl Config {
         pub fn from_env() -> Result<Self, ConfigError> {
             let mut cfg = ::config::Config::new();
             cfg.merge(::config::Environment::new())?;
-            cfg.try_into()
+            let mut config: Config = cfg.try_into()?;
+
+            // Sessions run in the display zone, so ::date, date filters and day grouping in
+            // the queries follow its day boundaries
+            let zone = format!("-c TimeZone={}", config.timezone.display.name());
+            config.pg.options = Some(match config.pg.options.take() {
+                Some(options) => format!("{} {}", options, zone),
+                None => zone,
+            });
+            Ok(config)
+        }
+    }
+
+    /// Ledger accounts that generated journals post to. Override with e.g. JOURNAL.MPESA=1020
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(default)]
+    pub struct ChartOfAccounts {
+        pub mpesa: String,
+        pub airtel: String,
+        pub absa: String,
+        pub sidian: String,
+        pub cfc: String,
+        pub equity: String,
+        pub kcb: String,
+        pub coop: String,
+        pub ncba: String,
+        pub pdq_clearing: String,
+        pub patient_receipts: String,
+        pub bank_charges: String,
+        pub transfers_in_transit: String,
+    }
+
+    impl ChartOfAccounts {
+        /// The account that entries from a ledger source post to
+        pub fn account_for(&self, source: &str) -> Option<&String> {
+            match source {
+                "mpesa" => Some(&self.mpesa),
+                "airtel" => Some(&self.airtel),
+                "absa" => Some(&self.absa),
+                "sidian" => Some(&self.sidian),
+                "cfc" => Some(&self.cfc),
+                "equity" => Some(&self.equity),
+                "kcb" => Some(&self.kcb),
+                "coop" => Some(&self.coop),
+                "ncba" => Some(&self.ncba),
+                "pdq" => Some(&self.pdq_clearing),
+                _ => None,
+            }
+        }
+    }
+
+    /// Safaricom Daraja callbacks. Set DARAJA.SHORTCODES=600638,174379 to accept payments to
+    /// those shortcodes only and DARAJA.TOKEN to the ?token= every callback URL must carry.
+    /// Callbacks are refused while no token is set
+    #[derive(Deserialize, Clone, Debug, Default)]
+    #[serde(default)]
+    pub struct DarajaConfig {
+        pub shortcodes: String,
+        pub token: Option<String>,
+    }
+
+    impl DarajaConfig {
+        pub fn accepts_shortcode(&self, shortcode: &str) -> bool {
+            self.shortcodes.trim().is_empty()
+                || self.shortcodes.split(',').any(|s| s.trim() == shortcode)
+        }
+
+        /// Daraja does not sign callbacks, so the registered URLs carry a shared token
+        pub fn accepts_token(&self, token: Option<&str>) -> bool {
+            match self.token.as_deref() {
+                Some(expected) => !expected.is_empty() && token == Some(expected),
+                None => false,
+            }
+        }
+    }
+
+    /// Dashboards and the ledger convert amounts to REPORTING.CURRENCY unless a request asks
+    /// for another currency
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(default)]
+    pub struct ReportingConfig {
+        pub currency: String,
+    }
+
+    impl Default for ReportingConfig {
+        fn default() -> Self {
+            ReportingConfig {
+                currency: "KES".to_string(),
+            }
+        }
+    }
+
+    /// Times are stored as UTC instants. Day boundaries, date filters and schedules follow
+    /// TIMEZONE.DISPLAY, an IANA zone name
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(default)]
+    pub struct TimeZoneConfig {
+        pub display: Tz,
+    }
+
+    impl Default for TimeZoneConfig {
+        fn default() -> Self {
+            TimeZoneConfig {
+                display: Tz::Africa__Nairobi,
+            }
+        }
+    }
+
+    /// How a source writes dates and amounts, e.g. PARSING.ABSA.DATE_FORMATS=%d-%b-%Y;%d/%m/%Y
+    /// or PARSING.PDQ.THOUSANDS_SEPARATOR= for none. Unset fields keep the source's defaults
+    #[derive(Deserialize, Clone, Debug, Default)]
+    #[serde(default)]
+    pub struct FormatConfig {
+        /// chrono layouts separated by ;
+        pub date_formats: Option<String>,
+        pub thousands_separator: Option<String>,
+        pub decimal_separator: Option<String>,
+        pub bracketed_negatives: Option<bool>,
+    }
+
+    /// Streamed uploads are refused with 413 once the body passes UPLOADS.MAX_BODY_BYTES or
+    /// a single row passes UPLOADS.MAX_ROW_BYTES
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(default)]
+    pub struct UploadLimits {
+        pub max_body_bytes: usize,
+        pub max_row_bytes: usize,
+    }
+
+    impl Default for UploadLimits {
+        fn default() -> Self {
+            UploadLimits {
+                max_body_bytes: 1 << 30,
+                max_row_bytes: 1 << 20,
+            }
+        }
+    }
+
+    /// HIS.PG.HOST, HIS.PG.DBNAME etc. configure the connection like PG.*
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(default)]
+    pub struct HisConfig {
+        pub pg: deadpool_postgres::Config,
+        /// Rows pulled per query. A run keeps pulling until it is caught up
+        pub batch_size: i64,
+        /// Unit recorded on bill lines and registered patients, which the HIS does not carry
+        pub unit: String,
+    }
+
+    impl Default for HisConfig {
+        fn default() -> Self {
+            HisConfig {
+                pg: deadpool_postgres::Config::default(),
+                batch_size: 5000,
+                unit: "main".to_string(),
+            }
+        }
+    }
+
+    impl Default for ChartOfAccounts {
+        fn default() -> Self {
+            ChartOfAccounts {
+                mpesa: "1010".to_string(),
+                airtel: "1015".to_string(),
+                absa: "1020".to_string(),
+                sidian: "1030".to_string(),
+                cfc: "1040".to_string(),
+                equity: "1060".to_string(),
+                kcb: "1065".to_string(),
+                coop: "1070".to_string(),
+                ncba: "1075".to_string(),
+                pdq_clearing: "1050".to_string(),
+                transfers_in_transit: "1090".to_string(),
+                patient_receipts: "4000".to_string(),
+                bank_charges: "6100".to_string(),
+            }
         }
     }
 }
@@ -9,6 +9,15 @@ pub mod errors {
     #[derive(Display, From, Debug)]
     pub enum MyError {
         NotFound,
+        Unauthorized,
+        #[from(ignore)]
+        BadRequest(String),
+        #[from(ignore)]
+        Forbidden(String),
+        #[from(ignore)]
+        Locked(String),
+        #[from(ignore)]
+        PayloadTooLarge(String),
         PGError(PGError),
         PGMError(PGMError),
         PoolError(PoolError),
@@ -20,6 +29,17 @@ pub mod errors {
         fn error_response(&self) -> HttpResponse {
             match *self {
                 MyError::NotFound => HttpResponse::NotFound().finish(),
+                MyError::Unauthorized => HttpResponse::Unauthorized().finish(),
+                MyError::BadRequest(ref message) => {
+                    HttpResponse::BadRequest().body(message.to_string())
+                }
+                MyError::Forbidden(ref message) => {
+                    HttpResponse::Forbidden().body(message.to_string())
+                }
+                MyError::Locked(ref message) => HttpResponse::Conflict().body(message.to_string()),
+                MyError::PayloadTooLarge(ref message) => {
+                    HttpResponse::PayloadTooLarge().body(message.to_string())
+                }
                 MyError::PoolError(ref err) => {
                     HttpResponse::InternalServerError().body(err.to_string())
                 }
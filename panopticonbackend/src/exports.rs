@@ -0,0 +1,97 @@
+pub mod exports {
+    use crate::models::models::{Journal, JournalFormat};
+    use rust_decimal::Decimal;
+
+    /// Render journals in the layout the accounting package imports.
+    /// Returns the content type and the file body
+    pub fn export_journals(journals: &[Journal], format: JournalFormat) -> (&'static str, String) {
+        match format {
+            JournalFormat::Csv => ("text/csv", journals_to_csv(journals)),
+            JournalFormat::Iif => ("text/plain", journals_to_iif(journals)),
+            JournalFormat::Sage => ("text/csv", journals_to_sage(journals)),
+        }
+    }
+
+    // One row per journal line
+    fn journals_to_csv(journals: &[Journal]) -> String {
+        let mut out = String::from("Date,Reference,Account,Memo,Debit,Credit\n");
+        for journal in journals {
+            for line in journal.lines.iter() {
+                out.push_str(&format!(
+                    "{},{},{},{},{:.2},{:.2}\n",
+                    journal.date.format("%Y-%m-%d"),
+                    csv_field(&journal.reference),
+                    csv_field(&line.account),
+                    csv_field(&journal.memo),
+                    line.debit,
+                    line.credit
+                ));
+            }
+        }
+        out
+    }
+
+    // QuickBooks IIF general journal. Debits are positive and credits negative.
+    // The first line of each journal is the TRNS row and the rest are SPL rows
+    fn journals_to_iif(journals: &[Journal]) -> String {
+        let mut out = String::from(
+            "!TRNS\tTRNSTYPE\tDATE\tACCNT\tDOCNUM\tAMOUNT\tMEMO\n\
+             !SPL\tTRNSTYPE\tDATE\tACCNT\tDOCNUM\tAMOUNT\tMEMO\n\
+             !ENDTRNS\n",
+        );
+        for journal in journals {
+            for (index, line) in journal.lines.iter().enumerate() {
+                out.push_str(&format!(
+                    "{}\tGENERAL JOURNAL\t{}\t{}\t{}\t{:.2}\t{}\n",
+                    if index == 0 { "TRNS" } else { "SPL" },
+                    journal.date.format("%m/%d/%Y"),
+                    iif_field(&line.account),
+                    iif_field(&journal.reference),
+                    line.debit - line.credit,
+                    iif_field(&journal.memo)
+                ));
+            }
+            out.push_str("ENDTRNS\n");
+        }
+        out
+    }
+
+    // Sage 50 audit trail import. JD rows are journal debits and JC rows journal credits
+    fn journals_to_sage(journals: &[Journal]) -> String {
+        let mut out = String::from(
+            "Type,Account Reference,Nominal A/C Ref,Department Code,Date,Reference,Details,Net Amount,Tax Code,Tax Amount\n",
+        );
+        for journal in journals {
+            for line in journal.lines.iter() {
+                let (kind, amount) = if line.debit > Decimal::ZERO {
+                    ("JD", line.debit)
+                } else {
+                    ("JC", line.credit)
+                };
+                out.push_str(&format!(
+                    "{},,{},0,{},{},{},{:.2},T9,0.00\n",
+                    kind,
+                    csv_field(&line.account),
+                    journal.date.format("%d/%m/%Y"),
+                    csv_field(&journal.reference),
+                    csv_field(&journal.memo),
+                    amount
+                ));
+            }
+        }
+        out
+    }
+
+    fn csv_field(value: &str) -> String {
+        if value.contains([',', '"', '\n']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+
+    // IIF is tab separated and has no quoting
+    fn iif_field(value: &str) -> String {
+        value.replace(['\t', '\n'], " ")
+    }
+}
@@ -37,18 +37,33 @@ pub mod handlers {
     pub mod mpesa_handlers {
 
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{MpesaStatement, MpesaStatementInsert},
+            models::models::{
+                upload_dates, BankAccount, Categorizer, DateRange, MpesaShortcode,
+                MpesaShortcodeInsert, MpesaStatement, MpesaStatementInsert, PeriodLock,
+                ReconciliationEvent, ReconciliationScope, ShortcodeFilter, UploadSummary,
+            },
+            notifications::notifications::Notifier,
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
-        use actix_web::{get, post, web, Error, HttpResponse};
+        use actix_web::{delete, get, post, put, web, Error, HttpResponse};
+        use chrono::NaiveDate;
         use deadpool_postgres::{Client, Pool};
+        use rust_decimal::Decimal;
+        use std::collections::BTreeSet;
         use uuid::Uuid;
 
         /// Define the handlers for Mpesa
         /// Scope is /statements/mpesa
         /// fn get_mpesa_statement returns the full mpesa statement.
         #[get("/statements/mpesa")]
-        pub async fn get_mpesa_statement(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+        pub async fn get_mpesa_statement(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
             let request_id = Uuid::new_v4();
             tracing::info!("request_id {} - Getting the mpesa statement", request_id);
 
@@ -61,7 +76,7 @@ pub mod handlers {
                 request_id
             );
 
-            match MpesaStatement::get_statement(&client).await {
+            match MpesaStatement::get_unit_statement(&client, caller.units()).await {
                 Ok(new_statement) => {
                     tracing::info!("Database query completed succesfully");
                     Ok(HttpResponse::Ok().json(new_statement))
@@ -74,71 +89,243 @@ pub mod handlers {
         }
 
         /// Query the reconciled mpesa statement using a specific date
+        /// Staff decisions from /reconciliations/decisions override the automatic matches
+        /// ?unit_name= or ?shortcode= reconciles one branch's receipts only. Otherwise the
+        /// receipts of every unit the caller reads are reconciled
         #[get("/reconciliations/mpesa/{date}")]
         pub async fn reconcile_mpesa_statement(
+            caller: Caller,
             db_pool: web::Data<Pool>,
-            date: web::Path<String>,
+            date: web::Path<NaiveDate>,
+            scope: web::Query<ReconciliationScope>,
         ) -> Result<HttpResponse, MyError> {
             let date = date.into_inner();
 
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
+            let units = caller.narrow(scope.unit_name(&client).await?)?;
             let reconciled_statement =
-                MpesaStatement::get_reconciled_statement(&client, date).await?;
+                MpesaStatement::get_reconciled_statement(&client, date, units.as_deref()).await?;
 
             Ok(HttpResponse::Ok().json(reconciled_statement))
         }
 
+        /// Reversals paired with the transactions they reverse
+        /// Scope is /statements/mpesa/reversals?from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[get("/statements/mpesa/reversals")]
+        pub async fn get_mpesa_reversals(
+            caller: Caller,
+            range: web::Query<DateRange>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let reversals = MpesaStatement::get_reversals(&client, &range, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(reversals))
+        }
+
+        /// Gross payments, reversals, charges and net paid in per day
+        /// Scope is /statements/mpesa/breakdown?from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[get("/statements/mpesa/breakdown")]
+        pub async fn get_mpesa_breakdown(
+            caller: Caller,
+            range: web::Query<DateRange>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let breakdown =
+                MpesaStatement::get_daily_breakdown(&client, &range, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(breakdown))
+        }
+
         // Post to the Mpesa Statement. This handler takes a String of Json POSTed by the user
+        /// ?shortcode= tags the rows with the paybill or till the statement belongs to
         #[post("/statements/mpesa/update")]
         pub async fn update_mpesa_statement(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
+            filter: web::Query<ShortcodeFilter>,
             db_pool: web::Data<Pool>,
+            notifier: web::Data<Notifier>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<MpesaStatementInsert> = serde_json::from_str(&payment)?;
+            let shortcode = filter.shortcode()?;
+
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<MpesaStatementInsert>::new(payload, formats.rows("mpesa"), &limits);
+            let format = formats.source("mpesa");
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = MpesaStatementInsert::update(&mut client, datas).await?;
+            let mut entries = Vec::new();
+            let mut payments = Vec::new();
+            let mut shortcodes = BTreeSet::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(mut row) = rows.next().await? {
+                if row.shortcode.is_none() {
+                    row.shortcode = shortcode.clone();
+                }
+                shortcodes.insert(row.shortcode.clone());
+                entries.push(row.balance_entry(&format));
+                if row.paid_in.unwrap_or(Decimal::ZERO) > Decimal::ZERO {
+                    payments.push(row.receipt_no.clone());
+                }
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            for shortcode in &shortcodes {
+                caller
+                    .ensure_account_in(&*tx, BankAccount::Mpesa.name(), shortcode.as_deref())
+                    .await?;
+            }
 
-            Ok(HttpResponse::Ok().json(insertion))
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(&*tx, BankAccount::Mpesa.name(), upload_dates(&entries))
+                .await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(BankAccount::Mpesa, insertion, entries);
+
+            summary.rows_categorized =
+                Categorizer::categorize_new_rows(&mut client, BankAccount::Mpesa).await?;
+
+            notifier.publish(ReconciliationEvent::for_payments(&client, &payments).await?);
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+
+        /// Paybills and tills with their unit and latest balance
+        #[get("/statements/mpesa/shortcodes")]
+        pub async fn get_mpesa_shortcodes(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let shortcodes = MpesaShortcode::get_shortcodes(&client).await?;
+
+            Ok(HttpResponse::Ok().json(shortcodes))
+        }
+
+        /// Map a paybill or till to the unit it collects for
+        #[put("/statements/mpesa/shortcodes/{shortcode}")]
+        pub async fn put_mpesa_shortcode(
+            caller: Caller,
+            shortcode: web::Path<String>,
+            mapping: web::Json<MpesaShortcodeInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let shortcode = ShortcodeFilter {
+                shortcode: Some(shortcode.into_inner()),
+            }
+            .shortcode()?
+            .ok_or(MyError::NotFound)?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            MpesaShortcode::upsert(&client, &shortcode, mapping.into_inner()).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        #[delete("/statements/mpesa/shortcodes/{shortcode}")]
+        pub async fn delete_mpesa_shortcode(
+            caller: Caller,
+            shortcode: web::Path<String>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            MpesaShortcode::delete(&client, &shortcode).await?;
+
+            Ok(HttpResponse::NoContent().finish())
         }
     }
     pub mod collection_details_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{CollectionDetails, CollectionDetailsInsert},
+            models::models::{
+                CollectionDetails, CollectionDetailsInsert, CollectionReceipt, PeriodLock,
+                ReconciliationEvent,
+            },
+            notifications::notifications::Notifier,
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
+        use std::collections::BTreeSet;
 
         #[get("/statements/collectiondetails")]
         pub async fn get_collection_details(
+            caller: Caller,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            let new_statement = CollectionDetails::get_statement(&client).await?;
+            let new_statement =
+                CollectionDetails::get_unit_statement(&client, caller.units()).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
         // Post to staging.collectiondetails. This handler takes a String of Json POSTed by the user
         #[post("/statements/collectiondetails/update")]
         pub async fn update_collection_details(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
+            notifier: web::Data<Notifier>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<CollectionDetailsInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read. Of the body only the fields matched for
+            // cashiers are kept, for each M-Pesa receipt
+            let mut rows = JsonRows::<CollectionDetailsInsert>::new(
+                payload,
+                formats.rows("collectiondetails"),
+                &limits,
+            );
+            let format = formats.source("collectiondetails");
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = CollectionDetailsInsert::update(&mut client, datas).await?;
+            let mut dates = BTreeSet::new();
+            let mut receipts = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                caller.ensure_unit(row.unit_name.as_deref())?;
+                if let Some(date) = format.parse_date(&row.receipt_date) {
+                    dates.insert(date.date());
+                }
+                copy.write(&row).await?;
+                receipts.extend(CollectionReceipt::from_collection(&row));
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Collections feed the M-Pesa reconciliation, so they may not change a signed-off M-Pesa period
+            PeriodLock::ensure_unlocked_in(&*tx, "mpesa", dates.into_iter().collect()).await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            // Tell cashiers whether the codes they typed match a payment, a batch at a time
+            for batch in receipts.chunks(1000) {
+                notifier.publish(ReconciliationEvent::for_receipts_in(&**client, batch).await?);
+            }
 
             Ok(HttpResponse::Ok().json(insertion))
         }
@@ -146,34 +333,49 @@ pub mod handlers {
 
     pub mod bill_details_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
             models::models::{BillDetails, BillDetailsInsert},
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
 
         #[get("/statements/billdetails")]
-        pub async fn get_bill_details(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+        pub async fn get_bill_details(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            let new_statement = BillDetails::get_statement(&client).await?;
+            let new_statement = BillDetails::get_unit_statement(&client, caller.units()).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
         // Post to staging.billdetails. This handler takes a String of Json POSTed by the user
         #[post("/statements/billdetails/update")]
         pub async fn update_bill_details(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<BillDetailsInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<BillDetailsInsert>::new(payload, formats.rows("billdetails"), &limits);
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = BillDetailsInsert::update(&mut client, datas).await?;
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                caller.ensure_unit(Some(&row.unit))?;
+                copy.write(&row).await?;
+            }
+            let insertion = copy.commit().await?;
 
             Ok(HttpResponse::Ok().json(insertion))
         }
@@ -181,8 +383,12 @@ pub mod handlers {
 
     pub mod lab_visits_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
             models::models::{LabVisits, LabVisitsInsert},
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         //use chrono::NaiveDateTime;
@@ -190,60 +396,83 @@ pub mod handlers {
         // Post to staging.lab_visits. This handler takes a String of Json POSTed by the user
         #[post("/statements/labvisits/update")]
         pub async fn update_lab_visits(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<LabVisitsInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<LabVisitsInsert>::new(payload, formats.rows("labvisits"), &limits);
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = LabVisitsInsert::update(&mut client, datas).await?;
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                caller.ensure_unit(row.unit_name.as_deref())?;
+                copy.write(&row).await?;
+            }
+            let insertion = copy.commit().await?;
 
             Ok(HttpResponse::Ok().json(insertion))
         }
 
         #[get("/statements/labvisits")]
-        pub async fn get_lab_visits(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+        pub async fn get_lab_visits(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            let new_statement = LabVisits::get_statement(&client).await?;
+            let new_statement = LabVisits::get_unit_statement(&client, caller.units()).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
     }
 
     pub mod registered_patients_handlers {
-        use crate::{errors::errors::MyError, models::models::RegisteredPatients};
+        use crate::{
+            access::access::Caller, errors::errors::MyError, models::models::RegisteredPatients,
+        };
         use actix_web::{get, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
 
         /// Query the registered patients table
         #[get("/statements/registeredpatients")]
         pub async fn get_registered_patients(
+            caller: Caller,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            let new_statement = RegisteredPatients::get_statement(&client).await?;
+            let new_statement =
+                RegisteredPatients::get_unit_statement(&client, caller.units()).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
     }
     pub mod mtiba_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
             models::models::{MtibaStatement, MtibaStatementInsert},
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
 
         #[get("/statements/mtiba")]
-        pub async fn get_mtiba_statement(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+        pub async fn get_mtiba_statement(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
+            caller.ensure_account(&client, "mtiba", None).await?;
             let new_statement = MtibaStatement::get_statement(&client).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
@@ -251,17 +480,26 @@ pub mod handlers {
         // Post to staging.mtiba. This handler takes a String of Json POSTed by the user
         #[post("/statements/mtiba/update")]
         pub async fn update_mtiba_statement(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<MtibaStatementInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<MtibaStatementInsert>::new(payload, formats.rows("mtiba"), &limits);
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = MtibaStatementInsert::update(&mut client, datas).await?;
+            caller.ensure_account(&client, "mtiba", None).await?;
+
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                copy.write(&row).await?;
+            }
+            let insertion = copy.commit().await?;
 
             Ok(HttpResponse::Ok().json(insertion))
         }
@@ -269,8 +507,15 @@ pub mod handlers {
 
     pub mod sidian_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{Sidian, SidianInsert},
+            models::models::{
+                upload_dates, BankAccount, Categorizer, PeriodLock, Sidian, SidianInsert,
+                UploadSummary,
+            },
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
@@ -278,98 +523,188 @@ pub mod handlers {
         /// Get the entire Sidian Bank Statement
         #[get("/statements/sidian")]
         pub async fn get_sidian_bank_statement(
+            caller: Caller,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
+            caller
+                .ensure_account(&client, BankAccount::Sidian.name(), None)
+                .await?;
             let new_statement = Sidian::get_statement(&client).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
         #[post("/statements/sidian/update")]
         pub async fn update_sidian_statement(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<SidianInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows = JsonRows::<SidianInsert>::new(payload, formats.rows("sidian"), &limits);
+            let format = formats.source("sidian");
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = SidianInsert::update(&mut client, datas).await?;
+            caller
+                .ensure_account(&client, BankAccount::Sidian.name(), None)
+                .await?;
 
-            Ok(HttpResponse::Ok().json(insertion))
+            let mut entries = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                entries.push(row.balance_entry(&format));
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(
+                &*tx,
+                BankAccount::Sidian.name(),
+                upload_dates(&entries),
+            )
+            .await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(BankAccount::Sidian, insertion, entries);
+
+            summary.rows_categorized =
+                Categorizer::categorize_new_rows(&mut client, BankAccount::Sidian).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
         }
     }
 
     pub mod absa_bank_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{ABSAInsert, ABSA},
+            models::models::{
+                upload_dates, ABSAInsert, Absa, BankAccount, Categorizer, PeriodLock, UploadSummary,
+            },
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
 
         #[get("/statements/absa")]
         pub async fn get_absa_bank_statement(
+            caller: Caller,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            let new_statement = ABSA::get_statement(&client).await?;
+            caller
+                .ensure_account(&client, BankAccount::Absa.name(), None)
+                .await?;
+            let new_statement = Absa::get_statement(&client).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
 
         #[post("/statements/absa/update")]
         pub async fn update_absa_statement(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<ABSAInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows = JsonRows::<ABSAInsert>::new(payload, formats.rows("absa"), &limits);
+            let format = formats.source("absa");
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = ABSAInsert::update(&mut client, datas).await?;
+            caller
+                .ensure_account(&client, BankAccount::Absa.name(), None)
+                .await?;
 
-            Ok(HttpResponse::Ok().json(insertion))
+            let mut entries = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                entries.push(row.balance_entry(&format));
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(&*tx, BankAccount::Absa.name(), upload_dates(&entries))
+                .await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(BankAccount::Absa, insertion, entries);
+
+            summary.rows_categorized =
+                Categorizer::categorize_new_rows(&mut client, BankAccount::Absa).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
         }
     }
 
     pub mod pdq_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{PdqBreakdown, PdqBreakdownInsert},
+            models::models::{PdqBreakdown, PdqBreakdownInsert, PeriodLock},
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
+        use std::collections::BTreeSet;
 
         #[get("/statements/pdq")]
-        pub async fn get_pdq_statement(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+        pub async fn get_pdq_statement(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
+            caller.ensure_account(&client, "pdq", None).await?;
             let new_statement = PdqBreakdown::get_statement(&client).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
         #[post("/statements/pdq/update")]
         pub async fn update_pdq_breakdowns(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of MpesaPayment
-            let datas: Vec<PdqBreakdownInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<PdqBreakdownInsert>::new(payload, formats.rows("pdq"), &limits);
+            let format = formats.source("pdq");
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = PdqBreakdownInsert::update(&mut client, datas).await?;
+            caller.ensure_account(&client, "pdq", None).await?;
+
+            let mut dates = BTreeSet::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                if let Some(date) = row.txn_date.as_deref().and_then(|d| format.parse_date(d)) {
+                    dates.insert(date.date());
+                }
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            PeriodLock::ensure_unlocked_in(&*tx, "pdq", dates.into_iter().collect()).await?;
+            tx.commit().await.map_err(MyError::PGError)?;
 
             Ok(HttpResponse::Ok().json(insertion))
         }
@@ -377,37 +712,2160 @@ pub mod handlers {
 
     pub mod cfc_handlers {
         use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
             errors::errors::MyError,
-            models::models::{Cfc, CfcInsert},
+            models::models::{
+                upload_dates, BankAccount, Categorizer, Cfc, CfcInsert, PeriodLock, UploadSummary,
+            },
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
         };
         use actix_web::{get, post, web, Error, HttpResponse};
         use deadpool_postgres::{Client, Pool};
 
         #[get("/statements/cfc")]
         pub async fn get_cfc_bank_statement(
+            caller: Caller,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
             let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
+            caller
+                .ensure_account(&client, BankAccount::Cfc.name(), None)
+                .await?;
             let new_statement = Cfc::get_statement(&client).await?;
 
             Ok(HttpResponse::Ok().json(new_statement))
         }
         #[post("/statements/cfc/update")]
         pub async fn update_cfc_statement(
-            payment: String,
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
             db_pool: web::Data<Pool>,
         ) -> Result<HttpResponse, Error> {
-            // Deserialize into a vec of CFC transactions
-            let datas: Vec<CfcInsert> = serde_json::from_str(&payment)?;
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows = JsonRows::<CfcInsert>::new(payload, formats.rows("cfc"), &limits);
 
             // Create the database connection
             let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-            // Call the db insertion handler. Insert the vec of Mpesa Payments. Result from DB should be number of rows inserted
-            let insertion = CfcInsert::update(&mut client, datas).await?;
+            caller
+                .ensure_account(&client, BankAccount::Cfc.name(), None)
+                .await?;
 
-            Ok(HttpResponse::Ok().json(insertion))
+            let mut entries = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                entries.push(row.balance_entry());
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(&*tx, BankAccount::Cfc.name(), upload_dates(&entries))
+                .await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(BankAccount::Cfc, insertion, entries);
+
+            summary.rows_categorized =
+                Categorizer::categorize_new_rows(&mut client, BankAccount::Cfc).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+    }
+
+    pub mod airtel_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
+            errors::errors::MyError,
+            models::models::{
+                upload_dates, AirtelStatement, AirtelStatementInsert, BankAccount, Categorizer,
+                PeriodLock, UploadSummary,
+            },
+            parsing::parsing::Formats,
+            streaming::streaming::{CopyIn, JsonRows},
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        #[get("/statements/airtel")]
+        pub async fn get_airtel_statement(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller
+                .ensure_account(&client, BankAccount::Airtel.name(), None)
+                .await?;
+            let new_statement = AirtelStatement::get_statement(&client).await?;
+
+            Ok(HttpResponse::Ok().json(new_statement))
+        }
+        #[post("/statements/airtel/update")]
+        pub async fn update_airtel_statement(
+            caller: Caller,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<AirtelStatementInsert>::new(payload, formats.rows("airtel"), &limits);
+
+            // Create the database connection
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller
+                .ensure_account(&client, BankAccount::Airtel.name(), None)
+                .await?;
+
+            let mut entries = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(row) = rows.next().await? {
+                entries.push(row.balance_entry());
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(
+                &*tx,
+                BankAccount::Airtel.name(),
+                upload_dates(&entries),
+            )
+            .await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(BankAccount::Airtel, insertion, entries);
+
+            summary.rows_categorized =
+                Categorizer::categorize_new_rows(&mut client, BankAccount::Airtel).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+    }
+
+    pub mod bank_statement_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
+            errors::errors::MyError,
+            models::models::{
+                upload_dates, BankAccount, BankStatement, BankStatementInsert, Categorizer,
+                CurrencyQuery, PeriodLock, UploadSummary,
+            },
+            parsing::parsing::Formats,
+            sources::sources::copy_rows_in,
+            statement_import::statement_import::parse_export,
+            streaming::streaming::{read_body, CopyIn, JsonRows},
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        // Equity, KCB, Co-operative Bank and NCBA share production.bank_statements. The
+        // other accounts have their own routes
+        fn statement_bank(bank: web::Path<BankAccount>) -> Result<BankAccount, MyError> {
+            let bank = bank.into_inner();
+            if bank.in_bank_statements() {
+                Ok(bank)
+            } else {
+                Err(MyError::NotFound)
+            }
+        }
+
+        /// Scope is /statements/{equity|kcb|coop|ncba}
+        #[get("/statements/{bank}")]
+        pub async fn get_bank_statement(
+            caller: Caller,
+            bank: web::Path<BankAccount>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let bank = statement_bank(bank)?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller.ensure_account(&client, bank.name(), None).await?;
+            let statement = BankStatement::get_bank_statement(&client, bank).await?;
+
+            Ok(HttpResponse::Ok().json(statement))
+        }
+
+        /// Rows as JSON, like the other statements. `bank` is taken from the route
+        #[post("/statements/{bank}/update")]
+        pub async fn update_bank_statement(
+            caller: Caller,
+            bank: web::Path<BankAccount>,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            formats: web::Data<Formats>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let bank = statement_bank(bank)?;
+
+            // Rows are copied in as they are read, so the body is never held whole
+            let mut rows =
+                JsonRows::<BankStatementInsert>::new(payload, formats.rows(bank.name()), &limits);
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller.ensure_account(&client, bank.name(), None).await?;
+
+            let mut entries = Vec::new();
+            let mut copy = CopyIn::begin(&mut client).await?;
+            while let Some(mut row) = rows.next().await? {
+                row.bank = bank.name().to_string();
+                entries.push(row.balance_entry());
+                copy.write(&row).await?;
+            }
+            let (tx, insertion) = copy.finish().await?;
+
+            // Uploads may not change a signed-off period. A locked upload is rolled back
+            PeriodLock::ensure_unlocked_in(&*tx, bank.name(), upload_dates(&entries)).await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(bank, insertion, entries);
+
+            summary.rows_categorized = Categorizer::categorize_new_rows(&mut client, bank).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+
+        /// The bank's own CSV or Excel export as the body. ?currency=USD for a foreign currency
+        /// account
+        #[post("/statements/{bank}/import")]
+        pub async fn import_bank_statement(
+            caller: Caller,
+            bank: web::Path<BankAccount>,
+            currency: web::Query<CurrencyQuery>,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            db_pool: web::Data<Pool>,
+            formats: web::Data<Formats>,
+        ) -> Result<HttpResponse, Error> {
+            let bank = statement_bank(bank)?;
+
+            // Workbooks are zip archives, so the export is read whole before its rows
+            let body = read_body(payload, &limits).await?;
+            let datas = parse_export(
+                bank,
+                &body,
+                currency.currency.as_deref(),
+                &formats.source(bank.name()),
+            )?;
+            let entries: Vec<_> = datas.iter().map(|row| row.balance_entry()).collect();
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller.ensure_account(&client, bank.name(), None).await?;
+
+            // Like the JSON uploads, an export of a locked period is rolled back
+            let mut tx = client.transaction().await.map_err(MyError::PGError)?;
+            let insertion = copy_rows_in(&mut tx, &datas).await?;
+            PeriodLock::ensure_unlocked_in(&*tx, bank.name(), upload_dates(&entries)).await?;
+            tx.commit().await.map_err(MyError::PGError)?;
+
+            let mut summary = UploadSummary::new(bank, insertion, entries);
+
+            summary.rows_categorized = Categorizer::categorize_new_rows(&mut client, bank).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+    }
+
+    pub mod continuity_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{
+                BalanceEntry, BankAccount, ContinuityReport, DateRange, ShortcodeFilter,
+            },
+        };
+        use actix_web::{get, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Check the running balance of a bank or M-Pesa statement for a date range
+        /// Scope is /statements/{account}/continuity?from=YYYY-MM-DD&to=YYYY-MM-DD
+        /// Add &shortcode= to check one M-Pesa paybill or till
+        #[get("/statements/{account}/continuity")]
+        pub async fn get_balance_continuity(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+            account: web::Path<BankAccount>,
+            range: web::Query<DateRange>,
+            filter: web::Query<ShortcodeFilter>,
+        ) -> Result<HttpResponse, Error> {
+            let account = account.into_inner();
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let shortcode = filter.shortcode()?;
+            caller
+                .ensure_account(&client, account.name(), shortcode.as_deref())
+                .await?;
+
+            // Each M-Pesa paybill and till keeps its own balance
+            let entries = match (account, shortcode) {
+                (BankAccount::Mpesa, Some(shortcode)) => {
+                    BalanceEntry::get_shortcode_entries(&client, &shortcode, &range).await?
+                }
+                _ => BalanceEntry::get_entries(&client, account, &range).await?,
+            };
+
+            let report = ContinuityReport::check(account, entries);
+
+            Ok(HttpResponse::Ok().json(report))
+        }
+    }
+
+    pub mod ledger_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::ReportingConfig,
+            errors::errors::MyError,
+            models::models::{Ledger, LedgerFilter},
+        };
+        use actix_web::{get, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// The cash book across M-Pesa, the banks and PDQ
+        /// Scope is /ledger?from=YYYY-MM-DD&to=YYYY-MM-DD&account=absa&search=text&currency=USD
+        /// Only the rows and accounts of the caller's units are included
+        #[get("/ledger")]
+        pub async fn get_ledger(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+            reporting: web::Data<ReportingConfig>,
+            filter: web::Query<LedgerFilter>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let ledger =
+                Ledger::get_ledger(&client, &filter, caller.units(), &reporting.currency).await?;
+
+            Ok(HttpResponse::Ok().json(ledger))
+        }
+    }
+
+    pub mod journal_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::{ChartOfAccounts, ReportingConfig},
+            errors::errors::MyError,
+            exports::exports::export_journals,
+            models::models::{DateRange, Journal, JournalQuery},
+        };
+        use actix_web::{get, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Double-entry journals for a date range
+        /// Scope is /journals?from=YYYY-MM-DD&to=YYYY-MM-DD&format=csv|iif|sage
+        #[get("/journals")]
+        pub async fn get_journals(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+            chart: web::Data<ChartOfAccounts>,
+            reporting: web::Data<ReportingConfig>,
+            query: web::Query<JournalQuery>,
+        ) -> Result<HttpResponse, Error> {
+            // Journals post every unit's takings to the one chart of accounts
+            caller.ensure_consolidated()?;
+
+            let query = query.into_inner();
+            let range = DateRange {
+                from: query.from,
+                to: query.to,
+            };
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let journals =
+                Journal::get_journals(&client, &chart, &range, &reporting.currency).await?;
+
+            match query.format {
+                Some(format) => {
+                    let (content_type, body) = export_journals(&journals, format);
+                    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+                }
+                None => Ok(HttpResponse::Ok().json(journals)),
+            }
+        }
+    }
+
+    pub mod transfer_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::TimeZoneConfig,
+            errors::errors::MyError,
+            models::models::{DateRange, Transfer, TransferQuery},
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Transfers already detected for a date range
+        #[get("/transfers")]
+        pub async fn get_transfers(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+            range: web::Query<DateRange>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let transfers = Transfer::get_transfers(&client, &range).await?;
+
+            Ok(HttpResponse::Ok().json(transfers))
+        }
+
+        /// Pair debits and credits between accounts and tag them as transfers. Returns the pairs
+        /// this run stored
+        /// Scope is /transfers/detect?from=YYYY-MM-DD&to=YYYY-MM-DD&window_days=3
+        #[post("/transfers/detect")]
+        pub async fn detect_transfers(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+            time_zone: web::Data<TimeZoneConfig>,
+            query: web::Query<TransferQuery>,
+        ) -> Result<HttpResponse, Error> {
+            // Transfers move money between units
+            caller.ensure_consolidated()?;
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let transfers = Transfer::detect(&mut client, &query, time_zone.display).await?;
+
+            tracing::info!("Detected {} new transfers", transfers.len());
+
+            Ok(HttpResponse::Ok().json(transfers))
+        }
+    }
+
+    pub mod categorization_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{
+                CategorizationRule, CategorizationRuleInsert, CategorizationRun, Categorizer,
+            },
+        };
+        use actix_web::{delete, get, post, put, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// All categorization rules in the order they are tried
+        #[get("/categorization/rules")]
+        pub async fn get_categorization_rules(
+            _caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let rules = CategorizationRule::get_rules(&client).await?;
+
+            Ok(HttpResponse::Ok().json(rules))
+        }
+
+        #[post("/categorization/rules")]
+        pub async fn create_categorization_rule(
+            caller: Caller,
+            rule: web::Json<CategorizationRuleInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Rules apply to the accounts of every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let rule = CategorizationRule::create(&client, rule.into_inner()).await?;
+
+            Ok(HttpResponse::Created().json(rule))
+        }
+
+        #[put("/categorization/rules/{id}")]
+        pub async fn update_categorization_rule(
+            caller: Caller,
+            id: web::Path<i64>,
+            rule: web::Json<CategorizationRuleInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Rules apply to the accounts of every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let rule =
+                CategorizationRule::update(&client, id.into_inner(), rule.into_inner()).await?;
+
+            Ok(HttpResponse::Ok().json(rule))
+        }
+
+        #[delete("/categorization/rules/{id}")]
+        pub async fn delete_categorization_rule(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Rules apply to the accounts of every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            CategorizationRule::delete(&client, id.into_inner()).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        /// Run the rules again over already categorized rows, e.g. after the rules changed
+        /// Scope is /categorization/run?account=absa&from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[post("/categorization/run")]
+        pub async fn rerun_categorization(
+            caller: Caller,
+            run: web::Query<CategorizationRun>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Rules apply to the accounts of every unit
+            caller.ensure_consolidated()?;
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let categorizer = Categorizer::load(&client).await?;
+
+            let categorized = categorizer
+                .run(&mut client, run.account.as_deref(), run.from, run.to, true)
+                .await?;
+
+            Ok(HttpResponse::Ok().json(categorized))
+        }
+    }
+
+    pub mod reconciliation_decision_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{
+                ReconciliationDecision, ReconciliationDecisionFilter, ReconciliationDecisionInsert,
+                ReconciliationDecisionReversal,
+            },
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Decision history. Reversed decisions are only included with include_reversed=true
+        #[get("/reconciliations/decisions")]
+        pub async fn get_reconciliation_decisions(
+            caller: Caller,
+            filter: web::Query<ReconciliationDecisionFilter>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let decisions =
+                ReconciliationDecision::get_decisions(&client, &filter, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(decisions))
+        }
+
+        /// Confirm, reject or manually create a match between a receipt and a statement row
+        #[post("/reconciliations/decisions")]
+        pub async fn create_reconciliation_decision(
+            caller: Caller,
+            decision: web::Json<ReconciliationDecisionInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            caller
+                .ensure_bill(&client, &decision.billing_number)
+                .await?;
+
+            let decision = ReconciliationDecision::create(
+                &mut client,
+                decision.into_inner(),
+                &caller.username,
+            )
+            .await?;
+
+            tracing::info!(
+                "{} recorded {} decision {} for bill {}",
+                decision.decided_by,
+                decision.decision,
+                decision.id,
+                decision.billing_number
+            );
+
+            Ok(HttpResponse::Created().json(decision))
+        }
+
+        #[post("/reconciliations/decisions/{id}/reverse")]
+        pub async fn reverse_reconciliation_decision(
+            caller: Caller,
+            id: web::Path<i64>,
+            reversal: web::Json<ReconciliationDecisionReversal>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let id = id.into_inner();
+            let decision = ReconciliationDecision::get_decision(&client, id).await?;
+            caller
+                .ensure_bill(&client, &decision.billing_number)
+                .await?;
+
+            let decision = ReconciliationDecision::reverse(
+                &mut client,
+                id,
+                reversal.into_inner(),
+                &caller.username,
+            )
+            .await?;
+
+            Ok(HttpResponse::Ok().json(decision))
+        }
+    }
+
+    pub mod period_lock_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{PeriodLock, PeriodLockFilter, PeriodLockInsert, PeriodUnlock},
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        #[get("/periods/locks")]
+        pub async fn get_period_locks(
+            caller: Caller,
+            filter: web::Query<PeriodLockFilter>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let locks = PeriodLock::get_locks(&client, &filter, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(locks))
+        }
+
+        /// Sign off a period of a source. Admins only
+        #[post("/periods/locks")]
+        pub async fn lock_period(
+            caller: Caller,
+            lock: web::Json<PeriodLockInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_admin()?;
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let lock = PeriodLock::lock(&mut client, lock.into_inner(), &caller.username).await?;
+
+            tracing::info!(
+                "{} locked {} from {} to {}",
+                lock.locked_by,
+                lock.source,
+                lock.from_date,
+                lock.to_date
+            );
+
+            Ok(HttpResponse::Created().json(lock))
+        }
+
+        /// Reopen a locked period. Admins only, and a reason is required
+        #[post("/periods/locks/{id}/unlock")]
+        pub async fn unlock_period(
+            caller: Caller,
+            id: web::Path<i64>,
+            unlock: web::Json<PeriodUnlock>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_admin()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let lock = PeriodLock::unlock(
+                &client,
+                id.into_inner(),
+                unlock.into_inner(),
+                &caller.username,
+            )
+            .await?;
+
+            tracing::info!(
+                "{} unlocked {} from {} to {}",
+                lock.unlocked_by.as_deref().unwrap_or_default(),
+                lock.source,
+                lock.from_date,
+                lock.to_date
+            );
+
+            Ok(HttpResponse::Ok().json(lock))
+        }
+
+        /// The reconciliation results stored when the period was locked
+        #[get("/periods/locks/{id}/snapshot")]
+        pub async fn get_period_lock_snapshot(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // The snapshot holds the reconciliation of every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let snapshot = PeriodLock::get_snapshot(&client, id.into_inner()).await?;
+
+            Ok(HttpResponse::Ok().json(snapshot))
+        }
+    }
+
+    pub mod exception_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{
+                DateRange, ExceptionFilter, ExceptionItem, ExceptionNoteInsert, ExceptionUpdate,
+            },
+        };
+        use actix_web::{get, post, put, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// The exception queue
+        /// Scope is /exceptions?status=open&kind=unmatched_receipt&assigned_to=jane&from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[get("/exceptions")]
+        pub async fn get_exceptions(
+            caller: Caller,
+            filter: web::Query<ExceptionFilter>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let exceptions =
+                ExceptionItem::get_exceptions(&client, &filter, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(exceptions))
+        }
+
+        /// An item with its notes
+        #[get("/exceptions/{id}")]
+        pub async fn get_exception(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let details = ExceptionItem::get_details(&client, id.into_inner()).await?;
+            caller.ensure_unit(details.item.unit_name.as_deref())?;
+
+            Ok(HttpResponse::Ok().json(details))
+        }
+
+        #[put("/exceptions/{id}")]
+        pub async fn update_exception(
+            caller: Caller,
+            id: web::Path<i64>,
+            update: web::Json<ExceptionUpdate>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let id = id.into_inner();
+            let item = ExceptionItem::get_item(&client, id).await?;
+            caller.ensure_unit(item.unit_name.as_deref())?;
+
+            let item =
+                ExceptionItem::update(&mut client, id, update.into_inner(), &caller.username)
+                    .await?;
+
+            Ok(HttpResponse::Ok().json(item))
+        }
+
+        #[post("/exceptions/{id}/notes")]
+        pub async fn add_exception_note(
+            caller: Caller,
+            id: web::Path<i64>,
+            note: web::Json<ExceptionNoteInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let id = id.into_inner();
+            let item = ExceptionItem::get_item(&client, id).await?;
+            caller.ensure_unit(item.unit_name.as_deref())?;
+
+            let note =
+                ExceptionItem::add_note(&client, id, note.into_inner(), &caller.username).await?;
+
+            Ok(HttpResponse::Created().json(note))
+        }
+
+        /// Run the reconciliation over a date range and queue the exceptions it finds
+        #[post("/exceptions/generate")]
+        pub async fn generate_exceptions(
+            caller: Caller,
+            range: web::Query<DateRange>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // The run covers every unit
+            caller.ensure_consolidated()?;
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let created = ExceptionItem::generate(&mut client, &range).await?;
+
+            tracing::info!("Queued {} new exceptions", created);
+
+            Ok(HttpResponse::Ok().json(created))
+        }
+    }
+
+    pub mod fraud_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{DateRange, EmployeeFraudSignals},
+        };
+        use actix_web::{get, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Reused, over-claimed, late and incomplete M-Pesa codes per cashier
+        /// Scope is /fraud/signals?from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[get("/fraud/signals")]
+        pub async fn get_fraud_signals(
+            caller: Caller,
+            range: web::Query<DateRange>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let signals = EmployeeFraudSignals::detect(&client, &range, caller.units()).await?;
+
+            Ok(HttpResponse::Ok().json(signals))
+        }
+    }
+
+    pub mod daraja_handlers {
+        use crate::{
+            configs::config::DarajaConfig,
+            errors::errors::MyError,
+            models::models::{
+                daraja_body, record_daraja_callback, settle_daraja_callback, BankAccount,
+                C2BPayment, Categorizer, DarajaResponse, DarajaResult, DarajaStatementRow,
+                ReconciliationEvent,
+            },
+            notifications::notifications::Notifier,
+        };
+        use actix_web::{post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+        use serde::Deserialize;
+
+        #[derive(Deserialize)]
+        pub struct DarajaToken {
+            token: Option<String>,
+        }
+
+        fn check_token(config: &DarajaConfig, query: &DarajaToken) -> Result<(), MyError> {
+            if config.accepts_token(query.token.as_deref()) {
+                Ok(())
+            } else {
+                Err(MyError::Forbidden("Invalid callback token".to_string()))
+            }
+        }
+
+        // Store a payment Daraja reported, categorize it like an uploaded statement row and tell
+        // the cashiers whose receipts claim it
+        async fn save_payment(
+            client: &mut Client,
+            notifier: &Notifier,
+            row: &DarajaStatementRow,
+        ) -> Result<(), MyError> {
+            if row.save(client).await? > 0 {
+                Categorizer::categorize_new_rows(client, BankAccount::Mpesa).await?;
+                if row.paid_in.is_some() {
+                    let receipt_nos = [row.receipt_no.clone()];
+                    notifier
+                        .publish(ReconciliationEvent::for_payments(client, &receipt_nos).await?);
+                }
+            }
+            tracing::info!("Stored M-Pesa payment {} from Daraja", row.receipt_no);
+            Ok(())
+        }
+
+        /// Daraja C2B validation. Safaricom only completes the payment when we accept it
+        #[post("/daraja/c2b/validation")]
+        pub async fn daraja_c2b_validation(
+            query: web::Query<DarajaToken>,
+            body: web::Bytes,
+            config: web::Data<DarajaConfig>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            check_token(&config, &query)?;
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let body = daraja_body(&body);
+            let receipt_no = body.get("TransID").and_then(|id| id.as_str());
+            let callback =
+                record_daraja_callback(&client, "c2b_validation", receipt_no, &body).await?;
+
+            let response = match serde_json::from_value::<C2BPayment>(body.clone()) {
+                Ok(payment) => match payment.validate(&config) {
+                    Ok(()) => DarajaResponse::accepted(),
+                    Err(rejection) => rejection,
+                },
+                Err(e) => {
+                    tracing::error!("Unreadable C2B validation: {:?}", e);
+                    DarajaResponse::rejected("C2B00016", "Other Error")
+                }
+            };
+
+            settle_daraja_callback(&client, callback, None, response.result_code == "0").await?;
+
+            Ok(HttpResponse::Ok().json(response))
+        }
+
+        /// Daraja C2B confirmation. The money has moved, so the payment is always acknowledged
+        /// and the raw body is kept when it cannot be stored
+        #[post("/daraja/c2b/confirmation")]
+        pub async fn daraja_c2b_confirmation(
+            query: web::Query<DarajaToken>,
+            body: web::Bytes,
+            config: web::Data<DarajaConfig>,
+            db_pool: web::Data<Pool>,
+            notifier: web::Data<Notifier>,
+        ) -> Result<HttpResponse, Error> {
+            check_token(&config, &query)?;
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let body = daraja_body(&body);
+            let receipt_no = body.get("TransID").and_then(|id| id.as_str());
+            let callback =
+                record_daraja_callback(&client, "c2b_confirmation", receipt_no, &body).await?;
+
+            let row = serde_json::from_value::<C2BPayment>(body.clone())
+                .map_err(MyError::SerdeError)
+                .and_then(|payment| payment.statement_row());
+            let stored = match row {
+                Ok(row) => save_payment(&mut client, &notifier, &row).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &stored {
+                tracing::error!("Failed to store C2B confirmation: {:?}", e);
+            }
+
+            settle_daraja_callback(&client, callback, None, stored.is_ok()).await?;
+
+            Ok(HttpResponse::Ok().json(DarajaResponse::accepted()))
+        }
+
+        /// Result of a B2C payment request. Like confirmations, results are acknowledged
+        /// even when they cannot be read and are kept as they arrived
+        #[post("/daraja/b2c/result")]
+        pub async fn daraja_b2c_result(
+            query: web::Query<DarajaToken>,
+            body: web::Bytes,
+            config: web::Data<DarajaConfig>,
+            db_pool: web::Data<Pool>,
+            notifier: web::Data<Notifier>,
+        ) -> Result<HttpResponse, Error> {
+            check_token(&config, &query)?;
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let body = daraja_body(&body);
+            let callback = record_daraja_callback(&client, "b2c_result", None, &body).await?;
+
+            let row = serde_json::from_value::<DarajaResult>(body)
+                .map_err(MyError::SerdeError)
+                .and_then(|result| result.b2c_row());
+            let (stored, receipt_no) = store_result(&mut client, &notifier, row).await;
+            if let Err(e) = &stored {
+                tracing::error!("Failed to store B2C result: {:?}", e);
+            }
+
+            settle_daraja_callback(&client, callback, receipt_no.as_deref(), stored.is_ok())
+                .await?;
+
+            Ok(HttpResponse::Ok().json(DarajaResponse::accepted()))
+        }
+
+        /// Result of a transaction status query. Updates the status of a payment already
+        /// received through Daraja or stores it when it is new
+        #[post("/daraja/transaction-status/result")]
+        pub async fn daraja_transaction_status_result(
+            query: web::Query<DarajaToken>,
+            body: web::Bytes,
+            config: web::Data<DarajaConfig>,
+            db_pool: web::Data<Pool>,
+            notifier: web::Data<Notifier>,
+        ) -> Result<HttpResponse, Error> {
+            check_token(&config, &query)?;
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let body = daraja_body(&body);
+            let callback =
+                record_daraja_callback(&client, "transaction_status_result", None, &body).await?;
+
+            let row = serde_json::from_value::<DarajaResult>(body)
+                .map_err(MyError::SerdeError)
+                .and_then(|result| result.transaction_status_row(&config));
+            let (stored, receipt_no) = store_result(&mut client, &notifier, row).await;
+            if let Err(e) = &stored {
+                tracing::error!("Failed to store transaction status result: {:?}", e);
+            }
+
+            settle_daraja_callback(&client, callback, receipt_no.as_deref(), stored.is_ok())
+                .await?;
+
+            Ok(HttpResponse::Ok().json(DarajaResponse::accepted()))
+        }
+
+        // Store the payment a result reports, if it reports one. Returns the outcome and the
+        // receipt number read
+        async fn store_result(
+            client: &mut Client,
+            notifier: &Notifier,
+            row: Result<Option<DarajaStatementRow>, MyError>,
+        ) -> (Result<(), MyError>, Option<String>) {
+            match row {
+                Ok(Some(row)) => (
+                    save_payment(client, notifier, &row).await,
+                    Some(row.receipt_no),
+                ),
+                Ok(None) => (Ok(()), None),
+                Err(e) => (Err(e), None),
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::test_database::test_database;
+            use actix_web::{http::StatusCode, test, App};
+            use serde_json::{json, Value};
+            use std::cell::Cell;
+            use std::collections::HashMap;
+
+            const TOKEN: &str = "callback-secret";
+
+            // Safaricom gives up on a callback after this many attempts
+            const ATTEMPTS: usize = 3;
+
+            // Safaricom refuses to register URLs containing these words
+            const FORBIDDEN_URL_WORDS: [&str; 7] =
+                ["m-pesa", "mpesa", "safaricom", "exe", "exec", "cmd", "sql"];
+
+            // Safaricom's side of Daraja. URLs are registered for a shortcode, each payment is
+            // validated and then confirmed at them, and results are sent to the URL given with
+            // the request. A callback is retried until it gets a 200 with ResultCode 0
+            struct MockDaraja<S> {
+                app: S,
+                registered: HashMap<String, (String, String)>,
+                // Replies to retried callbacks lost on the way back, which Safaricom takes for
+                // timeouts
+                lost_replies: Cell<usize>,
+            }
+
+            // How Safaricom saw a delivery
+            #[derive(Debug)]
+            struct Delivery {
+                attempts: usize,
+                acknowledged: bool,
+                status: StatusCode,
+                reply: Value,
+            }
+
+            #[derive(Debug)]
+            struct Payment {
+                validation: Option<Delivery>,
+                confirmation: Option<Delivery>,
+            }
+
+            impl<S, B> MockDaraja<S>
+            where
+                S: actix_web::dev::Service<
+                    actix_http::Request,
+                    Response = actix_web::dev::ServiceResponse<B>,
+                    Error = actix_web::Error,
+                >,
+                B: actix_web::body::MessageBody,
+            {
+                // POST /mpesa/c2b/v1/registerurl
+                fn register(
+                    &mut self,
+                    shortcode: &str,
+                    validation_url: &str,
+                    confirmation_url: &str,
+                ) -> Value {
+                    let forbidden = [validation_url, confirmation_url].iter().any(|url| {
+                        let url = url.to_lowercase();
+                        FORBIDDEN_URL_WORDS.iter().any(|word| url.contains(word))
+                    });
+                    if forbidden {
+                        return json!({
+                            "errorCode": "400.003.02",
+                            "errorMessage": "Bad Request - Invalid URL"
+                        });
+                    }
+                    self.registered.insert(
+                        shortcode.to_string(),
+                        (validation_url.to_string(), confirmation_url.to_string()),
+                    );
+                    json!({ "ResponseCode": "0", "ResponseDescription": "Success" })
+                }
+
+                fn lose_replies(&self, replies: usize) {
+                    self.lost_replies.set(replies);
+                }
+
+                async fn post(&self, url: &str, body: &[u8]) -> (StatusCode, Value) {
+                    let request = test::TestRequest::post()
+                        .uri(url)
+                        .insert_header(("content-type", "application/json"))
+                        .set_payload(body.to_vec())
+                        .to_request();
+                    let response = test::call_service(&self.app, request).await;
+                    let status = response.status();
+                    let body = test::read_body(response).await;
+                    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+                }
+
+                async fn deliver(&self, url: &str, body: &[u8], retry: bool) -> Delivery {
+                    let mut attempts = 0;
+                    loop {
+                        attempts += 1;
+                        let (status, reply) = self.post(url, body).await;
+                        let lost = retry && self.lost_replies.get() > 0;
+                        if lost {
+                            self.lost_replies.set(self.lost_replies.get() - 1);
+                        }
+                        let acknowledged =
+                            !lost && status == StatusCode::OK && reply["ResultCode"] == "0";
+                        if acknowledged || !retry || attempts == ATTEMPTS {
+                            return Delivery {
+                                attempts,
+                                acknowledged,
+                                status,
+                                reply,
+                            };
+                        }
+                    }
+                }
+
+                // A customer pays the shortcode. Nothing is sent for a shortcode without
+                // registered URLs, and a payment the validation rejects is cancelled. A
+                // validation that does not answer is completed, as registered
+                async fn pay(&self, payment: &Value) -> Payment {
+                    let shortcode = payment["BusinessShortCode"].as_str().unwrap();
+                    let (validation_url, confirmation_url) = match self.registered.get(shortcode) {
+                        Some(urls) => urls,
+                        None => {
+                            return Payment {
+                                validation: None,
+                                confirmation: None,
+                            }
+                        }
+                    };
+                    let body = payment.to_string();
+
+                    let validation = self.deliver(validation_url, body.as_bytes(), false).await;
+                    let rejected = validation.status == StatusCode::OK
+                        && validation.reply["ResultCode"] != "0";
+                    let confirmation = if rejected {
+                        None
+                    } else {
+                        Some(self.deliver(confirmation_url, body.as_bytes(), true).await)
+                    };
+                    Payment {
+                        validation: Some(validation),
+                        confirmation,
+                    }
+                }
+
+                // The result of a B2C or transaction status request, sent to its ResultURL
+                async fn send_result(&self, result_url: &str, body: &[u8]) -> Delivery {
+                    self.deliver(result_url, body, true).await
+                }
+            }
+
+            async fn mock_daraja(
+                pool: Pool,
+                config: DarajaConfig,
+            ) -> MockDaraja<
+                impl actix_web::dev::Service<
+                    actix_http::Request,
+                    Response = actix_web::dev::ServiceResponse,
+                    Error = actix_web::Error,
+                >,
+            > {
+                let app = test::init_service(
+                    App::new()
+                        .app_data(web::Data::new(pool))
+                        .app_data(web::Data::new(config))
+                        .app_data(web::Data::new(Notifier::new(16)))
+                        .service(daraja_c2b_validation)
+                        .service(daraja_c2b_confirmation)
+                        .service(daraja_b2c_result)
+                        .service(daraja_transaction_status_result),
+                )
+                .await;
+                MockDaraja {
+                    app,
+                    registered: HashMap::new(),
+                    lost_replies: Cell::new(0),
+                }
+            }
+
+            fn url(path: &str, token: &str) -> String {
+                format!("{}?token={}", path, token)
+            }
+
+            // Registers the routes as the operator does, with the callback token
+            fn register<S, B>(daraja: &mut MockDaraja<S>, shortcode: &str, token: &str)
+            where
+                S: actix_web::dev::Service<
+                    actix_http::Request,
+                    Response = actix_web::dev::ServiceResponse<B>,
+                    Error = actix_web::Error,
+                >,
+                B: actix_web::body::MessageBody,
+            {
+                let response = daraja.register(
+                    shortcode,
+                    &url("/daraja/c2b/validation", token),
+                    &url("/daraja/c2b/confirmation", token),
+                );
+                assert_eq!(response["ResponseCode"], "0", "{}", response);
+            }
+
+            fn config() -> DarajaConfig {
+                DarajaConfig {
+                    shortcodes: "600638".to_string(),
+                    token: Some(TOKEN.to_string()),
+                }
+            }
+
+            // Never connected to: the token is checked first
+            fn unused_pool() -> Pool {
+                test_database::pool_for("host=localhost dbname=unused")
+            }
+
+            fn payment(trans_id: &str, shortcode: &str) -> Value {
+                json!({
+                    "TransactionType": "Pay Bill",
+                    "TransID": trans_id,
+                    "TransTime": "20240312152301",
+                    "TransAmount": "1500.00",
+                    "BusinessShortCode": shortcode,
+                    "BillRefNumber": "OPD-1001",
+                    "InvoiceNumber": "",
+                    "OrgAccountBalance": "48250.00",
+                    "MSISDN": "254712345678",
+                    "FirstName": "JANE",
+                    "MiddleName": "",
+                    "LastName": "DOE"
+                })
+            }
+
+            fn result(result_code: i64, parameters: Value) -> Value {
+                json!({
+                    "Result": {
+                        "ResultType": 0,
+                        "ResultCode": result_code,
+                        "ResultDesc": "The service request is processed successfully.",
+                        "OriginatorConversationID": "10571-7910404-1",
+                        "ConversationID": "AG_20240312_00004e48cf7e3533f581",
+                        "TransactionID": "SCL0000090",
+                        "ResultParameters": { "ResultParameter": parameters }
+                    }
+                })
+            }
+
+            fn b2c_result(receipt_no: &str) -> Value {
+                result(
+                    0,
+                    json!([
+                        { "Key": "TransactionAmount", "Value": 250 },
+                        { "Key": "TransactionReceipt", "Value": receipt_no },
+                        { "Key": "ReceiverPartyPublicName", "Value": "254712345678 - JOHN DOE" },
+                        { "Key": "TransactionCompletedDateTime", "Value": "12.03.2024 16:20:05" },
+                        { "Key": "B2CUtilityAccountAvailableFunds", "Value": 10116.00 }
+                    ]),
+                )
+            }
+
+            fn status_result(receipt_no: &str, status: &str) -> Value {
+                result(
+                    0,
+                    json!([
+                        { "Key": "ReceiptNo", "Value": receipt_no },
+                        { "Key": "DebitPartyName", "Value": "254712345678 - JANE DOE" },
+                        { "Key": "CreditPartyName", "Value": "600638 - PANOPTICON CLINIC" },
+                        { "Key": "InitiatedTime", "Value": 20240312152301i64 },
+                        { "Key": "FinalisedTime", "Value": 20240312152302i64 },
+                        { "Key": "Amount", "Value": 1500 },
+                        { "Key": "TransactionStatus", "Value": status },
+                        { "Key": "ReasonType", "Value": "Pay Bill Online" }
+                    ]),
+                )
+            }
+
+            async fn count(pool: &Pool, stmt: &str, receipt_no: &str) -> i64 {
+                let client = pool.get().await.unwrap();
+                client.query_one(stmt, &[&receipt_no]).await.unwrap().get(0)
+            }
+
+            async fn statement_rows(pool: &Pool, receipt_no: &str) -> i64 {
+                count(
+                    pool,
+                    "select count(*) from production.mpesa_statement where receipt_no = $1",
+                    receipt_no,
+                )
+                .await
+            }
+
+            // Callbacks recorded for the receipt, and how many of them were accepted
+            async fn callbacks(pool: &Pool, kind: &str, receipt_no: &str) -> (i64, i64) {
+                let client = pool.get().await.unwrap();
+                let row = client
+                    .query_one(
+                        "select count(*), count(*) filter (where accepted) from production.daraja_callbacks where kind = $1 and receipt_no = $2",
+                        &[&kind, &receipt_no],
+                    )
+                    .await
+                    .unwrap();
+                (row.get(0), row.get(1))
+            }
+
+            #[actix_web::test]
+            async fn registers_only_allowed_urls() {
+                let mut daraja = mock_daraja(unused_pool(), config()).await;
+
+                register(&mut daraja, "600638", TOKEN);
+                let response = daraja.register(
+                    "600638",
+                    "/mpesa/validation",
+                    &url("/daraja/c2b/confirmation", TOKEN),
+                );
+                assert_eq!(response["errorCode"], "400.003.02");
+            }
+
+            #[actix_web::test]
+            async fn refuses_callbacks_with_a_bad_token() {
+                let mut daraja = mock_daraja(unused_pool(), config()).await;
+                register(&mut daraja, "600638", "wrong");
+
+                let payment = daraja.pay(&payment("SCL0000001", "600638")).await;
+                let validation = payment.validation.unwrap();
+                assert_eq!(validation.status, StatusCode::FORBIDDEN);
+                // Safaricom completes the payment and keeps trying to confirm it
+                let confirmation = payment.confirmation.unwrap();
+                assert_eq!(confirmation.status, StatusCode::FORBIDDEN);
+                assert_eq!(confirmation.attempts, ATTEMPTS);
+                assert!(!confirmation.acknowledged);
+
+                for path in ["/daraja/b2c/result", "/daraja/transaction-status/result"] {
+                    let delivery = daraja
+                        .send_result(
+                            &url(path, "wrong"),
+                            b2c_result("SCL0000001").to_string().as_bytes(),
+                        )
+                        .await;
+                    assert_eq!(delivery.status, StatusCode::FORBIDDEN);
+                }
+            }
+
+            #[actix_web::test]
+            async fn refuses_callbacks_without_a_configured_token() {
+                let config = DarajaConfig {
+                    token: None,
+                    ..config()
+                };
+                let mut daraja = mock_daraja(unused_pool(), config).await;
+                register(&mut daraja, "600638", "");
+
+                let payment = daraja.pay(&payment("SCL0000002", "600638")).await;
+                assert_eq!(payment.confirmation.unwrap().status, StatusCode::FORBIDDEN);
+            }
+
+            #[actix_web::test]
+            async fn is_retried_while_the_database_is_down() {
+                // Nothing listens on port 1, so no callback can be recorded
+                let pool = test_database::pool_for("host=localhost port=1 dbname=unused");
+                let mut daraja = mock_daraja(pool, config()).await;
+                register(&mut daraja, "600638", TOKEN);
+
+                let payment = daraja.pay(&payment("SCL0000008", "600638")).await;
+                let confirmation = payment.confirmation.unwrap();
+                assert_eq!(confirmation.status, StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(confirmation.attempts, ATTEMPTS);
+
+                let delivery = daraja
+                    .send_result(
+                        &url("/daraja/b2c/result", TOKEN),
+                        b2c_result("SCL0000008").to_string().as_bytes(),
+                    )
+                    .await;
+                assert_eq!(delivery.attempts, ATTEMPTS);
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn validates_payments() {
+                let pool = test_database::pool().await;
+                let mut daraja = mock_daraja(pool.clone(), config()).await;
+                register(&mut daraja, "600638", TOKEN);
+                register(&mut daraja, "174379", TOKEN);
+
+                let accepted = daraja.pay(&payment("SCL0000003", "600638")).await;
+                assert_eq!(accepted.validation.unwrap().reply["ResultCode"], "0");
+                assert!(accepted.confirmation.unwrap().acknowledged);
+
+                let other = daraja.pay(&payment("SCL0000004", "174379")).await;
+                assert_eq!(other.validation.unwrap().reply["ResultCode"], "C2B00015");
+                assert!(other.confirmation.is_none());
+
+                let mut zero = payment("SCL0000005", "600638");
+                zero["TransAmount"] = json!(0);
+                let zero = daraja.pay(&zero).await;
+                assert_eq!(zero.validation.unwrap().reply["ResultCode"], "C2B00013");
+                assert!(zero.confirmation.is_none());
+
+                // Validation never stores the payment, only the callback
+                assert_eq!(
+                    callbacks(&pool, "c2b_validation", "SCL0000003").await,
+                    (1, 1)
+                );
+                assert_eq!(
+                    callbacks(&pool, "c2b_validation", "SCL0000004").await,
+                    (1, 0)
+                );
+                assert_eq!(statement_rows(&pool, "SCL0000003").await, 1);
+                assert_eq!(statement_rows(&pool, "SCL0000004").await, 0);
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn stores_confirmed_payments_once() {
+                let pool = test_database::pool().await;
+                let mut daraja = mock_daraja(pool.clone(), config()).await;
+                register(&mut daraja, "600638", TOKEN);
+
+                // The first reply is lost, so Safaricom sends the confirmation again
+                daraja.lose_replies(1);
+                let payment = daraja.pay(&payment("SCL0000006", "600638")).await;
+                let confirmation = payment.confirmation.unwrap();
+                assert_eq!(confirmation.attempts, 2);
+                assert!(confirmation.acknowledged);
+
+                assert_eq!(statement_rows(&pool, "SCL0000006").await, 1);
+                assert_eq!(
+                    callbacks(&pool, "c2b_confirmation", "SCL0000006").await,
+                    (2, 2)
+                );
+
+                let client = pool.get().await.unwrap();
+                let row = client
+                    .query_one(
+                        "select paid_in::text, shortcode, source from production.mpesa_statement where receipt_no = $1",
+                        &[&"SCL0000006"],
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(row.get::<_, String>(0), "1500.00");
+                assert_eq!(row.get::<_, String>(1), "600638");
+                assert_eq!(row.get::<_, String>(2), "daraja");
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn keeps_unreadable_confirmations() {
+                let pool = test_database::pool().await;
+                let mut daraja = mock_daraja(pool.clone(), config()).await;
+                register(&mut daraja, "600638", TOKEN);
+
+                // Validation is down, so Safaricom completes the payment as registered
+                let mut unreadable = payment("SCL0000007", "600638");
+                unreadable["TransTime"] = json!("yesterday");
+                let confirmation = daraja
+                    .deliver(
+                        &url("/daraja/c2b/confirmation", TOKEN),
+                        unreadable.to_string().as_bytes(),
+                        true,
+                    )
+                    .await;
+                // The money moved, so Daraja is told the payment was received
+                assert_eq!(confirmation.attempts, 1);
+                assert!(confirmation.acknowledged);
+
+                assert_eq!(statement_rows(&pool, "SCL0000007").await, 0);
+                assert_eq!(
+                    callbacks(&pool, "c2b_confirmation", "SCL0000007").await,
+                    (1, 0)
+                );
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn stores_b2c_results() {
+                let pool = test_database::pool().await;
+                let daraja = mock_daraja(pool.clone(), config()).await;
+                let result_url = url("/daraja/b2c/result", TOKEN);
+
+                let delivery = daraja
+                    .send_result(&result_url, b2c_result("SCL0000010").to_string().as_bytes())
+                    .await;
+                assert_eq!(delivery.attempts, 1);
+                assert!(delivery.acknowledged);
+
+                assert_eq!(callbacks(&pool, "b2c_result", "SCL0000010").await, (1, 1));
+                let client = pool.get().await.unwrap();
+                let row = client
+                    .query_one(
+                        "select withdrawn::text, details from production.mpesa_statement where receipt_no = $1",
+                        &[&"SCL0000010"],
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(row.get::<_, String>(0), "-250");
+                assert_eq!(
+                    row.get::<_, String>(1),
+                    "Business Payment to 254712345678 - JOHN DOE"
+                );
+
+                // A failed payment moved no money
+                let failed = result(2001, json!([]));
+                let delivery = daraja
+                    .send_result(&result_url, failed.to_string().as_bytes())
+                    .await;
+                assert!(delivery.acknowledged);
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn updates_payments_from_transaction_status_results() {
+                let pool = test_database::pool().await;
+                let mut daraja = mock_daraja(pool.clone(), config()).await;
+                register(&mut daraja, "600638", TOKEN);
+                let result_url = url("/daraja/transaction-status/result", TOKEN);
+
+                // A status query for a payment that never reached the confirmation URL
+                let delivery = daraja
+                    .send_result(
+                        &result_url,
+                        status_result("SCL0000011", "Completed")
+                            .to_string()
+                            .as_bytes(),
+                    )
+                    .await;
+                assert!(delivery.acknowledged);
+                assert_eq!(statement_rows(&pool, "SCL0000011").await, 1);
+
+                // A later query updates the stored payment
+                let delivery = daraja
+                    .send_result(
+                        &result_url,
+                        status_result("SCL0000011", "Reversed")
+                            .to_string()
+                            .as_bytes(),
+                    )
+                    .await;
+                assert!(delivery.acknowledged);
+                assert_eq!(statement_rows(&pool, "SCL0000011").await, 1);
+                assert_eq!(
+                    callbacks(&pool, "transaction_status_result", "SCL0000011").await,
+                    (2, 2)
+                );
+
+                let client = pool.get().await.unwrap();
+                let row = client
+                    .query_one(
+                        "select transaction_status, paid_in::text, shortcode from production.mpesa_statement where receipt_no = $1",
+                        &[&"SCL0000011"],
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(row.get::<_, String>(0), "Reversed");
+                assert_eq!(row.get::<_, String>(1), "1500");
+                assert_eq!(row.get::<_, String>(2), "600638");
+            }
+
+            #[actix_web::test]
+            #[ignore = "needs TEST_DATABASE_URL"]
+            async fn acknowledges_unreadable_results() {
+                let pool = test_database::pool().await;
+                let daraja = mock_daraja(pool.clone(), config()).await;
+
+                let missing_receipt = result(0, json!([{ "Key": "Amount", "Value": 1500 }]));
+                for (path, body) in [
+                    ("/daraja/b2c/result", b"not json".to_vec()),
+                    (
+                        "/daraja/transaction-status/result",
+                        missing_receipt.to_string().into_bytes(),
+                    ),
+                ] {
+                    let delivery = daraja.send_result(&url(path, TOKEN), &body).await;
+                    assert_eq!(delivery.status, StatusCode::OK);
+                    assert!(delivery.acknowledged, "{}", path);
+                }
+
+                // Both are kept as they arrived, marked as not accepted
+                let client = pool.get().await.unwrap();
+                let row = client
+                    .query_one(
+                        "select accepted from production.daraja_callbacks where kind = 'b2c_result' and payload = to_jsonb('not json'::text)",
+                        &[],
+                    )
+                    .await
+                    .unwrap();
+                assert!(!row.get::<_, bool>(0));
+                let row = client
+                    .query_one(
+                        "select accepted from production.daraja_callbacks where kind = 'transaction_status_result' and payload = $1",
+                        &[&missing_receipt],
+                    )
+                    .await
+                    .unwrap();
+                assert!(!row.get::<_, bool>(0));
+            }
+        }
+    }
+
+    pub mod notification_handlers {
+        use crate::{
+            access::access::{Caller, StreamTicket},
+            errors::errors::MyError,
+            models::models::NotificationScope,
+            notifications::notifications::Notifier,
+        };
+        use actix_web::{get, post, web, FromRequest, HttpRequest, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// A ticket to open the notification stream with from a browser
+        #[post("/notifications/tickets")]
+        pub async fn create_notification_ticket(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, MyError> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let ticket = StreamTicket::issue(&client, &caller).await?;
+
+            Ok(HttpResponse::Created().json(ticket))
+        }
+
+        /// Server-sent events for new collections and M-Pesa payments and whether they matched
+        /// Scope is /notifications/reconciliation?unit_name=OPD&cashier=jane
+        /// Only events of the caller's units are sent. Browsers pass &ticket= from
+        /// POST /notifications/tickets instead of the Authorization header
+        #[get("/notifications/reconciliation")]
+        pub async fn reconciliation_notifications(
+            req: HttpRequest,
+            scope: web::Query<NotificationScope>,
+            notifier: web::Data<Notifier>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, MyError> {
+            let mut scope = scope.into_inner();
+            let caller = match scope.ticket.take() {
+                Some(ticket) => {
+                    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+                    Caller::from_ticket(&client, &ticket).await?
+                }
+                None => Caller::extract(&req).await?,
+            };
+            if let Some(unit_name) = scope.unit_name.as_deref() {
+                caller.ensure_unit(Some(unit_name))?;
+            }
+            scope.units = caller.units().map(<[String]>::to_vec);
+
+            Ok(HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .insert_header(("Cache-Control", "no-cache"))
+                .streaming(notifier.event_stream(scope)))
+        }
+    }
+
+    pub mod his_sync_handlers {
+        use crate::{
+            access::access::Caller, errors::errors::MyError, his_sync::his_sync::HisSync,
+            models::models::SyncStatus,
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Last run, last success and watermark of each table pulled from the HIS
+        #[get("/sync/his")]
+        pub async fn get_his_sync_status(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // The HIS is synced for every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let statuses = SyncStatus::get_all(&client).await?;
+
+            Ok(HttpResponse::Ok().json(statuses))
+        }
+
+        /// Sync now instead of waiting for the next interval
+        #[post("/sync/his/run")]
+        pub async fn run_his_sync(
+            caller: Caller,
+            his_sync: Option<web::Data<HisSync>>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let his_sync = his_sync.ok_or_else(|| {
+                MyError::BadRequest("HIS sync is not configured. Set HIS.PG.*".to_string())
+            })?;
+
+            let statuses = his_sync.sync_all().await?;
+
+            Ok(HttpResponse::Ok().json(statuses))
+        }
+    }
+
+    pub mod unit_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::ReportingConfig,
+            errors::errors::MyError,
+            models::models::{
+                currency_code, CurrencyQuery, DateRange, Unit, UnitInsert, UnitSummary,
+            },
+        };
+        use actix_web::{delete, get, put, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Units with their users, accounts and shortcodes. Units are managed by head office
+        /// admins
+        #[get("/units")]
+        pub async fn get_units(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let units = Unit::get_units(&client).await?;
+
+            Ok(HttpResponse::Ok().json(units))
+        }
+
+        /// Collections and mobile money per unit and day, with the consolidated total
+        /// Scope is /units/summary?from=YYYY-MM-DD&to=YYYY-MM-DD&currency=USD
+        #[get("/units/summary")]
+        pub async fn get_unit_summary(
+            caller: Caller,
+            range: web::Query<DateRange>,
+            currency: web::Query<CurrencyQuery>,
+            reporting: web::Data<ReportingConfig>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+            let currency =
+                currency_code(currency.currency.as_deref().unwrap_or(&reporting.currency))?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let summary = UnitSummary::get_summary(&client, &range, &currency).await?;
+
+            Ok(HttpResponse::Ok().json(summary))
+        }
+
+        #[put("/units/{unit_name}")]
+        pub async fn put_unit(
+            caller: Caller,
+            unit_name: web::Path<String>,
+            unit: web::Json<UnitInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            Unit::upsert(&client, &unit_name, unit.into_inner()).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        #[put("/units/{unit_name}/users/{username}")]
+        pub async fn put_unit_user(
+            caller: Caller,
+            path: web::Path<(String, String)>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+            let (unit_name, username) = path.into_inner();
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            Unit::add_user(&client, &unit_name, &username).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        #[delete("/units/{unit_name}/users/{username}")]
+        pub async fn delete_unit_user(
+            caller: Caller,
+            path: web::Path<(String, String)>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+            let (unit_name, username) = path.into_inner();
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            Unit::remove_user(&client, &unit_name, &username).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        /// Assign a bank or wallet account to the unit it collects for
+        #[put("/units/{unit_name}/accounts/{account}")]
+        pub async fn put_unit_account(
+            caller: Caller,
+            path: web::Path<(String, String)>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+            let (unit_name, account) = path.into_inner();
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            Unit::add_account(&client, &unit_name, &account).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        /// Return the account to head office
+        #[delete("/units/{unit_name}/accounts/{account}")]
+        pub async fn delete_unit_account(
+            caller: Caller,
+            path: web::Path<(String, String)>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+            let (unit_name, account) = path.into_inner();
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            Unit::remove_account(&client, &unit_name, &account).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+    }
+
+    pub mod fx_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{FxRate, FxRateFilter},
+        };
+        use actix_web::{get, post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Exchange rates used to convert reports
+        /// Scope is /fx/rates?currency=USD&from=YYYY-MM-DD&to=YYYY-MM-DD
+        #[get("/fx/rates")]
+        pub async fn get_fx_rates(
+            _caller: Caller,
+            filter: web::Query<FxRateFilter>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let rates = FxRate::get_rates(&client, &filter).await?;
+
+            Ok(HttpResponse::Ok().json(rates))
+        }
+
+        /// A JSON array of dated rates. Rates already uploaded for a pair and day are replaced
+        #[post("/fx/rates/update")]
+        pub async fn upload_fx_rates(
+            caller: Caller,
+            rates: web::Json<Vec<FxRate>>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let upserted = FxRate::upsert(&mut client, rates.into_inner()).await?;
+
+            Ok(HttpResponse::Ok().json(upserted))
+        }
+    }
+
+    pub mod time_zone_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::TimeZoneConfig,
+            errors::errors::MyError,
+            models::models::{SourceTimeZone, SourceTimeZoneInsert},
+        };
+        use actix_web::{delete, get, put, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// The display zone and the sources that write their times in another zone
+        #[get("/timezones")]
+        pub async fn get_time_zones(
+            _caller: Caller,
+            time_zone: web::Data<TimeZoneConfig>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let sources = SourceTimeZone::get_zones(&client).await?;
+
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "display": time_zone.display.name(),
+                "sources": sources,
+            })))
+        }
+
+        /// Read a source's times in another zone, e.g. {"time_zone": "UTC"}
+        #[put("/timezones/{source}")]
+        pub async fn put_source_time_zone(
+            caller: Caller,
+            source: web::Path<String>,
+            zone: web::Json<SourceTimeZoneInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            SourceTimeZone::upsert(&client, &source, zone.into_inner()).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        #[delete("/timezones/{source}")]
+        pub async fn delete_source_time_zone(
+            caller: Caller,
+            source: web::Path<String>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            SourceTimeZone::delete(&client, &source).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+    }
+
+    pub mod job_handlers {
+        use crate::{
+            access::access::Caller,
+            errors::errors::MyError,
+            models::models::{Job, JobFilter, JobInsert, JobSchedule, JobScheduleInsert},
+        };
+        use actix_web::{delete, get, post, put, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Recent jobs, newest first
+        /// Scope is /jobs?status=failed&kind=his_sync
+        #[get("/jobs")]
+        pub async fn get_jobs(
+            caller: Caller,
+            filter: web::Query<JobFilter>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let jobs = Job::get_jobs(&client, &filter, caller.requester()).await?;
+
+            Ok(HttpResponse::Ok().json(jobs))
+        }
+
+        #[post("/jobs")]
+        pub async fn enqueue_job(
+            caller: Caller,
+            job: web::Json<JobInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Jobs run for every unit. Users follow their own uploads
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let job = Job::enqueue(&client, job.into_inner(), Some(&caller.username)).await?;
+
+            Ok(HttpResponse::Created().json(job))
+        }
+
+        #[get("/jobs/schedules")]
+        pub async fn get_job_schedules(
+            caller: Caller,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let schedules = JobSchedule::get_schedules(&client).await?;
+
+            Ok(HttpResponse::Ok().json(schedules))
+        }
+
+        /// Create or replace a schedule
+        #[put("/jobs/schedules/{name}")]
+        pub async fn put_job_schedule(
+            caller: Caller,
+            name: web::Path<String>,
+            schedule: web::Json<JobScheduleInsert>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let schedule = JobSchedule::upsert(&client, &name, schedule.into_inner()).await?;
+
+            Ok(HttpResponse::Ok().json(schedule))
+        }
+
+        #[delete("/jobs/schedules/{name}")]
+        pub async fn delete_job_schedule(
+            caller: Caller,
+            name: web::Path<String>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            JobSchedule::delete(&client, &name).await?;
+
+            Ok(HttpResponse::NoContent().finish())
+        }
+
+        /// A job with its result
+        #[get("/jobs/{id}")]
+        pub async fn get_job(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let job = Job::get_job(&client, id.into_inner()).await?;
+            caller.ensure_job(&job)?;
+
+            Ok(HttpResponse::Ok().json(job))
+        }
+
+        /// The file produced by an export job
+        #[get("/jobs/{id}/download")]
+        pub async fn download_job_result(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            // Exports cover every unit
+            caller.ensure_consolidated()?;
+
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let job = Job::get_job(&client, id.into_inner()).await?;
+
+            let result = job.result.unwrap_or_default();
+            match (result["content_type"].as_str(), result["body"].as_str()) {
+                (Some(content_type), Some(body)) => Ok(HttpResponse::Ok()
+                    .content_type(content_type.to_string())
+                    .body(body.to_string())),
+                _ => Err(MyError::NotFound.into()),
+            }
+        }
+
+        #[post("/jobs/{id}/retry")]
+        pub async fn retry_job(
+            caller: Caller,
+            id: web::Path<i64>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            let id = id.into_inner();
+            caller.ensure_job(&Job::get_job(&client, id).await?)?;
+
+            let job = Job::retry(&client, id).await?;
+
+            Ok(HttpResponse::Ok().json(job))
+        }
+    }
+
+    pub mod upload_handlers {
+        use crate::{
+            access::access::Caller,
+            configs::config::UploadLimits,
+            errors::errors::MyError,
+            models::models::{Job, JobInsert, ShortcodeFilter, Upload},
+            parsing::parsing::RowFormat,
+            streaming::streaming::JsonRows,
+            uploads::uploads::{UploadPayload, UploadSource},
+        };
+        use actix_web::{post, web, Error, HttpResponse};
+        use deadpool_postgres::{Client, Pool};
+
+        /// Store a JSON array or NDJSON upload as it arrives and copy it in the background.
+        /// Poll /jobs/{id} for the rows processed, the rows that failed and completion.
+        /// ?shortcode= tags M-Pesa rows
+        #[post("/uploads/{source}")]
+        pub async fn upload_file(
+            caller: Caller,
+            source: web::Path<String>,
+            filter: web::Query<ShortcodeFilter>,
+            payload: web::Payload,
+            limits: web::Data<UploadLimits>,
+            db_pool: web::Data<Pool>,
+        ) -> Result<HttpResponse, Error> {
+            let source = UploadSource::from_name(&source)?;
+            let shortcode = filter.shortcode()?;
+
+            // Rows are stored as sent. The job reads their dates and amounts
+            let mut rows =
+                JsonRows::<serde_json::Value>::new(payload, RowFormat::default(), &limits);
+
+            let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+            // Rows of the HIS exports and M-Pesa rows with their own shortcode are checked
+            // as the job copies them
+            if let Some(account) = source.account_name() {
+                caller
+                    .ensure_account(&client, account, shortcode.as_deref())
+                    .await?;
+            }
+
+            let upload_id = Upload::store(&mut client, source.name(), &mut rows).await?;
+
+            let job = Job::enqueue(
+                &client,
+                JobInsert {
+                    kind: "upload".to_string(),
+                    payload: serde_json::to_value(UploadPayload {
+                        source: source.name().to_string(),
+                        upload_id,
+                        shortcode,
+                    })?,
+                    run_at: None,
+                    max_attempts: None,
+                },
+                Some(&caller.username),
+            )
+            .await?;
+
+            Ok(HttpResponse::Accepted().json(job))
         }
     }
 }
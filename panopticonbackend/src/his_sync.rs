@@ -0,0 +1,211 @@
+pub mod his_sync {
+    use crate::configs::config::HisConfig;
+    use crate::errors::errors::MyError;
+    use crate::models::models::{
+        BillDetailsInsert, CollectionDetailsInsert, PeriodLock, ReconciliationEvent,
+        RegisteredPatientsInsert, SyncStatus,
+    };
+    use crate::notifications::notifications::Notifier;
+    use crate::parsing::parsing::Formats;
+    use crate::sources::sources::copy_rows_in;
+    use chrono::{NaiveDate, NaiveDateTime};
+    use deadpool_postgres::{Client, Pool, Transaction};
+    use tokio::sync::Mutex;
+    use tokio_pg_mapper::FromTokioPostgresRow;
+    use tokio_postgres::Row;
+
+    /// Tables pulled from the HIS database
+    #[derive(Clone, Copy, Debug)]
+    pub enum HisSource {
+        CollectionDetails,
+        BillDetails,
+        RegisteredPatients,
+    }
+
+    impl HisSource {
+        pub const ALL: [HisSource; 3] = [
+            HisSource::CollectionDetails,
+            HisSource::BillDetails,
+            HisSource::RegisteredPatients,
+        ];
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                HisSource::CollectionDetails => "his_collection_details",
+                HisSource::BillDetails => "his_bill_details",
+                HisSource::RegisteredPatients => "his_registered_patients",
+            }
+        }
+    }
+
+    /// Copies new HIS rows through the same insert paths as the JSON uploads. Runs as the
+    /// his_sync job
+    pub struct HisSync {
+        his: Pool,
+        db: Pool,
+        config: HisConfig,
+        notifier: Notifier,
+        // Dates of HIS rows are read like uploaded ones
+        formats: Formats,
+        // One run at a time, whether from the timer or the API
+        running: Mutex<()>,
+    }
+
+    impl HisSync {
+        pub fn new(
+            his: Pool,
+            db: Pool,
+            config: HisConfig,
+            notifier: Notifier,
+            formats: Formats,
+        ) -> Self {
+            HisSync {
+                his,
+                db,
+                config,
+                notifier,
+                formats,
+                running: Mutex::new(()),
+            }
+        }
+
+        /// Pull every source until it is caught up. A failing source records its error and
+        /// keeps its watermark without stopping the others
+        pub async fn sync_all(&self) -> Result<Vec<SyncStatus>, MyError> {
+            let _running = self.running.lock().await;
+
+            let his: Client = self.his.get().await.map_err(MyError::PoolError)?;
+            let mut db: Client = self.db.get().await.map_err(MyError::PoolError)?;
+
+            let mut statuses = Vec::new();
+            for source in HisSource::ALL {
+                let status = match self.sync_source(&his, &mut db, source).await {
+                    Ok((watermark, rows)) => {
+                        SyncStatus::record(&db, source.name(), watermark, rows, None).await?
+                    }
+                    Err(e) => {
+                        tracing::error!("HIS sync of {} failed: {:?}", source.name(), e);
+                        SyncStatus::record(&db, source.name(), None, 0, Some(e.to_string())).await?
+                    }
+                };
+                statuses.push(status);
+            }
+            Ok(statuses)
+        }
+
+        // Returns the new watermark, if any rows arrived, and the number of rows copied
+        async fn sync_source(
+            &self,
+            his: &Client,
+            db: &mut Client,
+            source: HisSource,
+        ) -> Result<(Option<NaiveDateTime>, i64), MyError> {
+            let mut watermark = SyncStatus::watermark(db, source.name())
+                .await?
+                .unwrap_or_else(|| NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0));
+            let mut moved = None;
+            let mut total = 0;
+
+            loop {
+                let rows = self.pull(his, source, watermark).await?;
+                let batch = rows.len() as i64;
+                let latest = match rows
+                    .iter()
+                    .map(|row| row.get::<_, NaiveDateTime>("watermark"))
+                    .max()
+                {
+                    Some(latest) => latest,
+                    None => break,
+                };
+
+                // Each batch is committed with its watermark, after the period lock check
+                let mut tx = db.transaction().await?;
+                let events = self.write(&mut tx, source, &rows).await?;
+                SyncStatus::record_in(&*tx, source.name(), Some(latest), batch, None).await?;
+                tx.commit().await?;
+                self.notifier.publish(events);
+                watermark = latest;
+                moved = Some(latest);
+                total += batch;
+
+                if batch < self.config.batch_size {
+                    break;
+                }
+            }
+
+            Ok((moved, total))
+        }
+
+        async fn pull(
+            &self,
+            his: &Client,
+            source: HisSource,
+            watermark: NaiveDateTime,
+        ) -> Result<Vec<Row>, MyError> {
+            let batch_size = self.config.batch_size;
+            let rows = match source {
+                HisSource::CollectionDetails => {
+                    let stmt = include_str!("../sql/user_actions/his_get_collection_details.sql");
+                    his.query(stmt, &[&watermark, &batch_size]).await?
+                }
+                HisSource::BillDetails => {
+                    let stmt = include_str!("../sql/user_actions/his_get_bill_details.sql");
+                    his.query(stmt, &[&watermark, &batch_size, &self.config.unit])
+                        .await?
+                }
+                HisSource::RegisteredPatients => {
+                    let stmt = include_str!("../sql/user_actions/his_get_registered_patients.sql");
+                    his.query(stmt, &[&watermark, &batch_size, &self.config.unit])
+                        .await?
+                }
+            };
+            Ok(rows)
+        }
+
+        // Returns the events to publish once the batch is committed
+        async fn write(
+            &self,
+            tx: &mut Transaction<'_>,
+            source: HisSource,
+            rows: &[Row],
+        ) -> Result<Vec<ReconciliationEvent>, MyError> {
+            match source {
+                HisSource::CollectionDetails => {
+                    let data = rows
+                        .iter()
+                        .map(CollectionDetailsInsert::from_row_ref)
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    // Same rule as uploads: collections may not change a signed-off M-Pesa period
+                    let format = self.formats.source("collectiondetails");
+                    let dates = data
+                        .iter()
+                        .filter_map(|c| format.parse_date(&c.receipt_date))
+                        .map(|date| date.date())
+                        .collect();
+                    PeriodLock::ensure_unlocked_in(&**tx, "mpesa", dates).await?;
+
+                    let events = ReconciliationEvent::for_collections_in(&**tx, &data).await?;
+                    copy_rows_in(tx, &data).await?;
+                    Ok(events)
+                }
+                HisSource::BillDetails => {
+                    let data = rows
+                        .iter()
+                        .map(BillDetailsInsert::from_row_ref)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    copy_rows_in(tx, &data).await?;
+                    Ok(Vec::new())
+                }
+                HisSource::RegisteredPatients => {
+                    let data = rows
+                        .iter()
+                        .map(RegisteredPatientsInsert::from_row_ref)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    copy_rows_in(tx, &data).await?;
+                    Ok(Vec::new())
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,26 @@
 use crate::handlers::handlers::{
-    absa_bank_handlers::*, bill_details_handlers::*, cfc_handlers::*,
-    collection_details_handlers::*, dashboard, health_check, index, lab_visits_handlers::*,
-    mpesa_handlers::*, mtiba_handlers::*, pdq_handlers::*, registered_patients_handlers::*,
-    sidian_handlers::*,
+    absa_bank_handlers::*, airtel_handlers::*, bank_statement_handlers::*,
+    bill_details_handlers::*, categorization_handlers::*, cfc_handlers::*,
+    collection_details_handlers::*, continuity_handlers::*, daraja_handlers::*, dashboard,
+    exception_handlers::*, fraud_handlers::*, fx_handlers::*, health_check, his_sync_handlers::*,
+    index, job_handlers::*, journal_handlers::*, lab_visits_handlers::*, ledger_handlers::*,
+    mpesa_handlers::*, mtiba_handlers::*, notification_handlers::*, pdq_handlers::*,
+    period_lock_handlers::*, reconciliation_decision_handlers::*, registered_patients_handlers::*,
+    sidian_handlers::*, time_zone_handlers::*, transfer_handlers::*, unit_handlers::*,
+    upload_handlers::*,
 };
 
 use crate::configs::config::Config;
+use crate::his_sync::his_sync::HisSync;
+use crate::jobs::jobs::JobRunner;
+use crate::notifications::notifications::Notifier;
+use crate::parsing::parsing::Formats;
 
 use rustls::ServerConfig;
 
 use deadpool_postgres::Pool;
+use std::sync::Arc;
+use tokio_postgres::NoTls;
 
 use actix_cors::Cors;
 use actix_web::middleware::Compress;
@@ -21,6 +32,42 @@ pub async fn initialize_server(
     rustlsconfig: ServerConfig,
     config: Config,
 ) -> std::io::Result<()> {
+    let chart_of_accounts = config.journal.clone();
+    let daraja = config.daraja.clone();
+    if !daraja.accepts_token(daraja.token.as_deref()) {
+        tracing::warn!("DARAJA.TOKEN is not set, Daraja callbacks will be refused");
+    }
+    let reporting = config.reporting.clone();
+    let time_zone = config.timezone.clone();
+    let formats = Formats::new(config.parsing.clone());
+    let upload_limits = config.uploads.clone();
+    let notifier = Notifier::new(1024);
+
+    // Pull collections, bills and patients from the HIS database when it is configured
+    let his_sync = config.his.map(|his| {
+        let his_pool = his.pg.create_pool(None, NoTls).unwrap();
+        Arc::new(HisSync::new(
+            his_pool,
+            pool.clone(),
+            his,
+            notifier.clone(),
+            formats.clone(),
+        ))
+    });
+
+    // Background jobs and their schedules
+    JobRunner::new(
+        pool.clone(),
+        chart_of_accounts.clone(),
+        reporting.clone(),
+        his_sync.clone(),
+        notifier.clone(),
+        config.timezone.display,
+        formats.clone(),
+    )
+    .start();
+    let his_sync = his_sync.map(web::Data::from);
+
     // Instantiate the Actix-Web Server
     let server = HttpServer::new(move || {
         App::new()
@@ -30,10 +77,31 @@ pub async fn initialize_server(
             .wrap(Compress::default())
             // Allows us to pass app state to handlers. In this case, the db pool
             .app_data(web::Data::new(pool.clone()))
-            // Set the maximum payload size to 32MB
+            // Chart of accounts used when generating journals
+            .app_data(web::Data::new(chart_of_accounts.clone()))
+            // Shortcodes and callback token for the Daraja webhooks
+            .app_data(web::Data::new(daraja.clone()))
+            // Currency that reports are converted to by default
+            .app_data(web::Data::new(reporting.clone()))
+            // Zone of day boundaries and of times written by the sources
+            .app_data(web::Data::new(time_zone.clone()))
+            // Date and amount formats of the uploaded sources
+            .app_data(web::Data::new(formats.clone()))
+            // Reconciliation events pushed to connected cashiers
+            .app_data(web::Data::new(notifier.clone()))
+            // Present only when HIS.PG.* is set
+            .app_data(his_sync.clone())
+            // Size limits of the streamed uploads
+            .app_data(web::Data::new(upload_limits.clone()))
+            // Set the maximum size of bodies read whole to 32MB
             .app_data(web::PayloadConfig::new(1 << 25))
             .service(health_check)
             .service(get_mpesa_statement)
+            .service(get_mpesa_reversals)
+            .service(get_mpesa_breakdown)
+            .service(get_mpesa_shortcodes)
+            .service(put_mpesa_shortcode)
+            .service(delete_mpesa_shortcode)
             .service(get_collection_details)
             .service(get_bill_details)
             .service(get_lab_visits)
@@ -43,6 +111,7 @@ pub async fn initialize_server(
             .service(get_cfc_bank_statement)
             .service(get_absa_bank_statement)
             .service(get_pdq_statement)
+            .service(get_airtel_statement)
             .service(update_mpesa_statement)
             .service(update_collection_details)
             .service(update_bill_details)
@@ -52,8 +121,66 @@ pub async fn initialize_server(
             .service(update_absa_statement)
             .service(update_sidian_statement)
             .service(update_lab_visits)
+            .service(update_airtel_statement)
+            .service(get_balance_continuity)
+            .service(get_ledger)
+            .service(get_journals)
+            .service(get_transfers)
+            .service(detect_transfers)
+            .service(get_categorization_rules)
+            .service(create_categorization_rule)
+            .service(update_categorization_rule)
+            .service(delete_categorization_rule)
+            .service(rerun_categorization)
+            .service(reconcile_mpesa_statement)
+            .service(get_reconciliation_decisions)
+            .service(create_reconciliation_decision)
+            .service(reverse_reconciliation_decision)
+            .service(get_period_locks)
+            .service(lock_period)
+            .service(unlock_period)
+            .service(get_period_lock_snapshot)
+            .service(generate_exceptions)
+            .service(get_exceptions)
+            .service(get_exception)
+            .service(update_exception)
+            .service(add_exception_note)
+            .service(get_fraud_signals)
+            .service(daraja_c2b_validation)
+            .service(daraja_c2b_confirmation)
+            .service(daraja_b2c_result)
+            .service(daraja_transaction_status_result)
+            .service(create_notification_ticket)
+            .service(reconciliation_notifications)
+            .service(get_his_sync_status)
+            .service(run_his_sync)
+            .service(get_units)
+            .service(get_unit_summary)
+            .service(put_unit)
+            .service(put_unit_user)
+            .service(delete_unit_user)
+            .service(put_unit_account)
+            .service(delete_unit_account)
+            .service(get_fx_rates)
+            .service(upload_fx_rates)
+            .service(get_time_zones)
+            .service(put_source_time_zone)
+            .service(delete_source_time_zone)
+            .service(get_jobs)
+            .service(enqueue_job)
+            .service(get_job_schedules)
+            .service(put_job_schedule)
+            .service(delete_job_schedule)
+            .service(get_job)
+            .service(download_job_result)
+            .service(retry_job)
+            .service(upload_file)
             .service(index)
             .service(dashboard)
+            // /statements/{bank} last so the fixed /statements routes match first
+            .service(get_bank_statement)
+            .service(update_bank_statement)
+            .service(import_bank_statement)
     })
     .bind_rustls(config.server_addr.clone(), rustlsconfig)?
     .run();
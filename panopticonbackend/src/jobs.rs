@@ -0,0 +1,485 @@
+pub mod jobs {
+    use crate::configs::config::{ChartOfAccounts, ReportingConfig};
+    use crate::errors::errors::MyError;
+    use crate::exports::exports::export_journals;
+    use crate::his_sync::his_sync::HisSync;
+    use crate::models::models::{
+        refresh_materialized_views, DateRange, ExceptionItem, Job, JobInsert, JobSchedule, Journal,
+        JournalQuery, Transfer, TransferQuery,
+    };
+    use crate::notifications::notifications::Notifier;
+    use crate::parsing::parsing::Formats;
+    use crate::uploads::uploads;
+    use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, Timelike, Utc};
+    use chrono_tz::Tz;
+    use deadpool_postgres::{Client, Pool};
+    use futures_util::future::{select, Either};
+    use serde::Deserialize;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    // How long an idle worker waits before looking for due jobs again
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+    // How often a running job's lock is renewed. Locks older than 5 minutes are taken over
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+    // Retries wait 30s, 1m, 2m ... up to an hour
+    const BASE_BACKOFF_SECONDS: f64 = 30.0;
+    const MAX_BACKOFF_SECONDS: f64 = 3600.0;
+
+    // Seconds before the next attempt, None once the attempts are used up
+    fn backoff(attempts: i32, max_attempts: i32) -> Option<f64> {
+        if attempts < max_attempts {
+            Some((BASE_BACKOFF_SECONDS * 2f64.powi(attempts - 1)).min(MAX_BACKOFF_SECONDS))
+        } else {
+            None
+        }
+    }
+
+    /// A five field cron expression: minute hour day-of-month month day-of-week.
+    /// Fields take *, numbers, ranges (1-5), lists (1,15) and steps (*/5). Sunday is 0 or 7
+    #[derive(Debug)]
+    pub struct Cron {
+        minutes: Vec<bool>,
+        hours: Vec<bool>,
+        days: Vec<bool>,
+        months: Vec<bool>,
+        weekdays: Vec<bool>,
+        any_day: bool,
+        any_weekday: bool,
+    }
+
+    impl Cron {
+        pub fn parse(expression: &str) -> Result<Cron, String> {
+            let fields: Vec<&str> = expression.split_whitespace().collect();
+            if fields.len() != 5 {
+                return Err(format!(
+                    "Cron expression {} needs 5 fields: minute hour day month weekday",
+                    expression
+                ));
+            }
+            let mut weekdays = Cron::field(fields[4], 0, 7)?;
+            // 7 is another name for Sunday
+            if weekdays[7] {
+                weekdays[0] = true;
+            }
+            Ok(Cron {
+                minutes: Cron::field(fields[0], 0, 59)?,
+                hours: Cron::field(fields[1], 0, 23)?,
+                days: Cron::field(fields[2], 1, 31)?,
+                months: Cron::field(fields[3], 1, 12)?,
+                weekdays,
+                any_day: fields[2] == "*",
+                any_weekday: fields[4] == "*",
+            })
+        }
+
+        // Values allowed by one field, indexed by value
+        fn field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+            let mut allowed = vec![false; max + 1];
+            for part in field.split(',') {
+                let (range, step) = match part.split_once('/') {
+                    Some((range, step)) => (
+                        range,
+                        step.parse::<usize>()
+                            .ok()
+                            .filter(|step| *step > 0)
+                            .ok_or_else(|| format!("Invalid step in {}", field))?,
+                    ),
+                    None => (part, 1),
+                };
+                let (start, end) = if range == "*" {
+                    (min, max)
+                } else if let Some((start, end)) = range.split_once('-') {
+                    (Cron::value(start, field)?, Cron::value(end, field)?)
+                } else {
+                    let value = Cron::value(range, field)?;
+                    // 5/10 means every 10 starting at 5
+                    (value, if step > 1 { max } else { value })
+                };
+                if start < min || end > max || start > end {
+                    return Err(format!("{} is outside {}-{}", field, min, max));
+                }
+                for value in (start..=end).step_by(step) {
+                    allowed[value] = true;
+                }
+            }
+            Ok(allowed)
+        }
+
+        fn value(value: &str, field: &str) -> Result<usize, String> {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value {} in {}", value, field))
+        }
+
+        /// Whether the schedule fires in the minute of `time`. As in cron, a job restricted by
+        /// both day of month and day of week runs when either matches
+        pub fn matches(&self, time: &NaiveDateTime) -> bool {
+            let day = self.days[time.day() as usize];
+            let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => weekday,
+                (false, true) => day,
+                (false, false) => day || weekday,
+            };
+            self.minutes[time.minute() as usize]
+                && self.hours[time.hour() as usize]
+                && self.months[time.month() as usize]
+                && day_matches
+        }
+    }
+
+    /// Payload of reconciliation jobs. Both ends default to yesterday
+    #[derive(Deserialize)]
+    struct ReconciliationPayload {
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    }
+
+    /// Runs queued jobs and enqueues scheduled ones. Schedules and "yesterday" follow `zone`
+    pub struct JobRunner {
+        db: Pool,
+        chart: ChartOfAccounts,
+        reporting: ReportingConfig,
+        his_sync: Option<Arc<HisSync>>,
+        notifier: Notifier,
+        zone: Tz,
+        formats: Formats,
+        worker: String,
+    }
+
+    impl JobRunner {
+        pub fn new(
+            db: Pool,
+            chart: ChartOfAccounts,
+            reporting: ReportingConfig,
+            his_sync: Option<Arc<HisSync>>,
+            notifier: Notifier,
+            zone: Tz,
+            formats: Formats,
+        ) -> Self {
+            JobRunner {
+                db,
+                chart,
+                reporting,
+                his_sync,
+                notifier,
+                zone,
+                formats,
+                worker: format!("worker-{}", Uuid::new_v4()),
+            }
+        }
+
+        /// Start the worker and the scheduler for the life of the server
+        pub fn start(self) {
+            let runner = Arc::new(self);
+            actix_web::rt::spawn(runner.clone().work_forever());
+            actix_web::rt::spawn(runner.schedule_forever());
+        }
+
+        async fn work_forever(self: Arc<Self>) {
+            loop {
+                match self.work_once().await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("Job worker failed: {:?}", e),
+                }
+                actix_web::rt::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+
+        // Run the next due job. False when there was none
+        async fn work_once(&self) -> Result<bool, MyError> {
+            let mut client: Client = self.db.get().await.map_err(MyError::PoolError)?;
+
+            let job = match Job::claim(&client, &self.worker).await? {
+                Some(job) => job,
+                None => return Ok(false),
+            };
+            // Claiming counts an attempt, so a job whose worker stopped during its last
+            // attempt is failed instead of run again
+            if job.attempts > job.max_attempts {
+                let error =
+                    MyError::BadRequest("The worker running its last attempt stopped".to_string());
+                return self.fail(&client, &job, error).await.map(|_| true);
+            }
+            tracing::info!(
+                "Running job {} ({}), attempt {}",
+                job.id,
+                job.kind,
+                job.attempts
+            );
+
+            // The job stops if another worker takes it over
+            let outcome = match select(
+                Box::pin(self.execute(&mut client, &job)),
+                Box::pin(self.heartbeat(job.id)),
+            )
+            .await
+            {
+                Either::Left((outcome, _)) => outcome,
+                Either::Right(((), _)) => {
+                    tracing::warn!("Job {} was taken over by another worker", job.id);
+                    return Ok(true);
+                }
+            };
+
+            match outcome {
+                Ok(result) => {
+                    if !Job::complete(&client, job.id, &self.worker, &result).await? {
+                        tracing::warn!("Job {} finished after another worker took it", job.id);
+                    }
+                }
+                Err(e) => self.fail(&client, &job, e).await?,
+            }
+            Ok(true)
+        }
+
+        // Requeue the job with a backoff, or fail it for good once its attempts are used up
+        async fn fail(&self, client: &Client, job: &Job, e: MyError) -> Result<(), MyError> {
+            let retry_in = backoff(job.attempts, job.max_attempts);
+            tracing::error!(
+                "Job {} ({}) failed: {:?}. Retry in {:?} seconds",
+                job.id,
+                job.kind,
+                e,
+                retry_in
+            );
+            if !Job::fail(client, job.id, &self.worker, &e.to_string(), retry_in).await? {
+                tracing::warn!("Job {} failed after another worker took it", job.id);
+                return Ok(());
+            }
+            // An upload that will not be retried has no more use for its rows
+            if retry_in.is_none() && job.kind == "upload" {
+                uploads::discard(client, job).await?;
+            }
+            Ok(())
+        }
+
+        // Renew the job's lock until another worker holds it. Runs beside every job
+        async fn heartbeat(&self, id: i64) {
+            loop {
+                actix_web::rt::time::sleep(HEARTBEAT_INTERVAL).await;
+                let renewed = match self.db.get().await {
+                    Ok(client) => Job::renew_lock(&client, id, &self.worker).await,
+                    Err(e) => Err(MyError::PoolError(e)),
+                };
+                match renewed {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(e) => tracing::error!("Could not renew the lock of job {}: {:?}", id, e),
+                }
+            }
+        }
+
+        async fn execute(
+            &self,
+            client: &mut Client,
+            job: &Job,
+        ) -> Result<serde_json::Value, MyError> {
+            match job.kind.as_str() {
+                "reconciliation" => {
+                    let payload: ReconciliationPayload =
+                        serde_json::from_value(job.payload.clone())?;
+                    let yesterday = Utc::now().with_timezone(&self.zone).date().naive_local()
+                        - ChronoDuration::days(1);
+                    let range = DateRange {
+                        from: payload.from.unwrap_or(yesterday),
+                        to: payload.to.unwrap_or(yesterday),
+                    };
+                    let transfers = Transfer::detect(
+                        client,
+                        &TransferQuery {
+                            from: range.from,
+                            to: range.to,
+                            window_days: None,
+                        },
+                        self.zone,
+                    )
+                    .await?;
+                    let exceptions = ExceptionItem::generate(client, &range).await?;
+                    Ok(serde_json::json!({
+                        "from": range.from,
+                        "to": range.to,
+                        "transfers": transfers.len(),
+                        "exceptions": exceptions,
+                    }))
+                }
+                "refresh_materialized_views" => {
+                    refresh_materialized_views(client).await?;
+                    Ok(serde_json::json!({}))
+                }
+                "his_sync" => match &self.his_sync {
+                    Some(his_sync) => Ok(serde_json::to_value(his_sync.sync_all().await?)?),
+                    None => Ok(serde_json::json!({ "skipped": "HIS sync is not configured" })),
+                },
+                "journal_export" => {
+                    let query: JournalQuery = serde_json::from_value(job.payload.clone())?;
+                    let range = DateRange {
+                        from: query.from,
+                        to: query.to,
+                    };
+                    let journals = Journal::get_journals(
+                        client,
+                        &self.chart,
+                        &range,
+                        &self.reporting.currency,
+                    )
+                    .await?;
+                    match query.format {
+                        Some(format) => {
+                            let (content_type, body) = export_journals(&journals, format);
+                            Ok(serde_json::json!({
+                                "content_type": content_type,
+                                "body": body,
+                            }))
+                        }
+                        None => Ok(serde_json::to_value(journals)?),
+                    }
+                }
+                "upload" => {
+                    uploads::process(client, &self.db, &self.notifier, &self.formats, job).await
+                }
+                kind => Err(MyError::BadRequest(format!("Unknown job kind {}", kind))),
+            }
+        }
+
+        // Check the schedules twice a minute and enqueue those due in the current minute
+        async fn schedule_forever(self: Arc<Self>) {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.enqueue_due().await {
+                    tracing::error!("Job scheduler failed: {:?}", e);
+                }
+            }
+        }
+
+        async fn enqueue_due(&self) -> Result<(), MyError> {
+            let client: Client = self.db.get().await.map_err(MyError::PoolError)?;
+
+            let now = Utc::now().with_timezone(&self.zone);
+            let minute = now.date().and_hms(now.hour(), now.minute(), 0);
+
+            for schedule in JobSchedule::get_schedules(&client).await? {
+                if !schedule.enabled {
+                    continue;
+                }
+                let cron = match Cron::parse(&schedule.cron) {
+                    Ok(cron) => cron,
+                    Err(e) => {
+                        tracing::error!("Schedule {} is invalid: {}", schedule.name, e);
+                        continue;
+                    }
+                };
+                if cron.matches(&minute.naive_local())
+                    && JobSchedule::claim(&client, &schedule.name, minute.with_timezone(&Utc))
+                        .await?
+                {
+                    let job = Job::enqueue(
+                        &client,
+                        JobInsert {
+                            kind: schedule.kind,
+                            payload: schedule.payload,
+                            run_at: None,
+                            max_attempts: None,
+                        },
+                        None,
+                    )
+                    .await?;
+                    tracing::info!("Schedule {} enqueued job {}", schedule.name, job.id);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn at(date: &str) -> NaiveDateTime {
+            NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+        }
+
+        fn minutes(expression: &str) -> Vec<u32> {
+            let cron = Cron::parse(&format!("{} * * * *", expression)).unwrap();
+            (0..60)
+                .filter(|minute| {
+                    cron.matches(&at("2024-03-12 10:00").with_minute(*minute).unwrap())
+                })
+                .collect()
+        }
+
+        #[test]
+        fn reads_steps_and_ranges() {
+            assert_eq!(minutes("5/10"), vec![5, 15, 25, 35, 45, 55]);
+            assert_eq!(minutes("1-5/2"), vec![1, 3, 5]);
+            assert_eq!(minutes("*/20"), vec![0, 20, 40]);
+            assert_eq!(minutes("1,15,30-31"), vec![1, 15, 30, 31]);
+            assert_eq!(minutes("7"), vec![7]);
+        }
+
+        #[test]
+        fn takes_7_for_sunday() {
+            let cron = Cron::parse("0 6 * * 7").unwrap();
+            // The 10th of March 2024 was a Sunday
+            assert!(cron.matches(&at("2024-03-10 06:00")));
+            assert!(!cron.matches(&at("2024-03-11 06:00")));
+            assert!(Cron::parse("0 6 * * 0")
+                .unwrap()
+                .matches(&at("2024-03-10 06:00")));
+        }
+
+        #[test]
+        fn runs_on_either_day_of_month_or_day_of_week() {
+            // The 1st, or any Monday
+            let cron = Cron::parse("0 0 1 * 1").unwrap();
+            assert!(cron.matches(&at("2024-03-01 00:00")));
+            assert!(cron.matches(&at("2024-03-04 00:00")));
+            assert!(!cron.matches(&at("2024-03-05 00:00")));
+
+            // With a * day of week only the day of month counts
+            let cron = Cron::parse("0 0 1 * *").unwrap();
+            assert!(cron.matches(&at("2024-03-01 00:00")));
+            assert!(!cron.matches(&at("2024-03-04 00:00")));
+
+            let cron = Cron::parse("30 2 * 3 1-5").unwrap();
+            assert!(cron.matches(&at("2024-03-12 02:30")));
+            assert!(!cron.matches(&at("2024-03-10 02:30")));
+            assert!(!cron.matches(&at("2024-04-02 02:30")));
+        }
+
+        #[test]
+        fn refuses_invalid_expressions() {
+            for expression in [
+                "60 * * * *",
+                "* 24 * * *",
+                "* * 0 * *",
+                "* * 32 * *",
+                "* * * 13 *",
+                "* * * * 8",
+                "5-1 * * * *",
+                "*/0 * * * *",
+                "5/ * * * *",
+                "a * * * *",
+                "* * * *",
+                "* * * * * *",
+            ] {
+                assert!(Cron::parse(expression).is_err(), "{}", expression);
+            }
+        }
+
+        #[test]
+        fn backs_off_until_the_attempts_are_used_up() {
+            assert_eq!(backoff(1, 5), Some(30.0));
+            assert_eq!(backoff(2, 5), Some(60.0));
+            assert_eq!(backoff(4, 5), Some(240.0));
+            assert_eq!(backoff(5, 5), None);
+            assert_eq!(backoff(6, 5), None);
+            assert_eq!(backoff(10, 20), Some(MAX_BACKOFF_SECONDS));
+        }
+    }
+}
@@ -1,10 +1,25 @@
+// Each file wraps its items in a module of the same name
+#![allow(clippy::module_inception)]
+
+mod access;
 mod configs;
 mod errors;
+mod exports;
 mod handlers;
+mod his_sync;
 mod https_config;
 mod initializeserver;
+mod jobs;
 mod models;
+mod notifications;
+mod parsing;
+mod sources;
+mod statement_import;
+mod streaming;
 mod telemetry;
+#[cfg(test)]
+mod test_database;
+mod uploads;
 
 use crate::https_config::rustls_config::load_rustls_config;
 use crate::initializeserver::initialize_server;
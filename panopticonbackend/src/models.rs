@@ -1,1134 +1,4242 @@
 pub mod models {
 
+    use crate::configs::config::{ChartOfAccounts, DarajaConfig};
     use crate::errors::errors::MyError;
-    use chrono::NaiveDateTime;
+    use crate::jobs::jobs::Cron;
+    use crate::parsing::parsing::SourceFormat;
+    use crate::sources::sources::statement_source;
+    use crate::streaming::streaming::{CopyIn, CopyRow, JsonRows};
+    use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+    use chrono_tz::Tz;
     use deadpool_postgres::Client;
 
-    use futures_util::pin_mut;
+    use regex::Regex;
+    use rust_decimal::Decimal;
     use serde::{Deserialize, Serialize};
+    use std::collections::{HashMap, HashSet, VecDeque};
     use tokio_pg_mapper::FromTokioPostgresRow;
     use tokio_pg_mapper_derive::PostgresMapper;
-    use tokio_postgres::binary_copy::BinaryCopyInWriter;
+    use tokio_postgres::error::SqlState;
     use tokio_postgres::types::{ToSql, Type};
+    use tokio_postgres::GenericClient;
+
+    statement_source! {
+        #[derive(Debug)]
+        pub struct MpesaStatementInsert in "staging.mpesa_statement" {
+            pub receipt_no: String,
+            pub completion_time: String as date,
+            pub initiation_time: String as date,
+            pub details: String,
+            pub transaction_status: String,
+            pub paid_in: Option<Decimal> as amount,
+            pub withdrawn: Option<Decimal> as amount,
+            pub balance: Option<Decimal> as amount,
+            pub balance_confirmed: bool,
+            pub reason_type: String,
+            pub other_party_info: String,
+            pub linked_transaction_id: Option<String>,
+            pub ac_no: String,
+            /// The paybill or till. Set from ?shortcode= when the rows do not carry it
+            #[serde(default)]
+            pub shortcode: Option<String>,
+        }
 
-    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
-    #[pg_mapper(table = "staging.mpesa_statement")]
-    pub struct MpesaStatementInsert {
-        pub receipt_no: String,
-        pub completion_time: String,
-        pub initiation_time: String,
-        pub details: String,
-        pub transaction_status: String,
-        pub paid_in: Option<f64>,
-        pub withdrawn: Option<f64>,
-        pub balance: Option<f64>,
-        pub balance_confirmed: bool,
-        pub reason_type: String,
-        pub other_party_info: String,
-        pub linked_transaction_id: Option<String>,
-        pub ac_no: String,
+        pub struct MpesaStatement in "production.mpesa_statement" limit 1000
+            unit "production.statement_unit('mpesa', shortcode)" {
+            pub receipt_no: String,
+            pub completion_time: DateTime<Utc>,
+            pub initiation_time: DateTime<Utc>,
+            pub details: String,
+            pub transaction_status: String,
+            pub paid_in: Option<Decimal>,
+            pub withdrawn: Option<Decimal>,
+            /// Empty for Daraja payments that did not report the account balance
+            pub balance: Option<Decimal>,
+            pub balance_confirmed: bool,
+            pub reason_type: String,
+            pub other_party_info: String,
+            pub linked_transaction_id: Option<String>,
+            pub ac_no: Option<String>,
+            pub shortcode: Option<String>,
+            /// statement, or daraja until the statement upload replaces the row
+            pub source: String,
+        }
     }
     impl MpesaStatementInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<MpesaStatementInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self, format: &SourceFormat) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: format
+                    .parse_date(&self.completion_time)
+                    .ok_or_else(|| self.completion_time.clone())?,
+                reference: Some(self.receipt_no.clone()),
+                debit: self.withdrawn.unwrap_or(Decimal::ZERO).abs(),
+                credit: self.paid_in.unwrap_or(Decimal::ZERO),
+                balance: self.balance,
+            })
+        }
+    }
+
+    #[derive(Deserialize, PostgresMapper, Serialize)]
+    #[pg_mapper(table = "production.mpesa_reconciliations")]
+    pub struct ReconciledMpesa {
+        pub billing_number: String,
+        pub cashier: Option<String>,
+        pub receipt_date: Option<DateTime<Utc>>,
+        pub patient_name: Option<String>,
+        pub mpesa: Decimal,
+        pub transaction_code: Option<String>,
+        /// The statement the receipt is matched against: mpesa or airtel, from the code
+        pub wallet: String,
+        pub unit_name: Option<String>,
+        pub receipt_no: Option<String>,
+        /// Paid in net of reversals
+        pub paid_in: Option<Decimal>,
+        pub reversed: Option<Decimal>,
+        pub completion_time: Option<DateTime<Utc>>,
+        /// The paybill or till the payment went to
+        pub shortcode: Option<String>,
+        pub distance: Option<i32>,
+        /// automatic, confirmed, manual or unmatched
+        pub match_source: String,
+        pub comments: Option<String>,
+    }
+
+    impl MpesaStatement {
+        /// Collections of the day, or of the given units' receipts, matched to the statement
+        pub async fn get_reconciled_statement(
+            client: &Client,
+            date: NaiveDate,
+            units: Option<&[String]>,
+        ) -> Result<Vec<ReconciledMpesa>, MyError> {
+            MpesaStatement::get_reconciled_statement_in(&***client, date, units).await
+        }
+
+        /// The same statement read inside an open transaction
+        pub async fn get_reconciled_statement_in<C: GenericClient + Sync>(
+            client: &C,
+            date: NaiveDate,
+            units: Option<&[String]>,
+        ) -> Result<Vec<ReconciledMpesa>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_reconciled_mpesa_statement.sql");
 
-            let stmt = include_str!("../sql/user_actions/update_mpesa_statement.sql");
+            let res = client
+                .query(stmt, &[&date, &units])
+                .await?
+                .into_iter()
+                .map(|row| ReconciledMpesa::from_row_ref(&row).unwrap())
+                .collect::<Vec<ReconciledMpesa>>();
+            Ok(res)
+        }
 
-            //let _stmt = _stmt.replace("$table_fields", &User::sql_table_fields());
+        /// Reversals in the range paired with the transaction they reverse
+        pub async fn get_reversals(
+            client: &Client,
+            range: &DateRange,
+            units: Option<&[String]>,
+        ) -> Result<Vec<MpesaReversal>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_mpesa_reversals.sql");
 
-            // Create a prepared statement through transaction.prepare method
-            let statement = tx.prepare(stmt).await?;
+            let res = client
+                .query(stmt, &[&range.from, &range.to, &units])
+                .await?
+                .into_iter()
+                .map(|row| MpesaReversal::from_row_ref(&row).unwrap())
+                .collect::<Vec<MpesaReversal>>();
+            Ok(res)
+        }
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+        pub async fn get_daily_breakdown(
+            client: &Client,
+            range: &DateRange,
+            units: Option<&[String]>,
+        ) -> Result<Vec<MpesaDailyBreakdown>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_mpesa_daily_breakdown.sql");
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::BOOL,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-            ];
+            let res = client
+                .query(stmt, &[&range.from, &range.to, &units])
+                .await?
+                .into_iter()
+                .map(|row| MpesaDailyBreakdown::from_row_ref(&row).unwrap())
+                .collect::<Vec<MpesaDailyBreakdown>>();
+            Ok(res)
+        }
+    }
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+    /// ?shortcode= on M-Pesa routes
+    #[derive(Deserialize, Debug)]
+    pub struct ShortcodeFilter {
+        pub shortcode: Option<String>,
+    }
 
-            let num_written = Self::writee(writer, &data).await?;
+    impl ShortcodeFilter {
+        /// The shortcode trimmed, or None when not given. Paybills and tills are numbers
+        pub fn shortcode(&self) -> Result<Option<String>, MyError> {
+            match self.shortcode.as_deref().map(str::trim) {
+                None | Some("") => Ok(None),
+                Some(shortcode) if shortcode.chars().all(|c| c.is_ascii_digit()) => {
+                    Ok(Some(shortcode.to_string()))
+                }
+                Some(shortcode) => Err(MyError::BadRequest(format!(
+                    "Invalid shortcode {}. Paybill and till numbers are digits",
+                    shortcode
+                ))),
+            }
+        }
+    }
 
-            tx.commit().await?;
+    /// Limits /reconciliations/mpesa/{date} to the receipts of one unit, named directly or
+    /// through the shortcode mapped to it
+    #[derive(Deserialize, Debug)]
+    pub struct ReconciliationScope {
+        pub unit_name: Option<String>,
+        pub shortcode: Option<String>,
+    }
 
-            Ok(num_written)
+    impl ReconciliationScope {
+        pub async fn unit_name(&self, client: &Client) -> Result<Option<String>, MyError> {
+            match (&self.unit_name, &self.shortcode) {
+                (Some(unit_name), _) => Ok(Some(unit_name.clone())),
+                (None, Some(shortcode)) => MpesaShortcode::get_shortcodes(client)
+                    .await?
+                    .into_iter()
+                    .find(|mapped| &mapped.shortcode == shortcode)
+                    .and_then(|mapped| mapped.unit_name)
+                    .map(Some)
+                    .ok_or_else(|| {
+                        MyError::BadRequest(format!(
+                            "Shortcode {} is not mapped to a unit",
+                            shortcode
+                        ))
+                    }),
+                (None, None) => Ok(None),
+            }
         }
+    }
 
-        pub async fn writee(
-            writer: BinaryCopyInWriter,
-            data: &Vec<MpesaStatementInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+    /// A paybill or till: its unit, if mapped, and the balance on its latest statement row
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.mpesa_shortcodes")]
+    pub struct MpesaShortcode {
+        pub shortcode: String,
+        /// Empty for shortcodes seen on statements but not mapped yet
+        pub unit_name: Option<String>,
+        pub description: Option<String>,
+        pub balance: Option<Decimal>,
+        pub balance_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct MpesaShortcodeInsert {
+        pub unit_name: String,
+        pub description: Option<String>,
+    }
+
+    impl MpesaShortcode {
+        pub async fn get_shortcodes(client: &Client) -> Result<Vec<MpesaShortcode>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_mpesa_shortcodes.sql");
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.receipt_no);
-                row.push(&m.completion_time);
-                row.push(&m.initiation_time);
-                row.push(&m.details);
-                row.push(&m.transaction_status);
-                row.push(&m.paid_in);
-                row.push(&m.withdrawn);
-                row.push(&m.balance);
-                row.push(&m.balance_confirmed);
-                row.push(&m.reason_type);
-                row.push(&m.other_party_info);
-                row.push(&m.linked_transaction_id);
-                row.push(&m.ac_no);
+            let res = client
+                .query(stmt, &[])
+                .await?
+                .iter()
+                .map(MpesaShortcode::from_row_ref)
+                .collect::<Result<Vec<MpesaShortcode>, _>>()?;
+            Ok(res)
+        }
 
-                writer.as_mut().write(&row).await?;
+        /// Map a shortcode to a unit, replacing any earlier mapping
+        pub async fn upsert(
+            client: &Client,
+            shortcode: &str,
+            mapping: MpesaShortcodeInsert,
+        ) -> Result<(), MyError> {
+            if mapping.unit_name.trim().is_empty() {
+                return Err(MyError::BadRequest("unit_name is required".to_string()));
             }
+            let stmt = include_str!("../sql/user_actions/upsert_mpesa_shortcode.sql");
+
+            client
+                .execute(
+                    stmt,
+                    &[&shortcode, &mapping.unit_name.trim(), &mapping.description],
+                )
+                .await
+                .map_err(|e| match e.code() {
+                    Some(&SqlState::FOREIGN_KEY_VIOLATION) => MyError::BadRequest(format!(
+                        "Unit {} does not exist. Add it under /units first",
+                        mapping.unit_name.trim()
+                    )),
+                    _ => MyError::PGError(e),
+                })?;
+            Ok(())
+        }
 
-            writer.finish().await?;
+        pub async fn delete(client: &Client, shortcode: &str) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_mpesa_shortcode.sql");
 
-            Ok(data.len())
+            match client.execute(stmt, &[&shortcode]).await? {
+                0 => Err(MyError::NotFound),
+                _ => Ok(()),
+            }
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.mpesa_reconciliations")]
-    pub struct ReconciledMpesa {
-        billing_number: String,
-        cashier: String,
-        receipt_date: chrono::NaiveDateTime,
-        patient_name: String,
-        mpesa: f64,
-        transaction_code: String,
-        comments: String,
+
+    /// A branch with the users, accounts and M-Pesa shortcodes assigned to it
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.units")]
+    pub struct Unit {
+        pub unit_name: String,
+        pub description: Option<String>,
+        pub users: Vec<String>,
+        pub accounts: Vec<String>,
+        pub shortcodes: Vec<String>,
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.mpesa_statement")]
-    pub struct MpesaStatement {
-        pub receipt_no: String,
-        pub completion_time: NaiveDateTime,
-        pub initiation_time: NaiveDateTime,
-        pub details: String,
-        pub transaction_status: String,
-        pub paid_in: Option<f64>,
-        pub withdrawn: Option<f64>,
-        pub balance: f64,
-        pub balance_confirmed: bool,
-        pub reason_type: String,
-        pub other_party_info: String,
-        pub linked_transaction_id: Option<String>,
-        pub ac_no: Option<String>,
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct UnitInsert {
+        pub description: Option<String>,
     }
 
-    impl MpesaStatement {
-        pub async fn get_statement(client: &Client) -> Result<Vec<MpesaStatement>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_mpesa_statement.sql");
+    impl Unit {
+        pub async fn get_units(client: &Client) -> Result<Vec<Unit>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_units.sql");
 
             let res = client
                 .query(stmt, &[])
                 .await?
                 .into_iter()
-                .map(|row| MpesaStatement::from_row_ref(&row).unwrap())
-                .collect::<Vec<MpesaStatement>>();
+                .map(|row| Unit::from_row_ref(&row).unwrap())
+                .collect::<Vec<Unit>>();
             Ok(res)
         }
-        pub async fn get_reconciled_statement(
+
+        pub async fn upsert(
             client: &Client,
-            date: String,
-        ) -> Result<Vec<ReconciledMpesa>, MyError> {
-            let date = date;
+            unit_name: &str,
+            unit: UnitInsert,
+        ) -> Result<(), MyError> {
+            if unit_name.trim().is_empty() {
+                return Err(MyError::BadRequest("unit_name is required".to_string()));
+            }
+            let stmt = include_str!("../sql/user_actions/upsert_unit.sql");
 
-            let stmt = include_str!("../sql/user_actions/get_reconciled_mpesa_statement.sql");
+            client
+                .execute(stmt, &[&unit_name.trim(), &unit.description])
+                .await?;
+            Ok(())
+        }
+
+        /// Let a user read the unit
+        pub async fn add_user(
+            client: &Client,
+            unit_name: &str,
+            username: &str,
+        ) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/insert_user_unit.sql");
+
+            client
+                .execute(stmt, &[&username, &unit_name])
+                .await
+                .map_err(|e| match e.code() {
+                    Some(&SqlState::FOREIGN_KEY_VIOLATION) => MyError::NotFound,
+                    _ => MyError::PGError(e),
+                })?;
+            Ok(())
+        }
+
+        pub async fn remove_user(
+            client: &Client,
+            unit_name: &str,
+            username: &str,
+        ) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_user_unit.sql");
+
+            match client.execute(stmt, &[&username, &unit_name]).await? {
+                0 => Err(MyError::NotFound),
+                _ => Ok(()),
+            }
+        }
+
+        /// Move a bank or wallet account to the unit. M-Pesa shortcodes are mapped under
+        /// /statements/mpesa/shortcodes instead
+        pub async fn add_account(
+            client: &Client,
+            unit_name: &str,
+            account: &str,
+        ) -> Result<(), MyError> {
+            if !LOCKABLE_SOURCES.contains(&account) {
+                return Err(MyError::BadRequest(format!("Unknown account {}", account)));
+            }
+            let stmt = include_str!("../sql/user_actions/upsert_account_unit.sql");
+
+            client
+                .execute(stmt, &[&account, &unit_name])
+                .await
+                .map_err(|e| match e.code() {
+                    Some(&SqlState::FOREIGN_KEY_VIOLATION) => MyError::NotFound,
+                    _ => MyError::PGError(e),
+                })?;
+            Ok(())
+        }
+
+        /// Return the account to head office. NotFound when the account is not with this unit
+        pub async fn remove_account(
+            client: &Client,
+            unit_name: &str,
+            account: &str,
+        ) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_account_unit.sql");
+
+            match client.execute(stmt, &[&account, &unit_name]).await? {
+                0 => Err(MyError::NotFound),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Collections receipted and mobile money received by a unit on a day. The consolidated
+    /// row of the day adds up every unit and head office
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.collection_details")]
+    pub struct UnitSummary {
+        pub day: NaiveDate,
+        /// Empty for head office and for the consolidated row
+        pub unit_name: Option<String>,
+        pub consolidated: bool,
+        /// The reporting currency of the amounts
+        pub currency: String,
+        /// Empty when there is no rate from shillings on the day
+        pub collected: Option<Decimal>,
+        pub mobile_money_receipted: Option<Decimal>,
+        pub mobile_money_paid_in: Option<Decimal>,
+    }
+
+    impl UnitSummary {
+        pub async fn get_summary(
+            client: &Client,
+            range: &DateRange,
+            currency: &str,
+        ) -> Result<Vec<UnitSummary>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_unit_summary.sql");
 
             let res = client
-                .query(stmt, &[&date])
+                .query(stmt, &[&range.from, &range.to, &currency])
                 .await?
                 .into_iter()
-                .map(|row| ReconciledMpesa::from_row_ref(&row).unwrap())
-                .collect::<Vec<ReconciledMpesa>>();
+                .map(|row| UnitSummary::from_row_ref(&row).unwrap())
+                .collect::<Vec<UnitSummary>>();
             Ok(res)
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.collection_details")]
-    pub struct CollectionDetailsInsert {
-        pub receipt_no: Option<String>,
-        pub receipt_date: String,
-        pub patient_name: Option<String>,
-        pub payee: Option<String>,
-        pub cash: Option<f64>,
-        pub cheque: Option<f64>,
-        pub card: Option<f64>,
-        pub card_no: Option<f64>,
-        pub mpesa: Option<f64>,
-        pub e_transfer: Option<f64>,
-        pub transaction_no: Option<String>,
-        pub adv_used: Option<f64>,
-        pub employee_name: Option<String>,
-        pub unit_name: Option<String>,
+
+    /// The currency collections, M-Pesa and Airtel Money are kept in
+    pub const HOME_CURRENCY: &str = "KES";
+
+    /// Check an ISO 4217 code such as KES or USD and return it in upper case
+    pub fn currency_code(code: &str) -> Result<String, MyError> {
+        let code = code.trim().to_uppercase();
+        if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+            Ok(code)
+        } else {
+            Err(MyError::BadRequest(format!(
+                "{} is not a three letter currency code",
+                code
+            )))
+        }
     }
-    impl CollectionDetailsInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<CollectionDetailsInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_collection_details.sql");
+    /// Scope is ?currency=USD. The reporting currency of a report, or the currency of an
+    /// imported statement
+    #[derive(Deserialize, Debug)]
+    pub struct CurrencyQuery {
+        pub currency: Option<String>,
+    }
 
-            let statement = tx.prepare(stmt).await?;
+    /// Units of `quote_currency` bought by one unit of `base_currency` on `rate_date`. A rate
+    /// holds until a later one is uploaded, and is also used inverted
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.fx_rates")]
+    pub struct FxRate {
+        pub base_currency: String,
+        pub quote_currency: String,
+        pub rate_date: NaiveDate,
+        pub rate: Decimal,
+    }
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+    /// Scope is /fx/rates?currency=USD&from=YYYY-MM-DD&to=YYYY-MM-DD, all optional
+    #[derive(Deserialize, Debug)]
+    pub struct FxRateFilter {
+        /// Rates with the currency on either side
+        pub currency: Option<String>,
+        pub from: Option<NaiveDate>,
+        pub to: Option<NaiveDate>,
+    }
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-            ];
+    impl FxRate {
+        pub async fn get_rates(
+            client: &Client,
+            filter: &FxRateFilter,
+        ) -> Result<Vec<FxRate>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_fx_rates.sql");
+            let currency = filter.currency.as_deref().map(currency_code).transpose()?;
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+            let res = client
+                .query(stmt, &[&currency, &filter.from, &filter.to])
+                .await?
+                .into_iter()
+                .map(|row| FxRate::from_row_ref(&row).unwrap())
+                .collect::<Vec<FxRate>>();
+            Ok(res)
+        }
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+        /// Insert the rates in one transaction. A rate already uploaded for the pair and day is
+        /// replaced
+        pub async fn upsert(client: &mut Client, rates: Vec<FxRate>) -> Result<u64, MyError> {
+            let mut checked = Vec::with_capacity(rates.len());
+            for rate in rates {
+                let base = currency_code(&rate.base_currency)?;
+                let quote = currency_code(&rate.quote_currency)?;
+                if base == quote {
+                    return Err(MyError::BadRequest(format!(
+                        "{} {} is a rate between the same currency",
+                        base, rate.rate_date
+                    )));
+                }
+                if rate.rate <= Decimal::ZERO {
+                    return Err(MyError::BadRequest(format!(
+                        "{}/{} {}: the rate must be above zero",
+                        base, quote, rate.rate_date
+                    )));
+                }
+                checked.push((base, quote, rate.rate_date, rate.rate));
+            }
 
+            let stmt = include_str!("../sql/user_actions/upsert_fx_rate.sql");
+            let tx = client.transaction().await?;
+            let stmt = tx.prepare(stmt).await?;
+            let mut upserted = 0;
+            for (base, quote, rate_date, rate) in &checked {
+                upserted += tx.execute(&stmt, &[base, quote, rate_date, rate]).await?;
+            }
             tx.commit().await?;
+            Ok(upserted)
+        }
+    }
+
+    /// Sources whose times can be read in a zone of their own when moved to production. his
+    /// is the collections pulled from or exported by the HIS
+    pub const TIME_ZONE_SOURCES: [&str; 7] =
+        ["mpesa", "airtel", "equity", "kcb", "coop", "ncba", "his"];
 
-            Ok(num_written)
+    /// The zone a source writes its times in. Sources without one use TIMEZONE.DISPLAY
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.source_time_zones")]
+    pub struct SourceTimeZone {
+        pub source: String,
+        pub time_zone: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct SourceTimeZoneInsert {
+        /// An IANA zone name such as UTC or Africa/Nairobi
+        pub time_zone: Tz,
+    }
+
+    impl SourceTimeZone {
+        pub async fn get_zones(client: &Client) -> Result<Vec<SourceTimeZone>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_source_time_zones.sql");
+
+            let res = client
+                .query(stmt, &[])
+                .await?
+                .iter()
+                .map(SourceTimeZone::from_row_ref)
+                .collect::<Result<Vec<SourceTimeZone>, _>>()?;
+            Ok(res)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<CollectionDetailsInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+        /// Read the source's later uploads in the zone. Rows already stored keep their times
+        pub async fn upsert(
+            client: &Client,
+            source: &str,
+            zone: SourceTimeZoneInsert,
+        ) -> Result<(), MyError> {
+            if !TIME_ZONE_SOURCES.contains(&source) {
+                return Err(MyError::BadRequest(format!(
+                    "{} has no time zone of its own. Use one of {}",
+                    source,
+                    TIME_ZONE_SOURCES.join(", ")
+                )));
+            }
+            let stmt = include_str!("../sql/user_actions/upsert_source_time_zone.sql");
+
+            client
+                .execute(stmt, &[&source, &zone.time_zone.name()])
+                .await?;
+            Ok(())
+        }
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.receipt_no);
-                row.push(&m.receipt_date);
-                row.push(&m.patient_name);
-                row.push(&m.payee);
-                row.push(&m.cash);
-                row.push(&m.cheque);
-                row.push(&m.card);
-                row.push(&m.card_no);
-                row.push(&m.mpesa);
-                row.push(&m.e_transfer);
-                row.push(&m.transaction_no);
-                row.push(&m.adv_used);
-                row.push(&m.employee_name);
-                row.push(&m.unit_name);
+        /// Read the source in the display zone again
+        pub async fn delete(client: &Client, source: &str) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_source_time_zone.sql");
 
-                writer.as_mut().write(&row).await?;
+            match client.execute(stmt, &[&source]).await? {
+                0 => Err(MyError::NotFound),
+                _ => Ok(()),
             }
+        }
+    }
+
+    /// A reversal and the transaction it points at through linked_transaction_id
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.mpesa_transactions")]
+    pub struct MpesaReversal {
+        pub receipt_no: String,
+        pub completion_time: DateTime<Utc>,
+        pub amount: Decimal,
+        pub linked_transaction_id: Option<String>,
+        pub reason_type: Option<String>,
+        pub unit_name: Option<String>,
+        /// Empty when the original is not on the uploaded statement
+        pub original_completion_time: Option<DateTime<Utc>>,
+        pub original_amount: Option<Decimal>,
+    }
+
+    /// Gross and net M-Pesa movement of a day
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.mpesa_transactions")]
+    pub struct MpesaDailyBreakdown {
+        pub day: NaiveDate,
+        pub payments: i64,
+        pub gross_paid_in: Decimal,
+        pub reversals: i64,
+        pub reversed: Decimal,
+        pub charges: i64,
+        pub charged: Decimal,
+        /// Payments less reversals
+        pub net_paid_in: Decimal,
+        pub withdrawn: Decimal,
+    }
 
-            writer.finish().await?;
+    statement_source! {
+        /// A row of an Airtel Money statement
+        pub struct AirtelStatementInsert in "staging.airtel_statement" {
+            pub transaction_id: String,
+            pub transaction_time: NaiveDateTime as date,
+            pub transaction_type: String,
+            pub transaction_status: String,
+            pub sender_msisdn: Option<String>,
+            pub sender_name: Option<String>,
+            pub paid_in: Option<Decimal> as amount,
+            pub withdrawn: Option<Decimal> as amount,
+            pub balance: Option<Decimal> as amount,
+            pub linked_transaction_id: Option<String>,
+        }
 
-            Ok(data.len())
+        pub struct AirtelStatement in "production.airtel_statement" limit 1000 {
+            pub transaction_id: String,
+            pub transaction_time: DateTime<Utc>,
+            pub transaction_type: String,
+            pub transaction_status: String,
+            pub sender_msisdn: Option<String>,
+            pub sender_name: Option<String>,
+            pub paid_in: Option<Decimal>,
+            pub withdrawn: Option<Decimal>,
+            pub balance: Option<Decimal>,
+            pub linked_transaction_id: Option<String>,
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
+    impl AirtelStatementInsert {
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: self.transaction_time,
+                reference: Some(self.transaction_id.clone()),
+                debit: self.withdrawn.unwrap_or(Decimal::ZERO).abs(),
+                credit: self.paid_in.unwrap_or(Decimal::ZERO),
+                balance: self.balance,
+            })
+        }
+    }
+
+    /// An M-Pesa receipt and the payment it matched, if any
+    #[derive(Deserialize, PostgresMapper, Serialize, Clone, Debug)]
     #[pg_mapper(table = "production.collection_details")]
-    pub struct CollectionDetails {
+    pub struct ReconciliationMatch {
+        pub billing_number: Option<String>,
+        pub cashier: Option<String>,
+        pub unit_name: Option<String>,
+        pub transaction_code: Option<String>,
+        pub mpesa: Option<Decimal>,
         pub receipt_no: Option<String>,
-        pub receipt_date: chrono::NaiveDateTime,
-        pub patient_name: Option<String>,
-        pub payee: Option<String>,
-        pub cash: Option<f64>,
-        pub cheque: Option<f64>,
-        pub card: Option<f64>,
-        pub card_no: Option<String>,
-        pub mpesa: Option<f64>,
-        pub e_transfer: Option<f64>,
-        pub transaction_no: Option<String>,
-        pub adv_used: Option<f64>,
-        pub employee_name: Option<String>,
+        pub paid_in: Option<Decimal>,
+    }
+
+    /// Pushed to cashiers when collections or M-Pesa payments arrive
+    #[derive(Serialize, Clone, Debug)]
+    pub struct ReconciliationEvent {
+        /// collection or payment
+        pub kind: &'static str,
+        pub matched: bool,
+        #[serde(flatten)]
+        pub details: ReconciliationMatch,
+    }
+
+    /// The fields of a collection paid by M-Pesa that are matched against the payments
+    pub struct CollectionReceipt {
+        pub billing_number: Option<String>,
+        pub cashier: Option<String>,
+        pub unit_name: Option<String>,
+        pub transaction_code: Option<String>,
+        pub mpesa: Option<Decimal>,
+    }
+
+    impl CollectionReceipt {
+        /// None for collections not paid by M-Pesa, which are not reported to cashiers
+        pub fn from_collection(c: &CollectionDetailsInsert) -> Option<CollectionReceipt> {
+            if c.mpesa.unwrap_or(Decimal::ZERO) <= Decimal::ZERO || c.transaction_no.is_none() {
+                return None;
+            }
+            Some(CollectionReceipt {
+                billing_number: c.receipt_no.clone(),
+                cashier: c.employee_name.clone(),
+                unit_name: c.unit_name.clone(),
+                transaction_code: c.transaction_no.clone(),
+                mpesa: c.mpesa,
+            })
+        }
+    }
+
+    /// Limits the notification stream to one unit, one cashier or both
+    #[derive(Deserialize, Debug)]
+    pub struct NotificationScope {
         pub unit_name: Option<String>,
+        pub cashier: Option<String>,
+        /// A stream ticket in place of the Authorization header
+        pub ticket: Option<String>,
+        /// The units of the subscriber, None for every unit. Set from the caller
+        #[serde(skip)]
+        pub units: Option<Vec<String>>,
     }
-    impl CollectionDetails {
-        pub async fn get_statement(client: &Client) -> Result<Vec<CollectionDetails>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_collection_details.sql");
+
+    impl ReconciliationEvent {
+        fn new(kind: &'static str, details: ReconciliationMatch) -> Self {
+            ReconciliationEvent {
+                kind,
+                matched: details.billing_number.is_some() && details.receipt_no.is_some(),
+                details,
+            }
+        }
+
+        /// Whether the uploaded M-Pesa receipts match a payment on the statement, read inside
+        /// the transaction that copies them
+        pub async fn for_collections_in<C: GenericClient + Sync>(
+            client: &C,
+            collections: &[CollectionDetailsInsert],
+        ) -> Result<Vec<ReconciliationEvent>, MyError> {
+            let receipts: Vec<CollectionReceipt> = collections
+                .iter()
+                .filter_map(CollectionReceipt::from_collection)
+                .collect();
+            ReconciliationEvent::for_receipts_in(client, &receipts).await
+        }
+
+        /// Whether M-Pesa receipts kept from collections match a payment on the statement
+        pub async fn for_receipts_in<C: GenericClient + Sync>(
+            client: &C,
+            receipts: &[CollectionReceipt],
+        ) -> Result<Vec<ReconciliationEvent>, MyError> {
+            if receipts.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let billing_numbers: Vec<&Option<String>> =
+                receipts.iter().map(|c| &c.billing_number).collect();
+            let cashiers: Vec<&Option<String>> = receipts.iter().map(|c| &c.cashier).collect();
+            let units: Vec<&Option<String>> = receipts.iter().map(|c| &c.unit_name).collect();
+            let codes: Vec<&Option<String>> =
+                receipts.iter().map(|c| &c.transaction_code).collect();
+            let amounts: Vec<&Option<Decimal>> = receipts.iter().map(|c| &c.mpesa).collect();
+
+            let stmt = include_str!("../sql/user_actions/get_collection_matches.sql");
 
             let res = client
-                .query(stmt, &[])
+                .query(
+                    stmt,
+                    &[&billing_numbers, &cashiers, &units, &codes, &amounts],
+                )
+                .await?
+                .into_iter()
+                .map(|row| ReconciliationMatch::from_row_ref(&row).unwrap())
+                .map(|details| ReconciliationEvent::new("collection", details))
+                .collect::<Vec<ReconciliationEvent>>();
+            Ok(res)
+        }
+
+        /// The receipts, if any, that claim newly arrived payments
+        pub async fn for_payments(
+            client: &Client,
+            receipt_nos: &[String],
+        ) -> Result<Vec<ReconciliationEvent>, MyError> {
+            if receipt_nos.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let stmt = include_str!("../sql/user_actions/get_payment_matches.sql");
+
+            let res = client
+                .query(stmt, &[&receipt_nos])
                 .await?
                 .into_iter()
-                .map(|row| CollectionDetails::from_row_ref(&row).unwrap())
-                .collect::<Vec<CollectionDetails>>();
+                .map(|row| ReconciliationMatch::from_row_ref(&row).unwrap())
+                .map(|details| ReconciliationEvent::new("payment", details))
+                .collect::<Vec<ReconciliationEvent>>();
             Ok(res)
         }
+
+        pub fn visible_to(&self, scope: &NotificationScope) -> bool {
+            fn allows(filter: &Option<String>, value: &Option<String>) -> bool {
+                match (filter, value) {
+                    (None, _) => true,
+                    (Some(filter), Some(value)) => filter.eq_ignore_ascii_case(value),
+                    (Some(_), None) => false,
+                }
+            }
+            let readable = match (&scope.units, &self.details.unit_name) {
+                (None, _) => true,
+                (Some(units), Some(unit_name)) => units.contains(unit_name),
+                (Some(_), None) => false,
+            };
+            readable
+                && allows(&scope.unit_name, &self.details.unit_name)
+                && allows(&scope.cashier, &self.details.cashier)
+        }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.bill_details")]
-    pub struct BillDetailsInsert {
-        pub bill_date: String,
-        pub bill_no: Option<String>,
-        pub skypeid: Option<String>,
-        pub uhid: Option<String>,
-        pub visit_type: Option<String>,
-        pub patient_name: Option<String>,
-        pub payee: Option<String>,
-        pub service_name: Option<String>,
-        pub quantity: Option<f64>,
-        pub rate_per_unit: Option<f64>,
-        pub discount: Option<f64>,
-        pub gross: Option<f64>,
-        pub paid_amount: Option<f64>,
-        pub outstanding: Option<f64>,
-        pub service_doc: Option<String>,
-        pub department: Option<String>,
-        pub consulting_dr: Option<String>,
-        pub referring_dr: Option<String>,
-        pub servicing_dr: Option<String>,
-        pub payment_mode: Option<String>,
-        pub unit: String,
-    }
-    impl BillDetailsInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<BillDetailsInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_bill_details.sql");
-
-            let statement = tx.prepare(stmt).await?;
-
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
-
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-            ];
-
-            let writer = BinaryCopyInWriter::new(sink, types);
-
-            let num_written = Self::insertintodb(writer, &data).await?;
+    /// Reply Daraja expects from the C2B validation and confirmation callbacks
+    #[derive(Serialize, Debug)]
+    pub struct DarajaResponse {
+        #[serde(rename = "ResultCode")]
+        pub result_code: &'static str,
+        #[serde(rename = "ResultDesc")]
+        pub result_desc: String,
+    }
 
-            tx.commit().await?;
+    impl DarajaResponse {
+        pub fn accepted() -> Self {
+            DarajaResponse {
+                result_code: "0",
+                result_desc: "Accepted".to_string(),
+            }
+        }
 
-            Ok(num_written)
+        pub fn rejected(result_code: &'static str, reason: &str) -> Self {
+            DarajaResponse {
+                result_code,
+                result_desc: format!("Rejected: {}", reason),
+            }
         }
+    }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<BillDetailsInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
-
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.bill_date);
-                row.push(&m.bill_no);
-                row.push(&m.skypeid);
-                row.push(&m.uhid);
-                row.push(&m.visit_type);
-                row.push(&m.patient_name);
-                row.push(&m.payee);
-                row.push(&m.service_name);
-                row.push(&m.quantity);
-                row.push(&m.rate_per_unit);
-                row.push(&m.discount);
-                row.push(&m.gross);
-                row.push(&m.paid_amount);
-                row.push(&m.outstanding);
-                row.push(&m.service_doc);
-                row.push(&m.department);
-                row.push(&m.consulting_dr);
-                row.push(&m.referring_dr);
-                row.push(&m.servicing_dr);
-                row.push(&m.payment_mode);
-                row.push(&m.unit);
-
-                writer.as_mut().write(&row).await?;
-            }
-
-            writer.finish().await?;
-
-            Ok(data.len())
+    /// Body of the Daraja C2B validation and confirmation callbacks
+    #[derive(Deserialize, Serialize, Debug)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct C2BPayment {
+        pub transaction_type: String,
+        #[serde(rename = "TransID")]
+        pub trans_id: String,
+        pub trans_time: String,
+        /// Sent as a string by production and as a number by the sandbox
+        pub trans_amount: serde_json::Value,
+        pub business_short_code: String,
+        pub bill_ref_number: Option<String>,
+        pub invoice_number: Option<String>,
+        pub org_account_balance: Option<serde_json::Value>,
+        #[serde(rename = "MSISDN")]
+        pub msisdn: String,
+        pub first_name: Option<String>,
+        pub middle_name: Option<String>,
+        pub last_name: Option<String>,
+    }
+
+    impl C2BPayment {
+        /// Checks done before Safaricom completes the payment. The error is the reply to send
+        pub fn validate(&self, config: &DarajaConfig) -> Result<(), DarajaResponse> {
+            if !config.accepts_shortcode(&self.business_short_code) {
+                return Err(DarajaResponse::rejected("C2B00015", "Invalid Short Code"));
+            }
+            if !matches!(daraja_amount(&self.trans_amount), Some(amount) if amount > Decimal::ZERO)
+            {
+                return Err(DarajaResponse::rejected("C2B00013", "Invalid Amount"));
+            }
+            if daraja_time(&serde_json::Value::from(self.trans_time.as_str())).is_none() {
+                return Err(DarajaResponse::rejected("C2B00016", "Other Error"));
+            }
+            Ok(())
+        }
+
+        /// The payment in the shape of an M-Pesa statement row
+        pub fn statement_row(&self) -> Result<DarajaStatementRow, MyError> {
+            let completion_time = daraja_time(&serde_json::Value::from(self.trans_time.as_str()))
+                .ok_or_else(|| {
+                MyError::BadRequest(format!("Unreadable TransTime {}", self.trans_time))
+            })?;
+            let amount = daraja_amount(&self.trans_amount)
+                .ok_or_else(|| MyError::BadRequest("Unreadable TransAmount".to_string()))?;
+            let name = [&self.first_name, &self.middle_name, &self.last_name]
+                .iter()
+                .filter_map(|part| part.as_deref())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<&str>>()
+                .join(" ");
+            let account = self
+                .bill_ref_number
+                .clone()
+                .filter(|account| !account.is_empty());
+
+            Ok(DarajaStatementRow {
+                receipt_no: self.trans_id.clone(),
+                completion_time,
+                initiation_time: completion_time,
+                details: match &account {
+                    Some(account) => format!(
+                        "{} from {} - {} Acc. {}",
+                        self.transaction_type, self.msisdn, name, account
+                    ),
+                    None => format!("{} from {} - {}", self.transaction_type, self.msisdn, name),
+                },
+                transaction_status: "Completed".to_string(),
+                paid_in: Some(amount),
+                withdrawn: None,
+                balance: self.org_account_balance.as_ref().and_then(daraja_amount),
+                reason_type: self.transaction_type.clone(),
+                other_party_info: format!("{} - {}", self.msisdn, name),
+                ac_no: account,
+                shortcode: Some(self.business_short_code.clone()),
+            })
         }
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.bill_details")]
-    pub struct BillDetails {
-        pub bill_date: NaiveDateTime,
-        pub bill_no: Option<String>,
-        pub skypeid: Option<String>,
-        pub uhid: Option<String>,
-        pub visit: Option<String>,
-        pub patient_name: Option<String>,
-        pub payee: Option<String>,
-        pub service_name: Option<String>,
-        pub quantity: Option<f64>,
-        pub rate_per_unit: Option<f64>,
-        pub discount: Option<f64>,
-        pub gross: Option<f64>,
-        pub paid_amount: Option<f64>,
-        pub outstanding: Option<f64>,
-        pub service_doc: Option<String>,
-        pub department: Option<String>,
-        pub consulting_dr: Option<String>,
-        pub referring_dr: Option<String>,
-        pub servicing_dr: Option<String>,
-        pub payment_mode: Option<String>,
-    }
-    impl BillDetails {
-        // Get all Bill Details
-        pub async fn get_statement(client: &Client) -> Result<Vec<BillDetails>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_bill_details.sql");
+    /// Body of the Daraja result callbacks for B2C payments and transaction status queries
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct DarajaResult {
+        #[serde(rename = "Result")]
+        pub result: DarajaResultBody,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct DarajaResultBody {
+        pub result_code: serde_json::Value,
+        pub result_desc: String,
+        #[serde(rename = "TransactionID")]
+        pub transaction_id: Option<String>,
+        pub result_parameters: Option<DarajaResultParameters>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct DarajaResultParameters {
+        #[serde(rename = "ResultParameter")]
+        pub result_parameter: Vec<DarajaResultParameter>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct DarajaResultParameter {
+        #[serde(rename = "Key")]
+        pub key: String,
+        #[serde(rename = "Value")]
+        pub value: Option<serde_json::Value>,
+    }
+
+    impl DarajaResult {
+        pub fn succeeded(&self) -> bool {
+            daraja_amount(&self.result.result_code) == Some(Decimal::ZERO)
+        }
+
+        fn parameter(&self, key: &str) -> Option<&serde_json::Value> {
+            self.result
+                .result_parameters
+                .as_ref()?
+                .result_parameter
+                .iter()
+                .find(|p| p.key == key)?
+                .value
+                .as_ref()
+        }
+
+        fn text(&self, key: &str) -> Option<String> {
+            match self.parameter(key)? {
+                serde_json::Value::String(value) => Some(value.clone()),
+                value => Some(value.to_string()),
+            }
+        }
+
+        /// A completed B2C payment is money leaving the paybill. Failed payments yield nothing
+        pub fn b2c_row(&self) -> Result<Option<DarajaStatementRow>, MyError> {
+            if !self.succeeded() {
+                return Ok(None);
+            }
+            let receipt_no = self
+                .text("TransactionReceipt")
+                .or_else(|| self.result.transaction_id.clone())
+                .ok_or_else(|| MyError::BadRequest("Missing TransactionReceipt".to_string()))?;
+            let completion_time = self
+                .parameter("TransactionCompletedDateTime")
+                .and_then(daraja_time)
+                .ok_or_else(|| {
+                    MyError::BadRequest("Unreadable TransactionCompletedDateTime".to_string())
+                })?;
+            let amount = self
+                .parameter("TransactionAmount")
+                .and_then(daraja_amount)
+                .ok_or_else(|| MyError::BadRequest("Unreadable TransactionAmount".to_string()))?;
+            let receiver = self.text("ReceiverPartyPublicName").unwrap_or_default();
+
+            Ok(Some(DarajaStatementRow {
+                receipt_no,
+                completion_time,
+                initiation_time: completion_time,
+                details: format!("Business Payment to {}", receiver),
+                transaction_status: "Completed".to_string(),
+                paid_in: None,
+                withdrawn: Some(-amount),
+                balance: self
+                    .parameter("B2CUtilityAccountAvailableFunds")
+                    .and_then(daraja_amount),
+                reason_type: "Business Payment".to_string(),
+                other_party_info: receiver,
+                ac_no: None,
+                shortcode: None,
+            }))
+        }
+
+        /// The result of a transaction status query. Money leaves when the debit party is one of
+        /// our shortcodes and arrives otherwise
+        pub fn transaction_status_row(
+            &self,
+            config: &DarajaConfig,
+        ) -> Result<Option<DarajaStatementRow>, MyError> {
+            if !self.succeeded() {
+                return Ok(None);
+            }
+            let receipt_no = self
+                .text("ReceiptNo")
+                .ok_or_else(|| MyError::BadRequest("Missing ReceiptNo".to_string()))?;
+            let completion_time = self
+                .parameter("FinalisedTime")
+                .and_then(daraja_time)
+                .ok_or_else(|| MyError::BadRequest("Unreadable FinalisedTime".to_string()))?;
+            let initiation_time = self
+                .parameter("InitiatedTime")
+                .and_then(daraja_time)
+                .unwrap_or(completion_time);
+            let amount = self
+                .parameter("Amount")
+                .and_then(daraja_amount)
+                .ok_or_else(|| MyError::BadRequest("Unreadable Amount".to_string()))?;
+            let debit_party = self.text("DebitPartyName").unwrap_or_default();
+            let credit_party = self.text("CreditPartyName").unwrap_or_default();
+            let debit_shortcode = debit_party.split(" - ").next().unwrap_or_default();
+            let outgoing =
+                !config.shortcodes.trim().is_empty() && config.accepts_shortcode(debit_shortcode);
+            let shortcode = if outgoing {
+                debit_shortcode
+            } else {
+                credit_party.split(" - ").next().unwrap_or_default()
+            };
+            let reason_type = self.text("ReasonType").unwrap_or_default();
+
+            Ok(Some(DarajaStatementRow {
+                receipt_no,
+                completion_time,
+                initiation_time,
+                details: reason_type.clone(),
+                transaction_status: self
+                    .text("TransactionStatus")
+                    .unwrap_or_else(|| "Completed".to_string()),
+                paid_in: if outgoing { None } else { Some(amount) },
+                withdrawn: if outgoing { Some(-amount) } else { None },
+                balance: None,
+                reason_type,
+                shortcode: Some(shortcode.to_string()).filter(|s| !s.is_empty()),
+                other_party_info: if outgoing { credit_party } else { debit_party },
+                ac_no: None,
+            }))
+        }
+    }
+
+    /// A payment received through Daraja before it appears on a statement
+    #[derive(Serialize, Debug)]
+    pub struct DarajaStatementRow {
+        pub receipt_no: String,
+        pub completion_time: NaiveDateTime,
+        pub initiation_time: NaiveDateTime,
+        pub details: String,
+        pub transaction_status: String,
+        pub paid_in: Option<Decimal>,
+        pub withdrawn: Option<Decimal>,
+        pub balance: Option<Decimal>,
+        pub reason_type: String,
+        pub other_party_info: String,
+        pub ac_no: Option<String>,
+        pub shortcode: Option<String>,
+    }
+
+    impl DarajaStatementRow {
+        /// Store the payment keyed by receipt_no. Returns 0 when the receipt is already known
+        pub async fn save(&self, client: &Client) -> Result<u64, MyError> {
+            let stmt = include_str!("../sql/user_actions/upsert_daraja_payment.sql");
+
+            Ok(client
+                .execute(
+                    stmt,
+                    &[
+                        &self.receipt_no,
+                        &self.completion_time,
+                        &self.initiation_time,
+                        &self.details,
+                        &self.transaction_status,
+                        &self.paid_in,
+                        &self.withdrawn,
+                        &self.balance,
+                        &self.reason_type,
+                        &self.other_party_info,
+                        &self.ac_no,
+                        &self.shortcode,
+                    ],
+                )
+                .await?)
+        }
+    }
+
+    /// Keep the raw body of a Daraja callback before it is read. Returns its id
+    pub async fn record_daraja_callback(
+        client: &Client,
+        kind: &str,
+        receipt_no: Option<&str>,
+        payload: &serde_json::Value,
+    ) -> Result<i64, MyError> {
+        let stmt = include_str!("../sql/user_actions/insert_daraja_callback.sql");
+
+        let row = client
+            .query_one(stmt, &[&kind, &receipt_no, payload])
+            .await?;
+        Ok(row.get("id"))
+    }
+
+    /// Whether a recorded callback was accepted, with the receipt read from it if any
+    pub async fn settle_daraja_callback(
+        client: &Client,
+        id: i64,
+        receipt_no: Option<&str>,
+        accepted: bool,
+    ) -> Result<(), MyError> {
+        let stmt = include_str!("../sql/user_actions/settle_daraja_callback.sql");
+
+        client.execute(stmt, &[&id, &receipt_no, &accepted]).await?;
+        Ok(())
+    }
+
+    /// The body of a Daraja callback as JSON. A body that is not JSON is kept as a string
+    pub fn daraja_body(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(body).into_owned())
+        })
+    }
+
+    // Daraja sends amounts as strings or numbers
+    fn daraja_amount(value: &serde_json::Value) -> Option<Decimal> {
+        match value {
+            serde_json::Value::Number(number) => number.to_string().parse().ok(),
+            serde_json::Value::String(text) => text.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Times come as 20191122063845, either quoted or not, except B2C which uses 19.12.2019 11:45:50
+    fn daraja_time(value: &serde_json::Value) -> Option<NaiveDateTime> {
+        let text = match value {
+            serde_json::Value::String(text) => text.trim().to_string(),
+            value => value.to_string(),
+        };
+        NaiveDateTime::parse_from_str(&text, "%Y%m%d%H%M%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(&text, "%d.%m.%Y %H:%M:%S"))
+            .ok()
+    }
+
+    statement_source! {
+        pub struct CollectionDetailsInsert in "staging.collection_details" {
+            pub receipt_no: Option<String>,
+            pub receipt_date: String as date,
+            pub patient_name: Option<String>,
+            pub payee: Option<String>,
+            pub cash: Option<Decimal> as amount,
+            pub cheque: Option<Decimal> as amount,
+            pub card: Option<Decimal> as amount,
+            pub card_no: Option<String>,
+            pub mpesa: Option<Decimal> as amount,
+            pub e_transfer: Option<Decimal> as amount,
+            pub transaction_no: Option<String>,
+            pub adv_used: Option<Decimal> as amount,
+            pub employee_name: Option<String>,
+            pub unit_name: Option<String>,
+        }
+
+        pub struct CollectionDetails in "production.collection_details" limit 1000 unit "unit_name" {
+            pub receipt_no: Option<String>,
+            pub receipt_date: DateTime<Utc>,
+            pub patient_name: Option<String>,
+            pub payee: Option<String>,
+            pub cash: Option<Decimal>,
+            pub cheque: Option<Decimal>,
+            pub card: Option<Decimal>,
+            pub card_no: Option<String>,
+            pub mpesa: Option<Decimal>,
+            pub e_transfer: Option<Decimal>,
+            pub transaction_no: Option<String>,
+            pub adv_used: Option<Decimal>,
+            pub employee_name: Option<String>,
+            pub unit_name: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct BillDetailsInsert in "staging.bill_details" {
+            pub bill_date: String as date,
+            pub bill_no: Option<String>,
+            pub skypeid: Option<String>,
+            pub uhid: Option<String>,
+            pub visit_type: Option<String>,
+            pub patient_name: Option<String>,
+            pub payee: Option<String>,
+            pub service_name: Option<String>,
+            pub quantity: Option<f64>,
+            pub rate_per_unit: Option<Decimal> as amount,
+            pub discount: Option<Decimal> as amount,
+            pub gross: Option<Decimal> as amount,
+            pub paid_amount: Option<Decimal> as amount,
+            pub outstanding: Option<Decimal> as amount,
+            pub service_doc: Option<String> => service_doctor,
+            pub department: Option<String>,
+            pub consulting_dr: Option<String> => consulting_doctor,
+            pub referring_dr: Option<String> => referring_doctor,
+            pub servicing_dr: Option<String> => servicing_doctor,
+            pub payment_mode: Option<String>,
+            pub unit: String,
+        }
+
+        pub struct BillDetails in "production.bill_details" unit "unit" {
+            pub bill_date: DateTime<Utc>,
+            pub bill_no: Option<String>,
+            pub skypeid: Option<String>,
+            pub uhid: Option<String>,
+            pub visit: Option<String>,
+            pub patient_name: Option<String>,
+            pub payee: Option<String>,
+            pub service_name: Option<String>,
+            pub quantity: Option<i32>,
+            pub rate_per_unit: Option<Decimal>,
+            pub discount: Option<Decimal>,
+            pub gross: Option<Decimal>,
+            pub paid_amount: Option<Decimal>,
+            pub outstanding: Option<Decimal>,
+            pub service_doctor: Option<String>,
+            pub department: Option<String>,
+            pub consulting_doctor: Option<String>,
+            pub referring_doctor: Option<String>,
+            pub servicing_doctor: Option<String>,
+            pub payment_mode: Option<String>,
+            pub unit: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct LabVisitsInsert in "staging.lab_visits" {
+            pub sample_number: String,
+            pub name: String,
+            pub id_passport_no: Option<String>,
+            pub age: f64,
+            pub age_unit: String,
+            pub gender: String,
+            pub phone_number: Option<String>,
+            pub sample_date: String as date,
+            pub result: String,
+            pub email_address: Option<String>,
+            #[serde(default)]
+            pub unit_name: Option<String>,
+        }
+
+        pub struct LabVisits in "production.lab_visits" unit "unit_name" {
+            pub sample_number: String,
+            pub name: Option<String>,
+            pub id_passport_no: Option<String>,
+            pub age: Option<i32>,
+            pub age_unit: Option<String>,
+            pub gender: Option<String>,
+            pub phone_number: Option<String>,
+            pub sample_date: Option<DateTime<Utc>>,
+            pub result: Option<String>,
+            pub email_address: Option<String>,
+            pub unit_name: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct RegisteredPatientsInsert in "staging.registered_patients" {
+            pub uhid: Option<String>,
+            pub date: String as date,
+            pub patient_name: Option<String>,
+            pub age: Option<String>,
+            pub gender: Option<String>,
+            pub address: Option<String>,
+            pub contact_no: Option<String>,
+            #[serde(default)]
+            pub unit_name: Option<String>,
+        }
+
+        pub struct RegisteredPatients in "production.registered_patients" unit "unit_name" {
+            pub uhid: Option<String>,
+            pub date: Option<DateTime<Utc>>,
+            pub patient_name: Option<String>,
+            pub age: Option<String>,
+            pub gender: Option<String>,
+            pub address: Option<String>,
+            pub contact_no: Option<String>,
+            pub unit_name: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct MtibaStatementInsert in "staging.mtiba_statement" {
+            transactionstateid: Option<i32>,
+            transactiontypeid: Option<i32>,
+            facilityzohold: String,
+            facilityname: String,
+            fullreferencenumber: String,
+            phonenumber: String => phone_number,
+            payername: String => payer_name,
+            sendername: String => sender_name,
+            medicalprogramname: String => medical_program_name,
+            amountfordisplay: Option<Decimal> as amount => amount_for_display,
+            transactiondate: chrono::NaiveDateTime as date => transaction_date,
+            paymentdate: chrono::NaiveDateTime as date => payment_date,
+            transactiontype: String => transaction_type,
+        }
+
+        pub struct MtibaStatement in "production.mtiba_statement" {
+            transactionstateid: Option<String>,
+            transactiontypeid: Option<i32>,
+            facilityzohold: Option<String>,
+            facilityname: Option<String>,
+            fullreferencenumber: Option<String>,
+            phonenumber: Option<String>,
+            payername: Option<String>,
+            sendername: Option<String>,
+            medicalprogramname: Option<String>,
+            amountfordisplay: Option<Decimal>,
+            transactiondate: Option<DateTime<Utc>>,
+            paymentdate: Option<DateTime<Utc>>,
+            transactiontype: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct ABSAInsert in "staging.absa" {
+            pub transaction_date: String as date,
+            pub value_date: String as date,
+            pub description: String,
+            pub user_reference_number: Option<String>,
+            pub cheque_number: Option<i32>,
+            pub debit_amount: Option<Decimal> as amount,
+            pub credit_amount: Option<Decimal> as amount,
+            pub running_balance: Option<Decimal> as amount,
+            /// ISO currency code, KES when not given
+            #[serde(default)]
+            pub currency: Option<String>,
+        }
+
+        pub struct Absa in "production.absa_statement" {
+            pub transaction_date: DateTime<Utc>,
+            pub value_date: DateTime<Utc>,
+            pub description: String,
+            pub user_reference_number: Option<String>,
+            pub cheque_number: Option<i32>,
+            pub debit_amount: Option<Decimal>,
+            pub credit_amount: Option<Decimal>,
+            pub running_balance: Option<Decimal>,
+            pub currency: String,
+        }
+    }
+    impl ABSAInsert {
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self, format: &SourceFormat) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: format
+                    .parse_date(&self.transaction_date)
+                    .ok_or_else(|| self.transaction_date.clone())?,
+                reference: Some(self.description.clone()),
+                debit: self.debit_amount.unwrap_or(Decimal::ZERO),
+                credit: self.credit_amount.unwrap_or(Decimal::ZERO),
+                balance: self.running_balance,
+            })
+        }
+    }
+
+    statement_source! {
+        pub struct PdqBreakdownInsert in "staging.pdq_breakdowns" {
+            pub account_no: Option<i32>,
+            pub location_no: Option<i32>,
+            pub legal_name: Option<String>,
+            pub card_no: String,
+            pub txn_date: Option<String> as date,
+            pub processing_date: Option<String> as date,
+            pub payment_date: Option<String> as date,
+            pub terminal_id: Option<i32>,
+            pub auth_id: Option<String>,
+            pub amount: Option<Decimal> as amount,
+            pub commission: Option<Decimal> as amount,
+            pub net_amount: Option<Decimal> as amount,
+            pub trxn_type: Option<String>,
+            pub currency: Option<String>,
+            pub pmnt_type: Option<String>,
+            pub trxn_source: Option<String>,
+            pub scheme: Option<String>,
+            pub commercial_name: Option<String>,
+            pub arn_reference: Option<String>,
+            pub retrieval_ref_no: Option<String>,
+            pub tip_amount: Option<Decimal> as amount,
+            pub card_present: Option<String>,
+        }
+
+        pub struct PdqBreakdown in "production.pdq_breakdowns" {
+            pub account_no: Option<i32>,
+            pub location_no: Option<i32>,
+            pub legal_name: Option<String>,
+            pub card_no: Option<String>,
+            pub txn_date: Option<DateTime<Utc>>,
+            pub processing_date: Option<DateTime<Utc>>,
+            pub payment_date: Option<DateTime<Utc>>,
+            pub terminal_id: Option<i32>,
+            pub auth_id: Option<String>,
+            pub amount: Option<Decimal>,
+            pub commission: Option<Decimal>,
+            pub net_amount: Option<Decimal>,
+            pub trxn_type: Option<String>,
+            pub currency: Option<String>,
+            pub pmnt_type: Option<String>,
+            pub trxn_source: Option<String>,
+            pub scheme: Option<String>,
+            pub commercial_name: Option<String>,
+            pub arn_reference: Option<String>,
+            pub retrieval_ref_no: Option<String>,
+            pub tip_amount: Option<Decimal>,
+            pub card_present: Option<String>,
+        }
+    }
+
+    statement_source! {
+        pub struct SidianInsert in "staging.sidian_statement" {
+            pub date: String as date,
+            pub valuedate: Option<String> as date,
+            pub reference: Option<String>,
+            pub narration: Option<String>,
+            pub chequenumber: Option<i32>,
+            pub debit: Option<Decimal> as amount,
+            pub credit: Option<Decimal> as amount,
+            pub balance: Decimal as amount,
+            /// ISO currency code, KES when not given
+            #[serde(default)]
+            pub currency: Option<String>,
+        }
+
+        pub struct Sidian in "production.sidian_statement" {
+            pub date: DateTime<Utc>,
+            pub valuedate: Option<DateTime<Utc>>,
+            pub reference: Option<String>,
+            pub narration: Option<String>,
+            pub chequenumber: Option<i32>,
+            pub debit: Option<Decimal>,
+            pub credit: Option<Decimal>,
+            pub balance: Decimal,
+            pub currency: String,
+        }
+    }
+    impl SidianInsert {
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self, format: &SourceFormat) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: format
+                    .parse_date(&self.date)
+                    .ok_or_else(|| self.date.clone())?,
+                reference: self.reference.clone().or_else(|| self.narration.clone()),
+                debit: self.debit.unwrap_or(Decimal::ZERO),
+                credit: self.credit.unwrap_or(Decimal::ZERO),
+                balance: Some(self.balance),
+            })
+        }
+    }
+
+    statement_source! {
+        pub struct CfcInsert in "staging.cfc_statement" {
+            pub date: chrono::NaiveDateTime as date,
+            pub transaction: String,
+            pub value_date: chrono::NaiveDateTime as date,
+            pub debit: Option<Decimal> as amount,
+            pub credit: Option<Decimal> as amount,
+            pub ledger_balance: Option<Decimal> as amount,
+            pub available_balance: Option<Decimal> as amount,
+            /// ISO currency code, KES when not given
+            #[serde(default)]
+            pub currency: Option<String>,
+        }
+
+        pub struct Cfc in "production.cfc_statement" {
+            pub date: DateTime<Utc>,
+            pub transaction: String,
+            pub value_date: DateTime<Utc>,
+            pub debit: Option<Decimal>,
+            pub credit: Option<Decimal>,
+            pub ledger_balance: Option<Decimal>,
+            pub available_balance: Option<Decimal>,
+            pub currency: String,
+        }
+    }
+    impl CfcInsert {
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: self.date,
+                reference: Some(self.transaction.clone()),
+                debit: self.debit.unwrap_or(Decimal::ZERO),
+                credit: self.credit.unwrap_or(Decimal::ZERO),
+                balance: self.ledger_balance,
+            })
+        }
+    }
+
+    statement_source! {
+        /// A row of an Equity, KCB, Co-operative Bank or NCBA statement. `bank` is set from the
+        /// route, not read from the body
+        pub struct BankStatementInsert in "staging.bank_statements" {
+            #[serde(default)]
+            pub bank: String,
+            pub transaction_date: chrono::NaiveDateTime as date,
+            pub value_date: Option<chrono::NaiveDateTime> as date,
+            pub narration: Option<String>,
+            pub reference: Option<String>,
+            pub debit: Option<Decimal> as amount,
+            pub credit: Option<Decimal> as amount,
+            pub balance: Option<Decimal> as amount,
+            /// ISO currency code, KES when not given
+            #[serde(default)]
+            pub currency: Option<String>,
+        }
+
+        pub struct BankStatement in "production.bank_statements" {
+            pub bank: String,
+            pub transaction_date: DateTime<Utc>,
+            pub value_date: Option<DateTime<Utc>>,
+            pub narration: Option<String>,
+            pub reference: Option<String>,
+            pub debit: Option<Decimal>,
+            pub credit: Option<Decimal>,
+            pub balance: Option<Decimal>,
+            pub currency: String,
+        }
+    }
+    impl BankStatementInsert {
+        /// The row as seen by the balance continuity check
+        pub fn balance_entry(&self) -> Result<BalanceEntry, String> {
+            Ok(BalanceEntry {
+                date: self.transaction_date,
+                reference: self.reference.clone().or_else(|| self.narration.clone()),
+                debit: self.debit.unwrap_or(Decimal::ZERO),
+                credit: self.credit.unwrap_or(Decimal::ZERO),
+                balance: self.balance,
+            })
+        }
+    }
+
+    impl BankStatement {
+        /// The production rows of one bank, oldest first
+        pub async fn get_bank_statement(
+            client: &Client,
+            bank: BankAccount,
+        ) -> Result<Vec<BankStatement>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_bank_statement.sql");
+
+            let res = client
+                .query(stmt, &[&bank.name()])
+                .await?
+                .iter()
+                .map(BankStatement::from_row_ref)
+                .collect::<Result<Vec<BankStatement>, _>>()?;
+            Ok(res)
+        }
+    }
+
+    /// The accounts that carry a running balance on their statements
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BankAccount {
+        Absa,
+        Sidian,
+        Cfc,
+        Mpesa,
+        Airtel,
+        Equity,
+        Kcb,
+        Coop,
+        Ncba,
+    }
+
+    impl BankAccount {
+        pub fn from_name(name: &str) -> Option<BankAccount> {
+            match name {
+                "absa" => Some(BankAccount::Absa),
+                "sidian" => Some(BankAccount::Sidian),
+                "cfc" => Some(BankAccount::Cfc),
+                "mpesa" => Some(BankAccount::Mpesa),
+                "airtel" => Some(BankAccount::Airtel),
+                "equity" => Some(BankAccount::Equity),
+                "kcb" => Some(BankAccount::Kcb),
+                "coop" => Some(BankAccount::Coop),
+                "ncba" => Some(BankAccount::Ncba),
+                _ => None,
+            }
+        }
+
+        /// The account name used in the ledger
+        pub fn name(&self) -> &'static str {
+            match self {
+                BankAccount::Absa => "absa",
+                BankAccount::Sidian => "sidian",
+                BankAccount::Cfc => "cfc",
+                BankAccount::Mpesa => "mpesa",
+                BankAccount::Airtel => "airtel",
+                BankAccount::Equity => "equity",
+                BankAccount::Kcb => "kcb",
+                BankAccount::Coop => "coop",
+                BankAccount::Ncba => "ncba",
+            }
+        }
+
+        /// Whether the account is stored in production.bank_statements
+        pub fn in_bank_statements(&self) -> bool {
+            matches!(
+                self,
+                BankAccount::Equity | BankAccount::Kcb | BankAccount::Coop | BankAccount::Ncba
+            )
+        }
+    }
+
+    /// Date range used by the on-demand reports. Dates are inclusive.
+    #[derive(Deserialize, Debug)]
+    pub struct DateRange {
+        pub from: NaiveDate,
+        pub to: NaiveDate,
+    }
+
+    /// A single statement row reduced to what is needed to follow the running balance
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone)]
+    #[pg_mapper(table = "balance_entries")]
+    pub struct BalanceEntry {
+        pub date: NaiveDateTime,
+        pub reference: Option<String>,
+        pub debit: Decimal,
+        pub credit: Decimal,
+        pub balance: Option<Decimal>,
+    }
+
+    impl BalanceEntry {
+        /// Read the production rows of an account for a date range, oldest first
+        pub async fn get_entries(
+            client: &Client,
+            account: BankAccount,
+            range: &DateRange,
+        ) -> Result<Vec<BalanceEntry>, MyError> {
+            BalanceEntry::get_entries_in(&***client, account, range).await
+        }
+
+        /// The same rows read inside an open transaction
+        pub async fn get_entries_in<C: GenericClient + Sync>(
+            client: &C,
+            account: BankAccount,
+            range: &DateRange,
+        ) -> Result<Vec<BalanceEntry>, MyError> {
+            let stmt = match account {
+                BankAccount::Absa => {
+                    include_str!("../sql/user_actions/get_absa_balance_entries.sql")
+                }
+                BankAccount::Sidian => {
+                    include_str!("../sql/user_actions/get_sidian_balance_entries.sql")
+                }
+                BankAccount::Cfc => include_str!("../sql/user_actions/get_cfc_balance_entries.sql"),
+                BankAccount::Mpesa => {
+                    include_str!("../sql/user_actions/get_mpesa_balance_entries.sql")
+                }
+                BankAccount::Airtel => {
+                    include_str!("../sql/user_actions/get_airtel_balance_entries.sql")
+                }
+                BankAccount::Equity | BankAccount::Kcb | BankAccount::Coop | BankAccount::Ncba => {
+                    include_str!("../sql/user_actions/get_bank_balance_entries.sql")
+                }
+            };
+            // The shared bank table also needs the bank
+            let bank = account.name();
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![&range.from, &range.to];
+            if account.in_bank_statements() {
+                params.push(&bank);
+            }
+
+            let res = client
+                .query(stmt, &params)
+                .await?
+                .into_iter()
+                .map(|row| BalanceEntry::from_row_ref(&row).unwrap())
+                .collect::<Vec<BalanceEntry>>();
+            Ok(res)
+        }
+
+        /// The M-Pesa rows of one paybill or till, whose balance runs separately from the others
+        pub async fn get_shortcode_entries(
+            client: &Client,
+            shortcode: &str,
+            range: &DateRange,
+        ) -> Result<Vec<BalanceEntry>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_shortcode_balance_entries.sql");
+
+            let res = client
+                .query(stmt, &[&range.from, &range.to, &shortcode])
+                .await?
+                .iter()
+                .map(BalanceEntry::from_row_ref)
+                .collect::<Result<Vec<BalanceEntry>, _>>()?;
+            Ok(res)
+        }
+
+        // Whether this row carries on from the given balance
+        fn follows(&self, previous_balance: Decimal) -> bool {
+            self.previous_balance() == Some(previous_balance)
+        }
+
+        // The balance the row carries on from
+        fn previous_balance(&self) -> Option<Decimal> {
+            self.balance
+                .map(|balance| balance - self.credit + self.debit)
+        }
+
+        // Rows with the same key are the same row listed twice
+        fn row_key(
+            &self,
+        ) -> (
+            NaiveDateTime,
+            Option<&str>,
+            Decimal,
+            Decimal,
+            Option<Decimal>,
+        ) {
+            (
+                self.date,
+                self.reference.as_deref(),
+                self.debit,
+                self.credit,
+                self.balance,
+            )
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    pub enum BalanceGap {
+        /// The balance does not follow from the previous row. Rows are probably missing in between
+        MissingRows {
+            after: NaiveDateTime,
+            before: NaiveDateTime,
+            expected_balance: Decimal,
+            actual_balance: Decimal,
+            difference: Decimal,
+        },
+        /// No rows for one or more whole days
+        MissingDays {
+            from: NaiveDate,
+            to: NaiveDate,
+            days: i64,
+            balance_continuous: bool,
+        },
+        /// The same row appears more than once
+        DuplicateRow {
+            date: NaiveDateTime,
+            reference: Option<String>,
+            occurrences: usize,
+        },
+        /// The row has a date that could not be read
+        UnreadableDate { row: usize, value: String },
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ContinuityReport {
+        pub account: BankAccount,
+        pub rows_checked: usize,
+        pub opening_balance: Option<Decimal>,
+        pub closing_balance: Option<Decimal>,
+        pub gaps: Vec<BalanceGap>,
+    }
+
+    impl ContinuityReport {
+        /// Walk the entries chronologically and check previous_balance + credit - debit = balance.
+        /// Rows sharing a timestamp are put in the order that keeps the balance chain intact.
+        pub fn check(account: BankAccount, mut entries: Vec<BalanceEntry>) -> ContinuityReport {
+            let mut gaps = Vec::new();
+
+            entries.sort_by_key(|e| e.date);
+
+            // Report and drop exact duplicates so they do not also show up as breaks in the chain
+            let mut occurrences: HashMap<_, usize> = HashMap::with_capacity(entries.len());
+            for entry in entries.iter() {
+                *occurrences.entry(entry.row_key()).or_default() += 1;
+            }
+            let mut seen = HashSet::with_capacity(occurrences.len());
+            let mut unique: Vec<BalanceEntry> = Vec::with_capacity(occurrences.len());
+            for entry in entries.iter() {
+                let key = entry.row_key();
+                if !seen.insert(key) {
+                    continue;
+                }
+                if occurrences[&key] > 1 {
+                    gaps.push(BalanceGap::DuplicateRow {
+                        date: entry.date,
+                        reference: entry.reference.clone(),
+                        occurrences: occurrences[&key],
+                    });
+                }
+                unique.push(entry.clone());
+            }
+
+            let ordered = Self::order_within_timestamps(unique);
+
+            let mut previous: Option<&BalanceEntry> = None;
+            for entry in ordered.iter() {
+                if let Some(prev) = previous {
+                    let continuous = match (prev.balance, entry.balance) {
+                        (Some(prev_balance), Some(balance)) => {
+                            let expected = prev_balance + entry.credit - entry.debit;
+                            if entry.follows(prev_balance) {
+                                true
+                            } else {
+                                gaps.push(BalanceGap::MissingRows {
+                                    after: prev.date,
+                                    before: entry.date,
+                                    expected_balance: expected,
+                                    actual_balance: balance,
+                                    difference: balance - expected,
+                                });
+                                false
+                            }
+                        }
+                        // Rows without a balance cannot be checked
+                        _ => true,
+                    };
+
+                    let days = (entry.date.date() - prev.date.date()).num_days();
+                    if days > 1 {
+                        gaps.push(BalanceGap::MissingDays {
+                            from: prev.date.date().succ(),
+                            to: entry.date.date().pred(),
+                            days: days - 1,
+                            balance_continuous: continuous,
+                        });
+                    }
+                }
+                previous = Some(entry);
+            }
+
+            ContinuityReport {
+                account,
+                rows_checked: entries.len(),
+                opening_balance: ordered
+                    .first()
+                    .and_then(|e| e.balance.map(|b| b - e.credit + e.debit)),
+                closing_balance: ordered.last().and_then(|e| e.balance),
+                gaps,
+            }
+        }
+
+        // Statements list several rows under the same timestamp in no reliable order.
+        // Within each timestamp pick the row that follows on from the running balance.
+        fn order_within_timestamps(entries: Vec<BalanceEntry>) -> Vec<BalanceEntry> {
+            let mut ordered: Vec<BalanceEntry> = Vec::with_capacity(entries.len());
+            let mut group: Vec<BalanceEntry> = Vec::new();
+
+            for entry in entries.into_iter() {
+                if matches!(group.first(), Some(g) if g.date != entry.date) {
+                    Self::drain_group(&mut ordered, &mut group);
+                }
+                group.push(entry);
+            }
+            Self::drain_group(&mut ordered, &mut group);
+
+            ordered
+        }
+
+        // Rows are looked up by the balance they carry on from, so a timestamp with many rows
+        // is ordered in one pass. When no row follows on, the next row that starts a chain of its
+        // own is taken, then the first left in the group
+        fn drain_group(ordered: &mut Vec<BalanceEntry>, group: &mut Vec<BalanceEntry>) {
+            let mut by_previous: HashMap<Decimal, VecDeque<usize>> = HashMap::new();
+            for (index, entry) in group.iter().enumerate() {
+                if let Some(previous) = entry.previous_balance() {
+                    by_previous.entry(previous).or_default().push_back(index);
+                }
+            }
+            // Rows that no other row in the group leads to start a chain
+            let balances: HashSet<Decimal> = group.iter().filter_map(|e| e.balance).collect();
+            let mut heads: VecDeque<usize> = group
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| !matches!(e.previous_balance(), Some(p) if balances.contains(&p)))
+                .map(|(index, _)| index)
+                .collect();
+
+            let mut left: Vec<Option<BalanceEntry>> = group.drain(..).map(Some).collect();
+            let mut first = 0;
+            for _ in 0..left.len() {
+                let following = ordered
+                    .last()
+                    .and_then(|prev| prev.balance)
+                    .and_then(|balance| by_previous.get_mut(&balance))
+                    .and_then(|candidates| {
+                        while let Some(&index) = candidates.front() {
+                            if left[index].is_some() {
+                                return Some(index);
+                            }
+                            candidates.pop_front();
+                        }
+                        None
+                    });
+                let next = match following {
+                    Some(index) => index,
+                    None => {
+                        while matches!(heads.front(), Some(&index) if left[index].is_none()) {
+                            heads.pop_front();
+                        }
+                        match heads.pop_front() {
+                            Some(index) => index,
+                            None => {
+                                while left[first].is_none() {
+                                    first += 1;
+                                }
+                                first
+                            }
+                        }
+                    }
+                };
+                ordered.extend(left[next].take());
+            }
+        }
+    }
+
+    /// Returned by the bank and M-Pesa uploads so gaps are visible as soon as a statement is loaded
+    #[derive(Serialize, Debug)]
+    pub struct UploadSummary {
+        pub rows_inserted: usize,
+        pub rows_categorized: usize,
+        pub continuity: ContinuityReport,
+    }
+
+    impl UploadSummary {
+        /// Rows whose date could not be read come in as `Err` with the raw value
+        pub fn new(
+            account: BankAccount,
+            rows_inserted: usize,
+            rows: Vec<Result<BalanceEntry, String>>,
+        ) -> UploadSummary {
+            let mut unreadable = Vec::new();
+            let mut entries = Vec::with_capacity(rows.len());
+            for (index, row) in rows.into_iter().enumerate() {
+                match row {
+                    Ok(entry) => entries.push(entry),
+                    Err(value) => unreadable.push(BalanceGap::UnreadableDate {
+                        row: index + 1,
+                        value,
+                    }),
+                }
+            }
+
+            let mut continuity = ContinuityReport::check(account, entries);
+            continuity.gaps.extend(unreadable);
+
+            UploadSummary {
+                rows_inserted,
+                rows_categorized: 0,
+                continuity,
+            }
+        }
+    }
+
+    /// A row of any money source in one shape. Read from the production.ledger_entries view
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.ledger_entries")]
+    pub struct LedgerEntry {
+        pub account: String,
+        pub date: DateTime<Utc>,
+        pub value_date: Option<DateTime<Utc>>,
+        pub reference: Option<String>,
+        pub narration: Option<String>,
+        pub debit: Decimal,
+        pub credit: Decimal,
+        pub statement_balance: Option<Decimal>,
+        pub running_balance: Decimal,
+        pub source_row_id: i64,
+        /// Null for rows of head office accounts
+        pub unit_name: Option<String>,
+        /// The currency of the amounts and balances of the row
+        pub currency: String,
+        /// The M-Pesa paybill or till whose running balance the row follows
+        pub shortcode: Option<String>,
+        /// Paired with the other side of an inter-account transfer
+        pub is_transfer: bool,
+        pub category: Option<String>,
+        /// Debit and credit in the reporting currency. Empty without an exchange rate
+        pub reporting_debit: Option<Decimal>,
+        pub reporting_credit: Option<Decimal>,
+    }
+
+    /// Filters accepted by /ledger. All of them are optional
+    #[derive(Deserialize, Debug)]
+    pub struct LedgerFilter {
+        pub from: Option<NaiveDate>,
+        pub to: Option<NaiveDate>,
+        pub account: Option<String>,
+        pub search: Option<String>,
+        /// Leave out transfers between our own accounts
+        pub exclude_transfers: Option<bool>,
+        /// Reporting currency, REPORTING.CURRENCY when not given
+        pub currency: Option<String>,
+    }
+
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.ledger_entries")]
+    pub struct AccountPosition {
+        pub account: String,
+        /// Each M-Pesa paybill or till has its own balance
+        pub shortcode: Option<String>,
+        pub currency: String,
+        pub balance: Decimal,
+        /// Empty without an exchange rate to the reporting currency
+        pub reporting_balance: Option<Decimal>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct Ledger {
+        pub entries: Vec<LedgerEntry>,
+        /// Closing balance of each account, M-Pesa shortcode and currency at the end of the range
+        pub positions: Vec<AccountPosition>,
+        pub reporting_currency: String,
+        /// Sum of the closing balances in the reporting currency
+        pub cash_position: Decimal,
+        /// Currencies held without a rate to the reporting currency. Their balances are left
+        /// out of the cash position
+        pub unconverted: Vec<String>,
+    }
+
+    impl Ledger {
+        /// Rows and positions of the given units' accounts, of every account for None. Amounts
+        /// are also converted to `filter.currency`, or `reporting_currency` when not given
+        pub async fn get_ledger(
+            client: &Client,
+            filter: &LedgerFilter,
+            units: Option<&[String]>,
+            reporting_currency: &str,
+        ) -> Result<Ledger, MyError> {
+            Ledger::get_ledger_in(&***client, filter, units, reporting_currency).await
+        }
+
+        /// The same ledger read inside an open transaction
+        pub async fn get_ledger_in<C: GenericClient + Sync>(
+            client: &C,
+            filter: &LedgerFilter,
+            units: Option<&[String]>,
+            reporting_currency: &str,
+        ) -> Result<Ledger, MyError> {
+            let reporting_currency = match filter.currency.as_deref() {
+                Some(currency) => currency_code(currency)?,
+                None => reporting_currency.to_string(),
+            };
+            let stmt = include_str!("../sql/user_actions/get_ledger_entries.sql");
+
+            let entries = client
+                .query(
+                    stmt,
+                    &[
+                        &filter.from,
+                        &filter.to,
+                        &filter.account,
+                        &filter.search,
+                        &filter.exclude_transfers,
+                        &units,
+                        &reporting_currency,
+                    ],
+                )
+                .await?
+                .into_iter()
+                .map(|row| LedgerEntry::from_row_ref(&row).unwrap())
+                .collect::<Vec<LedgerEntry>>();
+
+            let stmt = include_str!("../sql/user_actions/get_ledger_positions.sql");
+
+            let positions = client
+                .query(stmt, &[&filter.to, &units, &reporting_currency])
+                .await?
+                .into_iter()
+                .map(|row| AccountPosition::from_row_ref(&row).unwrap())
+                .filter(|p| filter.account.is_none() || filter.account.as_ref() == Some(&p.account))
+                .collect::<Vec<AccountPosition>>();
+
+            let cash_position = positions.iter().filter_map(|p| p.reporting_balance).sum();
+            let mut unconverted: Vec<String> = positions
+                .iter()
+                .filter(|p| p.reporting_balance.is_none())
+                .map(|p| p.currency.clone())
+                .collect();
+            unconverted.sort();
+            unconverted.dedup();
+
+            Ok(Ledger {
+                entries,
+                positions,
+                reporting_currency,
+                cash_position,
+                unconverted,
+            })
+        }
+    }
+
+    /// A daily total that becomes one journal
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "journal_sources")]
+    pub struct JournalSource {
+        pub day: NaiveDate,
+        pub kind: String,
+        pub amount: Decimal,
+        pub currency: String,
+        /// `amount` in the reporting currency. Empty without an exchange rate
+        pub reporting_amount: Option<Decimal>,
+        pub debit_account: Option<String>,
+        pub credit_account: Option<String>,
+    }
+
+    #[derive(Serialize, Debug, Clone)]
+    pub struct JournalLine {
+        pub account: String,
+        pub debit: Decimal,
+        pub credit: Decimal,
+    }
+
+    #[derive(Serialize, Debug, Clone)]
+    pub struct Journal {
+        pub date: NaiveDate,
+        pub reference: String,
+        pub memo: String,
+        /// The reporting currency of the lines
+        pub currency: String,
+        pub lines: Vec<JournalLine>,
+    }
+
+    impl Journal {
+        /// Build the journals for a date range from reconciled collections, PDQ commission,
+        /// M-Pesa charges, M-Pesa withdrawals to the bank and transfers, in `currency`. A total
+        /// without a rate to `currency` on its day refuses the whole range, so no journal is
+        /// posted at a made-up rate
+        pub async fn get_journals(
+            client: &Client,
+            chart: &ChartOfAccounts,
+            range: &DateRange,
+            currency: &str,
+        ) -> Result<Vec<Journal>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_journal_sources.sql");
+
+            let sources = client
+                .query(stmt, &[&range.from, &range.to, &currency])
+                .await?
+                .into_iter()
+                .map(|row| JournalSource::from_row_ref(&row).unwrap())
+                .collect::<Vec<JournalSource>>();
+
+            let mut res = Vec::with_capacity(sources.len());
+            for source in sources.iter() {
+                let amount = source.reporting_amount.ok_or_else(|| {
+                    MyError::BadRequest(format!(
+                        "No {} to {} rate on {} for the {} journal. Upload one to /fx/rates/update",
+                        source.currency, currency, source.day, source.kind
+                    ))
+                })?;
+                res.extend(Journal::from_source(chart, source, amount, currency));
+            }
+            Ok(res)
+        }
+
+        fn from_source(
+            chart: &ChartOfAccounts,
+            source: &JournalSource,
+            amount: Decimal,
+            currency: &str,
+        ) -> Option<Journal> {
+            let (debit_account, credit_account, prefix, memo) = match source.kind.as_str() {
+                // The receiving account is debited in the books
+                "transfer" => (
+                    chart.account_for(source.credit_account.as_deref()?)?,
+                    chart.account_for(source.debit_account.as_deref()?)?,
+                    "TRF",
+                    "Transfer between accounts",
+                ),
+                "mpesa_collections" => (
+                    &chart.mpesa,
+                    &chart.patient_receipts,
+                    "MPCOL",
+                    "Reconciled M-Pesa collections",
+                ),
+                "airtel_collections" => (
+                    &chart.airtel,
+                    &chart.patient_receipts,
+                    "ATCOL",
+                    "Reconciled Airtel Money collections",
+                ),
+                "card_collections" => (
+                    &chart.pdq_clearing,
+                    &chart.patient_receipts,
+                    "CDCOL",
+                    "Card collections",
+                ),
+                "pdq_commission" => (
+                    &chart.bank_charges,
+                    &chart.pdq_clearing,
+                    "PDQCM",
+                    "PDQ commission",
+                ),
+                "mpesa_charges" => (
+                    &chart.bank_charges,
+                    &chart.mpesa,
+                    "MPCHG",
+                    "M-Pesa transaction charges",
+                ),
+                "mpesa_transfers" => (
+                    &chart.transfers_in_transit,
+                    &chart.mpesa,
+                    "MPTRF",
+                    "M-Pesa withdrawals to bank",
+                ),
+                _ => return None,
+            };
+
+            // Totals in another currency are told apart from the day's reporting currency ones
+            let foreign = if source.currency == currency {
+                ""
+            } else {
+                source.currency.as_str()
+            };
+
+            Some(Journal {
+                date: source.day,
+                reference: match (&source.debit_account, &source.credit_account) {
+                    (Some(from), Some(to)) => format!(
+                        "{}{}{}{}{}",
+                        prefix,
+                        source.day.format("%Y%m%d"),
+                        from.to_uppercase(),
+                        to.to_uppercase(),
+                        foreign
+                    ),
+                    _ => format!("{}{}{}", prefix, source.day.format("%Y%m%d"), foreign),
+                },
+                memo: match (&source.debit_account, &source.credit_account) {
+                    (Some(from), Some(to)) => format!("{} {} to {}", memo, from, to),
+                    _ => memo.to_string(),
+                },
+                currency: currency.to_string(),
+                lines: vec![
+                    JournalLine {
+                        account: debit_account.clone(),
+                        debit: amount,
+                        credit: Decimal::ZERO,
+                    },
+                    JournalLine {
+                        account: credit_account.clone(),
+                        debit: Decimal::ZERO,
+                        credit: amount,
+                    },
+                ],
+            })
+        }
+    }
+
+    /// Query for /journals. Without a format the journals are returned as JSON
+    #[derive(Deserialize, Debug)]
+    pub struct JournalQuery {
+        pub from: NaiveDate,
+        pub to: NaiveDate,
+        pub format: Option<JournalFormat>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum JournalFormat {
+        Csv,
+        Iif,
+        Sage,
+    }
+
+    /// One side of a possible transfer, read from the ledger
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone)]
+    #[pg_mapper(table = "production.ledger_entries")]
+    pub struct TransferCandidate {
+        pub side: String,
+        pub account: String,
+        pub row_id: Option<i64>,
+        pub date: DateTime<Utc>,
+        pub amount: Decimal,
+        pub currency: String,
+        pub reference: Option<String>,
+        pub narration: Option<String>,
+        /// The PDQ rows a settlement total is made of
+        pub settled_row_ids: Option<Vec<i64>>,
+    }
+
+    // Words of a narration or reference that mark money moved between the hospital's own
+    // accounts
+    const TRANSFER_HINTS: [&str; 17] = [
+        "transfer",
+        "trf",
+        "tfr",
+        "rtgs",
+        "eft",
+        "pesalink",
+        "swift",
+        "sweep",
+        "settlement",
+        "b2b",
+        "business to business",
+        "funds",
+        "withdraw",
+        "withdrawal",
+        "float",
+        "interbank",
+        "own account",
+    ];
+    // Bank rows naming the wallet or card terminal the money came from or went to
+    const BANK_HINTS: [&str; 6] = ["mpesa", "m pesa", "safaricom", "pdq", "pos", "merchant"];
+    // M-Pesa rows naming the bank the money came from or went to
+    const WALLET_HINTS: [&str; 1] = ["bank"];
+    // M-Pesa credits paid in by customers
+    const CUSTOMER_RECEIPT_HINTS: [&str; 6] = [
+        "pay bill from",
+        "pay bill online",
+        "customer payment",
+        "customer transfer",
+        "buy goods from",
+        "funds received from",
+    ];
+
+    impl TransferCandidate {
+        /// Whether the row reads as one side of a bank to bank or bank to till movement. PDQ
+        /// settlements always do. Airtel Money, insurer payments and customer receipts on a
+        /// paybill or till never do
+        fn looks_like_transfer(&self) -> bool {
+            if self.account == "pdq" {
+                return true;
+            }
+            let extra_hints: &[&str] = match BankAccount::from_name(&self.account) {
+                None | Some(BankAccount::Airtel) => return false,
+                Some(BankAccount::Mpesa) => &WALLET_HINTS,
+                Some(_) => &BANK_HINTS,
+            };
+            // Words padded with spaces, so "eft" does not match "left"
+            let text = format!(
+                "{} {}",
+                self.reference.as_deref().unwrap_or_default(),
+                self.narration.as_deref().unwrap_or_default()
+            )
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    ' '
+                }
+            })
+            .collect::<String>()
+            .split_whitespace()
+            .fold(String::from(" "), |text, word| text + word + " ");
+            let mentions = |hint: &&str| text.contains(&format!(" {} ", hint));
+            if self.account == BankAccount::Mpesa.name()
+                && self.side == "credit"
+                && CUSTOMER_RECEIPT_HINTS.iter().any(mentions)
+            {
+                return false;
+            }
+            TRANSFER_HINTS.iter().chain(extra_hints).any(mentions)
+        }
+    }
+
+    /// A debit in one account paired with the matching credit in another
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.transfers")]
+    pub struct Transfer {
+        pub debit_account: String,
+        pub debit_row_id: Option<i64>,
+        /// The PDQ rows settled when the debit is a daily PDQ total
+        pub settled_row_ids: Option<Vec<i64>>,
+        pub debit_date: DateTime<Utc>,
+        pub credit_account: String,
+        pub credit_row_id: i64,
+        pub credit_date: DateTime<Utc>,
+        pub amount: Decimal,
+        /// Both sides are in this currency
+        pub currency: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct TransferQuery {
+        pub from: NaiveDate,
+        pub to: NaiveDate,
+        /// How many days apart the two sides may be. Defaults to 3
+        pub window_days: Option<i64>,
+    }
+
+    impl Transfer {
+        pub async fn get_transfers(
+            client: &Client,
+            range: &DateRange,
+        ) -> Result<Vec<Transfer>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_transfers.sql");
+
+            let res = client
+                .query(stmt, &[&range.from, &range.to])
+                .await?
+                .into_iter()
+                .map(|row| Transfer::from_row_ref(&row).unwrap())
+                .collect::<Vec<Transfer>>();
+            Ok(res)
+        }
+
+        /// Pair unmatched debits and credits in the range and store the pairs found. Returns only
+        /// the pairs stored by this run. The range is in days of `zone`
+        pub async fn detect(
+            client: &mut Client,
+            query: &TransferQuery,
+            zone: Tz,
+        ) -> Result<Vec<Transfer>, MyError> {
+            let window = Duration::days(query.window_days.unwrap_or(3));
+
+            // Look past both ends of the range so pairs straddling the edges are found
+            let from = query.from - window;
+            let to = query.to + window;
+
+            let stmt = include_str!("../sql/user_actions/get_transfer_candidates.sql");
+
+            let candidates = client
+                .query(stmt, &[&from, &to])
+                .await?
+                .into_iter()
+                .map(|row| TransferCandidate::from_row_ref(&row).unwrap())
+                .collect::<Vec<TransferCandidate>>();
+
+            let transfers = Transfer::pair(candidates, window)
+                .into_iter()
+                .filter(|t| {
+                    let day = t.debit_date.with_timezone(&zone).date().naive_local();
+                    day >= query.from && day <= query.to
+                })
+                .collect::<Vec<Transfer>>();
+
+            let tx = client.transaction().await?;
+
+            let stmt = tx
+                .prepare(include_str!("../sql/user_actions/insert_transfer.sql"))
+                .await?;
+
+            // Pairs stored by an earlier run come back empty
+            let mut inserted = Vec::with_capacity(transfers.len());
+            for t in transfers.iter() {
+                let row = tx
+                    .query_opt(
+                        &stmt,
+                        &[
+                            &t.debit_account,
+                            &t.debit_row_id,
+                            &t.settled_row_ids,
+                            &t.debit_date,
+                            &t.credit_account,
+                            &t.credit_row_id,
+                            &t.credit_date,
+                            &t.amount,
+                            &t.currency,
+                        ],
+                    )
+                    .await?;
+                if let Some(row) = row {
+                    inserted.push(Transfer::from_row_ref(&row)?);
+                }
+            }
+
+            tx.commit().await?;
+
+            Ok(inserted)
+        }
+
+        // A debit is paired with a credit of the same amount and currency in another account
+        // that arrives at most the window later, when each is the other's only such match.
+        // Rows with more than one possible match are left for a person to pair
+        fn pair(candidates: Vec<TransferCandidate>, window: Duration) -> Vec<Transfer> {
+            let (debits, credits): (Vec<TransferCandidate>, Vec<TransferCandidate>) = candidates
+                .into_iter()
+                .filter(TransferCandidate::looks_like_transfer)
+                .partition(|c| c.side == "debit");
+
+            let mut credits_by_amount: HashMap<(&str, Decimal), Vec<usize>> = HashMap::new();
+            for (index, credit) in credits.iter().enumerate() {
+                credits_by_amount
+                    .entry((credit.currency.as_str(), credit.amount))
+                    .or_default()
+                    .push(index);
+            }
+
+            let matches: Vec<Vec<usize>> = debits
+                .iter()
+                .map(|debit| {
+                    credits_by_amount
+                        .get(&(debit.currency.as_str(), debit.amount))
+                        .into_iter()
+                        .flatten()
+                        .copied()
+                        .filter(|index| {
+                            let credit = &credits[*index];
+                            credit.account != debit.account
+                                && credit.date >= debit.date
+                                && credit.date - debit.date <= window
+                        })
+                        .collect()
+                })
+                .collect();
+
+            let mut debits_per_credit = vec![0; credits.len()];
+            for index in matches.iter().flatten() {
+                debits_per_credit[*index] += 1;
+            }
+
+            let mut transfers = Vec::new();
+            for (debit, matched) in debits.iter().zip(&matches) {
+                if let [index] = matched[..] {
+                    if debits_per_credit[index] != 1 {
+                        continue;
+                    }
+                    let credit = &credits[index];
+                    transfers.push(Transfer {
+                        debit_account: debit.account.clone(),
+                        debit_row_id: debit.row_id,
+                        settled_row_ids: debit.settled_row_ids.clone(),
+                        debit_date: debit.date,
+                        credit_account: credit.account.clone(),
+                        credit_row_id: credit.row_id.unwrap_or_default(),
+                        credit_date: credit.date,
+                        amount: debit.amount,
+                        currency: debit.currency.clone(),
+                    });
+                }
+            }
+
+            transfers
+        }
+    }
+
+    /// A rule that assigns a category to statement rows. Lower priorities are tried first
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone)]
+    #[pg_mapper(table = "production.categorization_rules")]
+    pub struct CategorizationRule {
+        pub id: i64,
+        pub category: String,
+        pub priority: i32,
+        /// Only apply to this ledger account, e.g. absa
+        pub account: Option<String>,
+        /// keyword or regex
+        pub match_type: String,
+        /// Comma separated keywords or a regular expression, matched case-insensitively
+        pub pattern: Option<String>,
+        /// debit or credit
+        pub direction: Option<String>,
+        pub min_amount: Option<Decimal>,
+        pub max_amount: Option<Decimal>,
+        pub active: bool,
+    }
+
+    /// Body of the create and update rule requests
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct CategorizationRuleInsert {
+        pub category: String,
+        pub priority: Option<i32>,
+        pub account: Option<String>,
+        pub match_type: Option<String>,
+        pub pattern: Option<String>,
+        pub direction: Option<String>,
+        pub min_amount: Option<Decimal>,
+        pub max_amount: Option<Decimal>,
+        pub active: Option<bool>,
+    }
+
+    impl CategorizationRuleInsert {
+        fn validate(&self) -> Result<(), MyError> {
+            match self.match_type.as_deref() {
+                None | Some("keyword") => {}
+                Some("regex") => {
+                    let pattern = self.pattern.as_deref().unwrap_or_default();
+                    Regex::new(pattern).map_err(|e| MyError::BadRequest(e.to_string()))?;
+                }
+                Some(other) => {
+                    return Err(MyError::BadRequest(format!("Unknown match type {}", other)))
+                }
+            }
+            match self.direction.as_deref() {
+                None | Some("debit") | Some("credit") => Ok(()),
+                Some(other) => Err(MyError::BadRequest(format!("Unknown direction {}", other))),
+            }
+        }
+    }
+
+    impl CategorizationRule {
+        pub async fn get_rules(client: &Client) -> Result<Vec<CategorizationRule>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_categorization_rules.sql");
+
+            let res = client
+                .query(stmt, &[])
+                .await?
+                .into_iter()
+                .map(|row| CategorizationRule::from_row_ref(&row).unwrap())
+                .collect::<Vec<CategorizationRule>>();
+            Ok(res)
+        }
+
+        pub async fn create(
+            client: &Client,
+            rule: CategorizationRuleInsert,
+        ) -> Result<CategorizationRule, MyError> {
+            rule.validate()?;
+
+            let stmt = include_str!("../sql/user_actions/insert_categorization_rule.sql");
+
+            let row = client
+                .query_one(
+                    stmt,
+                    &[
+                        &rule.category,
+                        &rule.priority.unwrap_or(100),
+                        &rule.account,
+                        &rule.match_type.as_deref().unwrap_or("keyword"),
+                        &rule.pattern,
+                        &rule.direction,
+                        &rule.min_amount,
+                        &rule.max_amount,
+                        &rule.active.unwrap_or(true),
+                    ],
+                )
+                .await?;
+            Ok(CategorizationRule::from_row_ref(&row)?)
+        }
+
+        pub async fn update(
+            client: &Client,
+            id: i64,
+            rule: CategorizationRuleInsert,
+        ) -> Result<CategorizationRule, MyError> {
+            rule.validate()?;
+
+            let stmt = include_str!("../sql/user_actions/update_categorization_rule.sql");
+
+            let row = client
+                .query_opt(
+                    stmt,
+                    &[
+                        &id,
+                        &rule.category,
+                        &rule.priority.unwrap_or(100),
+                        &rule.account,
+                        &rule.match_type.as_deref().unwrap_or("keyword"),
+                        &rule.pattern,
+                        &rule.direction,
+                        &rule.min_amount,
+                        &rule.max_amount,
+                        &rule.active.unwrap_or(true),
+                    ],
+                )
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(CategorizationRule::from_row_ref(&row)?)
+        }
+
+        pub async fn delete(client: &Client, id: i64) -> Result<u64, MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_categorization_rule.sql");
+
+            match client.execute(stmt, &[&id]).await? {
+                0 => Err(MyError::NotFound),
+                deleted => Ok(deleted),
+            }
+        }
+
+        fn matches(&self, regex: Option<&Regex>, entry: &CategorizableEntry) -> bool {
+            if let Some(account) = &self.account {
+                if account != &entry.account {
+                    return false;
+                }
+            }
+
+            let (direction, amount) = if entry.debit > Decimal::ZERO {
+                ("debit", entry.debit)
+            } else {
+                ("credit", entry.credit)
+            };
+            if matches!(self.direction.as_deref(), Some(d) if d != direction)
+                || matches!(self.min_amount, Some(min) if amount < min)
+                || matches!(self.max_amount, Some(max) if amount > max)
+            {
+                return false;
+            }
+
+            let text = format!(
+                "{} {}",
+                entry.reference.as_deref().unwrap_or_default(),
+                entry.narration.as_deref().unwrap_or_default()
+            );
+            match (regex, &self.pattern) {
+                (Some(regex), _) => regex.is_match(&text),
+                (None, Some(pattern)) => {
+                    let text = text.to_lowercase();
+                    pattern
+                        .split(',')
+                        .map(|keyword| keyword.trim().to_lowercase())
+                        .filter(|keyword| !keyword.is_empty())
+                        .any(|keyword| text.contains(&keyword))
+                }
+                (None, None) => true,
+            }
+        }
+    }
+
+    /// The active rules, in priority order, with their patterns compiled
+    pub struct Categorizer {
+        rules: Vec<(CategorizationRule, Option<Regex>)>,
+    }
+
+    impl Categorizer {
+        pub async fn load(client: &Client) -> Result<Categorizer, MyError> {
+            let rules = CategorizationRule::get_rules(client)
+                .await?
+                .into_iter()
+                .filter(|rule| rule.active)
+                .filter_map(|rule| {
+                    if rule.match_type != "regex" {
+                        return Some((rule, None));
+                    }
+                    // Case-insensitive like the keyword rules
+                    let pattern = format!("(?i){}", rule.pattern.as_deref().unwrap_or_default());
+                    match Regex::new(&pattern) {
+                        Ok(regex) => Some((rule, Some(regex))),
+                        Err(e) => {
+                            tracing::error!("Skipping categorization rule {}: {}", rule.id, e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            Ok(Categorizer { rules })
+        }
+
+        /// Categorize the rows of an account the rules have not seen yet. Run after every upload
+        pub async fn categorize_new_rows(
+            client: &mut Client,
+            account: BankAccount,
+        ) -> Result<usize, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_new_entries_to_categorize.sql");
+
+            let entries = client
+                .query(stmt, &[&account.name()])
+                .await?
+                .into_iter()
+                .map(|row| CategorizableEntry::from_row_ref(&row).unwrap())
+                .collect::<Vec<CategorizableEntry>>();
+            if entries.is_empty() {
+                return Ok(0);
+            }
+
+            let categorizer = Categorizer::load(client).await?;
+            categorizer.apply(client, &entries).await
+        }
+
+        pub fn categorize(&self, entry: &CategorizableEntry) -> Option<&CategorizationRule> {
+            self.rules
+                .iter()
+                .find(|(rule, regex)| rule.matches(regex.as_ref(), entry))
+                .map(|(rule, _)| rule)
+        }
+
+        /// Categorize the ledger rows of an account (or all accounts) in a date range.
+        /// Without `overwrite` only rows that have no category yet are touched.
+        /// Returns the number of rows that got a category
+        pub async fn run(
+            &self,
+            client: &mut Client,
+            account: Option<&str>,
+            from: Option<NaiveDate>,
+            to: Option<NaiveDate>,
+            overwrite: bool,
+        ) -> Result<usize, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_entries_to_categorize.sql");
+
+            let entries = client
+                .query(stmt, &[&account, &from, &to, &overwrite])
+                .await?
+                .into_iter()
+                .map(|row| CategorizableEntry::from_row_ref(&row).unwrap())
+                .collect::<Vec<CategorizableEntry>>();
+
+            self.apply(client, &entries).await
+        }
+
+        // Store the category of every entry. Entries no rule matches are stored without one,
+        // which also clears a category a rule no longer gives them
+        async fn apply(
+            &self,
+            client: &mut Client,
+            entries: &[CategorizableEntry],
+        ) -> Result<usize, MyError> {
+            let tx = client.transaction().await?;
+
+            let upsert = tx
+                .prepare(include_str!(
+                    "../sql/user_actions/upsert_transaction_category.sql"
+                ))
+                .await?;
+
+            let mut categorized = 0;
+            for entry in entries.iter() {
+                let rule = self.categorize(entry);
+                tx.execute(
+                    &upsert,
+                    &[
+                        &entry.account,
+                        &entry.source_row_id,
+                        &rule.map(|rule| &rule.category),
+                        &rule.map(|rule| rule.id),
+                    ],
+                )
+                .await?;
+                if rule.is_some() {
+                    categorized += 1;
+                }
+            }
+
+            tx.commit().await?;
+
+            Ok(categorized)
+        }
+    }
+
+    /// A ledger row as seen by the categorization rules
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.ledger_entries")]
+    pub struct CategorizableEntry {
+        pub account: String,
+        pub source_row_id: i64,
+        pub reference: Option<String>,
+        pub narration: Option<String>,
+        pub debit: Decimal,
+        pub credit: Decimal,
+    }
+
+    /// Query for re-running the rules. Without dates the whole ledger is categorized again
+    #[derive(Deserialize, Debug)]
+    pub struct CategorizationRun {
+        pub account: Option<String>,
+        pub from: Option<NaiveDate>,
+        pub to: Option<NaiveDate>,
+    }
+
+    /// A staff decision on matching a collection receipt to a statement row
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.reconciliation_decisions")]
+    pub struct ReconciliationDecision {
+        pub id: i64,
+        pub billing_number: String,
+        pub statement_account: String,
+        pub statement_reference: String,
+        /// confirm, reject or manual
+        pub decision: String,
+        pub comment: Option<String>,
+        pub decided_by: String,
+        pub decided_at: DateTime<Utc>,
+        pub reversed_by: Option<String>,
+        pub reversed_at: Option<DateTime<Utc>>,
+        pub reversal_comment: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct ReconciliationDecisionInsert {
+        pub billing_number: String,
+        /// Defaults to mpesa
+        pub statement_account: Option<String>,
+        pub statement_reference: String,
+        pub decision: String,
+        pub comment: Option<String>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct ReconciliationDecisionReversal {
+        pub comment: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ReconciliationDecisionFilter {
+        pub billing_number: Option<String>,
+        pub statement_reference: Option<String>,
+        pub include_reversed: Option<bool>,
+    }
+
+    impl ReconciliationDecision {
+        /// Decisions on the given units' receipts, on every receipt for None
+        pub async fn get_decisions(
+            client: &Client,
+            filter: &ReconciliationDecisionFilter,
+            units: Option<&[String]>,
+        ) -> Result<Vec<ReconciliationDecision>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_reconciliation_decisions.sql");
+
+            let res = client
+                .query(
+                    stmt,
+                    &[
+                        &filter.billing_number,
+                        &filter.statement_reference,
+                        &filter.include_reversed.unwrap_or(false),
+                        &units,
+                    ],
+                )
+                .await?
+                .into_iter()
+                .map(|row| ReconciliationDecision::from_row_ref(&row).unwrap())
+                .collect::<Vec<ReconciliationDecision>>();
+            Ok(res)
+        }
+
+        pub async fn get_decision(
+            client: &Client,
+            id: i64,
+        ) -> Result<ReconciliationDecision, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_reconciliation_decision.sql");
+
+            let row = client
+                .query_opt(stmt, &[&id])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(ReconciliationDecision::from_row_ref(&row)?)
+        }
+
+        /// Record a decision. Only one decision per pair can be active, so an existing one
+        /// has to be reversed first
+        pub async fn create(
+            client: &mut Client,
+            decision: ReconciliationDecisionInsert,
+            decided_by: &str,
+        ) -> Result<ReconciliationDecision, MyError> {
+            if !["confirm", "reject", "manual"].contains(&decision.decision.as_str()) {
+                return Err(MyError::BadRequest(format!(
+                    "Unknown decision {}",
+                    decision.decision
+                )));
+            }
+
+            let account = decision.statement_account.as_deref().unwrap_or("mpesa");
+            let tx = client.transaction().await?;
+            PeriodLock::ensure_bill_unlocked_in(&*tx, account, &decision.billing_number).await?;
+
+            let stmt = include_str!("../sql/user_actions/insert_reconciliation_decision.sql");
+
+            let row = tx
+                .query_one(
+                    stmt,
+                    &[
+                        &decision.billing_number,
+                        &account,
+                        &decision.statement_reference.trim().to_uppercase(),
+                        &decision.decision,
+                        &decision.comment,
+                        &decided_by,
+                    ],
+                )
+                .await
+                .map_err(|e| match e.code() {
+                    Some(&SqlState::UNIQUE_VIOLATION) => MyError::BadRequest(
+                        "An active decision already exists for this pair. Reverse it first"
+                            .to_string(),
+                    ),
+                    _ => MyError::PGError(e),
+                })?;
+            tx.commit().await?;
+            Ok(ReconciliationDecision::from_row_ref(&row)?)
+        }
+
+        /// Reverse an active decision. The automatic match applies again afterwards
+        pub async fn reverse(
+            client: &mut Client,
+            id: i64,
+            reversal: ReconciliationDecisionReversal,
+            reversed_by: &str,
+        ) -> Result<ReconciliationDecision, MyError> {
+            let decision = ReconciliationDecision::get_decision(client, id).await?;
+
+            let tx = client.transaction().await?;
+            PeriodLock::ensure_bill_unlocked_in(
+                &*tx,
+                &decision.statement_account,
+                &decision.billing_number,
+            )
+            .await?;
+
+            let stmt = include_str!("../sql/user_actions/reverse_reconciliation_decision.sql");
+
+            let row = tx
+                .query_opt(stmt, &[&id, &reversed_by, &reversal.comment])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            tx.commit().await?;
+            Ok(ReconciliationDecision::from_row_ref(&row)?)
+        }
+    }
+
+    /// An item in the exception queue
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.exceptions")]
+    pub struct ExceptionItem {
+        pub id: i64,
+        pub item_key: String,
+        /// unmatched_payment, unmatched_receipt, typo_transaction_code, over_claimed_payment or reversed_transaction
+        pub kind: String,
+        pub day: NaiveDate,
+        pub billing_number: Option<String>,
+        pub statement_reference: Option<String>,
+        pub amount: Option<Decimal>,
+        pub details: Option<String>,
+        /// Empty for items of head office accounts
+        pub unit_name: Option<String>,
+        /// open, investigating or resolved
+        pub status: String,
+        pub assigned_to: Option<String>,
+        pub created_at: DateTime<Utc>,
+        pub updated_at: DateTime<Utc>,
+    }
+
+    /// An exception found by a reconciliation run, before it is queued
+    #[derive(Serialize, Debug)]
+    pub struct ExceptionInsert {
+        pub item_key: String,
+        pub kind: &'static str,
+        pub day: NaiveDate,
+        pub billing_number: Option<String>,
+        pub statement_reference: Option<String>,
+        pub amount: Option<Decimal>,
+        pub details: Option<String>,
+        pub unit_name: Option<String>,
+    }
+
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.exception_notes")]
+    pub struct ExceptionNote {
+        pub id: i64,
+        pub exception_id: i64,
+        pub author: String,
+        pub note: String,
+        pub created_at: DateTime<Utc>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct ExceptionNoteInsert {
+        pub note: String,
+    }
+
+    /// Change the status or the assignee of an item. The change is recorded as a note
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct ExceptionUpdate {
+        pub status: Option<String>,
+        pub assigned_to: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ExceptionFilter {
+        pub status: Option<String>,
+        pub kind: Option<String>,
+        pub assigned_to: Option<String>,
+        pub from: Option<NaiveDate>,
+        pub to: Option<NaiveDate>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ExceptionDetails {
+        pub item: ExceptionItem,
+        pub notes: Vec<ExceptionNote>,
+    }
+
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.mpesa_statement")]
+    pub struct UnmatchedMpesaPayment {
+        pub wallet: String,
+        pub receipt_no: String,
+        pub completion_time: DateTime<Utc>,
+        pub paid_in: Decimal,
+        pub other_party_info: Option<String>,
+        pub unit_name: Option<String>,
+    }
+
+    impl ExceptionItem {
+        /// Items of the given units, of every unit and head office for None
+        pub async fn get_exceptions(
+            client: &Client,
+            filter: &ExceptionFilter,
+            units: Option<&[String]>,
+        ) -> Result<Vec<ExceptionItem>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_exceptions.sql");
+
+            let res = client
+                .query(
+                    stmt,
+                    &[
+                        &filter.status,
+                        &filter.kind,
+                        &filter.assigned_to,
+                        &filter.from,
+                        &filter.to,
+                        &units,
+                    ],
+                )
+                .await?
+                .into_iter()
+                .map(|row| ExceptionItem::from_row_ref(&row).unwrap())
+                .collect::<Vec<ExceptionItem>>();
+            Ok(res)
+        }
+
+        pub async fn get_item(client: &Client, id: i64) -> Result<ExceptionItem, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_exception.sql");
+
+            let row = client
+                .query_opt(stmt, &[&id])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(ExceptionItem::from_row_ref(&row)?)
+        }
+
+        pub async fn get_details(client: &Client, id: i64) -> Result<ExceptionDetails, MyError> {
+            let item = ExceptionItem::get_item(client, id).await?;
+
+            let stmt = include_str!("../sql/user_actions/get_exception_notes.sql");
 
-            let res = client
-                .query(stmt, &[])
+            let notes = client
+                .query(stmt, &[&id])
                 .await?
                 .into_iter()
-                .map(|row| BillDetails::from_row_ref(&row).unwrap())
-                .collect::<Vec<BillDetails>>();
-            Ok(res)
-        }
-    }
+                .map(|row| ExceptionNote::from_row_ref(&row).unwrap())
+                .collect::<Vec<ExceptionNote>>();
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.lab_visits")]
-    pub struct LabVisitsInsert {
-        pub sample_number: String,
-        pub name: String,
-        pub id_passport_no: Option<String>,
-        pub age: f64,
-        pub age_unit: String,
-        pub gender: String,
-        pub phone_number: Option<String>,
-        pub sample_date: String,
-        pub result: String,
-        pub email_address: Option<String>,
-    }
+            Ok(ExceptionDetails { item, notes })
+        }
 
-    impl LabVisitsInsert {
         pub async fn update(
             client: &mut Client,
-            data: Vec<LabVisitsInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
+            id: i64,
+            update: ExceptionUpdate,
+            updated_by: &str,
+        ) -> Result<ExceptionItem, MyError> {
+            if let Some(status) = update.status.as_deref() {
+                if !["open", "investigating", "resolved"].contains(&status) {
+                    return Err(MyError::BadRequest(format!("Unknown status {}", status)));
+                }
+            }
+
             let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_lab_visits.sql");
+            let stmt = include_str!("../sql/user_actions/update_exception.sql");
+
+            let row = tx
+                .query_opt(stmt, &[&id, &update.status, &update.assigned_to])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            let item = ExceptionItem::from_row_ref(&row)?;
+
+            let mut changes = Vec::new();
+            if let Some(status) = &update.status {
+                changes.push(format!("Status set to {}", status));
+            }
+            if let Some(assigned_to) = &update.assigned_to {
+                changes.push(format!("Assigned to {}", assigned_to));
+            }
+            if !changes.is_empty() {
+                let stmt = include_str!("../sql/user_actions/insert_exception_note.sql");
+                tx.execute(stmt, &[&id, &updated_by, &changes.join(". ")])
+                    .await?;
+            }
+
+            tx.commit().await?;
 
-            let statement = tx.prepare(stmt).await?;
+            Ok(item)
+        }
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+        pub async fn add_note(
+            client: &Client,
+            id: i64,
+            note: ExceptionNoteInsert,
+            author: &str,
+        ) -> Result<ExceptionNote, MyError> {
+            let stmt = include_str!("../sql/user_actions/insert_exception_note.sql");
+
+            let row = client
+                .query_one(stmt, &[&id, &author, &note.note])
+                .await
+                .map_err(|e| match e.code() {
+                    Some(&SqlState::FOREIGN_KEY_VIOLATION) => MyError::NotFound,
+                    _ => MyError::PGError(e),
+                })?;
+            Ok(ExceptionNote::from_row_ref(&row)?)
+        }
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-            ];
+        /// Run the M-Pesa reconciliation for every day of the range and queue what needs
+        /// follow-up. Returns the number of new items
+        pub async fn generate(client: &mut Client, range: &DateRange) -> Result<u64, MyError> {
+            let mut found = Vec::new();
+
+            for day in days_between(range.from, range.to) {
+                let reconciled =
+                    MpesaStatement::get_reconciled_statement(client, day, None).await?;
+                found.extend(ExceptionItem::from_reconciliation(day, &reconciled));
+
+                let stmt = include_str!("../sql/user_actions/get_unmatched_mpesa_payments.sql");
+                found.extend(
+                    client
+                        .query(stmt, &[&day])
+                        .await?
+                        .into_iter()
+                        .map(|row| UnmatchedMpesaPayment::from_row_ref(&row).unwrap())
+                        .map(|payment| ExceptionInsert {
+                            item_key: format!("unmatched_payment:{}", payment.receipt_no),
+                            kind: "unmatched_payment",
+                            day,
+                            billing_number: None,
+                            statement_reference: Some(payment.receipt_no),
+                            amount: Some(payment.paid_in),
+                            details: payment.other_party_info.map(|party| {
+                                match payment.wallet.as_str() {
+                                    "airtel" => format!("Paid by {} through Airtel Money", party),
+                                    _ => format!("Paid by {}", party),
+                                }
+                            }),
+                            unit_name: payment.unit_name,
+                        }),
+                );
+
+                let reversals =
+                    MpesaStatement::get_reversals(client, &DateRange { from: day, to: day }, None)
+                        .await?;
+                found.extend(reversals.into_iter().map(|reversal| {
+                    ExceptionInsert {
+                        item_key: format!("reversed_transaction:{}", reversal.receipt_no),
+                        kind: "reversed_transaction",
+                        day,
+                        billing_number: None,
+                        statement_reference: Some(reversal.receipt_no),
+                        amount: Some(reversal.amount),
+                        details: reversal
+                            .linked_transaction_id
+                            .map(|original| format!("Reverses {}", original)),
+                        unit_name: reversal.unit_name,
+                    }
+                }));
+            }
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+            let tx = client.transaction().await?;
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+            let stmt = tx
+                .prepare(include_str!("../sql/user_actions/insert_exception.sql"))
+                .await?;
+
+            let mut inserted = 0;
+            for item in found.iter() {
+                inserted += tx
+                    .execute(
+                        &stmt,
+                        &[
+                            &item.item_key,
+                            &item.kind,
+                            &item.day,
+                            &item.billing_number,
+                            &item.statement_reference,
+                            &item.amount,
+                            &item.details,
+                            &item.unit_name,
+                        ],
+                    )
+                    .await?;
+            }
 
             tx.commit().await?;
 
-            Ok(num_written)
+            Ok(inserted)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<LabVisitsInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
-
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.sample_number);
-                row.push(&m.name);
-                row.push(&m.id_passport_no);
-                row.push(&m.age);
-                row.push(&m.age_unit);
-                row.push(&m.gender);
-                row.push(&m.phone_number);
-                row.push(&m.sample_date);
-                row.push(&m.result);
-                row.push(&m.email_address);
-
-                writer.as_mut().write(&row).await?;
+        // Receipts without a payment, receipts matched despite a typo in the code and payments
+        // claimed by bills for more than was paid in
+        fn from_reconciliation(day: NaiveDate, rows: &[ReconciledMpesa]) -> Vec<ExceptionInsert> {
+            let mut found = Vec::new();
+
+            for row in rows.iter() {
+                if row.match_source == "unmatched" {
+                    found.push(ExceptionInsert {
+                        item_key: format!("unmatched_receipt:{}", row.billing_number),
+                        kind: "unmatched_receipt",
+                        day,
+                        billing_number: Some(row.billing_number.clone()),
+                        statement_reference: row.transaction_code.clone(),
+                        amount: Some(row.mpesa),
+                        details: row.cashier.as_ref().map(|c| format!("Receipted by {}", c)),
+                        unit_name: row.unit_name.clone(),
+                    });
+                } else if row.match_source == "automatic" && row.distance.unwrap_or(0) > 0 {
+                    found.push(ExceptionInsert {
+                        item_key: format!("typo_transaction_code:{}", row.billing_number),
+                        kind: "typo_transaction_code",
+                        day,
+                        billing_number: Some(row.billing_number.clone()),
+                        statement_reference: row.receipt_no.clone(),
+                        amount: Some(row.mpesa),
+                        details: Some(format!(
+                            "Typed {} for {}",
+                            row.transaction_code.as_deref().unwrap_or_default(),
+                            row.receipt_no.as_deref().unwrap_or_default()
+                        )),
+                        unit_name: row.unit_name.clone(),
+                    });
+                }
             }
 
-            writer.finish().await?;
+            // Filed under the unit of the first bill that claims the payment
+            let mut claimed: Vec<(&str, Decimal, Decimal, usize, Option<&String>)> = Vec::new();
+            for row in rows.iter() {
+                if let (Some(receipt_no), Some(paid_in)) = (row.receipt_no.as_deref(), row.paid_in)
+                {
+                    match claimed.iter_mut().find(|c| c.0 == receipt_no) {
+                        Some(claim) => {
+                            claim.1 += row.mpesa;
+                            claim.3 += 1;
+                        }
+                        None => claimed.push((
+                            receipt_no,
+                            row.mpesa,
+                            paid_in,
+                            1,
+                            row.unit_name.as_ref(),
+                        )),
+                    }
+                }
+            }
+            for (receipt_no, total_billed, paid_in, bills, unit_name) in claimed {
+                if total_billed > paid_in {
+                    found.push(ExceptionInsert {
+                        item_key: format!("over_claimed_payment:{}", receipt_no),
+                        kind: "over_claimed_payment",
+                        day,
+                        billing_number: None,
+                        statement_reference: Some(receipt_no.to_string()),
+                        amount: Some(total_billed - paid_in),
+                        details: Some(format!(
+                            "{} bills claim {:.2} against {:.2} paid in",
+                            bills, total_billed, paid_in
+                        )),
+                        unit_name: unit_name.cloned(),
+                    });
+                }
+            }
 
-            Ok(data.len())
+            found
         }
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.lab_visits")]
-    pub struct LabVisits {
-        pub sample_number: String,
-        pub name: String,
-        pub id_passport_no: String,
-        pub age: i32,
-        pub age_unit: String,
-        pub gender: String,
-        pub phone_number: Option<String>,
-        pub sample_date: chrono::NaiveDateTime,
-        pub result: String,
-        pub email_address: Option<String>,
+    /// A collection receipt whose mobile money code shows at least one fraud signal
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.collection_details")]
+    pub struct FraudSignal {
+        pub employee_name: String,
+        pub billing_number: String,
+        pub receipt_date: Option<DateTime<Utc>>,
+        pub transaction_code: String,
+        /// mpesa or airtel
+        pub wallet: String,
+        pub mpesa: Decimal,
+        /// Bills across all days that carry this code
+        pub bills_on_code: i64,
+        /// Payments on the statement under this code that are not fully reversed
+        pub statement_payments: Option<i64>,
+        pub total_billed: Decimal,
+        /// Paid in under this code net of reversals
+        pub paid_in: Option<Decimal>,
+        pub completion_time: Option<DateTime<Utc>>,
+        /// The code is on more bills than the statement has payments for it
+        pub reused_code: bool,
+        /// The bills carrying the code add up to more than was paid in net of reversals
+        pub over_claimed: bool,
+        /// The payment completed after the receipt was issued
+        pub completed_after_receipt: bool,
+        /// The code belongs to a transaction that did not complete
+        pub not_completed: bool,
+    }
+
+    /// Fraud signals of one cashier
+    #[derive(Serialize, Debug)]
+    pub struct EmployeeFraudSignals {
+        pub employee_name: String,
+        pub receipts_flagged: usize,
+        pub reused_codes: usize,
+        pub over_claimed: usize,
+        pub completed_after_receipt: usize,
+        pub not_completed: usize,
+        /// Sum of M-Pesa on the flagged receipts
+        pub amount_flagged: Decimal,
+        pub signals: Vec<FraudSignal>,
     }
 
-    impl LabVisits {
-        /// Get all lab visits
-        pub async fn get_statement(client: &Client) -> Result<Vec<LabVisits>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_lab_visits.sql");
+    impl EmployeeFraudSignals {
+        /// Flagged receipts of the range grouped by cashier, most flagged first
+        pub async fn detect(
+            client: &Client,
+            range: &DateRange,
+            units: Option<&[String]>,
+        ) -> Result<Vec<EmployeeFraudSignals>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_fraud_signals.sql");
 
-            let res = client
-                .query(stmt, &[])
+            let signals = client
+                .query(stmt, &[&range.from, &range.to, &units])
                 .await?
                 .into_iter()
-                .map(|row| LabVisits::from_row_ref(&row).unwrap())
-                .collect::<Vec<LabVisits>>();
-            Ok(res)
+                .map(|row| FraudSignal::from_row_ref(&row).unwrap())
+                .collect::<Vec<FraudSignal>>();
+
+            Ok(EmployeeFraudSignals::aggregate(signals))
+        }
+
+        fn aggregate(signals: Vec<FraudSignal>) -> Vec<EmployeeFraudSignals> {
+            let mut employees: Vec<EmployeeFraudSignals> = Vec::new();
+
+            for signal in signals {
+                let index = match employees
+                    .iter()
+                    .position(|e| e.employee_name == signal.employee_name)
+                {
+                    Some(index) => index,
+                    None => {
+                        employees.push(EmployeeFraudSignals {
+                            employee_name: signal.employee_name.clone(),
+                            receipts_flagged: 0,
+                            reused_codes: 0,
+                            over_claimed: 0,
+                            completed_after_receipt: 0,
+                            not_completed: 0,
+                            amount_flagged: Decimal::ZERO,
+                            signals: Vec::new(),
+                        });
+                        employees.len() - 1
+                    }
+                };
+                let employee = &mut employees[index];
+                employee.receipts_flagged += 1;
+                employee.reused_codes += signal.reused_code as usize;
+                employee.over_claimed += signal.over_claimed as usize;
+                employee.completed_after_receipt += signal.completed_after_receipt as usize;
+                employee.not_completed += signal.not_completed as usize;
+                employee.amount_flagged += signal.mpesa;
+                employee.signals.push(signal);
+            }
+
+            employees.sort_by_key(|e| std::cmp::Reverse(e.receipts_flagged));
+            employees
         }
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "registered_patients")]
-    pub struct RegisteredPatients {
-        pub uhid: String,
-        pub date: chrono::NaiveDateTime,
-        pub patient_name: String,
-        pub age: String,
-        pub gender: String,
-        pub address: Option<String>,
-        pub contact_no: Option<String>,
-    }
-    impl RegisteredPatients {
-        pub async fn get_statement(client: &Client) -> Result<Vec<RegisteredPatients>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_registered_patients.sql");
+    /// Progress of one table pulled from the HIS database
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.sync_watermarks")]
+    pub struct SyncStatus {
+        pub source: String,
+        pub watermark: Option<NaiveDateTime>,
+        pub last_run_at: DateTime<Utc>,
+        pub last_success_at: Option<DateTime<Utc>>,
+        pub rows_last_run: i64,
+        pub last_error: Option<String>,
+    }
+
+    impl SyncStatus {
+        pub async fn get_all(client: &Client) -> Result<Vec<SyncStatus>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_sync_watermarks.sql");
 
             let res = client
                 .query(stmt, &[])
                 .await?
                 .into_iter()
-                .map(|row| RegisteredPatients::from_row_ref(&row).unwrap())
-                .collect::<Vec<RegisteredPatients>>();
+                .map(|row| SyncStatus::from_row_ref(&row).unwrap())
+                .collect::<Vec<SyncStatus>>();
             Ok(res)
         }
+
+        pub async fn watermark(
+            client: &Client,
+            source: &str,
+        ) -> Result<Option<NaiveDateTime>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_sync_watermark.sql");
+
+            Ok(client
+                .query_opt(stmt, &[&source])
+                .await?
+                .and_then(|row| row.get("watermark")))
+        }
+
+        /// Record a run. The watermark only moves when it is given
+        pub async fn record(
+            client: &Client,
+            source: &str,
+            watermark: Option<NaiveDateTime>,
+            rows: i64,
+            error: Option<String>,
+        ) -> Result<SyncStatus, MyError> {
+            SyncStatus::record_in(&***client, source, watermark, rows, error).await
+        }
+
+        /// The same record inside an open transaction, committed with the rows it counts
+        pub async fn record_in<C: GenericClient + Sync>(
+            client: &C,
+            source: &str,
+            watermark: Option<NaiveDateTime>,
+            rows: i64,
+            error: Option<String>,
+        ) -> Result<SyncStatus, MyError> {
+            let stmt = include_str!("../sql/user_actions/record_sync_run.sql");
+
+            let row = client
+                .query_one(stmt, &[&source, &watermark, &rows, &error])
+                .await?;
+            Ok(SyncStatus::from_row_ref(&row)?)
+        }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.mtiba_statement")]
-    pub struct MtibaStatementInsert {
-        transactionstateid: Option<i32>,
-        transactiontypeid: Option<i32>,
-        facilityzohold: String,
-        facilityname: String,
-        fullreferencenumber: String,
-        phonenumber: String,
-        payername: String,
-        sendername: String,
-        medicalprogramname: String,
-        amountfordisplay: Option<f64>,
-        transactiondate: chrono::NaiveDateTime,
-        paymentdate: chrono::NaiveDateTime,
-        transactiontype: String,
-    }
-    impl MtibaStatementInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<MtibaStatementInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_mtiba_statement.sql");
+    /// Kinds of background job the runner knows how to execute
+    pub const JOB_KINDS: [&str; 5] = [
+        "reconciliation",
+        "refresh_materialized_views",
+        "his_sync",
+        "journal_export",
+        "upload",
+    ];
+
+    fn ensure_job_kind(kind: &str) -> Result<(), MyError> {
+        if JOB_KINDS.contains(&kind) {
+            Ok(())
+        } else {
+            Err(MyError::BadRequest(format!(
+                "Unknown job kind {}. Expected one of {}",
+                kind,
+                JOB_KINDS.join(", ")
+            )))
+        }
+    }
 
-            let statement = tx.prepare(stmt).await?;
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.jobs")]
+    pub struct Job {
+        pub id: i64,
+        pub kind: String,
+        pub payload: serde_json::Value,
+        /// queued, running, succeeded or failed
+        pub status: String,
+        pub attempts: i32,
+        pub max_attempts: i32,
+        pub run_at: DateTime<Utc>,
+        pub locked_at: Option<DateTime<Utc>>,
+        pub locked_by: Option<String>,
+        pub last_error: Option<String>,
+        pub result: Option<serde_json::Value>,
+        pub created_at: DateTime<Utc>,
+        pub finished_at: Option<DateTime<Utc>>,
+        /// Rows of an upload read so far, including those that failed
+        pub rows_processed: i64,
+        pub rows_failed: i64,
+        /// The first rows that could not be read, as UploadRowError
+        pub errors: serde_json::Value,
+        /// None for jobs enqueued by a schedule
+        pub requested_by: Option<String>,
+    }
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct JobInsert {
+        pub kind: String,
+        #[serde(default)]
+        pub payload: serde_json::Value,
+        /// Defaults to now
+        pub run_at: Option<DateTime<Utc>>,
+        /// Defaults to 5
+        pub max_attempts: Option<i32>,
+    }
 
-            let types = &[
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-            ];
+    #[derive(Deserialize, Debug)]
+    pub struct JobFilter {
+        pub status: Option<String>,
+        pub kind: Option<String>,
+    }
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+    impl Job {
+        pub async fn enqueue(
+            client: &Client,
+            job: JobInsert,
+            requested_by: Option<&str>,
+        ) -> Result<Job, MyError> {
+            ensure_job_kind(&job.kind)?;
+            if matches!(job.max_attempts, Some(attempts) if attempts < 1) {
+                return Err(MyError::BadRequest(
+                    "max_attempts must be at least 1".to_string(),
+                ));
+            }
+            let payload = match job.payload {
+                serde_json::Value::Null => serde_json::json!({}),
+                payload => payload,
+            };
+
+            let stmt = include_str!("../sql/user_actions/insert_job.sql");
+
+            let row = client
+                .query_one(
+                    stmt,
+                    &[
+                        &job.kind,
+                        &payload,
+                        &job.run_at,
+                        &job.max_attempts,
+                        &requested_by,
+                    ],
+                )
+                .await?;
+            Ok(Job::from_row_ref(&row)?)
+        }
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+        /// Jobs requested by `requested_by`, every job for None
+        pub async fn get_jobs(
+            client: &Client,
+            filter: &JobFilter,
+            requested_by: Option<&str>,
+        ) -> Result<Vec<Job>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_jobs.sql");
 
-            tx.commit().await?;
+            let res = client
+                .query(stmt, &[&filter.status, &filter.kind, &requested_by])
+                .await?
+                .into_iter()
+                .map(|row| Job::from_row_ref(&row).unwrap())
+                .collect::<Vec<Job>>();
+            Ok(res)
+        }
+
+        pub async fn get_job(client: &Client, id: i64) -> Result<Job, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_job.sql");
 
-            Ok(num_written)
+            let row = client
+                .query_opt(stmt, &[&id])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(Job::from_row_ref(&row)?)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<MtibaStatementInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+        /// Queue a failed job again with a fresh set of attempts
+        pub async fn retry(client: &Client, id: i64) -> Result<Job, MyError> {
+            let stmt = include_str!("../sql/user_actions/retry_job.sql");
+
+            match client.query_opt(stmt, &[&id]).await? {
+                Some(row) => Ok(Job::from_row_ref(&row)?),
+                None => {
+                    let job = Job::get_job(client, id).await?;
+                    Err(MyError::BadRequest(format!(
+                        "Job {} is {}. Only failed jobs can be retried",
+                        job.id, job.status
+                    )))
+                }
+            }
+        }
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.transactionstateid);
-                row.push(&m.transactiontypeid);
-                row.push(&m.facilityzohold);
-                row.push(&m.facilityname);
-                row.push(&m.fullreferencenumber);
-                row.push(&m.phonenumber);
-                row.push(&m.payername);
-                row.push(&m.sendername);
-                row.push(&m.medicalprogramname);
-                row.push(&m.amountfordisplay);
-                row.push(&m.transactiondate);
-                row.push(&m.paymentdate);
-                row.push(&m.transactiontype);
+        /// Take the next due job for this worker
+        pub async fn claim(client: &Client, worker: &str) -> Result<Option<Job>, MyError> {
+            let stmt = include_str!("../sql/user_actions/claim_job.sql");
 
-                writer.as_mut().write(&row).await?;
+            match client.query_opt(stmt, &[&worker]).await? {
+                Some(row) => Ok(Some(Job::from_row_ref(&row)?)),
+                None => Ok(None),
             }
+        }
 
-            writer.finish().await?;
+        /// Keep the job from being taken for a stopped worker's. False once another worker
+        /// has taken it over
+        pub async fn renew_lock(client: &Client, id: i64, worker: &str) -> Result<bool, MyError> {
+            let stmt = include_str!("../sql/user_actions/renew_job_lock.sql");
 
-            Ok(data.len())
+            Ok(client.execute(stmt, &[&id, &worker]).await? > 0)
         }
-    }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.mtiba_statement")]
-    pub struct MtibaStatement {
-        transactionstateid: Option<i32>,
-        transactiontypeid: Option<i32>,
-        facilityzohold: String,
-        facilityname: String,
-        fullreferencenumber: String,
-        phonenumber: String,
-        payername: String,
-        sendername: String,
-        medicalprogramname: String,
-        amountfordisplay: f64,
-        transactiondate: chrono::NaiveDateTime,
-        paymentdate: chrono::NaiveDateTime,
-        transactiontype: String,
-    }
-    impl MtibaStatement {
-        pub async fn get_statement(client: &Client) -> Result<Vec<MtibaStatement>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_mtiba_statement.sql");
+        /// False when the worker no longer holds the job
+        pub async fn complete(
+            client: &Client,
+            id: i64,
+            worker: &str,
+            result: &serde_json::Value,
+        ) -> Result<bool, MyError> {
+            let stmt = include_str!("../sql/user_actions/complete_job.sql");
 
-            let res = client
-                .query(stmt, &[])
+            Ok(client.execute(stmt, &[&id, result, &worker]).await? > 0)
+        }
+
+        /// Requeue the job after `retry_in_seconds`, or fail it for good when that is None.
+        /// False when the worker no longer holds the job
+        pub async fn fail(
+            client: &Client,
+            id: i64,
+            worker: &str,
+            error: &str,
+            retry_in_seconds: Option<f64>,
+        ) -> Result<bool, MyError> {
+            let stmt = include_str!("../sql/user_actions/fail_job.sql");
+
+            Ok(client
+                .execute(stmt, &[&id, &error, &retry_in_seconds, &worker])
                 .await?
-                .into_iter()
-                .map(|row| MtibaStatement::from_row_ref(&row).unwrap())
-                .collect::<Vec<MtibaStatement>>();
-            Ok(res)
+                > 0)
+        }
+
+        /// Record how far an upload job has got
+        pub async fn progress(
+            client: &Client,
+            id: i64,
+            rows_processed: i64,
+            rows_failed: i64,
+            errors: &[UploadRowError],
+        ) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/update_job_progress.sql");
+
+            client
+                .execute(
+                    stmt,
+                    &[
+                        &id,
+                        &rows_processed,
+                        &rows_failed,
+                        &serde_json::to_value(errors)?,
+                    ],
+                )
+                .await?;
+            Ok(())
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.absa_statement")]
-    pub struct ABSAInsert {
-        pub transaction_date: String,
-        pub value_date: String,
-        pub description: String,
-        pub user_reference_number: Option<String>,
-        pub cheque_number: Option<f64>,
-        pub debit_amount: Option<f64>,
-        pub credit_amount: Option<f64>,
-        pub running_balance: Option<f64>,
+
+    /// A file accepted by /uploads/{source}. Its rows are kept as sent until the upload job
+    /// finishes with them
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.uploads")]
+    pub struct Upload {
+        pub id: i64,
+        pub source: String,
+        pub created_at: DateTime<Utc>,
+        /// When the stored rows were dropped
+        pub purged_at: Option<DateTime<Utc>>,
     }
-    impl ABSAInsert {
-        pub async fn update(client: &mut Client, data: Vec<ABSAInsert>) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_absa_statement.sql");
+    /// One stored row of an upload. Rows are numbered from 1
+    #[derive(PostgresMapper)]
+    #[pg_mapper(table = "production.upload_rows")]
+    pub struct UploadRow {
+        pub upload_id: i64,
+        pub row_no: i32,
+        pub row: serde_json::Value,
+    }
 
-            let statement = tx.prepare(stmt).await?;
+    impl CopyRow for UploadRow {
+        const STATEMENT: &'static str =
+            "COPY production.upload_rows (upload_id, row_no, row) FROM STDIN BINARY";
+        #[cfg(test)]
+        const TABLE: &'static str = "production.upload_rows";
+        #[cfg(test)]
+        const COLUMNS: &'static [&'static str] = &["upload_id", "row_no", "row"];
+        const TYPES: &'static [Type] = &[Type::INT8, Type::INT4, Type::JSONB];
+
+        fn copy_row<'a>(&'a self, row: &mut Vec<&'a (dyn ToSql + Sync)>) {
+            row.push(&self.upload_id);
+            row.push(&self.row_no);
+            row.push(&self.row);
+        }
+    }
 
-            // Create a sink that will take the vec of ABSA bank transactions
-            let sink = tx.copy_in(&statement).await?;
+    impl Upload {
+        /// Store the rows as they are read from the body, so the body is never held whole.
+        /// Nothing is kept if the body cannot be read to its end
+        pub async fn store(
+            client: &mut Client,
+            source: &str,
+            rows: &mut JsonRows<serde_json::Value>,
+        ) -> Result<i64, MyError> {
+            let mut tx = client.transaction().await?;
+
+            let stmt = include_str!("../sql/user_actions/insert_upload.sql");
+
+            let upload_id: i64 = tx.query_one(stmt, &[&source]).await?.get("id");
+
+            let mut copy = CopyIn::begin_in(&mut tx).await?;
+            let mut row_no = 0;
+            while let Some(row) = rows.next().await? {
+                row_no += 1;
+                copy.write(&UploadRow {
+                    upload_id,
+                    row_no,
+                    row,
+                })
+                .await?;
+            }
+            copy.commit().await?;
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-            ];
+            tx.commit().await?;
+            Ok(upload_id)
+        }
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+        pub async fn get_upload<C: GenericClient + Sync>(
+            client: &C,
+            id: i64,
+        ) -> Result<Upload, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_upload.sql");
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+            let row = client
+                .query_opt(stmt, &[&id])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(Upload::from_row_ref(&row)?)
+        }
 
-            tx.commit().await?;
+        /// Up to `limit` rows after row `after`, in the order they were sent
+        pub async fn get_rows<C: GenericClient + Sync>(
+            client: &C,
+            id: i64,
+            after: i32,
+            limit: i64,
+        ) -> Result<Vec<UploadRow>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_upload_rows.sql");
 
-            Ok(num_written)
+            let res = client
+                .query(stmt, &[&id, &after, &limit])
+                .await?
+                .iter()
+                .map(UploadRow::from_row_ref)
+                .collect::<Result<Vec<UploadRow>, _>>()?;
+            Ok(res)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<ABSAInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+        /// Drop the stored rows once the upload job has finished with them
+        pub async fn purge<C: GenericClient + Sync>(client: &C, id: i64) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/purge_upload.sql");
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.transaction_date);
-                row.push(&m.value_date);
-                row.push(&m.description);
-                row.push(&m.user_reference_number);
-                row.push(&m.cheque_number);
-                row.push(&m.debit_amount);
-                row.push(&m.credit_amount);
-                row.push(&m.running_balance);
+            client.execute(stmt, &[&id]).await?;
+            Ok(())
+        }
+    }
 
-                writer.as_mut().write(&row).await?;
-            }
+    /// An uploaded row that could not be read. Rows are numbered from 1
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct UploadRowError {
+        pub row: usize,
+        pub error: String,
+    }
 
-            writer.finish().await?;
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.job_schedules")]
+    pub struct JobSchedule {
+        pub name: String,
+        pub kind: String,
+        pub payload: serde_json::Value,
+        /// minute hour day-of-month month day-of-week
+        pub cron: String,
+        pub enabled: bool,
+        pub last_enqueued_at: Option<DateTime<Utc>>,
+    }
 
-            Ok(data.len())
-        }
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct JobScheduleInsert {
+        pub kind: String,
+        #[serde(default)]
+        pub payload: serde_json::Value,
+        pub cron: String,
+        pub enabled: Option<bool>,
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.absa_statement")]
-    pub struct ABSA {
-        pub transaction_date: chrono::NaiveDateTime,
-        pub value_date: chrono::NaiveDateTime,
-        pub description: String,
-        pub user_reference_number: Option<String>,
-        pub cheque_number: Option<i32>,
-        pub debit_amount: Option<f64>,
-        pub credit_amount: Option<f64>,
-        pub running_balance: Option<f64>,
-    }
-    impl ABSA {
-        pub async fn get_statement(client: &Client) -> Result<Vec<ABSA>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_absa_statement.sql");
+    impl JobSchedule {
+        pub async fn get_schedules(client: &Client) -> Result<Vec<JobSchedule>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_job_schedules.sql");
 
             let res = client
                 .query(stmt, &[])
                 .await?
                 .into_iter()
-                .map(|row| ABSA::from_row_ref(&row).unwrap())
-                .collect::<Vec<ABSA>>();
+                .map(|row| JobSchedule::from_row_ref(&row).unwrap())
+                .collect::<Vec<JobSchedule>>();
             Ok(res)
         }
-    }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.pdq_statement")]
-    pub struct PdqBreakdownInsert {
-        pub account_no: Option<f64>,
-        pub location_no: Option<f64>,
-        pub legal_name: Option<String>,
-        pub card_no: String,
-        pub txn_date: Option<String>,
-        pub processing_date: Option<String>,
-        pub payment_date: Option<String>,
-        pub terminal_id: Option<f64>,
-        pub auth_id: Option<String>,
-        pub amount: Option<f64>,
-        pub commission: Option<f64>,
-        pub net_amount: Option<f64>,
-        pub trxn_type: Option<String>,
-        pub currency: Option<String>,
-        pub pmnt_type: Option<String>,
-        pub trxn_source: Option<String>,
-        pub scheme: Option<String>,
-        pub commercial_name: Option<String>,
-        pub arn_reference: Option<String>,
-        pub retrieval_ref_no: Option<String>,
-        pub tip_amount: Option<f64>,
-        pub card_present: Option<String>,
-    }
-    impl PdqBreakdownInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<PdqBreakdownInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_pdq_breakdowns.sql");
-
-            let statement = tx.prepare(stmt).await?;
-
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
-
-            let types = &[
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::TEXT,
-            ];
-
-            let writer = BinaryCopyInWriter::new(sink, types);
-
-            let num_written = Self::insertintodb(writer, &data).await?;
+        pub async fn upsert(
+            client: &Client,
+            name: &str,
+            schedule: JobScheduleInsert,
+        ) -> Result<JobSchedule, MyError> {
+            ensure_job_kind(&schedule.kind)?;
+            Cron::parse(&schedule.cron).map_err(MyError::BadRequest)?;
+            let payload = match schedule.payload {
+                serde_json::Value::Null => serde_json::json!({}),
+                payload => payload,
+            };
+
+            let stmt = include_str!("../sql/user_actions/upsert_job_schedule.sql");
+
+            let row = client
+                .query_one(
+                    stmt,
+                    &[
+                        &name,
+                        &schedule.kind,
+                        &payload,
+                        &schedule.cron,
+                        &schedule.enabled.unwrap_or(true),
+                    ],
+                )
+                .await?;
+            Ok(JobSchedule::from_row_ref(&row)?)
+        }
 
-            tx.commit().await?;
+        pub async fn delete(client: &Client, name: &str) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/delete_job_schedule.sql");
 
-            Ok(num_written)
+            match client.execute(stmt, &[&name]).await? {
+                0 => Err(MyError::NotFound),
+                _ => Ok(()),
+            }
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<PdqBreakdownInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
-
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.account_no);
-                row.push(&m.location_no);
-                row.push(&m.legal_name);
-                row.push(&m.card_no);
-                row.push(&m.txn_date);
-                row.push(&m.processing_date);
-                row.push(&m.payment_date);
-                row.push(&m.terminal_id);
-                row.push(&m.auth_id);
-                row.push(&m.amount);
-                row.push(&m.commission);
-                row.push(&m.net_amount);
-                row.push(&m.trxn_type);
-                row.push(&m.currency);
-                row.push(&m.pmnt_type);
-                row.push(&m.trxn_source);
-                row.push(&m.scheme);
-                row.push(&m.commercial_name);
-                row.push(&m.arn_reference);
-                row.push(&m.retrieval_ref_no);
-                row.push(&m.tip_amount);
-                row.push(&m.card_present);
-
-                writer.as_mut().write(&row).await?;
-            }
-
-            writer.finish().await?;
-
-            Ok(data.len())
-        }
-    }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.pdq_statement")]
-    pub struct PdqBreakdown {
-        pub account_no: Option<f64>,
-        pub location_no: Option<f64>,
-        pub legal_name: Option<String>,
-        pub card_no: String,
-        pub txn_date: Option<chrono::NaiveDateTime>,
-        pub processing_date: Option<chrono::NaiveDateTime>,
-        pub payment_date: Option<chrono::NaiveDateTime>,
-        pub terminal_id: Option<f64>,
-        pub auth_id: Option<String>,
-        pub amount: Option<f64>,
-        pub commission: Option<f64>,
-        pub net_amount: Option<f64>,
-        pub trxn_type: Option<String>,
-        pub currency: Option<String>,
-        pub pmnt_type: Option<String>,
-        pub trxn_source: Option<String>,
-        pub scheme: Option<String>,
-        pub commercial_name: Option<String>,
-        pub arn_reference: Option<String>,
-        pubretrieval_ref_no: Option<String>,
-        pub tip_amount: Option<f64>,
-        pub card_present: Option<String>,
-    }
-    impl PdqBreakdown {
-        pub async fn get_statement(client: &Client) -> Result<Vec<PdqBreakdown>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_pdq_breakdowns.sql");
+        /// Mark the schedule enqueued for this minute. False when another process already did
+        pub async fn claim(
+            client: &Client,
+            name: &str,
+            minute: DateTime<Utc>,
+        ) -> Result<bool, MyError> {
+            let stmt = include_str!("../sql/user_actions/claim_job_schedule.sql");
 
-            let res = client
-                .query(stmt, &[])
-                .await?
-                .into_iter()
-                .map(|row| PdqBreakdown::from_row_ref(&row).unwrap())
-                .collect::<Vec<PdqBreakdown>>();
-            Ok(res)
+            Ok(client.query_opt(stmt, &[&name, &minute]).await?.is_some())
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.sidian_statement")]
-    pub struct SidianInsert {
-        pub date: String,
-        pub valuedate: Option<String>,
-        pub reference: Option<String>,
-        pub narration: Option<String>,
-        pub chequenumber: Option<f64>,
-        pub debit: Option<f64>,
-        pub credit: Option<f64>,
-        pub balance: f64,
+
+    pub async fn refresh_materialized_views(client: &Client) -> Result<(), MyError> {
+        let stmt = include_str!("../sql/user_actions/refresh_materialized_views.sql");
+
+        client.batch_execute(stmt).await?;
+        Ok(())
     }
-    impl SidianInsert {
-        pub async fn update(
-            client: &mut Client,
-            data: Vec<SidianInsert>,
-        ) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
-            let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_sidian_statement.sql");
+    /// Sources that can be signed off and locked
+    pub const LOCKABLE_SOURCES: [&str; 10] = [
+        "mpesa", "airtel", "absa", "sidian", "cfc", "pdq", "equity", "kcb", "coop", "ncba",
+    ];
 
-            let statement = tx.prepare(stmt).await?;
+    /// A signed-off date range of a source
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.period_locks")]
+    pub struct PeriodLock {
+        pub id: i64,
+        pub source: String,
+        pub from_date: NaiveDate,
+        pub to_date: NaiveDate,
+        pub locked_by: String,
+        pub locked_at: DateTime<Utc>,
+        pub comment: Option<String>,
+        pub unlocked_by: Option<String>,
+        pub unlocked_at: Option<DateTime<Utc>>,
+        pub unlock_reason: Option<String>,
+    }
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct PeriodLockInsert {
+        pub source: String,
+        pub from: NaiveDate,
+        pub to: NaiveDate,
+        pub comment: Option<String>,
+    }
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-            ];
+    #[derive(Deserialize, Serialize, Debug)]
+    pub struct PeriodUnlock {
+        pub reason: String,
+    }
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+    #[derive(Deserialize, Debug)]
+    pub struct PeriodLockFilter {
+        pub source: Option<String>,
+        pub include_unlocked: Option<bool>,
+    }
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+    #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+    #[pg_mapper(table = "production.period_lock_snapshots")]
+    pub struct PeriodLockSnapshot {
+        pub lock_id: i64,
+        pub snapshot: serde_json::Value,
+        pub created_at: DateTime<Utc>,
+    }
 
-            tx.commit().await?;
+    impl PeriodLock {
+        /// Locks of the accounts of the given units, of every account for None
+        pub async fn get_locks(
+            client: &Client,
+            filter: &PeriodLockFilter,
+            units: Option<&[String]>,
+        ) -> Result<Vec<PeriodLock>, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_period_locks.sql");
 
-            Ok(num_written)
+            let res = client
+                .query(
+                    stmt,
+                    &[
+                        &filter.source,
+                        &filter.include_unlocked.unwrap_or(false),
+                        &units,
+                    ],
+                )
+                .await?
+                .into_iter()
+                .map(|row| PeriodLock::from_row_ref(&row).unwrap())
+                .collect::<Vec<PeriodLock>>();
+            Ok(res)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<SidianInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+        /// Reject the change if any of the dates fall in an active lock of the source. Run inside
+        /// the transaction that writes the rows, for streamed uploads once every row has been
+        /// copied. The transaction holds the source's lock
+        /// until it ends, so a period cannot be locked before its rows are committed
+        pub async fn ensure_unlocked_in<C: GenericClient + Sync>(
+            client: &C,
+            source: &str,
+            mut dates: Vec<NaiveDate>,
+        ) -> Result<(), MyError> {
+            dates.sort();
+            dates.dedup();
+            if dates.is_empty() {
+                return Ok(());
+            }
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.date);
-                row.push(&m.valuedate);
-                row.push(&m.reference);
-                row.push(&m.narration);
-                row.push(&m.chequenumber);
-                row.push(&m.debit);
-                row.push(&m.credit);
-                row.push(&m.balance);
+            let stmt = include_str!("../sql/user_actions/share_source_lock.sql");
 
-                writer.as_mut().write(&row).await?;
-            }
+            client.execute(stmt, &[&source]).await?;
 
-            writer.finish().await?;
+            let stmt = include_str!("../sql/user_actions/get_covering_period_locks.sql");
 
-            Ok(data.len())
+            let lock = client
+                .query(stmt, &[&source, &dates])
+                .await?
+                .into_iter()
+                .map(|row| PeriodLock::from_row_ref(&row).unwrap())
+                .next();
+
+            match lock {
+                Some(lock) => Err(MyError::Locked(format!(
+                    "{} is locked from {} to {} by {}. An Admin has to unlock it first",
+                    lock.source, lock.from_date, lock.to_date, lock.locked_by
+                ))),
+                None => Ok(()),
+            }
         }
-    }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.sidian_statement")]
-    pub struct Sidian {
-        pub date: chrono::NaiveDateTime,
-        pub valuedate: Option<chrono::NaiveDate>,
-        pub reference: Option<String>,
-        pub narration: Option<String>,
-        pub chequenumber: Option<i32>,
-        pub debit: Option<f64>,
-        pub credit: Option<f64>,
-        pub balance: f64,
-    }
-    impl Sidian {
-        pub async fn get_statement(client: &Client) -> Result<Vec<Sidian>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_sidian_statement.sql");
 
-            let res = client
-                .query(stmt, &[])
+        /// Reject the change if the collection receipt falls in a locked period. Like
+        /// `ensure_unlocked_in`, the transaction keeps the period from being locked until it ends
+        pub async fn ensure_bill_unlocked_in<C: GenericClient + Sync>(
+            client: &C,
+            source: &str,
+            billing_number: &str,
+        ) -> Result<(), MyError> {
+            let stmt = include_str!("../sql/user_actions/get_billing_dates.sql");
+
+            let dates = client
+                .query(stmt, &[&billing_number])
                 .await?
                 .into_iter()
-                .map(|row| Sidian::from_row_ref(&row).unwrap())
-                .collect::<Vec<Sidian>>();
-            Ok(res)
+                .map(|row| row.get::<_, NaiveDate>("day"))
+                .collect::<Vec<NaiveDate>>();
+
+            PeriodLock::ensure_unlocked_in(client, source, dates).await
         }
-    }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "staging.cfc_statement")]
-    pub struct CfcInsert {
-        pub date: chrono::NaiveDateTime,
-        pub transaction: String,
-        pub value_date: chrono::NaiveDateTime,
-        pub debit: Option<f64>,
-        pub credit: Option<f64>,
-        pub ledger_balance: Option<f64>,
-        pub available_balance: Option<f64>,
-    }
-    impl CfcInsert {
-        pub async fn update(client: &mut Client, data: Vec<CfcInsert>) -> Result<usize, MyError> {
-            // Create the transaction to represent db tx
+
+        /// Lock a period and store the reconciliation results as they are now. The caller has
+        /// been checked to be an Admin
+        pub async fn lock(
+            client: &mut Client,
+            lock: PeriodLockInsert,
+            locked_by: &str,
+        ) -> Result<PeriodLock, MyError> {
+            if !LOCKABLE_SOURCES.contains(&lock.source.as_str()) {
+                return Err(MyError::BadRequest(format!(
+                    "Unknown source {}",
+                    lock.source
+                )));
+            }
+            if lock.from > lock.to {
+                return Err(MyError::BadRequest("from must not be after to".to_string()));
+            }
+
+            // Writers of the source finish first and wait for the lock to commit, so the
+            // snapshot holds every row the lock covers
             let tx = client.transaction().await?;
 
-            let stmt = include_str!("../sql/user_actions/update_cfc_statement.sql");
+            let stmt = include_str!("../sql/user_actions/take_source_lock.sql");
+
+            tx.execute(stmt, &[&lock.source]).await?;
 
-            let statement = tx.prepare(stmt).await?;
+            let days = days_between(lock.from, lock.to);
+            PeriodLock::ensure_unlocked_in(&*tx, &lock.source, days).await?;
 
-            // Create a sink that will take the vec of MpesaPayments
-            let sink = tx.copy_in(&statement).await?;
+            let snapshot = PeriodLock::snapshot(&*tx, &lock.source, lock.from, lock.to).await?;
 
-            let types = &[
-                Type::TEXT,
-                Type::TEXT,
-                Type::TEXT,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-                Type::FLOAT8,
-            ];
+            let stmt = include_str!("../sql/user_actions/insert_period_lock.sql");
 
-            let writer = BinaryCopyInWriter::new(sink, types);
+            let row = tx
+                .query_one(
+                    stmt,
+                    &[
+                        &lock.source,
+                        &lock.from,
+                        &lock.to,
+                        &locked_by,
+                        &lock.comment,
+                    ],
+                )
+                .await?;
+            let period_lock = PeriodLock::from_row_ref(&row)?;
 
-            let num_written = Self::insertintodb(writer, &data).await?;
+            let stmt = include_str!("../sql/user_actions/insert_period_lock_snapshot.sql");
+
+            tx.execute(stmt, &[&period_lock.id, &snapshot]).await?;
 
             tx.commit().await?;
 
-            Ok(num_written)
+            Ok(period_lock)
         }
 
-        pub async fn insertintodb(
-            writer: BinaryCopyInWriter,
-            data: &Vec<CfcInsert>,
-        ) -> Result<usize, MyError> {
-            pin_mut!(writer);
+        pub async fn unlock(
+            client: &Client,
+            id: i64,
+            unlock: PeriodUnlock,
+            unlocked_by: &str,
+        ) -> Result<PeriodLock, MyError> {
+            if unlock.reason.trim().is_empty() {
+                return Err(MyError::BadRequest(
+                    "A reason is required to unlock a period".to_string(),
+                ));
+            }
 
-            let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-            for m in data {
-                row.clear();
-                row.push(&m.date);
-                row.push(&m.transaction);
-                row.push(&m.value_date);
-                row.push(&m.debit);
-                row.push(&m.credit);
-                row.push(&m.ledger_balance);
-                row.push(&m.available_balance);
+            let stmt = include_str!("../sql/user_actions/unlock_period_lock.sql");
 
-                writer.as_mut().write(&row).await?;
-            }
+            let row = client
+                .query_opt(stmt, &[&id, &unlocked_by, &unlock.reason])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(PeriodLock::from_row_ref(&row)?)
+        }
+
+        pub async fn get_snapshot(client: &Client, id: i64) -> Result<PeriodLockSnapshot, MyError> {
+            let stmt = include_str!("../sql/user_actions/get_period_lock_snapshot.sql");
+
+            let row = client
+                .query_opt(stmt, &[&id])
+                .await?
+                .ok_or(MyError::NotFound)?;
+            Ok(PeriodLockSnapshot::from_row_ref(&row)?)
+        }
 
-            writer.finish().await?;
+        // M-Pesa and Airtel Money keep the daily reconciliations. Every source with a statement keeps its
+        // balance continuity and its ledger rows
+        async fn snapshot<C: GenericClient + Sync>(
+            client: &C,
+            source: &str,
+            from: NaiveDate,
+            to: NaiveDate,
+        ) -> Result<serde_json::Value, MyError> {
+            let range = DateRange { from, to };
+
+            let continuity = match BankAccount::from_name(source) {
+                Some(account) => {
+                    let entries = BalanceEntry::get_entries_in(client, account, &range).await?;
+                    Some(ContinuityReport::check(account, entries))
+                }
+                None => None,
+            };
+
+            let mut reconciliations = Vec::new();
+            if source == "mpesa" || source == "airtel" {
+                for day in days_between(from, to) {
+                    reconciliations.extend(
+                        MpesaStatement::get_reconciled_statement_in(client, day, None)
+                            .await?
+                            .into_iter()
+                            .filter(|row| row.wallet == source),
+                    );
+                }
+            }
 
-            Ok(data.len())
+            let filter = LedgerFilter {
+                from: Some(from),
+                to: Some(to),
+                account: Some(source.to_string()),
+                search: None,
+                exclude_transfers: None,
+                currency: None,
+            };
+            let ledger = Ledger::get_ledger_in(client, &filter, None, HOME_CURRENCY).await?;
+
+            Ok(serde_json::json!({
+                "source": source,
+                "from": from,
+                "to": to,
+                "reconciliations": reconciliations,
+                "continuity": continuity,
+                "ledger": ledger,
+            }))
         }
     }
-    #[derive(Deserialize, PostgresMapper, Serialize)]
-    #[pg_mapper(table = "production.cfc_statement")]
-    pub struct Cfc {
-        pub date: chrono::NaiveDateTime,
-        pub transaction: String,
-        pub value_date: chrono::NaiveDateTime,
-        pub debit: Option<f64>,
-        pub credit: Option<f64>,
-        pub ledger_balance: Option<f64>,
-        pub available_balance: Option<f64>,
-    }
-    impl Cfc {
-        pub async fn get_statement(client: &Client) -> Result<Vec<Cfc>, MyError> {
-            let stmt = include_str!("../sql/user_actions/get_cfc_statement.sql");
 
-            let res = client
-                .query(stmt, &[])
-                .await?
-                .into_iter()
-                .map(|row| Cfc::from_row_ref(&row).unwrap())
-                .collect::<Vec<Cfc>>();
-            Ok(res)
+    /// Every day from `from` to `to`, both included
+    pub fn days_between(from: NaiveDate, to: NaiveDate) -> Vec<NaiveDate> {
+        let mut days = Vec::new();
+        let mut day = from;
+        while day <= to {
+            days.push(day);
+            day = day.succ();
         }
+        days
     }
-    // Used to register a new user
+
+    /// The readable dates of an upload, used to check it against locked periods
+    pub fn upload_dates(entries: &[Result<BalanceEntry, String>]) -> Vec<NaiveDate> {
+        entries
+            .iter()
+            .filter_map(|entry| entry.as_ref().ok())
+            .map(|entry| entry.date.date())
+            .collect()
+    }
+
+    // Used to register a new user. Registration has no route yet
+    #[allow(dead_code)]
     #[derive(Deserialize, PostgresMapper, Serialize, Debug)]
     #[pg_mapper(table = "users")] // singular 'user' is a keyword..
     pub struct User {
@@ -1145,17 +4253,303 @@ pub mod models {
     // TODO check email does not match current users' email
     //
     // Define the various scopes available for the app's users
-    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
     #[serde(rename_all = "camelCase")]
-    enum Scope {
+    pub enum Scope {
+        #[default]
         Guest,
         User,
         Admin,
     }
 
-    impl Default for Scope {
-        fn default() -> Self {
-            Scope::Guest
+    impl Scope {
+        /// The scope stored in internal.users. Anything unknown is a Guest
+        pub fn from_name(name: Option<&str>) -> Self {
+            match name {
+                Some("admin") => Scope::Admin,
+                Some("user") => Scope::User,
+                _ => Scope::Guest,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_database::test_database;
+
+        fn entry(date: &str, debit: i64, credit: i64, balance: Option<i64>) -> BalanceEntry {
+            BalanceEntry {
+                date: NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap(),
+                reference: Some(format!("{}-{}-{}", date, debit, credit)),
+                debit: Decimal::from(debit),
+                credit: Decimal::from(credit),
+                balance: balance.map(Decimal::from),
+            }
+        }
+
+        fn check(entries: Vec<BalanceEntry>) -> ContinuityReport {
+            ContinuityReport::check(BankAccount::Mpesa, entries)
+        }
+
+        #[test]
+        fn follows_an_unbroken_statement() {
+            let report = check(vec![
+                entry("2024-03-11 09:00", 0, 500, Some(1500)),
+                entry("2024-03-11 10:00", 200, 0, Some(1300)),
+                entry("2024-03-12 08:00", 0, 100, Some(1400)),
+            ]);
+            assert!(report.gaps.is_empty(), "{:?}", report.gaps);
+            assert_eq!(report.rows_checked, 3);
+            assert_eq!(report.opening_balance, Some(Decimal::from(1000)));
+            assert_eq!(report.closing_balance, Some(Decimal::from(1400)));
+        }
+
+        #[test]
+        fn reports_missing_rows() {
+            let report = check(vec![
+                entry("2024-03-11 09:00", 0, 500, Some(1500)),
+                // A 300 credit is missing in between
+                entry("2024-03-11 11:00", 0, 100, Some(1900)),
+            ]);
+            match report.gaps.as_slice() {
+                [BalanceGap::MissingRows {
+                    expected_balance,
+                    actual_balance,
+                    difference,
+                    ..
+                }] => {
+                    assert_eq!(*expected_balance, Decimal::from(1600));
+                    assert_eq!(*actual_balance, Decimal::from(1900));
+                    assert_eq!(*difference, Decimal::from(300));
+                }
+                gaps => panic!("{:?}", gaps),
+            }
+        }
+
+        #[test]
+        fn reports_missing_days() {
+            let report = check(vec![
+                entry("2024-03-08 09:00", 0, 500, Some(1500)),
+                entry("2024-03-11 09:00", 0, 100, Some(1600)),
+                entry("2024-03-14 09:00", 0, 100, Some(1900)),
+            ]);
+            match report.gaps.as_slice() {
+                [BalanceGap::MissingDays {
+                    from: first_from,
+                    to: first_to,
+                    days: 2,
+                    balance_continuous: true,
+                }, BalanceGap::MissingRows { .. }, BalanceGap::MissingDays {
+                    days: 2,
+                    balance_continuous: false,
+                    ..
+                }] => {
+                    assert_eq!(*first_from, NaiveDate::from_ymd(2024, 3, 9));
+                    assert_eq!(*first_to, NaiveDate::from_ymd(2024, 3, 10));
+                }
+                gaps => panic!("{:?}", gaps),
+            }
+        }
+
+        #[test]
+        fn reports_duplicates_once() {
+            let row = entry("2024-03-11 10:00", 200, 0, Some(1300));
+            let report = check(vec![
+                entry("2024-03-11 09:00", 0, 500, Some(1500)),
+                row.clone(),
+                row.clone(),
+                row,
+                entry("2024-03-11 11:00", 0, 100, Some(1400)),
+            ]);
+            match report.gaps.as_slice() {
+                [BalanceGap::DuplicateRow { occurrences: 3, .. }] => {}
+                gaps => panic!("{:?}", gaps),
+            }
+            assert_eq!(report.rows_checked, 5);
+        }
+
+        #[test]
+        fn orders_rows_sharing_a_timestamp() {
+            // Listed out of order under one timestamp, and after an unrelated earlier row
+            let report = check(vec![
+                entry("2024-03-11 09:00", 0, 500, Some(1500)),
+                entry("2024-03-11 10:00", 0, 50, Some(1350)),
+                entry("2024-03-11 10:00", 0, 100, Some(1600)),
+                entry("2024-03-11 10:00", 300, 0, Some(1300)),
+            ]);
+            assert!(report.gaps.is_empty(), "{:?}", report.gaps);
+            assert_eq!(report.opening_balance, Some(Decimal::from(1000)));
+            assert_eq!(report.closing_balance, Some(Decimal::from(1350)));
+        }
+
+        #[test]
+        fn orders_a_large_timestamp_group() {
+            // Thousands of rows under one timestamp, listed newest first
+            let mut entries: Vec<BalanceEntry> = (1..=5000)
+                .map(|n| entry("2024-03-11 10:00", 0, n, Some(n * (n + 1) / 2)))
+                .collect();
+            entries.reverse();
+            let report = check(entries);
+            assert!(report.gaps.is_empty());
+            assert_eq!(report.opening_balance, Some(Decimal::ZERO));
+            assert_eq!(report.closing_balance, Some(Decimal::from(5000 * 5001 / 2)));
+        }
+
+        #[test]
+        fn skips_rows_without_a_balance() {
+            let report = check(vec![
+                entry("2024-03-11 09:00", 0, 500, None),
+                entry("2024-03-11 10:00", 0, 100, Some(1600)),
+            ]);
+            assert!(report.gaps.is_empty());
+            assert_eq!(report.opening_balance, None);
+            assert_eq!(report.closing_balance, Some(Decimal::from(1600)));
+        }
+
+        #[actix_web::test]
+        #[ignore = "needs TEST_DATABASE_URL"]
+        async fn pdq_transfers_tag_only_the_rows_they_settle() {
+            let pool = test_database::pool().await;
+            let mut client = pool.get().await.unwrap();
+
+            let insert_pdq = "insert into production.pdq_breakdowns (payment_date, txn_date, net_amount, currency) \
+                values ('2031-05-06', '2031-05-05', $1, $2) returning id";
+            let mut pdq = Vec::new();
+            for (net, currency) in [(400, "KES"), (200, "KES"), (400, "USD")] {
+                let row = client
+                    .query_one(insert_pdq, &[&Decimal::from(net), &currency])
+                    .await
+                    .unwrap();
+                pdq.push(row.get::<_, i64>(0));
+            }
+            client
+                .execute(
+                    "insert into production.bank_statements (bank, transaction_date, narration, credit) \
+                    values ('ncba', '2031-05-07', 'PDQ settlement', 600)",
+                    &[],
+                )
+                .await
+                .unwrap();
+
+            let query = TransferQuery {
+                from: NaiveDate::from_ymd(2031, 5, 6),
+                to: NaiveDate::from_ymd(2031, 5, 6),
+                window_days: None,
+            };
+            let transfers = Transfer::detect(&mut client, &query, chrono_tz::UTC)
+                .await
+                .unwrap();
+            match transfers.as_slice() {
+                [t] => assert_eq!(t.settled_row_ids, Some(vec![pdq[0], pdq[1]])),
+                transfers => panic!("{:?}", transfers),
+            }
+
+            // A row of the same day loaded after the settlement was paired
+            let late = client
+                .query_one(insert_pdq, &[&Decimal::from(50), &"KES"])
+                .await
+                .unwrap()
+                .get::<_, i64>(0);
+
+            let tagged = client
+                .query(
+                    "select source_row_id from production.ledger_entries \
+                    where account = 'pdq' and source_row_id = any($1) and is_transfer \
+                    order by source_row_id",
+                    &[&vec![pdq[0], pdq[1], pdq[2], late]],
+                )
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.get::<_, i64>(0))
+                .collect::<Vec<i64>>();
+            assert_eq!(tagged, vec![pdq[0], pdq[1]]);
+        }
+
+        #[actix_web::test]
+        #[ignore = "needs TEST_DATABASE_URL"]
+        async fn cashier_matches_follow_decisions() {
+            let pool = test_database::pool().await;
+            let client = pool.get().await.unwrap();
+
+            client
+                .batch_execute(
+                    "insert into production.mpesa_statement (receipt_no, completion_time, initiation_time, \
+                        details, transaction_status, paid_in) \
+                    values ('TQA37AAAA1', '2031-06-01', '2031-06-01', 'Pay Bill', 'Completed', 500), \
+                        ('TQA37AAAA2', '2031-06-01', '2031-06-01', 'Pay Bill', 'Completed', 500); \
+                    insert into production.collection_details (receipt_no, receipt_date, card, mpesa, transaction_no) \
+                    values ('T037-1', '2031-06-01', 0, 500, 'TQA37AAAA1'), \
+                        ('T037-2', '2031-06-01', 0, 500, 'TQA37AAAA1'), \
+                        ('T037-3', '2031-06-01', 0, 500, 'TQA37AAAA1'); \
+                    insert into production.reconciliation_decisions (billing_number, statement_reference, decision, decided_by) \
+                    values ('T037-2', 'TQA37AAAA1', 'reject', 'tester'), \
+                        ('T037-3', 'TQA37ZZZZ9', 'manual', 'tester');",
+                )
+                .await
+                .unwrap();
+
+            let receipts: Vec<CollectionReceipt> = (1..=3)
+                .map(|n| CollectionReceipt {
+                    billing_number: Some(format!("T037-{}", n)),
+                    cashier: None,
+                    unit_name: None,
+                    transaction_code: Some("TQA37AAAA1".to_string()),
+                    mpesa: Some(Decimal::from(500)),
+                })
+                .collect();
+            let matched = ReconciliationEvent::for_receipts_in(&**client, &receipts)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|e| (e.details.billing_number.unwrap(), e.details.receipt_no))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                matched,
+                vec![
+                    ("T037-1".to_string(), Some("TQA37AAAA1".to_string())),
+                    // The rejected payment gives way to the next closest
+                    ("T037-2".to_string(), Some("TQA37AAAA2".to_string())),
+                    ("T037-3".to_string(), Some("TQA37ZZZZ9".to_string())),
+                ]
+            );
+
+            let claimed = ReconciliationEvent::for_payments(&client, &["TQA37AAAA1".to_string()])
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|e| e.details.billing_number)
+                .collect::<Vec<_>>();
+            assert_eq!(claimed, vec![Some("T037-1".to_string())]);
+        }
+
+        #[actix_web::test]
+        #[ignore = "needs TEST_DATABASE_URL"]
+        async fn accounts_leave_only_their_own_unit() {
+            let pool = test_database::pool().await;
+            let client = pool.get().await.unwrap();
+
+            for unit_name in ["T046 North", "T046 South"] {
+                Unit::upsert(&client, unit_name, UnitInsert { description: None })
+                    .await
+                    .unwrap();
+            }
+            Unit::add_account(&client, "T046 North", "cfc")
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                Unit::remove_account(&client, "T046 South", "cfc").await,
+                Err(MyError::NotFound)
+            ));
+            Unit::remove_account(&client, "T046 North", "cfc")
+                .await
+                .unwrap();
+            assert!(matches!(
+                Unit::remove_account(&client, "T046 North", "cfc").await,
+                Err(MyError::NotFound)
+            ));
         }
     }
 }
@@ -0,0 +1,69 @@
+pub mod notifications {
+    use crate::models::models::{NotificationScope, ReconciliationEvent};
+    use actix_web::web::Bytes;
+    use futures_util::stream::{self, Stream};
+    use std::time::Duration;
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    // Idle connections get a comment line this often so proxies keep them open
+    const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+    /// Fans reconciliation events out to every connected client
+    #[derive(Clone)]
+    pub struct Notifier {
+        sender: broadcast::Sender<ReconciliationEvent>,
+    }
+
+    impl Notifier {
+        /// Clients that fall more than `capacity` events behind skip the oldest ones
+        pub fn new(capacity: usize) -> Self {
+            let (sender, _) = broadcast::channel(capacity);
+            Notifier { sender }
+        }
+
+        pub fn publish(&self, events: Vec<ReconciliationEvent>) {
+            for event in events {
+                // Sending only fails when nobody is listening
+                let _ = self.sender.send(event);
+            }
+        }
+
+        /// Server-sent events for everything the scope can see
+        pub fn event_stream(
+            &self,
+            scope: NotificationScope,
+        ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+            stream::unfold(
+                (self.sender.subscribe(), scope),
+                |(mut receiver, scope)| async move {
+                    loop {
+                        match actix_web::rt::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                            Err(_) => {
+                                return Some((
+                                    Ok(Bytes::from_static(b": keep-alive\n\n")),
+                                    (receiver, scope),
+                                ))
+                            }
+                            Ok(Ok(event)) if event.visible_to(&scope) => {
+                                let data = serde_json::to_string(&event).unwrap_or_default();
+                                return Some((
+                                    Ok(Bytes::from(format!(
+                                        "event: {}\ndata: {}\n\n",
+                                        event.kind, data
+                                    ))),
+                                    (receiver, scope),
+                                ));
+                            }
+                            Ok(Ok(_)) => continue,
+                            Ok(Err(RecvError::Lagged(skipped))) => {
+                                tracing::warn!("Notification client skipped {} events", skipped);
+                                continue;
+                            }
+                            Ok(Err(RecvError::Closed)) => return None,
+                        }
+                    }
+                },
+            )
+        }
+    }
+}
@@ -0,0 +1,466 @@
+pub mod parsing {
+    use crate::configs::config::FormatConfig;
+    use chrono::{Datelike, NaiveDate, NaiveDateTime};
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+    use std::marker::PhantomData;
+
+    // Tried before a source's own layouts. HIS rows and rows already read by this module
+    // arrive in these
+    const ISO_FORMATS: [&str; 3] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d"];
+
+    // How dates are written back into a row, read by the staging casts and NaiveDateTime alike
+    const NORMALIZED_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+    const DMY: &[&str] = &[
+        "%d/%m/%Y %H:%M:%S",
+        "%d-%m-%Y %H:%M:%S",
+        "%d/%m/%Y",
+        "%d-%m-%Y",
+    ];
+
+    const BANK_DATES: &[&str] = &[
+        "%d/%m/%Y %H:%M:%S",
+        "%d-%m-%Y %H:%M:%S",
+        "%d/%m/%Y %H:%M",
+        "%d-%m-%Y %H:%M",
+        "%d/%m/%Y",
+        "%d-%m-%Y",
+        "%d-%b-%Y",
+        "%d %b %Y",
+        "%d/%m/%y",
+        "%d-%m-%y",
+        "%d-%b-%y",
+        "%d.%m.%Y",
+        "%d %B %Y",
+    ];
+
+    /// What a field of an upload row holds, as far as this module reads it
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FieldKind {
+        Text,
+        Date,
+        Amount,
+    }
+
+    /// The fields of an upload row with what they hold and whether they are required. Implemented
+    /// by `statement_source!` from the fields declared `as date` and `as amount`
+    pub trait RowFields {
+        const FIELDS: &'static [(&'static str, FieldKind, bool)];
+    }
+
+    // Rows stored as sent, to be read once their source is known
+    impl RowFields for serde_json::Value {
+        const FIELDS: &'static [(&'static str, FieldKind, bool)] = &[];
+    }
+
+    // The layouts each source's dates come in
+    const DATE_FORMATS: [(&str, &[&str]); 14] = [
+        (
+            "mpesa",
+            &["%d/%m/%Y %H:%M:%S", "%d-%m-%Y %H:%M:%S", "%d/%m/%Y %H:%M"],
+        ),
+        ("airtel", DMY),
+        ("collectiondetails", DMY),
+        ("billdetails", DMY),
+        ("labvisits", DMY),
+        ("mtiba", DMY),
+        ("sidian", &["%d/%m/%Y", "%d/%m/%Y %H:%M:%S", "%d/%m/%y"]),
+        ("absa", &["%d-%b-%Y", "%d/%m/%Y"]),
+        ("pdq", &["%d/%m/%Y %H:%M:%S", "%d/%m/%Y"]),
+        ("cfc", &["%d/%m/%Y", "%d/%m/%Y %H:%M:%S", "%d/%m/%y"]),
+        ("equity", BANK_DATES),
+        ("kcb", BANK_DATES),
+        ("coop", BANK_DATES),
+        ("ncba", BANK_DATES),
+    ];
+
+    /// How one source writes dates and amounts
+    #[derive(Clone, Debug)]
+    pub struct SourceFormat {
+        /// chrono layouts, tried in order after the ISO ones. Layouts without a time read as
+        /// midnight
+        pub date_formats: Vec<String>,
+        pub thousands_separator: Option<char>,
+        pub decimal_separator: char,
+        /// (1,234.50) is -1234.50
+        pub bracketed_negatives: bool,
+    }
+
+    impl Default for SourceFormat {
+        fn default() -> Self {
+            SourceFormat {
+                date_formats: DMY.iter().map(|f| f.to_string()).collect(),
+                thousands_separator: Some(','),
+                decimal_separator: '.',
+                bracketed_negatives: true,
+            }
+        }
+    }
+
+    impl SourceFormat {
+        /// The first layout that reads the whole value. Day and month are never guessed:
+        /// 03/04/2024 is the 3rd of April only if a d/m layout is listed before any m/d one
+        pub fn parse_date(&self, value: &str) -> Option<NaiveDateTime> {
+            let value = value.trim();
+            ISO_FORMATS
+                .iter()
+                .copied()
+                .chain(self.date_formats.iter().map(String::as_str))
+                .find_map(|format| parse_with(value, format))
+        }
+
+        /// Read an amount as the source prints it: 1,234.50, (1,234.50), -1234.5,
+        /// 1,234.50 DR or KES 1,234.50
+        pub fn parse_amount(&self, value: &str) -> Option<Decimal> {
+            let mut value = value.trim().to_uppercase();
+            if let Some(rest) = ["KSHS", "KSH", "KES"]
+                .iter()
+                .find_map(|currency| value.strip_prefix(currency))
+            {
+                value = rest.trim_start_matches('.').trim().to_string();
+            }
+            let mut negative = false;
+            if let Some(rest) = value.strip_suffix("DR") {
+                negative = true;
+                value = rest.trim().to_string();
+            } else if let Some(rest) = value.strip_suffix("CR") {
+                value = rest.trim().to_string();
+            } else if let Some(rest) = value.strip_suffix('-') {
+                negative = true;
+                value = rest.trim().to_string();
+            }
+            if self.bracketed_negatives && value.starts_with('(') && value.ends_with(')') {
+                negative = true;
+                value = value[1..value.len() - 1].to_string();
+            }
+            let digits: String = value
+                .chars()
+                .filter(|c| Some(*c) != self.thousands_separator && *c != ' ')
+                .map(|c| if c == self.decimal_separator { '.' } else { c })
+                .collect();
+            let amount: Decimal = digits.parse().ok()?;
+            Some(if negative { -amount.abs() } else { amount })
+        }
+    }
+
+    // %Y reads 24 as the year 24, so such dates are left to a two digit layout
+    fn parse_with(value: &str, format: &str) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(value, format)
+                    .ok()
+                    .map(|date| date.and_hms(0, 0, 0))
+            })
+            .filter(|date| date.year() >= 1900)
+    }
+
+    /// A source's format for reading rows of `T`
+    pub struct RowFormat<T> {
+        pub format: SourceFormat,
+        row: PhantomData<T>,
+    }
+
+    impl<T> Default for RowFormat<T> {
+        fn default() -> Self {
+            RowFormat::new(SourceFormat::default())
+        }
+    }
+
+    impl<T> RowFormat<T> {
+        pub fn new(format: SourceFormat) -> Self {
+            RowFormat {
+                format,
+                row: PhantomData,
+            }
+        }
+    }
+
+    impl<T: RowFields> RowFormat<T> {
+        /// Rewrite the row's text dates as ISO and its text amounts as plain decimals before it is
+        /// deserialized. Empty text becomes null, or an error for a required field. Missing fields
+        /// are left to deserialization
+        pub fn normalize(&self, row: &mut serde_json::Value) -> Result<(), String> {
+            let fields = match row.as_object_mut() {
+                Some(fields) => fields,
+                None => return Ok(()),
+            };
+            let named = |kind| {
+                T::FIELDS
+                    .iter()
+                    .filter(move |(_, field_kind, _)| *field_kind == kind)
+                    .map(|(name, _, required)| (*name, *required))
+            };
+            for (name, required) in named(FieldKind::Date) {
+                if let Some(value) = fields.get_mut(name) {
+                    if let Some(text) = value.as_str().map(str::trim) {
+                        *value = match text {
+                            "" if required => return Err(format!("{} is empty", name)),
+                            "" => serde_json::Value::Null,
+                            text => {
+                                let date = self.format.parse_date(text).ok_or_else(|| {
+                                    format!(
+                                        "{} {} does not match any of {}",
+                                        name,
+                                        text,
+                                        self.format.date_formats.join(", ")
+                                    )
+                                })?;
+                                date.format(NORMALIZED_FORMAT).to_string().into()
+                            }
+                        };
+                    }
+                }
+            }
+            for (name, required) in named(FieldKind::Amount) {
+                if let Some(value) = fields.get_mut(name) {
+                    if let Some(text) = value.as_str().map(str::trim) {
+                        *value = match text {
+                            "" | "-" if required => return Err(format!("{} is empty", name)),
+                            "" | "-" => serde_json::Value::Null,
+                            text => {
+                                let amount = self
+                                    .format
+                                    .parse_amount(text)
+                                    .ok_or_else(|| format!("{} {} is not an amount", name, text))?;
+                                // Written back as text, which Decimal reads exactly
+                                amount.to_string().into()
+                            }
+                        };
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Each source's format with the PARSING.* overrides applied
+    #[derive(Clone, Debug, Default)]
+    pub struct Formats {
+        overrides: HashMap<String, FormatConfig>,
+    }
+
+    impl Formats {
+        pub fn new(overrides: HashMap<String, FormatConfig>) -> Self {
+            Formats { overrides }
+        }
+
+        pub fn source(&self, source: &str) -> SourceFormat {
+            let mut format = SourceFormat::default();
+            if let Some((_, date_formats)) = DATE_FORMATS.iter().find(|(name, _)| *name == source) {
+                format.date_formats = date_formats.iter().map(|f| f.to_string()).collect();
+            }
+            if let Some(config) = self.overrides.get(source) {
+                if let Some(date_formats) = &config.date_formats {
+                    format.date_formats = date_formats
+                        .split(';')
+                        .map(str::trim)
+                        .filter(|f| !f.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                }
+                if let Some(separator) = &config.thousands_separator {
+                    format.thousands_separator = separator.chars().next();
+                }
+                if let Some(separator) = config
+                    .decimal_separator
+                    .as_ref()
+                    .and_then(|s| s.chars().next())
+                {
+                    format.decimal_separator = separator;
+                }
+                if let Some(bracketed) = config.bracketed_negatives {
+                    format.bracketed_negatives = bracketed;
+                }
+            }
+            format
+        }
+
+        pub fn rows<T: RowFields>(&self, source: &str) -> RowFormat<T> {
+            RowFormat::new(self.source(source))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::models::models::{MpesaStatementInsert, SidianInsert};
+        use serde_json::json;
+
+        fn at(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> NaiveDateTime {
+            NaiveDate::from_ymd(y, m, d).and_hms(h, min, s)
+        }
+
+        fn amount(value: &str) -> Option<Decimal> {
+            SourceFormat::default().parse_amount(value)
+        }
+
+        #[test]
+        fn parse_date_reads_the_source_layouts_and_iso() {
+            let mpesa = Formats::default().source("mpesa");
+            assert_eq!(
+                mpesa.parse_date("03/04/2024 10:15:00"),
+                Some(at(2024, 4, 3, 10, 15, 0))
+            );
+            assert_eq!(
+                mpesa.parse_date(" 2024-04-03T10:15:00 "),
+                Some(at(2024, 4, 3, 10, 15, 0))
+            );
+            assert_eq!(
+                mpesa.parse_date("2024-04-03"),
+                Some(at(2024, 4, 3, 0, 0, 0))
+            );
+
+            let absa = Formats::default().source("absa");
+            assert_eq!(
+                absa.parse_date("03-Apr-2024"),
+                Some(at(2024, 4, 3, 0, 0, 0))
+            );
+            assert_eq!(absa.parse_date("yesterday"), None);
+        }
+
+        #[test]
+        fn parse_date_never_swaps_day_and_month() {
+            let kcb = Formats::default().source("kcb");
+            assert_eq!(kcb.parse_date("03/04/2024"), Some(at(2024, 4, 3, 0, 0, 0)));
+            assert_eq!(kcb.parse_date("13/04/2024"), Some(at(2024, 4, 13, 0, 0, 0)));
+            assert_eq!(kcb.parse_date("04/13/2024"), None);
+        }
+
+        #[test]
+        fn parse_date_reads_two_digit_years_as_this_century() {
+            let sidian = Formats::default().source("sidian");
+            assert_eq!(sidian.parse_date("03/04/24"), Some(at(2024, 4, 3, 0, 0, 0)));
+            // A source without a two digit layout does not read 24 as the year 24
+            let mpesa = Formats::default().source("mpesa");
+            assert_eq!(mpesa.parse_date("03/04/24 10:15:00"), None);
+        }
+
+        #[test]
+        fn parse_date_follows_the_overrides() {
+            let mut overrides = HashMap::new();
+            overrides.insert(
+                "mpesa".to_string(),
+                FormatConfig {
+                    date_formats: Some("%m/%d/%Y %H:%M:%S; ".to_string()),
+                    thousands_separator: Some(".".to_string()),
+                    decimal_separator: Some(",".to_string()),
+                    bracketed_negatives: Some(false),
+                },
+            );
+            let mpesa = Formats::new(overrides).source("mpesa");
+            assert_eq!(mpesa.date_formats, vec!["%m/%d/%Y %H:%M:%S".to_string()]);
+            assert_eq!(
+                mpesa.parse_date("04/13/2024 10:15:00"),
+                Some(at(2024, 4, 13, 10, 15, 0))
+            );
+            assert_eq!(mpesa.parse_amount("1.234,50"), "1234.50".parse().ok());
+            assert_eq!(mpesa.parse_amount("(1.234,50)"), None);
+        }
+
+        #[test]
+        fn parse_amount_reads_separators_and_signs() {
+            assert_eq!(amount("1,234.50"), "1234.50".parse().ok());
+            assert_eq!(amount(" -1234.5 "), "-1234.5".parse().ok());
+            assert_eq!(amount("1 234.50"), "1234.50".parse().ok());
+            assert_eq!(amount("abc"), None);
+            assert_eq!(amount(""), None);
+        }
+
+        #[test]
+        fn parse_amount_reads_dr_and_cr() {
+            assert_eq!(amount("1,234.50 DR"), "-1234.50".parse().ok());
+            assert_eq!(amount("1,234.50dr"), "-1234.50".parse().ok());
+            assert_eq!(amount("1,234.50 CR"), "1234.50".parse().ok());
+        }
+
+        #[test]
+        fn parse_amount_reads_a_trailing_minus() {
+            assert_eq!(amount("1,234.50-"), "-1234.50".parse().ok());
+            assert_eq!(amount("1,234.50 -"), "-1234.50".parse().ok());
+            assert_eq!(amount("-"), None);
+        }
+
+        #[test]
+        fn parse_amount_reads_brackets_as_negative() {
+            assert_eq!(amount("(1,234.50)"), "-1234.50".parse().ok());
+            assert_eq!(amount("(1,234.50) DR"), "-1234.50".parse().ok());
+        }
+
+        #[test]
+        fn parse_amount_drops_currency_prefixes() {
+            assert_eq!(amount("KES 1,234.50"), "1234.50".parse().ok());
+            assert_eq!(amount("Kshs. 1,234.50"), "1234.50".parse().ok());
+            assert_eq!(amount("KSH1,234.50 DR"), "-1234.50".parse().ok());
+        }
+
+        #[test]
+        fn parse_amount_keeps_every_digit() {
+            assert_eq!(
+                amount("12,345,678,901.23456789").map(|a| a.to_string()),
+                Some("12345678901.23456789".to_string())
+            );
+        }
+
+        #[test]
+        fn normalize_rewrites_declared_dates_and_amounts() {
+            let format = Formats::default().rows::<MpesaStatementInsert>("mpesa");
+            let mut row = json!({
+                "completion_time": "03/04/2024 10:15:00",
+                "initiation_time": "03/04/2024 10:14:00",
+                "paid_in": "1,234.50",
+                "withdrawn": "-",
+                "balance": 1500,
+                "details": "03/04/2024",
+            });
+            format.normalize(&mut row).unwrap();
+            assert_eq!(
+                row,
+                json!({
+                    "completion_time": "2024-04-03T10:15:00",
+                    "initiation_time": "2024-04-03T10:14:00",
+                    "paid_in": "1234.50",
+                    "withdrawn": null,
+                    "balance": 1500,
+                    "details": "03/04/2024",
+                })
+            );
+        }
+
+        #[test]
+        fn normalize_names_the_field_that_does_not_parse() {
+            let format = Formats::default().rows::<MpesaStatementInsert>("mpesa");
+
+            let mut row = json!({ "completion_time": "2024/13/45" });
+            let error = format.normalize(&mut row).unwrap_err();
+            assert!(error.starts_with("completion_time 2024/13/45 does not match"));
+
+            let mut row = json!({ "paid_in": "12abc" });
+            let error = format.normalize(&mut row).unwrap_err();
+            assert_eq!(error, "paid_in 12abc is not an amount");
+        }
+
+        #[test]
+        fn normalize_refuses_empty_required_fields() {
+            let format = Formats::default().rows::<MpesaStatementInsert>("mpesa");
+
+            let mut row = json!({ "initiation_time": " " });
+            let error = format.normalize(&mut row).unwrap_err();
+            assert_eq!(error, "initiation_time is empty");
+
+            let format = Formats::default().rows::<SidianInsert>("sidian");
+            let mut row = json!({ "balance": "-" });
+            let error = format.normalize(&mut row).unwrap_err();
+            assert_eq!(error, "balance is empty");
+        }
+
+        #[test]
+        fn normalize_leaves_rows_stored_as_sent() {
+            let format = RowFormat::<serde_json::Value>::default();
+            let mut row = json!({ "completion_time": "03/04/2024 10:15:00" });
+            format.normalize(&mut row).unwrap();
+            assert_eq!(row, json!({ "completion_time": "03/04/2024 10:15:00" }));
+        }
+    }
+}
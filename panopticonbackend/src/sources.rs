@@ -0,0 +1,371 @@
+pub mod sources {
+    use crate::errors::errors::MyError;
+    use crate::streaming::streaming::{CopyIn, CopyRow};
+    use chrono::{NaiveDate, NaiveDateTime};
+    use deadpool_postgres::{Client, Transaction};
+    use rust_decimal::Decimal;
+    use serde::de::DeserializeOwned;
+    use tokio_pg_mapper::FromTokioPostgresRow;
+    use tokio_postgres::types::Type;
+
+    /// The column type a field is copied as. Only the types below can be copied, so a field
+    /// of any other type does not compile. Each field is copied into the staging column of
+    /// its name; the tests check the names and types against the schema
+    pub trait CopyColumn {
+        const TYPE: Type;
+        /// Whether the field may be left empty
+        const NULLABLE: bool = false;
+    }
+
+    impl CopyColumn for String {
+        const TYPE: Type = Type::TEXT;
+    }
+
+    impl CopyColumn for bool {
+        const TYPE: Type = Type::BOOL;
+    }
+
+    impl CopyColumn for i32 {
+        const TYPE: Type = Type::INT4;
+    }
+
+    impl CopyColumn for i64 {
+        const TYPE: Type = Type::INT8;
+    }
+
+    impl CopyColumn for f64 {
+        const TYPE: Type = Type::FLOAT8;
+    }
+
+    impl CopyColumn for Decimal {
+        const TYPE: Type = Type::NUMERIC;
+    }
+
+    impl CopyColumn for NaiveDate {
+        const TYPE: Type = Type::DATE;
+    }
+
+    impl CopyColumn for NaiveDateTime {
+        const TYPE: Type = Type::TIMESTAMP;
+    }
+
+    impl<T: CopyColumn> CopyColumn for Option<T> {
+        const TYPE: Type = T::TYPE;
+        const NULLABLE: bool = true;
+    }
+
+    /// A statement or HIS export: rows are uploaded as `Insert` into the staging table and
+    /// read back as `Self` from the production table. Implemented by `statement_source!`
+    pub trait StatementSource: FromTokioPostgresRow {
+        type Insert: CopyRow + DeserializeOwned;
+
+        const PRODUCTION_TABLE: &'static str;
+        /// Production columns, named after the fields of `Self`
+        const COLUMNS: &'static [&'static str];
+        /// Most rows returned by `get_statement`, None for all
+        const READ_LIMIT: Option<i64>;
+    }
+
+    /// Rows of the production table
+    pub async fn read_statement<S: StatementSource>(client: &Client) -> Result<Vec<S>, MyError> {
+        read_rows(client, None).await
+    }
+
+    /// Rows of the production table whose unit is one of `units`, all rows for None. `unit` is
+    /// the unit column, or an expression over the row
+    pub async fn read_unit_statement<S: StatementSource>(
+        client: &Client,
+        unit: &str,
+        units: Option<&[String]>,
+    ) -> Result<Vec<S>, MyError> {
+        match units {
+            Some(units) => read_rows(client, Some((unit, units))).await,
+            None => read_rows(client, None).await,
+        }
+    }
+
+    async fn read_rows<S: StatementSource>(
+        client: &Client,
+        scope: Option<(&str, &[String])>,
+    ) -> Result<Vec<S>, MyError> {
+        let columns = S::COLUMNS
+            .iter()
+            .map(|column| format!("\"{}\"", column))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let limit = match S::READ_LIMIT {
+            Some(limit) => format!(" limit {}", limit),
+            None => String::new(),
+        };
+        let filter = match scope {
+            Some((unit, _)) => format!(" where {} = any($1)", unit),
+            None => String::new(),
+        };
+        let stmt = format!(
+            "select {} from {}{}{}",
+            columns,
+            S::PRODUCTION_TABLE,
+            filter,
+            limit
+        );
+
+        let rows = match scope {
+            Some((_, units)) => client.query(stmt.as_str(), &[&units]).await?,
+            None => client.query(stmt.as_str(), &[]).await?,
+        };
+        let res = rows
+            .iter()
+            .map(S::from_row_ref)
+            .collect::<Result<Vec<S>, _>>()?;
+        Ok(res)
+    }
+
+    /// COPY rows into their staging table in one transaction
+    pub async fn copy_rows<T: CopyRow>(client: &mut Client, rows: &[T]) -> Result<usize, MyError> {
+        let mut copy = CopyIn::begin(client).await?;
+        for row in rows {
+            copy.write(row).await?;
+        }
+        copy.commit().await
+    }
+
+    /// COPY rows into their staging table inside an open transaction
+    pub async fn copy_rows_in<T: CopyRow>(
+        tx: &mut Transaction<'_>,
+        rows: &[T],
+    ) -> Result<usize, MyError> {
+        let mut copy = CopyIn::begin_in(tx).await?;
+        for row in rows {
+            copy.write(row).await?;
+        }
+        copy.commit().await
+    }
+
+    /// Declare a source from its upload row and its production row. Generates both structs,
+    /// the COPY statement with its column list and types, the upload row's fields for parsing,
+    /// `Insert::update` and `Row::get_statement`. Upload fields written as the source prints
+    /// them are declared `as date` or `as amount`, and fields named unlike their staging column
+    /// `=> column`. Rows tagged with a unit name it after `unit` and also get
+    /// `Row::get_unit_statement`
+    macro_rules! statement_source {
+        (@limit) => {
+            None
+        };
+        (@limit $limit:literal) => {
+            Some($limit)
+        };
+        (@kind) => {
+            $crate::parsing::parsing::FieldKind::Text
+        };
+        (@kind date) => {
+            $crate::parsing::parsing::FieldKind::Date
+        };
+        (@kind amount) => {
+            $crate::parsing::parsing::FieldKind::Amount
+        };
+        (@column $field:ident) => {
+            stringify!($field)
+        };
+        (@column $field:ident $column:ident) => {
+            stringify!($column)
+        };
+        (@columns $first:ident $($first_column:ident)? $(, $rest:ident $($rest_column:ident)?)*) => {
+            concat!(
+                $crate::sources::sources::statement_source!(@column $first $($first_column)?)
+                $(, ", ", $crate::sources::sources::statement_source!(@column $rest $($rest_column)?))*
+            )
+        };
+        (
+            $(#[$insert_meta:meta])*
+            $insert_vis:vis struct $insert:ident in $staging:literal {
+                $(
+                    $(#[$insert_field_meta:meta])*
+                    $insert_field_vis:vis $insert_field:ident : $insert_ty:ty $(as $insert_kind:ident)? $(=> $insert_column:ident)?
+                ),* $(,)?
+            }
+
+            $(#[$row_meta:meta])*
+            $row_vis:vis struct $row:ident in $production:literal $(limit $limit:literal)? $(unit $unit:literal)? {
+                $(
+                    $(#[$row_field_meta:meta])*
+                    $row_field_vis:vis $row_field:ident : $row_ty:ty
+                ),* $(,)?
+            }
+        ) => {
+            $(#[$insert_meta])*
+            #[derive(serde::Deserialize, serde::Serialize, tokio_pg_mapper_derive::PostgresMapper)]
+            #[pg_mapper(table = $staging)]
+            $insert_vis struct $insert {
+                $(
+                    $(#[$insert_field_meta])*
+                    $insert_field_vis $insert_field: $insert_ty,
+                )*
+            }
+
+            impl $crate::streaming::streaming::CopyRow for $insert {
+                const STATEMENT: &'static str = concat!(
+                    "COPY ",
+                    $staging,
+                    " (",
+                    $crate::sources::sources::statement_source!(@columns $($insert_field $($insert_column)?),*),
+                    ") FROM STDIN BINARY"
+                );
+                #[cfg(test)]
+                const TABLE: &'static str = $staging;
+                #[cfg(test)]
+                const COLUMNS: &'static [&'static str] = &[
+                    $($crate::sources::sources::statement_source!(@column $insert_field $($insert_column)?)),*
+                ];
+                const TYPES: &'static [tokio_postgres::types::Type] =
+                    &[$(<$insert_ty as $crate::sources::sources::CopyColumn>::TYPE),*];
+
+                fn copy_row<'a>(
+                    &'a self,
+                    row: &mut Vec<&'a (dyn tokio_postgres::types::ToSql + Sync)>,
+                ) {
+                    $(row.push(&self.$insert_field);)*
+                }
+            }
+
+            impl $crate::parsing::parsing::RowFields for $insert {
+                const FIELDS: &'static [(&'static str, $crate::parsing::parsing::FieldKind, bool)] = &[
+                    $((
+                        stringify!($insert_field),
+                        $crate::sources::sources::statement_source!(@kind $($insert_kind)?),
+                        !<$insert_ty as $crate::sources::sources::CopyColumn>::NULLABLE,
+                    )),*
+                ];
+            }
+
+            impl $insert {
+                /// Copy the rows into the staging table. Returns the number of rows written
+                // Streamed routes and upload jobs copy inside their own transaction instead
+                #[allow(dead_code)]
+                pub async fn update(
+                    client: &mut deadpool_postgres::Client,
+                    data: Vec<$insert>,
+                ) -> Result<usize, $crate::errors::errors::MyError> {
+                    $crate::sources::sources::copy_rows(client, &data).await
+                }
+            }
+
+            $(#[$row_meta])*
+            #[derive(serde::Deserialize, serde::Serialize, tokio_pg_mapper_derive::PostgresMapper)]
+            #[pg_mapper(table = $production)]
+            $row_vis struct $row {
+                $(
+                    $(#[$row_field_meta])*
+                    $row_field_vis $row_field: $row_ty,
+                )*
+            }
+
+            impl $crate::sources::sources::StatementSource for $row {
+                type Insert = $insert;
+
+                const PRODUCTION_TABLE: &'static str = $production;
+                const COLUMNS: &'static [&'static str] = &[$(stringify!($row_field)),*];
+                const READ_LIMIT: Option<i64> = $crate::sources::sources::statement_source!(@limit $($limit)?);
+            }
+
+            impl $row {
+                // Sources that share a table are read per account instead
+                #[allow(dead_code)]
+                pub async fn get_statement(
+                    client: &deadpool_postgres::Client,
+                ) -> Result<Vec<$row>, $crate::errors::errors::MyError> {
+                    $crate::sources::sources::read_statement(client).await
+                }
+            }
+
+            $(
+                impl $row {
+                    /// Rows of the given units, all rows for None
+                    pub async fn get_unit_statement(
+                        client: &deadpool_postgres::Client,
+                        units: Option<&[String]>,
+                    ) -> Result<Vec<$row>, $crate::errors::errors::MyError> {
+                        $crate::sources::sources::read_unit_statement(client, $unit, units).await
+                    }
+                }
+            )?
+        };
+    }
+
+    pub(crate) use statement_source;
+
+    #[cfg(test)]
+    mod tests {
+        use crate::models::models::{
+            ABSAInsert, AirtelStatementInsert, BankStatementInsert, BillDetailsInsert, CfcInsert,
+            CollectionDetailsInsert, LabVisitsInsert, MpesaStatementInsert, MtibaStatementInsert,
+            PdqBreakdownInsert, RegisteredPatientsInsert, SidianInsert, UploadRow,
+        };
+        use crate::streaming::streaming::CopyRow;
+        use crate::test_database::test_database;
+        use deadpool_postgres::Client;
+
+        // Every copied column exists with the field's type, and the columns left out can be
+        // left null
+        async fn check<T: CopyRow>(client: &Client) -> Vec<String> {
+            let (schema, table) = T::TABLE.split_once('.').unwrap();
+            let columns = client
+                .query(
+                    "select column_name::text, udt_name::text, is_nullable = 'YES' or column_default is not null or is_identity = 'YES' from information_schema.columns where table_schema = $1 and table_name = $2",
+                    &[&schema, &table],
+                )
+                .await
+                .unwrap();
+            assert!(!columns.is_empty(), "{} does not exist", T::TABLE);
+            assert_eq!(T::COLUMNS.len(), T::TYPES.len());
+
+            let mut errors = Vec::new();
+            for (name, ty) in T::COLUMNS.iter().zip(T::TYPES) {
+                match columns.iter().find(|c| c.get::<_, String>(0) == *name) {
+                    Some(column) if column.get::<_, String>(1) != ty.name() => {
+                        errors.push(format!(
+                            "{}.{} is {}, the field is {}",
+                            T::TABLE,
+                            name,
+                            column.get::<_, String>(1),
+                            ty.name()
+                        ))
+                    }
+                    Some(_) => {}
+                    None => errors.push(format!("{} has no column {}", T::TABLE, name)),
+                }
+            }
+            for column in columns.iter() {
+                let name: String = column.get(0);
+                if !column.get::<_, bool>(2) && !T::COLUMNS.contains(&name.as_str()) {
+                    errors.push(format!("{}.{} is required but not copied", T::TABLE, name));
+                }
+            }
+            errors
+        }
+
+        #[actix_web::test]
+        #[ignore = "needs TEST_DATABASE_URL"]
+        async fn copied_fields_match_the_schema() {
+            let pool = test_database::pool().await;
+            let client = pool.get().await.unwrap();
+
+            let errors = [
+                check::<MpesaStatementInsert>(&client).await,
+                check::<AirtelStatementInsert>(&client).await,
+                check::<CollectionDetailsInsert>(&client).await,
+                check::<BillDetailsInsert>(&client).await,
+                check::<LabVisitsInsert>(&client).await,
+                check::<RegisteredPatientsInsert>(&client).await,
+                check::<MtibaStatementInsert>(&client).await,
+                check::<ABSAInsert>(&client).await,
+                check::<PdqBreakdownInsert>(&client).await,
+                check::<SidianInsert>(&client).await,
+                check::<CfcInsert>(&client).await,
+                check::<BankStatementInsert>(&client).await,
+                check::<UploadRow>(&client).await,
+            ]
+            .concat();
+            assert!(errors.is_empty(), "{}", errors.join("\n"));
+        }
+    }
+}
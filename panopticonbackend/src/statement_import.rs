@@ -0,0 +1,550 @@
+pub mod statement_import {
+    use crate::errors::errors::MyError;
+    use crate::models::models::{currency_code, BankAccount, BankStatementInsert};
+    use crate::parsing::parsing::SourceFormat;
+    use calamine::{open_workbook_auto_from_rs, Data, Reader};
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+    use rust_decimal::Decimal;
+    use std::io::Cursor;
+
+    // Rows searched for the header. Exports start with the account name, period etc.
+    const MAX_PREAMBLE_ROWS: usize = 30;
+
+    /// The header names a bank's export may use for each column. Names are compared
+    /// without case, spaces or punctuation. A bank either has debit and credit columns or a
+    /// single signed amount
+    struct ExportLayout {
+        date: &'static [&'static str],
+        value_date: &'static [&'static str],
+        narration: &'static [&'static str],
+        reference: &'static [&'static str],
+        debit: &'static [&'static str],
+        credit: &'static [&'static str],
+        amount: &'static [&'static str],
+        balance: &'static [&'static str],
+    }
+
+    const EQUITY: ExportLayout = ExportLayout {
+        date: &["transactiondate", "trandate", "date"],
+        value_date: &["valuedate"],
+        narration: &[
+            "narrative",
+            "description",
+            "transactiondetails",
+            "particulars",
+        ],
+        reference: &["transactionreference", "reference", "chequenumber", "refno"],
+        debit: &["debit", "moneyout", "withdrawals", "debitamount"],
+        credit: &["credit", "moneyin", "deposits", "creditamount"],
+        amount: &["amount"],
+        balance: &["runningbalance", "balance", "closingbalance"],
+    };
+
+    const KCB: ExportLayout = ExportLayout {
+        date: &["transactiondate", "txndate", "postingdate", "date"],
+        value_date: &["valuedate"],
+        narration: &[
+            "transactiondetails",
+            "description",
+            "narration",
+            "narrative",
+        ],
+        reference: &[
+            "bankreferencenumber",
+            "referencenumber",
+            "reference",
+            "chequenumber",
+        ],
+        debit: &["moneyout", "debit", "withdrawals", "debitamount"],
+        credit: &["moneyin", "credit", "deposits", "creditamount"],
+        amount: &["amount", "transactionamount"],
+        balance: &["ledgerbalance", "balance", "runningbalance"],
+    };
+
+    const COOP: ExportLayout = ExportLayout {
+        date: &["transactiondate", "postingdate", "trandate", "date"],
+        value_date: &["valuedate"],
+        narration: &[
+            "narrative",
+            "transactiondetails",
+            "description",
+            "narration",
+        ],
+        reference: &["reference", "refno", "referenceno", "chequeno"],
+        debit: &["debit", "debitamount", "withdrawals"],
+        credit: &["credit", "creditamount", "deposits"],
+        amount: &["amount"],
+        balance: &["balance", "bookbalance", "runningbalance"],
+    };
+
+    const NCBA: ExportLayout = ExportLayout {
+        date: &["transactiondate", "trandate", "postingdate", "date"],
+        value_date: &["valuedate"],
+        narration: &[
+            "description",
+            "narration",
+            "transactiondetails",
+            "narrative",
+        ],
+        reference: &[
+            "reference",
+            "chequeno",
+            "referenceno",
+            "transactionreference",
+        ],
+        debit: &["debit", "withdrawals", "debitamount"],
+        credit: &["credit", "deposits", "creditamount"],
+        amount: &["amount", "transactionamount"],
+        balance: &["balance", "closingbalance", "runningbalance"],
+    };
+
+    fn layout(bank: BankAccount) -> Result<&'static ExportLayout, MyError> {
+        match bank {
+            BankAccount::Equity => Ok(&EQUITY),
+            BankAccount::Kcb => Ok(&KCB),
+            BankAccount::Coop => Ok(&COOP),
+            BankAccount::Ncba => Ok(&NCBA),
+            other => Err(MyError::BadRequest(format!(
+                "There is no statement import for {}. Upload its rows as JSON",
+                other.name()
+            ))),
+        }
+    }
+
+    // Where each column of the layout sits in the export
+    struct Columns {
+        date: usize,
+        value_date: Option<usize>,
+        narration: Option<usize>,
+        reference: Option<usize>,
+        debit: Option<usize>,
+        credit: Option<usize>,
+        amount: Option<usize>,
+        balance: Option<usize>,
+    }
+
+    impl Columns {
+        // None unless the row has a date column and a way to read the amount
+        fn find(layout: &ExportLayout, header: &[String]) -> Option<Columns> {
+            let names: Vec<String> = header.iter().map(|name| normalize(name)).collect();
+            let position = |aliases: &[&str]| {
+                aliases
+                    .iter()
+                    .find_map(|alias| names.iter().position(|name| name == alias))
+            };
+            let columns = Columns {
+                date: position(layout.date)?,
+                value_date: position(layout.value_date),
+                narration: position(layout.narration),
+                reference: position(layout.reference),
+                debit: position(layout.debit),
+                credit: position(layout.credit),
+                amount: position(layout.amount),
+                balance: position(layout.balance),
+            };
+            let has_amount =
+                (columns.debit.is_some() && columns.credit.is_some()) || columns.amount.is_some();
+            has_amount.then_some(columns)
+        }
+    }
+
+    /// Read a bank's CSV or Excel export into statement rows. Workbooks are read from their
+    /// first sheet. The header is found past any preamble. Rows without a date or without an
+    /// amount (opening and closing balance lines, totals) are skipped. Rows are in `currency`,
+    /// KES when not given. Dates and amounts are read with the bank's `format`
+    pub fn parse_export(
+        bank: BankAccount,
+        body: &[u8],
+        currency: Option<&str>,
+        format: &SourceFormat,
+    ) -> Result<Vec<BankStatementInsert>, MyError> {
+        let layout = layout(bank)?;
+        let currency = currency.map(currency_code).transpose()?;
+        // xlsx and ods are zip archives, xls an OLE compound file
+        let records = if body.starts_with(b"PK\x03\x04") || body.starts_with(b"\xD0\xCF\x11\xE0") {
+            read_workbook(body)?
+        } else {
+            read_records(&decode(body))
+        };
+
+        let (header_index, columns) = records
+            .iter()
+            .take(MAX_PREAMBLE_ROWS)
+            .enumerate()
+            .find_map(|(index, record)| Columns::find(layout, record).map(|c| (index, c)))
+            .ok_or_else(|| {
+                MyError::BadRequest(format!(
+                    "No header row found in the first {} rows. Expected a {} statement export \
+                     with a date column and debit and credit (or amount) columns",
+                    MAX_PREAMBLE_ROWS,
+                    bank.name()
+                ))
+            })?;
+
+        let mut rows = Vec::new();
+        for (index, record) in records.iter().enumerate().skip(header_index + 1) {
+            let line = index + 1;
+            let cell = |column: Option<usize>| {
+                column
+                    .and_then(|column| record.get(column))
+                    .map(|value| value.trim())
+                    .filter(|value| !value.is_empty())
+            };
+            // A lone dash is printed for no amount
+            let read_amount = |column: Option<usize>| {
+                cell(column)
+                    .filter(|value| *value != "-")
+                    .map(|value| {
+                        format.parse_amount(value).ok_or_else(|| {
+                            MyError::BadRequest(format!("Row {}: invalid amount {}", line, value))
+                        })
+                    })
+                    .transpose()
+            };
+
+            let date = match cell(Some(columns.date)) {
+                Some(date) => date,
+                None => continue,
+            };
+            let split = columns.debit.is_some() && columns.credit.is_some();
+            let (debit, credit) = match columns.amount.filter(|_| !split) {
+                Some(amount) => match read_amount(Some(amount))? {
+                    Some(amount) if amount < Decimal::ZERO => (Some(-amount), None),
+                    Some(amount) => (None, Some(amount)),
+                    None => (None, None),
+                },
+                None => (
+                    read_amount(columns.debit)?.map(|debit| debit.abs()),
+                    read_amount(columns.credit)?.map(|credit| credit.abs()),
+                ),
+            };
+            if debit.is_none() && credit.is_none() {
+                continue;
+            }
+
+            let transaction_date = format.parse_date(date).ok_or_else(|| {
+                MyError::BadRequest(format!("Row {}: invalid date {}", line, date))
+            })?;
+            let value_date = match cell(columns.value_date) {
+                Some(value) => Some(format.parse_date(value).ok_or_else(|| {
+                    MyError::BadRequest(format!("Row {}: invalid value date {}", line, value))
+                })?),
+                None => None,
+            };
+
+            rows.push(BankStatementInsert {
+                bank: bank.name().to_string(),
+                transaction_date,
+                value_date,
+                narration: cell(columns.narration).map(str::to_string),
+                reference: cell(columns.reference).map(str::to_string),
+                debit,
+                credit,
+                balance: read_amount(columns.balance)?,
+                currency: currency.clone(),
+            });
+        }
+        Ok(rows)
+    }
+
+    // The first sheet as text, the way a CSV export writes it. Date cells become ISO dates
+    fn read_workbook(body: &[u8]) -> Result<Vec<Vec<String>>, MyError> {
+        let unreadable = |e: calamine::Error| {
+            MyError::BadRequest(format!("The workbook could not be read: {}", e))
+        };
+        let mut workbook = open_workbook_auto_from_rs(Cursor::new(body)).map_err(unreadable)?;
+        let sheet = workbook
+            .worksheet_range_at(0)
+            .ok_or_else(|| MyError::BadRequest("The workbook has no sheets".to_string()))?
+            .map_err(unreadable)?;
+
+        let cell = |data: &Data| match data {
+            Data::DateTime(date) => match excel_date(date.as_f64()) {
+                Some(date) => date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                None => date.to_string(),
+            },
+            Data::Empty | Data::Error(_) => String::new(),
+            data => data.to_string(),
+        };
+        Ok(sheet
+            .rows()
+            .map(|row| row.iter().map(cell).collect())
+            .collect())
+    }
+
+    // Excel stores dates as days since 1899-12-30, counting the 29th of February 1900 it
+    // believes in. Workbooks in the 1904 date system are not expected from banks
+    fn excel_date(serial: f64) -> Option<NaiveDateTime> {
+        let serial = if serial < 60.0 { serial + 1.0 } else { serial };
+        NaiveDate::from_ymd(1899, 12, 30)
+            .and_hms(0, 0, 0)
+            .checked_add_signed(Duration::milliseconds(
+                (serial * 86_400_000.0).round() as i64
+            ))
+    }
+
+    // Exports are UTF-8 or, from older Windows tools, Latin-1
+    fn decode(body: &[u8]) -> String {
+        let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+        match std::str::from_utf8(body) {
+            Ok(text) => text.to_string(),
+            Err(_) => body.iter().map(|&byte| byte as char).collect(),
+        }
+    }
+
+    fn normalize(name: &str) -> String {
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    }
+
+    /// Split CSV text into records. Fields may be quoted, with "" for a quote inside, and
+    /// quoted fields may span lines, which end in \n inside the field. The delimiter is
+    /// whichever of , ; or tab is on the most of the first lines, then fills the widest one.
+    /// Amounts like 1,500.00 put commas on the rows of a ; or tab export, but not its header
+    fn read_records(text: &str) -> Vec<Vec<String>> {
+        let spread = |delimiter: char| {
+            let counts: Vec<usize> = text
+                .lines()
+                .take(MAX_PREAMBLE_ROWS)
+                .map(|line| line.matches(delimiter).count())
+                .collect();
+            let lines = counts.iter().filter(|count| **count > 0).count();
+            (lines, counts.into_iter().max().unwrap_or(0))
+        };
+        // max_by_key keeps the last of equals, so a comma wins ties
+        let delimiter = ['\t', ';', ',']
+            .into_iter()
+            .max_by_key(|delimiter| spread(*delimiter))
+            .unwrap_or(',');
+
+        let mut records = Vec::new();
+        let mut record = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if quoted {
+                match c {
+                    '"' if chars.peek() == Some(&'"') => {
+                        field.push('"');
+                        chars.next();
+                    }
+                    '"' => quoted = false,
+                    '\r' if chars.peek() == Some(&'\n') => {}
+                    _ => field.push(c),
+                }
+                continue;
+            }
+            match c {
+                '"' => quoted = true,
+                '\r' => {}
+                '\n' => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                c if c == delimiter => record.push(std::mem::take(&mut field)),
+                _ => field.push(c),
+            }
+        }
+        if !field.is_empty() || !record.is_empty() {
+            record.push(field);
+            records.push(record);
+        }
+        records
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::parsing::parsing::Formats;
+
+        fn import(bank: BankAccount, body: &[u8]) -> Vec<BankStatementInsert> {
+            parse_export(bank, body, None, &Formats::default().source(bank.name())).unwrap()
+        }
+
+        fn date(y: i32, m: u32, d: u32) -> NaiveDateTime {
+            NaiveDate::from_ymd(y, m, d).and_hms(0, 0, 0)
+        }
+
+        fn amount(value: &str) -> Option<Decimal> {
+            Some(value.parse().unwrap())
+        }
+
+        fn header(names: &[&str]) -> Vec<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        }
+
+        #[test]
+        fn reads_an_equity_export() {
+            let rows = import(
+                BankAccount::Equity,
+                include_bytes!("../fixtures/statements/equity.csv"),
+            );
+
+            // The opening balance and totals lines are skipped
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0].bank, "equity");
+            assert_eq!(rows[0].transaction_date, date(2024, 3, 2));
+            assert_eq!(
+                rows[0].narration.as_deref(),
+                Some("MPESA SETTLEMENT\nPAYBILL 600638")
+            );
+            assert_eq!(rows[0].reference.as_deref(), Some("FT24062ABC12"));
+            assert_eq!(rows[0].debit, None);
+            assert_eq!(rows[0].credit, amount("25000.00"));
+            assert_eq!(rows[0].balance, amount("35000.00"));
+            assert_eq!(rows[1].value_date, Some(date(2024, 3, 5)));
+            assert_eq!(rows[1].narration.as_deref(), Some("CHEQUE \"1042\" PAID"));
+            assert_eq!(rows[1].debit, amount("4500.50"));
+            assert_eq!(rows[1].credit, None);
+        }
+
+        #[test]
+        fn reads_a_latin1_kcb_export() {
+            let rows = import(
+                BankAccount::Kcb,
+                include_bytes!("../fixtures/statements/kcb.csv"),
+            );
+
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0].transaction_date, date(2024, 3, 12));
+            assert_eq!(
+                rows[0].narration.as_deref(),
+                Some("Payment to Café Nairobi")
+            );
+            assert_eq!(rows[0].debit, amount("1250.00"));
+            assert_eq!(rows[0].balance, amount("8750.00"));
+            assert_eq!(rows[1].value_date, Some(date(2024, 3, 14)));
+            assert_eq!(rows[1].narration.as_deref(), Some("Deposit, branch 012"));
+            assert_eq!(rows[1].credit, amount("3000.00"));
+        }
+
+        #[test]
+        fn reads_a_coop_export_with_signed_amounts() {
+            let rows = import(
+                BankAccount::Coop,
+                include_bytes!("../fixtures/statements/coop.tsv"),
+            );
+
+            // The row with a dash for its amount is skipped
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0].narration.as_deref(), Some("POS SETTLEMENT, CARD"));
+            assert_eq!(rows[0].credit, amount("12000.00"));
+            assert_eq!(rows[0].debit, None);
+            assert_eq!(rows[1].debit, amount("350.00"));
+            assert_eq!(rows[1].credit, None);
+            assert_eq!(rows[1].balance, amount("51650.00"));
+        }
+
+        #[test]
+        fn reads_an_ncba_workbook() {
+            let rows = import(
+                BankAccount::Ncba,
+                include_bytes!("../fixtures/statements/ncba.xlsx"),
+            );
+
+            // Date cells and dates typed as text are both read
+            assert_eq!(rows.len(), 2);
+            assert_eq!(rows[0].transaction_date, date(2024, 3, 18));
+            assert_eq!(rows[0].credit, amount("7800.5"));
+            assert_eq!(rows[0].balance, amount("27800.5"));
+            assert_eq!(rows[1].transaction_date, date(2024, 3, 19));
+            assert_eq!(rows[1].value_date, Some(date(2024, 3, 20)));
+            assert_eq!(rows[1].reference.as_deref(), Some("NCBA02"));
+            assert_eq!(rows[1].debit, amount("30"));
+        }
+
+        #[test]
+        fn refuses_an_export_without_a_header() {
+            let body = b"Equity Bank\nDate,Details\n01/03/2024,Opening\n";
+            let format = Formats::default().source("equity");
+            assert!(parse_export(BankAccount::Equity, body, None, &format).is_err());
+            assert!(parse_export(BankAccount::Absa, b"", None, &format).is_err());
+        }
+
+        #[test]
+        fn read_records_splits_quoted_fields() {
+            let records = read_records("a,\"b, c\",\"say \"\"hi\"\"\"\r\n\"two\r\nlines\",,x");
+            assert_eq!(
+                records,
+                vec![
+                    header(&["a", "b, c", "say \"hi\""]),
+                    header(&["two\nlines", "", "x"]),
+                ]
+            );
+        }
+
+        #[test]
+        fn read_records_sniffs_the_delimiter() {
+            assert_eq!(
+                read_records("Date;Amount;Balance\n01/03/2024;1,500.00;2,000.00\n")[1],
+                header(&["01/03/2024", "1,500.00", "2,000.00"])
+            );
+            assert_eq!(
+                read_records("Date\tNarrative\n01/03/2024\tA, B\n")[1],
+                header(&["01/03/2024", "A, B"])
+            );
+            // A comma wins ties
+            assert_eq!(read_records("a,b;c\n")[0], header(&["a", "b;c"]));
+        }
+
+        #[test]
+        fn decode_falls_back_to_latin1() {
+            assert_eq!(decode(b"Caf\xe9"), "Café");
+            assert_eq!(decode("Café".as_bytes()), "Café");
+            assert_eq!(decode(b"\xEF\xBB\xBFDate"), "Date");
+        }
+
+        #[test]
+        fn columns_find_matches_aliases_without_case_or_punctuation() {
+            let columns = Columns::find(
+                &KCB,
+                &header(&[
+                    "TXN DATE",
+                    "Value-Date",
+                    "Transaction Details",
+                    "Money Out",
+                    "Money In",
+                    "Ledger Balance",
+                ]),
+            )
+            .unwrap();
+            assert_eq!(columns.date, 0);
+            assert_eq!(columns.value_date, Some(1));
+            assert_eq!(columns.narration, Some(2));
+            assert_eq!(columns.reference, None);
+            assert_eq!(columns.debit, Some(3));
+            assert_eq!(columns.credit, Some(4));
+            assert_eq!(columns.balance, Some(5));
+        }
+
+        #[test]
+        fn columns_find_prefers_earlier_aliases() {
+            // Transaction date is listed before date
+            let columns =
+                Columns::find(&EQUITY, &header(&["Date", "Transaction Date", "Amount"])).unwrap();
+            assert_eq!(columns.date, 1);
+            assert_eq!(columns.amount, Some(2));
+        }
+
+        #[test]
+        fn columns_find_needs_a_date_and_an_amount() {
+            assert!(Columns::find(&COOP, &header(&["Narrative", "Debit", "Credit"])).is_none());
+            assert!(Columns::find(&COOP, &header(&["Date", "Narrative", "Debit"])).is_none());
+            assert!(Columns::find(&COOP, &header(&["Date", "Debit", "Credit"])).is_some());
+            assert!(Columns::find(&NCBA, &header(&["Date", "Transaction Amount"])).is_some());
+        }
+
+        #[test]
+        fn excel_dates_count_from_1899() {
+            assert_eq!(excel_date(45369.0), Some(date(2024, 3, 18)));
+            assert_eq!(excel_date(1.0), Some(date(1900, 1, 1)));
+            assert_eq!(
+                excel_date(45369.5),
+                Some(NaiveDate::from_ymd(2024, 3, 18).and_hms(12, 0, 0))
+            );
+        }
+    }
+}
@@ -0,0 +1,443 @@
+pub mod streaming {
+    use crate::configs::config::UploadLimits;
+    use crate::errors::errors::MyError;
+    use crate::parsing::parsing::{RowFields, RowFormat};
+    use actix_web::web;
+    use deadpool_postgres::{Client, Transaction};
+    use futures_util::StreamExt;
+    use serde::de::DeserializeOwned;
+    use std::collections::VecDeque;
+    use std::marker::PhantomData;
+    use std::pin::Pin;
+    use tokio_postgres::binary_copy::BinaryCopyInWriter;
+    use tokio_postgres::types::{ToSql, Type};
+
+    /// A row that can be COPYed into its staging table
+    pub trait CopyRow {
+        /// The `COPY table (columns) FROM STDIN BINARY` statement
+        const STATEMENT: &'static str;
+        /// The table copied into, for the schema tests
+        #[cfg(test)]
+        const TABLE: &'static str;
+        /// Columns in the order of `copy_row`, as listed in `STATEMENT`
+        #[cfg(test)]
+        const COLUMNS: &'static [&'static str];
+        /// Column types in the order of `copy_row`
+        const TYPES: &'static [Type];
+
+        fn copy_row<'a>(&'a self, row: &mut Vec<&'a (dyn ToSql + Sync)>);
+    }
+
+    /// A COPY into a staging table inside its own transaction, written a row at a time
+    pub struct CopyIn<'a, T> {
+        tx: Transaction<'a>,
+        writer: Pin<Box<BinaryCopyInWriter>>,
+        rows: usize,
+        row: PhantomData<T>,
+    }
+
+    impl<'a, T: CopyRow> CopyIn<'a, T> {
+        pub async fn begin(client: &'a mut Client) -> Result<CopyIn<'a, T>, MyError> {
+            CopyIn::start(client.transaction().await?).await
+        }
+
+        /// A COPY inside a transaction that is already open. Committing it only releases its
+        /// savepoint, so the rows still go with the outer transaction
+        pub async fn begin_in(tx: &'a mut Transaction<'_>) -> Result<CopyIn<'a, T>, MyError> {
+            CopyIn::start(tx.transaction().await?).await
+        }
+
+        async fn start(tx: Transaction<'a>) -> Result<CopyIn<'a, T>, MyError> {
+            let statement = tx.prepare(T::STATEMENT).await?;
+
+            let sink = tx.copy_in(&statement).await?;
+
+            Ok(CopyIn {
+                tx,
+                writer: Box::pin(BinaryCopyInWriter::new(sink, T::TYPES)),
+                rows: 0,
+                row: PhantomData,
+            })
+        }
+
+        pub async fn write(&mut self, value: &T) -> Result<(), MyError> {
+            let mut row = Vec::with_capacity(T::TYPES.len());
+            value.copy_row(&mut row);
+
+            self.writer.as_mut().write(&row).await?;
+            self.rows += 1;
+            Ok(())
+        }
+
+        /// End the COPY and return the open transaction with the number of rows written, so
+        /// checks that need every row can still roll the upload back
+        pub async fn finish(mut self) -> Result<(Transaction<'a>, usize), MyError> {
+            self.writer.as_mut().finish().await?;
+            Ok((self.tx, self.rows))
+        }
+
+        pub async fn commit(self) -> Result<usize, MyError> {
+            let (tx, rows) = self.finish().await?;
+            tx.commit().await?;
+            Ok(rows)
+        }
+    }
+
+    /// Rows read one at a time from a request body holding either a JSON array of objects or
+    /// one object per line (NDJSON). Only the row being read is held in memory
+    pub struct JsonRows<T> {
+        payload: web::Payload,
+        splitter: RowSplitter,
+        ready: VecDeque<Vec<u8>>,
+        ended: bool,
+        rows_read: usize,
+        bytes_read: usize,
+        max_body_bytes: usize,
+        format: RowFormat<T>,
+    }
+
+    impl<T: DeserializeOwned + RowFields> JsonRows<T> {
+        /// Dates and amounts are read with `format` before the row is deserialized
+        pub fn new(
+            payload: web::Payload,
+            format: RowFormat<T>,
+            limits: &UploadLimits,
+        ) -> JsonRows<T> {
+            JsonRows {
+                payload,
+                splitter: RowSplitter::new(limits.max_row_bytes),
+                ready: VecDeque::new(),
+                ended: false,
+                rows_read: 0,
+                bytes_read: 0,
+                max_body_bytes: limits.max_body_bytes,
+                format,
+            }
+        }
+
+        /// The next row, or None at the end of the body
+        pub async fn next(&mut self) -> Result<Option<T>, MyError> {
+            loop {
+                if let Some(bytes) = self.ready.pop_front() {
+                    self.rows_read += 1;
+                    return self.read(&bytes).map(Some).map_err(|e| {
+                        MyError::BadRequest(format!("Row {}: {}", self.rows_read, e))
+                    });
+                }
+                if self.ended {
+                    return Ok(None);
+                }
+                match self.payload.next().await {
+                    Some(chunk) => {
+                        let chunk = chunk.map_err(|e| MyError::BadRequest(e.to_string()))?;
+                        self.bytes_read += chunk.len();
+                        if self.bytes_read > self.max_body_bytes {
+                            return Err(body_too_large(self.max_body_bytes));
+                        }
+                        self.splitter.push(&chunk, &mut self.ready)?;
+                    }
+                    None => {
+                        self.ended = true;
+                        self.splitter.finish()?;
+                    }
+                }
+            }
+        }
+
+        fn read(&self, bytes: &[u8]) -> Result<T, String> {
+            let mut row: serde_json::Value =
+                serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+            self.format.normalize(&mut row)?;
+            T::deserialize(row).map_err(|e| e.to_string())
+        }
+    }
+
+    /// Read a whole body, for exports like workbooks that cannot be read a row at a time.
+    /// Held to the same limit as the streamed uploads
+    pub async fn read_body(
+        mut payload: web::Payload,
+        limits: &UploadLimits,
+    ) -> Result<web::BytesMut, MyError> {
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| MyError::BadRequest(e.to_string()))?;
+            if body.len() + chunk.len() > limits.max_body_bytes {
+                return Err(body_too_large(limits.max_body_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    fn body_too_large(max_body_bytes: usize) -> MyError {
+        MyError::PayloadTooLarge(format!("The body is over {} bytes", max_body_bytes))
+    }
+
+    // What the splitter accepts next outside a row
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Expect {
+        // The start of the body: [ or the first NDJSON row
+        Body,
+        // The first row of an array, or ] when it is empty
+        FirstRow,
+        // A row after a comma
+        Row,
+        // , or ] after a row of an array
+        Separator,
+        // A new line after an NDJSON row
+        LineEnd,
+        // The next NDJSON row
+        Line,
+        // Nothing but whitespace after ]
+        End,
+    }
+
+    impl Expect {
+        fn describe(self) -> &'static str {
+            match self {
+                Expect::Body => "a JSON array of objects or one object per line",
+                Expect::FirstRow => "an object or ]",
+                Expect::Row => "an object after ,",
+                Expect::Separator => ", or ] after a row",
+                Expect::LineEnd => "a new line after a row",
+                Expect::Line => "an object",
+                Expect::End => "nothing after the closing ]",
+            }
+        }
+    }
+
+    // Cuts a body into its top level objects without parsing them. Brackets are only matched
+    // and counted, serde reads what is inside a row
+    struct RowSplitter {
+        max_row_bytes: usize,
+        expect: Expect,
+        array: bool,
+        rows: usize,
+        current: Vec<u8>,
+        // Brackets open in the current row, innermost last
+        open: Vec<u8>,
+        in_string: bool,
+        escaped: bool,
+    }
+
+    impl RowSplitter {
+        fn new(max_row_bytes: usize) -> RowSplitter {
+            RowSplitter {
+                max_row_bytes,
+                expect: Expect::Body,
+                array: false,
+                rows: 0,
+                current: Vec::new(),
+                open: Vec::new(),
+                in_string: false,
+                escaped: false,
+            }
+        }
+
+        fn push(&mut self, chunk: &[u8], ready: &mut VecDeque<Vec<u8>>) -> Result<(), MyError> {
+            for &byte in chunk {
+                if !self.open.is_empty() {
+                    self.push_in_row(byte, ready)?;
+                    continue;
+                }
+
+                // Between rows
+                self.expect = match (self.expect, byte) {
+                    (Expect::LineEnd, b'\n') => Expect::Line,
+                    (expect, b' ' | b'\t' | b'\r' | b'\n') => expect,
+                    (Expect::Body, b'[') => {
+                        self.array = true;
+                        Expect::FirstRow
+                    }
+                    (Expect::Body | Expect::Line | Expect::FirstRow | Expect::Row, b'{') => {
+                        self.current.push(byte);
+                        self.open.push(byte);
+                        self.expect
+                    }
+                    (Expect::Separator, b',') => Expect::Row,
+                    (Expect::FirstRow | Expect::Separator, b']') => Expect::End,
+                    (Expect::Row, b']') => {
+                        return Err(MyError::BadRequest(format!(
+                            "A trailing , after row {} before the closing ]",
+                            self.rows
+                        )))
+                    }
+                    (expect, _) => {
+                        return Err(MyError::BadRequest(format!(
+                            "Unexpected '{}' after row {}, expected {}",
+                            byte as char,
+                            self.rows,
+                            expect.describe()
+                        )))
+                    }
+                };
+            }
+            Ok(())
+        }
+
+        fn push_in_row(&mut self, byte: u8, ready: &mut VecDeque<Vec<u8>>) -> Result<(), MyError> {
+            self.current.push(byte);
+            if self.current.len() > self.max_row_bytes {
+                return Err(MyError::PayloadTooLarge(format!(
+                    "Row {} is over {} bytes",
+                    self.rows + 1,
+                    self.max_row_bytes
+                )));
+            }
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+                return Ok(());
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.open.push(byte),
+                b'}' | b']' => {
+                    let opening = if byte == b'}' { b'{' } else { b'[' };
+                    if self.open.pop() != Some(opening) {
+                        return Err(MyError::BadRequest(format!(
+                            "Row {} closes a bracket it did not open with '{}'",
+                            self.rows + 1,
+                            byte as char
+                        )));
+                    }
+                    if self.open.is_empty() {
+                        self.rows += 1;
+                        self.expect = if self.array {
+                            Expect::Separator
+                        } else {
+                            Expect::LineEnd
+                        };
+                        ready.push_back(std::mem::take(&mut self.current));
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn finish(&self) -> Result<(), MyError> {
+            if !self.open.is_empty() {
+                return Err(MyError::BadRequest(format!(
+                    "The body ended in the middle of row {}",
+                    self.rows + 1
+                )));
+            }
+            match self.expect {
+                Expect::FirstRow | Expect::Row | Expect::Separator => Err(MyError::BadRequest(
+                    "The body ended before the closing ]".to_string(),
+                )),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Feeds the body in chunks of `size` bytes and returns the rows or the error
+        fn split(body: &str, size: usize) -> Result<Vec<String>, MyError> {
+            let mut splitter = RowSplitter::new(64);
+            let mut ready = VecDeque::new();
+            for chunk in body.as_bytes().chunks(size) {
+                splitter.push(chunk, &mut ready)?;
+            }
+            splitter.finish()?;
+            Ok(ready
+                .into_iter()
+                .map(|row| String::from_utf8(row).unwrap())
+                .collect())
+        }
+
+        fn rejected(body: &str) -> bool {
+            matches!(split(body, 1), Err(MyError::BadRequest(_)))
+                && matches!(split(body, body.len()), Err(MyError::BadRequest(_)))
+        }
+
+        #[test]
+        fn splits_an_array_across_chunks() {
+            let body = r#" [ {"a":1} , {"b":[1,{"c":2}]} ]
+"#;
+            for size in 1..=body.len() {
+                assert_eq!(
+                    split(body, size).unwrap(),
+                    vec![r#"{"a":1}"#, r#"{"b":[1,{"c":2}]}"#]
+                );
+            }
+            assert!(split("[]", 1).unwrap().is_empty());
+            assert!(split("", 1).unwrap().is_empty());
+        }
+
+        #[test]
+        fn splits_lines_across_chunks() {
+            let body = "{\"a\":1}\r\n\n{\"b\":2}\n{\"c\":3}";
+            for size in 1..=body.len() {
+                assert_eq!(
+                    split(body, size).unwrap(),
+                    vec![r#"{"a":1}"#, r#"{"b":2}"#, r#"{"c":3}"#]
+                );
+            }
+        }
+
+        #[test]
+        fn ignores_brackets_and_escaped_quotes_in_strings() {
+            let body = r#"[{"a":"}]\"{[,"},{"b":"\\"}]"#;
+            for size in 1..=body.len() {
+                assert_eq!(
+                    split(body, size).unwrap(),
+                    vec![r#"{"a":"}]\"{[,"}"#, r#"{"b":"\\"}"#]
+                );
+            }
+        }
+
+        #[test]
+        fn refuses_missing_and_extra_commas() {
+            assert!(rejected(r#"[{"a":1}{"a":2}]"#));
+            assert!(rejected(r#"[,{"a":1}]"#));
+            assert!(rejected(r#"[{"a":1},]"#));
+            assert!(rejected(r#"[{"a":1},,{"a":2}]"#));
+            assert!(rejected(r#"{"a":1}{"a":2}"#));
+            assert!(rejected(
+                r#"{"a":1},
+{"a":2}"#
+            ));
+        }
+
+        #[test]
+        fn refuses_mismatched_brackets() {
+            assert!(rejected(r#"[{"a":1]]"#));
+            assert!(rejected(r#"[{"a":[1}}]"#));
+        }
+
+        #[test]
+        fn refuses_truncated_bodies() {
+            assert!(rejected(r#"[{"a":1}"#));
+            assert!(rejected(r#"[{"a":1},"#));
+            assert!(rejected(r#"[{"a":"1"#));
+            assert!(rejected(r#"{"a":{"b":1}"#));
+            assert!(rejected("["));
+        }
+
+        #[test]
+        fn refuses_content_after_the_array() {
+            assert!(rejected(r#"[{"a":1}] {"a":2}"#));
+            assert!(rejected(r#"[{"a":1}]]"#));
+            assert!(rejected(r#"[{"a":1}],"#));
+            assert!(split("[{\"a\":1}] \n", 1).is_ok());
+        }
+
+        #[test]
+        fn refuses_rows_over_the_limit() {
+            let body = format!(r#"[{{"a":"{}"}}]"#, "x".repeat(64));
+            assert!(matches!(split(&body, 7), Err(MyError::PayloadTooLarge(_))));
+        }
+    }
+}
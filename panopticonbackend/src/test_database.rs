@@ -0,0 +1,50 @@
+pub mod test_database {
+    use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+    use tokio::sync::OnceCell;
+    use tokio_postgres::NoTls;
+
+    /// Tests that need Postgres are ignored by default. Run them with
+    /// `cargo test -- --ignored` and TEST_DATABASE_URL naming a scratch database, whose
+    /// schemas are dropped and created again
+    pub async fn pool() -> Pool {
+        let url = std::env::var("TEST_DATABASE_URL")
+            .expect("TEST_DATABASE_URL must name a scratch database for the ignored tests");
+        let pool = pool_for(&url);
+        create_schema(&pool).await;
+        pool
+    }
+
+    /// A pool that only connects when a client is taken from it
+    pub fn pool_for(url: &str) -> Pool {
+        let manager = Manager::from_config(
+            url.parse().unwrap(),
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        Pool::builder(manager).max_size(4).build().unwrap()
+    }
+
+    // Once per test run. Tests use their own receipt numbers and sources, so they share it
+    async fn create_schema(pool: &Pool) {
+        static SCHEMA: OnceCell<()> = OnceCell::const_new();
+        SCHEMA
+            .get_or_init(|| async {
+                let schema = [
+                    include_str!("../sql/schema/create_tables.sql"),
+                    "drop schema if exists internal cascade;",
+                    include_str!("../sql/schema/users.sql"),
+                ]
+                .join("\n")
+                .lines()
+                // psql meta-commands are not SQL
+                .filter(|line| !line.starts_with('\\'))
+                .collect::<Vec<_>>()
+                .join("\n");
+                let client = pool.get().await.unwrap();
+                client.batch_execute(&schema).await.unwrap();
+            })
+            .await;
+    }
+}
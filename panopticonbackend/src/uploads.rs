@@ -0,0 +1,450 @@
+pub mod uploads {
+    use crate::access::access::Caller;
+    use crate::errors::errors::MyError;
+    use crate::models::models::{
+        upload_dates, ABSAInsert, AirtelStatementInsert, BalanceEntry, BankAccount,
+        BankStatementInsert, BillDetailsInsert, Categorizer, CfcInsert, CollectionDetailsInsert,
+        ContinuityReport, Job, LabVisitsInsert, MpesaStatementInsert, MtibaStatementInsert,
+        PdqBreakdownInsert, PeriodLock, ReconciliationEvent, SidianInsert, Upload, UploadRow,
+        UploadRowError, UploadSummary,
+    };
+    use crate::notifications::notifications::Notifier;
+    use crate::parsing::parsing::{Formats, RowFields, RowFormat, SourceFormat};
+    use crate::sources::sources::copy_rows_in;
+    use deadpool_postgres::{Client, Pool, Transaction};
+    use rust_decimal::Decimal;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeSet;
+
+    // Rows copied per batch. Progress is recorded after each one
+    const BATCH_SIZE: usize = 5000;
+    // Failed rows kept on the job. The rest are only counted
+    const MAX_ERRORS: usize = 100;
+
+    /// Sources accepted by /uploads/{source}, named as in their /statements/{source}/update routes
+    #[derive(Clone, Copy, Debug)]
+    pub enum UploadSource {
+        Mpesa,
+        Airtel,
+        CollectionDetails,
+        BillDetails,
+        LabVisits,
+        Mtiba,
+        Sidian,
+        Absa,
+        Pdq,
+        Cfc,
+        Equity,
+        Kcb,
+        Coop,
+        Ncba,
+    }
+
+    impl UploadSource {
+        pub const ALL: [UploadSource; 14] = [
+            UploadSource::Mpesa,
+            UploadSource::Airtel,
+            UploadSource::CollectionDetails,
+            UploadSource::BillDetails,
+            UploadSource::LabVisits,
+            UploadSource::Mtiba,
+            UploadSource::Sidian,
+            UploadSource::Absa,
+            UploadSource::Pdq,
+            UploadSource::Cfc,
+            UploadSource::Equity,
+            UploadSource::Kcb,
+            UploadSource::Coop,
+            UploadSource::Ncba,
+        ];
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                UploadSource::Mpesa => "mpesa",
+                UploadSource::Airtel => "airtel",
+                UploadSource::CollectionDetails => "collectiondetails",
+                UploadSource::BillDetails => "billdetails",
+                UploadSource::LabVisits => "labvisits",
+                UploadSource::Mtiba => "mtiba",
+                UploadSource::Sidian => "sidian",
+                UploadSource::Absa => "absa",
+                UploadSource::Pdq => "pdq",
+                UploadSource::Cfc => "cfc",
+                UploadSource::Equity => "equity",
+                UploadSource::Kcb => "kcb",
+                UploadSource::Coop => "coop",
+                UploadSource::Ncba => "ncba",
+            }
+        }
+
+        /// The account an upload of the source goes to. Rows of the HIS exports carry their
+        /// own unit instead
+        pub fn account_name(&self) -> Option<&'static str> {
+            match self {
+                UploadSource::CollectionDetails
+                | UploadSource::BillDetails
+                | UploadSource::LabVisits => None,
+                other => Some(other.name()),
+            }
+        }
+
+        pub fn from_name(name: &str) -> Result<UploadSource, MyError> {
+            UploadSource::ALL
+                .into_iter()
+                .find(|source| source.name() == name)
+                .ok_or_else(|| {
+                    MyError::BadRequest(format!(
+                        "Unknown upload source {}. Expected one of {}",
+                        name,
+                        UploadSource::ALL.map(|source| source.name()).join(", ")
+                    ))
+                })
+        }
+
+        // Bank accounts get a continuity check and categorization
+        fn account(&self) -> Option<BankAccount> {
+            match self {
+                UploadSource::Mpesa => Some(BankAccount::Mpesa),
+                UploadSource::Airtel => Some(BankAccount::Airtel),
+                UploadSource::Sidian => Some(BankAccount::Sidian),
+                UploadSource::Absa => Some(BankAccount::Absa),
+                UploadSource::Cfc => Some(BankAccount::Cfc),
+                UploadSource::Equity => Some(BankAccount::Equity),
+                UploadSource::Kcb => Some(BankAccount::Kcb),
+                UploadSource::Coop => Some(BankAccount::Coop),
+                UploadSource::Ncba => Some(BankAccount::Ncba),
+                _ => None,
+            }
+        }
+    }
+
+    /// Payload of upload jobs
+    #[derive(Deserialize, Serialize)]
+    pub struct UploadPayload {
+        pub source: String,
+        pub upload_id: i64,
+        /// M-Pesa paybill or till for rows that do not carry one
+        #[serde(default)]
+        pub shortcode: Option<String>,
+    }
+
+    /// Result of a finished upload job
+    #[derive(Serialize)]
+    pub struct UploadResult {
+        pub source: &'static str,
+        pub rows_processed: i64,
+        pub rows_inserted: usize,
+        pub rows_failed: i64,
+        pub rows_categorized: usize,
+        /// Only for bank accounts, and only over the rows copied by this attempt
+        pub continuity: Option<ContinuityReport>,
+    }
+
+    // Rows read back from the upload and how their source writes dates and amounts
+    struct Batch<'a> {
+        rows: &'a [UploadRow],
+        format: &'a SourceFormat,
+    }
+
+    // What one batch added. Notifications wait until the whole upload is committed
+    #[derive(Default)]
+    struct BatchOutcome {
+        rows_inserted: usize,
+        entries: Vec<Result<BalanceEntry, String>>,
+        payments: Vec<String>,
+        events: Vec<ReconciliationEvent>,
+    }
+
+    /// Copy a stored upload in batches, recording progress on the job as it goes. Rows that
+    /// cannot be read are skipped and listed on the job. Every batch is copied in one
+    /// transaction, so an upload that fails part way leaves nothing behind and a retry starts
+    /// again from the first row. The stored rows are purged with the commit
+    pub async fn process(
+        client: &mut Client,
+        db: &Pool,
+        notifier: &Notifier,
+        formats: &Formats,
+        job: &Job,
+    ) -> Result<serde_json::Value, MyError> {
+        let payload: UploadPayload = serde_json::from_value(job.payload.clone())?;
+        let source = UploadSource::from_name(&payload.source)?;
+        let format = formats.source(source.name());
+        // The access the requester has now. Scheduled jobs are not limited
+        let caller = match job.requested_by.as_deref() {
+            Some(username) => Some(Caller::for_username(client, username).await?),
+            None => None,
+        };
+
+        let upload = Upload::get_upload(&***client, payload.upload_id).await?;
+        if upload.purged_at.is_some() {
+            return Err(MyError::BadRequest(format!(
+                "The rows of upload {} were purged when its job finished. Upload the file again",
+                upload.id
+            )));
+        }
+
+        // Progress goes through its own connection, as nothing copied is seen before the commit
+        let progress: Client = db.get().await.map_err(MyError::PoolError)?;
+
+        let mut rows_processed = 0;
+        let mut rows_failed = 0;
+        let mut errors: Vec<UploadRowError> = Vec::new();
+        let mut result = UploadResult {
+            source: source.name(),
+            rows_processed,
+            rows_inserted: 0,
+            rows_failed,
+            rows_categorized: 0,
+            continuity: None,
+        };
+        let mut entries = Vec::new();
+        let mut payments = Vec::new();
+        let mut events = Vec::new();
+
+        let mut tx = client.transaction().await?;
+        let mut last_row = 0;
+        loop {
+            let rows = Upload::get_rows(&*tx, upload.id, last_row, BATCH_SIZE as i64).await?;
+            last_row = match rows.last() {
+                Some(row) => row.row_no,
+                None => break,
+            };
+
+            let mut batch_errors = Vec::new();
+            let outcome = write_batch(
+                &mut tx,
+                source,
+                payload.shortcode.as_deref(),
+                caller.as_ref(),
+                Batch {
+                    rows: &rows,
+                    format: &format,
+                },
+                &mut batch_errors,
+            )
+            .await?;
+
+            rows_processed += rows.len() as i64;
+            rows_failed += batch_errors.len() as i64;
+            let room = MAX_ERRORS.saturating_sub(errors.len());
+            errors.extend(batch_errors.into_iter().take(room));
+            Job::progress(&progress, job.id, rows_processed, rows_failed, &errors).await?;
+
+            result.rows_inserted += outcome.rows_inserted;
+            entries.extend(outcome.entries);
+            payments.extend(outcome.payments);
+            events.extend(outcome.events);
+        }
+        Upload::purge(&*tx, upload.id).await?;
+        tx.commit().await?;
+
+        if let Some(account) = source.account() {
+            result.rows_categorized = Categorizer::categorize_new_rows(client, account).await?;
+        }
+        events.extend(ReconciliationEvent::for_payments(client, &payments).await?);
+        notifier.publish(events);
+
+        result.rows_processed = rows_processed;
+        result.rows_failed = rows_failed;
+        result.continuity = source
+            .account()
+            .map(|account| UploadSummary::new(account, result.rows_inserted, entries).continuity);
+
+        Ok(serde_json::to_value(result)?)
+    }
+
+    /// Purge the stored rows of an upload whose job has failed for good
+    pub async fn discard(client: &Client, job: &Job) -> Result<(), MyError> {
+        let payload: UploadPayload = serde_json::from_value(job.payload.clone())?;
+        Upload::purge(&***client, payload.upload_id).await
+    }
+
+    // Read the dates and amounts of a batch's rows and deserialize them, keeping the rows
+    // that fail aside
+    fn read_rows<T: DeserializeOwned + RowFields>(
+        batch: &Batch,
+        errors: &mut Vec<UploadRowError>,
+    ) -> Vec<T> {
+        let format = RowFormat::<T>::new(batch.format.clone());
+        let mut rows = Vec::with_capacity(batch.rows.len());
+        for upload_row in batch.rows {
+            let mut value = upload_row.row.clone();
+            let row = format
+                .normalize(&mut value)
+                .and_then(|_| T::deserialize(value).map_err(|e| e.to_string()));
+            match row {
+                Ok(row) => rows.push(row),
+                Err(e) => errors.push(UploadRowError {
+                    row: upload_row.row_no as usize,
+                    error: e,
+                }),
+            }
+        }
+        rows
+    }
+
+    // Rows of a unit the requester does not read refuse the upload, as on /statements/*/update
+    fn ensure_units<'a>(
+        caller: Option<&Caller>,
+        mut units: impl Iterator<Item = Option<&'a str>>,
+    ) -> Result<(), MyError> {
+        match caller {
+            Some(caller) => units.try_for_each(|unit_name| caller.ensure_unit(unit_name)),
+            None => Ok(()),
+        }
+    }
+
+    // The same steps as the matching /statements/{source}/update handler
+    async fn write_batch(
+        tx: &mut Transaction<'_>,
+        source: UploadSource,
+        shortcode: Option<&str>,
+        caller: Option<&Caller>,
+        batch: Batch<'_>,
+        errors: &mut Vec<UploadRowError>,
+    ) -> Result<BatchOutcome, MyError> {
+        let mut outcome = BatchOutcome::default();
+        match source {
+            UploadSource::Mpesa => {
+                let mut datas: Vec<MpesaStatementInsert> = read_rows(&batch, errors);
+                for row in datas.iter_mut().filter(|row| row.shortcode.is_none()) {
+                    row.shortcode = shortcode.map(str::to_string);
+                }
+                if let Some(caller) = caller {
+                    let shortcodes: BTreeSet<_> =
+                        datas.iter().map(|m| m.shortcode.as_deref()).collect();
+                    for shortcode in shortcodes {
+                        caller
+                            .ensure_account_in(&**tx, BankAccount::Mpesa.name(), shortcode)
+                            .await?;
+                    }
+                }
+                let entries: Vec<_> = datas
+                    .iter()
+                    .map(|m| m.balance_entry(batch.format))
+                    .collect();
+                let payments: Vec<String> = datas
+                    .iter()
+                    .filter(|m| m.paid_in.unwrap_or(Decimal::ZERO) > Decimal::ZERO)
+                    .map(|m| m.receipt_no.clone())
+                    .collect();
+                PeriodLock::ensure_unlocked_in(
+                    &**tx,
+                    BankAccount::Mpesa.name(),
+                    upload_dates(&entries),
+                )
+                .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+                outcome.payments = payments;
+            }
+            UploadSource::Airtel => {
+                let datas: Vec<AirtelStatementInsert> = read_rows(&batch, errors);
+                let entries: Vec<_> = datas.iter().map(|m| m.balance_entry()).collect();
+                PeriodLock::ensure_unlocked_in(
+                    &**tx,
+                    BankAccount::Airtel.name(),
+                    upload_dates(&entries),
+                )
+                .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+            }
+            UploadSource::CollectionDetails => {
+                let datas: Vec<CollectionDetailsInsert> = read_rows(&batch, errors);
+                ensure_units(caller, datas.iter().map(|c| c.unit_name.as_deref()))?;
+                let dates = datas
+                    .iter()
+                    .filter_map(|c| batch.format.parse_date(&c.receipt_date))
+                    .map(|date| date.date())
+                    .collect();
+                PeriodLock::ensure_unlocked_in(&**tx, "mpesa", dates).await?;
+                outcome.events = ReconciliationEvent::for_collections_in(&**tx, &datas).await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+            }
+            UploadSource::BillDetails => {
+                let datas: Vec<BillDetailsInsert> = read_rows(&batch, errors);
+                ensure_units(caller, datas.iter().map(|b| Some(b.unit.as_str())))?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+            }
+            UploadSource::LabVisits => {
+                let datas: Vec<LabVisitsInsert> = read_rows(&batch, errors);
+                ensure_units(caller, datas.iter().map(|l| l.unit_name.as_deref()))?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+            }
+            UploadSource::Mtiba => {
+                let datas: Vec<MtibaStatementInsert> = read_rows(&batch, errors);
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+            }
+            UploadSource::Pdq => {
+                let datas: Vec<PdqBreakdownInsert> = read_rows(&batch, errors);
+                let dates = datas
+                    .iter()
+                    .filter_map(|p| {
+                        p.txn_date
+                            .as_deref()
+                            .and_then(|d| batch.format.parse_date(d))
+                    })
+                    .map(|date| date.date())
+                    .collect();
+                PeriodLock::ensure_unlocked_in(&**tx, "pdq", dates).await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+            }
+            UploadSource::Sidian => {
+                let datas: Vec<SidianInsert> = read_rows(&batch, errors);
+                let entries: Vec<_> = datas
+                    .iter()
+                    .map(|m| m.balance_entry(batch.format))
+                    .collect();
+                PeriodLock::ensure_unlocked_in(
+                    &**tx,
+                    BankAccount::Sidian.name(),
+                    upload_dates(&entries),
+                )
+                .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+            }
+            UploadSource::Absa => {
+                let datas: Vec<ABSAInsert> = read_rows(&batch, errors);
+                let entries: Vec<_> = datas
+                    .iter()
+                    .map(|m| m.balance_entry(batch.format))
+                    .collect();
+                PeriodLock::ensure_unlocked_in(
+                    &**tx,
+                    BankAccount::Absa.name(),
+                    upload_dates(&entries),
+                )
+                .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+            }
+            UploadSource::Cfc => {
+                let datas: Vec<CfcInsert> = read_rows(&batch, errors);
+                let entries: Vec<_> = datas.iter().map(|m| m.balance_entry()).collect();
+                PeriodLock::ensure_unlocked_in(
+                    &**tx,
+                    BankAccount::Cfc.name(),
+                    upload_dates(&entries),
+                )
+                .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+            }
+            UploadSource::Equity | UploadSource::Kcb | UploadSource::Coop | UploadSource::Ncba => {
+                let mut datas: Vec<BankStatementInsert> = read_rows(&batch, errors);
+                for row in &mut datas {
+                    row.bank = source.name().to_string();
+                }
+                let entries: Vec<_> = datas.iter().map(|m| m.balance_entry()).collect();
+                PeriodLock::ensure_unlocked_in(&**tx, source.name(), upload_dates(&entries))
+                    .await?;
+                outcome.rows_inserted = copy_rows_in(tx, &datas).await?;
+                outcome.entries = entries;
+            }
+        }
+        Ok(outcome)
+    }
+}